@@ -0,0 +1,8 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS credit_file_import_errors;
+DROP TABLE IF EXISTS credit_file_imports;
+
+DROP INDEX IF EXISTS credit_file_borrower_id_idx;
+
+ALTER TABLE credit_file ALTER COLUMN num_historical_failed_to_pay TYPE REAL;
+ALTER TABLE credit_file ALTER COLUMN num_collections_last_12m TYPE REAL;
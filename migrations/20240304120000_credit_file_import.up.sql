@@ -0,0 +1,40 @@
+-- Add up migration script here
+
+-- Historical credit files come from the loan dataset CSV and have no matching borrowers row
+ALTER TABLE credit_file DROP CONSTRAINT IF EXISTS fk_borrower;
+
+-- Match the CreditFile struct. These come in as "NA" or empty in the CSV
+ALTER TABLE credit_file ALTER COLUMN emp_title DROP NOT NULL;
+ALTER TABLE credit_file ALTER COLUMN emp_length DROP NOT NULL;
+ALTER TABLE credit_file ALTER COLUMN debt_to_income DROP NOT NULL;
+ALTER TABLE credit_file ALTER COLUMN total_credit_lines DROP NOT NULL;
+ALTER TABLE credit_file ALTER COLUMN num_accounts_120d_past_due DROP NOT NULL;
+ALTER TABLE credit_file ALTER COLUMN num_collections_last_12m TYPE INTEGER;
+ALTER TABLE credit_file ALTER COLUMN num_historical_failed_to_pay TYPE INTEGER;
+
+-- Lets a re-run skip rows that are already loaded
+CREATE UNIQUE INDEX IF NOT EXISTS credit_file_borrower_id_idx ON credit_file (borrower_id);
+
+CREATE TABLE IF NOT EXISTS credit_file_imports (
+        import_id SERIAL PRIMARY KEY,
+        file_name TEXT NOT NULL UNIQUE,
+        -- Last CSV row (1-based, excluding header) committed to credit_file
+        rows_committed INTEGER NOT NULL DEFAULT 0,
+        rows_inserted INTEGER NOT NULL DEFAULT 0,
+        rows_failed INTEGER NOT NULL DEFAULT 0,
+        status TEXT NOT NULL DEFAULT 'running',
+        started_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        finished_at TIMESTAMPTZ DEFAULT NULL
+    );
+
+CREATE TABLE IF NOT EXISTS credit_file_import_errors (
+        import_error_id SERIAL PRIMARY KEY,
+        import_id INTEGER NOT NULL,
+        row_num INTEGER NOT NULL,
+        column_name TEXT NOT NULL,
+        reason TEXT NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        CONSTRAINT fk_import
+            FOREIGN KEY(import_id) 
+	            REFERENCES credit_file_imports(import_id)
+    );
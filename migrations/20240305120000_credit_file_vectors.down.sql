@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS credit_file_vectors;
+DROP TABLE IF EXISTS credit_file_encoders;
@@ -0,0 +1,26 @@
+-- Add up migration script here
+
+-- Normalization fitted over credit_file when the vectors were built. Queries have to be
+-- encoded with the same scaler as the vectors they are compared against.
+CREATE TABLE IF NOT EXISTS credit_file_encoders (
+        encoder_id SERIAL PRIMARY KEY,
+        dims INTEGER NOT NULL,
+        scaler JSONB NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+    );
+
+CREATE TABLE IF NOT EXISTS credit_file_vectors (
+        borrower_id INTEGER PRIMARY KEY,
+        encoder_id INTEGER NOT NULL,
+        -- See libs::credit_file_vector::FEATURE_DIMS
+        embedding vector(44) NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        CONSTRAINT fk_credit_file
+            FOREIGN KEY(borrower_id) 
+	            REFERENCES credit_file(borrower_id) ON DELETE CASCADE,
+        CONSTRAINT fk_encoder
+            FOREIGN KEY(encoder_id) 
+	            REFERENCES credit_file_encoders(encoder_id)
+    );
+
+CREATE INDEX IF NOT EXISTS credit_file_vectors_encoder_idx ON credit_file_vectors (encoder_id);
@@ -0,0 +1,17 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS credit_scores (
+        credit_score_id SERIAL PRIMARY KEY,
+        application_id INTEGER NOT NULL,
+        -- Version of model_files/credit_scorer_v{n}.json that produced the score
+        model_version INTEGER NOT NULL,
+        pd DOUBLE PRECISION NOT NULL,
+        grade CHAR(1) NOT NULL,
+        sub_grade CHAR(2) NOT NULL,
+        top_features JSONB NOT NULL DEFAULT '[]',
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        CONSTRAINT fk_application
+            FOREIGN KEY(application_id) 
+	            REFERENCES applications(application_id)
+    );
+
+CREATE INDEX IF NOT EXISTS credit_scores_application_idx ON credit_scores (application_id);
@@ -0,0 +1,10 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS loan_payments;
+
+ALTER TABLE loans DROP COLUMN IF EXISTS last_accrual_date;
+ALTER TABLE loans DROP COLUMN IF EXISTS next_due_date;
+ALTER TABLE loans DROP COLUMN IF EXISTS remaining_term;
+ALTER TABLE loans DROP COLUMN IF EXISTS due_paid;
+ALTER TABLE loans DROP COLUMN IF EXISTS fees_due;
+ALTER TABLE loans DROP COLUMN IF EXISTS interest_due;
+ALTER TABLE loans DROP COLUMN IF EXISTS accrual_method;
@@ -0,0 +1,40 @@
+-- Add up migration script here
+
+-- Servicing state. Amounts stay REAL like the rest of loans; the ledger below is in cents.
+-- 1 = Daily (actual/365), 2 = Monthly (1/12 of the annual rate per whole month)
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS accrual_method INTEGER NOT NULL DEFAULT 2;
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS interest_due REAL NOT NULL DEFAULT 0.0;
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS fees_due REAL NOT NULL DEFAULT 0.0;
+-- Paid so far toward the installment due on next_due_date
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS due_paid REAL NOT NULL DEFAULT 0.0;
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS remaining_term INTEGER NULL;
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS next_due_date DATE NULL;
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS last_accrual_date DATE NULL;
+
+-- Payment ledger. Postings are never updated or deleted, a reversal is a new row with
+-- negated amounts pointing at the posting it undoes. All amounts in cents.
+CREATE TABLE IF NOT EXISTS loan_payments (
+        payment_id SERIAL PRIMARY KEY,
+        loan_id INTEGER NOT NULL,
+        -- 1 = Regular, 2 = Prepayment, 3 = Reversal
+        kind INTEGER NOT NULL,
+        effective_date DATE NOT NULL,
+        amount BIGINT NOT NULL,
+        accrued BIGINT NOT NULL DEFAULT 0,
+        fees BIGINT NOT NULL DEFAULT 0,
+        interest BIGINT NOT NULL DEFAULT 0,
+        principal BIGINT NOT NULL DEFAULT 0,
+        unapplied BIGINT NOT NULL DEFAULT 0,
+        principal_after BIGINT NOT NULL,
+        installment_after BIGINT NOT NULL,
+        reverses_payment_id INTEGER NULL UNIQUE,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        CONSTRAINT fk_loan
+            FOREIGN KEY(loan_id) 
+	            REFERENCES loans(loan_id),
+        CONSTRAINT fk_reverses
+            FOREIGN KEY(reverses_payment_id) 
+	            REFERENCES loan_payments(payment_id)
+    );
+
+CREATE INDEX IF NOT EXISTS loan_payments_loan_idx ON loan_payments (loan_id, payment_id);
@@ -0,0 +1,5 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS loan_notices;
+DROP TABLE IF EXISTS loan_status_history;
+ALTER TABLE loans DROP COLUMN IF EXISTS days_past_due;
+ALTER TABLE loans DROP COLUMN IF EXISTS late_fee_due_date;
@@ -0,0 +1,43 @@
+-- Add up migration script here
+
+-- Installment (its due date) the last late fee was assessed for, so reruns don't charge twice
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS late_fee_due_date DATE NULL;
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS days_past_due INTEGER NOT NULL DEFAULT 0;
+
+-- Late fees are assessed into the payment ledger too. 4 = LateFee, amount and fees are the fee charged.
+-- Reversing one waives it.
+
+CREATE TABLE IF NOT EXISTS loan_status_history (
+        history_id SERIAL PRIMARY KEY,
+        loan_id INTEGER NOT NULL,
+        from_status INTEGER NOT NULL,
+        to_status INTEGER NOT NULL,
+        days_past_due INTEGER NOT NULL,
+        as_of DATE NOT NULL,
+        changed_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        CONSTRAINT fk_loan
+            FOREIGN KEY(loan_id) 
+	            REFERENCES loans(loan_id)
+    );
+
+CREATE INDEX IF NOT EXISTS loan_status_history_loan_idx ON loan_status_history (loan_id, history_id);
+
+-- Notices waiting to go out. channel is 'email' or 'in_app', sent_at is set by whatever delivers them.
+CREATE TABLE IF NOT EXISTS loan_notices (
+        notice_id SERIAL PRIMARY KEY,
+        loan_id INTEGER NOT NULL,
+        history_id INTEGER NOT NULL,
+        channel TEXT NOT NULL,
+        loan_status INTEGER NOT NULL,
+        days_past_due INTEGER NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        sent_at TIMESTAMPTZ NULL,
+        CONSTRAINT fk_loan
+            FOREIGN KEY(loan_id) 
+	            REFERENCES loans(loan_id),
+        CONSTRAINT fk_history
+            FOREIGN KEY(history_id) 
+	            REFERENCES loan_status_history(history_id)
+    );
+
+CREATE INDEX IF NOT EXISTS loan_notices_unsent_idx ON loan_notices (created_at) WHERE sent_at IS NULL;
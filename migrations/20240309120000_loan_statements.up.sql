@@ -0,0 +1,23 @@
+-- Add up migration script here
+
+-- A generated statement per loan and month. The rendered files are attachments rows
+-- (mime types 15 text/html and 14 text/csv, channel 'Statement').
+CREATE TABLE IF NOT EXISTS loan_statements (
+        statement_id SERIAL PRIMARY KEY,
+        loan_id INTEGER NOT NULL,
+        period_start DATE NOT NULL,
+        period_end DATE NOT NULL,
+        html_attachment_id INTEGER NOT NULL,
+        csv_attachment_id INTEGER NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        UNIQUE (loan_id, period_start),
+        CONSTRAINT fk_loan
+            FOREIGN KEY(loan_id) 
+	            REFERENCES loans(loan_id),
+        CONSTRAINT fk_html_attachment
+            FOREIGN KEY(html_attachment_id) 
+	            REFERENCES attachments(attachment_id),
+        CONSTRAINT fk_csv_attachment
+            FOREIGN KEY(csv_attachment_id) 
+	            REFERENCES attachments(attachment_id)
+    );
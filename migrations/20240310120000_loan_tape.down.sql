@@ -0,0 +1,8 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS loan_tape_imports;
+
+ALTER TABLE loans DROP CONSTRAINT IF EXISTS loans_application_source_check;
+ALTER TABLE loans DROP COLUMN IF EXISTS source;
+
+DROP INDEX IF EXISTS loans_external_loan_id_idx;
+ALTER TABLE loans DROP COLUMN IF EXISTS external_loan_id;
@@ -0,0 +1,26 @@
+-- Add up migration script here
+
+-- The tape's own loan identifier, what a re-import matches on
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS external_loan_id TEXT NULL;
+CREATE UNIQUE INDEX IF NOT EXISTS loans_external_loan_id_idx ON loans (external_loan_id);
+
+-- Where the loan came from. Imported tape loans never had an application here, so only they can
+-- leave application_id NULL. Both foreign keys still hold, a tape row needs its borrowers row.
+ALTER TABLE loans ADD COLUMN IF NOT EXISTS source TEXT NOT NULL DEFAULT 'originated'
+    CHECK (source IN ('originated', 'imported'));
+UPDATE loans SET source = 'imported' WHERE application_id IS NULL OR external_loan_id IS NOT NULL;
+ALTER TABLE loans ALTER COLUMN application_id DROP NOT NULL;
+ALTER TABLE loans ADD CONSTRAINT loans_application_source_check
+    CHECK (application_id IS NOT NULL OR source = 'imported');
+
+CREATE TABLE IF NOT EXISTS loan_tape_imports (
+        import_id SERIAL PRIMARY KEY,
+        file_name TEXT NOT NULL,
+        rows_read INTEGER NOT NULL,
+        rows_inserted INTEGER NOT NULL,
+        rows_updated INTEGER NOT NULL,
+        rows_failed INTEGER NOT NULL,
+        -- The full LoanTapeReport, errors and totals included
+        report JSONB NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+    );
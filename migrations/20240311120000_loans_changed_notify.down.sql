@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TRIGGER IF EXISTS loan_status_history_changed ON loan_status_history;
+DROP TRIGGER IF EXISTS loans_changed ON loans;
+DROP FUNCTION IF EXISTS loans_changed_trigger();
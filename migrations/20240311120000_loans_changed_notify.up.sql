@@ -0,0 +1,25 @@
+-- Add up migration script here
+
+-- Once per statement so a tape import or delinquency run sends one notification, not one per row.
+-- The app drops its cached portfolio analytics when it hears this.
+CREATE or REPLACE FUNCTION loans_changed_trigger() RETURNS trigger AS $$
+  BEGIN
+    PERFORM pg_notify('loans_changed', json_build_object('table', TG_TABLE_NAME, 'action_type', TG_OP)::text );
+    RETURN NULL;
+  END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS loans_changed ON loans;
+CREATE TRIGGER loans_changed
+    AFTER INSERT OR UPDATE OR DELETE
+    ON "loans"
+    FOR EACH STATEMENT
+    EXECUTE PROCEDURE loans_changed_trigger();
+
+-- Status changes feed the roll rate and vintage queries
+DROP TRIGGER IF EXISTS loan_status_history_changed ON loan_status_history;
+CREATE TRIGGER loan_status_history_changed
+    AFTER INSERT OR UPDATE OR DELETE
+    ON "loan_status_history"
+    FOR EACH STATEMENT
+    EXECUTE PROCEDURE loans_changed_trigger();
@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS application_status_history;
+ALTER TABLE applications DROP COLUMN IF EXISTS application_status;
@@ -0,0 +1,26 @@
+-- Add up migration script here
+
+-- 1 Draft, 2 Submitted, 3 UnderReview, 4 OffersPresented, 5 OfferAccepted, 6 Funded, 7 Declined, 8 Withdrawn.
+-- Everything already in the table came in through apply, so it starts out Submitted.
+ALTER TABLE applications ADD COLUMN IF NOT EXISTS application_status INTEGER NOT NULL DEFAULT 1;
+UPDATE applications SET application_status = 2;
+
+-- from_status is NULL for the row that records how the application was created.
+-- actor_user_id is NULL when the system made the change, e.g. the credit scorer declining.
+CREATE TABLE IF NOT EXISTS application_status_history (
+        history_id SERIAL PRIMARY KEY,
+        application_id INTEGER NOT NULL,
+        from_status INTEGER NULL,
+        to_status INTEGER NOT NULL,
+        actor_user_id INTEGER NULL,
+        reason TEXT NULL,
+        changed_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        CONSTRAINT fk_application
+            FOREIGN KEY(application_id) 
+	            REFERENCES applications(application_id),
+        CONSTRAINT fk_actor
+            FOREIGN KEY(actor_user_id) 
+	            REFERENCES users(user_id)
+    );
+
+CREATE INDEX IF NOT EXISTS application_status_history_application_idx ON application_status_history (application_id, history_id);
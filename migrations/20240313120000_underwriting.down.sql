@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS underwriting_decisions;
+DROP TABLE IF EXISTS underwriting_rule_sets;
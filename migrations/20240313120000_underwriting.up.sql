@@ -0,0 +1,29 @@
+-- Add up migration script here
+
+-- Every rule set version decisions have been made under, as loaded from the rules file
+CREATE TABLE IF NOT EXISTS underwriting_rule_sets (
+        version INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        rules JSONB NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+    );
+
+-- decision: 1 Approve, 2 Refer, 3 Decline. reason_codes are the failed rules behind the decision,
+-- results holds every rule's outcome.
+CREATE TABLE IF NOT EXISTS underwriting_decisions (
+        decision_id SERIAL PRIMARY KEY,
+        application_id INTEGER NOT NULL,
+        rule_set_version INTEGER NOT NULL,
+        decision INTEGER NOT NULL,
+        reason_codes TEXT[] NOT NULL DEFAULT '{}',
+        results JSONB NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        CONSTRAINT fk_application
+            FOREIGN KEY(application_id) 
+	            REFERENCES applications(application_id),
+        CONSTRAINT fk_rule_set
+            FOREIGN KEY(rule_set_version) 
+	            REFERENCES underwriting_rule_sets(version)
+    );
+
+CREATE INDEX IF NOT EXISTS underwriting_decisions_application_idx ON underwriting_decisions (application_id, decision_id);
@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS adverse_action_notices;
+DROP TABLE IF EXISTS email_queue;
@@ -0,0 +1,45 @@
+-- Add up migration script here
+
+-- Outgoing email, sent by the mail relay through SendGrid or the local file sink.
+-- Rows that fail five times stay unsent with last_error for someone to look at.
+CREATE TABLE IF NOT EXISTS email_queue (
+        email_id SERIAL PRIMARY KEY,
+        to_address TEXT NOT NULL,
+        subject TEXT NOT NULL,
+        html_body TEXT NOT NULL,
+        text_body TEXT NOT NULL,
+        attachment_id INTEGER NULL,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        last_error TEXT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        sent_at TIMESTAMPTZ NULL,
+        CONSTRAINT fk_attachment
+            FOREIGN KEY(attachment_id) 
+	            REFERENCES attachments(attachment_id)
+    );
+
+CREATE INDEX IF NOT EXISTS email_queue_unsent_idx ON email_queue (email_id) WHERE sent_at IS NULL;
+
+-- One per declined application. decision_id is NULL when a reviewer declined outside the rules.
+-- email_id is NULL when there was no address to send to.
+CREATE TABLE IF NOT EXISTS adverse_action_notices (
+        notice_id SERIAL PRIMARY KEY,
+        application_id INTEGER NOT NULL UNIQUE,
+        decision_id INTEGER NULL,
+        attachment_id INTEGER NOT NULL,
+        email_id INTEGER NULL,
+        reason_codes TEXT[] NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        CONSTRAINT fk_application
+            FOREIGN KEY(application_id) 
+	            REFERENCES applications(application_id),
+        CONSTRAINT fk_decision
+            FOREIGN KEY(decision_id) 
+	            REFERENCES underwriting_decisions(decision_id),
+        CONSTRAINT fk_attachment
+            FOREIGN KEY(attachment_id) 
+	            REFERENCES attachments(attachment_id),
+        CONSTRAINT fk_email
+            FOREIGN KEY(email_id) 
+	            REFERENCES email_queue(email_id)
+    );
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS co_borrowers;
+ALTER TABLE applications DROP COLUMN IF EXISTS monthly_debt;
+ALTER TABLE applications DROP COLUMN IF EXISTS application_type;
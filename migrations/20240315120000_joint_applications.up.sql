@@ -0,0 +1,24 @@
+-- Add up migration script here
+
+-- 1 Individual, 2 Joint, as in models::loan::ApplicationType.
+-- monthly_debt is what the applicant says they pay each month, NULL when left blank.
+ALTER TABLE applications ADD COLUMN IF NOT EXISTS application_type INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE applications ADD COLUMN IF NOT EXISTS monthly_debt INTEGER NULL;
+
+-- One co-borrower per joint application. The SSN is digested the same way as the applicant's.
+CREATE TABLE IF NOT EXISTS co_borrowers (
+        co_borrower_id SERIAL PRIMARY KEY,
+        application_id INTEGER NOT NULL UNIQUE,
+        first_name TEXT NOT NULL,
+        last_name TEXT NOT NULL,
+        ssn_nacl TEXT NOT NULL,
+        dob DATE NOT NULL,
+        annual_income INTEGER NOT NULL,
+        employment_status INTEGER NOT NULL,
+        emp_length INTEGER NOT NULL,
+        monthly_debt INTEGER NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        CONSTRAINT fk_application
+            FOREIGN KEY(application_id) 
+	            REFERENCES applications(application_id)
+    );
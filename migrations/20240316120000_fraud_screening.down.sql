@@ -0,0 +1,7 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS fraud_flags;
+DROP INDEX IF EXISTS co_borrowers_ssn_nacl_idx;
+DROP INDEX IF EXISTS applications_zip_idx;
+DROP INDEX IF EXISTS applications_ip_address_idx;
+DROP INDEX IF EXISTS applications_ssn_nacl_idx;
+ALTER TABLE applications DROP COLUMN IF EXISTS ip_address;
@@ -0,0 +1,28 @@
+-- Add up migration script here
+
+-- Where the application was submitted from, for velocity checks. NULL for rows from before this.
+ALTER TABLE applications ADD COLUMN IF NOT EXISTS ip_address TEXT NULL;
+
+CREATE INDEX IF NOT EXISTS applications_ssn_nacl_idx ON applications (ssn_nacl);
+CREATE INDEX IF NOT EXISTS applications_ip_address_idx ON applications (ip_address, created_at);
+CREATE INDEX IF NOT EXISTS applications_zip_idx ON applications (zip, created_at);
+CREATE INDEX IF NOT EXISTS co_borrowers_ssn_nacl_idx ON co_borrowers (ssn_nacl);
+
+-- 1 SsnIdentityMismatch, 2 IpVelocity, 3 AddressVelocity, 4 StateZipMismatch, 5 NearDuplicateAddress.
+-- related_application_id is the earlier application the flag was raised against, if there is one.
+CREATE TABLE IF NOT EXISTS fraud_flags (
+        flag_id SERIAL PRIMARY KEY,
+        application_id INTEGER NOT NULL,
+        flag_kind INTEGER NOT NULL,
+        detail TEXT NOT NULL,
+        related_application_id INTEGER NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        CONSTRAINT fk_application
+            FOREIGN KEY(application_id) 
+	            REFERENCES applications(application_id),
+        CONSTRAINT fk_related_application
+            FOREIGN KEY(related_application_id) 
+	            REFERENCES applications(application_id)
+    );
+
+CREATE INDEX IF NOT EXISTS fraud_flags_application_idx ON fraud_flags (application_id);
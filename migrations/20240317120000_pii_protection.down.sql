@@ -0,0 +1,13 @@
+-- Add down migration script here
+-- Rows written since the up migration have no plaintext to go back to, so the NOT NULLs stay dropped.
+DROP INDEX IF EXISTS co_borrowers_ssn_hmac_idx;
+DROP INDEX IF EXISTS applications_ssn_hmac_idx;
+ALTER TABLE co_borrowers DROP COLUMN IF EXISTS dob_enc;
+ALTER TABLE co_borrowers DROP COLUMN IF EXISTS ssn_last4_enc;
+ALTER TABLE co_borrowers DROP COLUMN IF EXISTS ssn_hmac;
+ALTER TABLE applications DROP COLUMN IF EXISTS address_two_enc;
+ALTER TABLE applications DROP COLUMN IF EXISTS address_one_enc;
+ALTER TABLE applications DROP COLUMN IF EXISTS phone_enc;
+ALTER TABLE applications DROP COLUMN IF EXISTS dob_enc;
+ALTER TABLE applications DROP COLUMN IF EXISTS ssn_last4_enc;
+ALTER TABLE applications DROP COLUMN IF EXISTS ssn_hmac;
@@ -0,0 +1,26 @@
+-- Add up migration script here
+
+-- ssn_hmac is a peppered HMAC-SHA256 lookup key, `<pepper version>$<hex>`, see src/libs/pii.rs.
+-- The pepper can't be rotated: screening matches stored keys against each other and there's no
+-- plaintext SSN to re-key them with, so every row has to stay under the same pepper.
+-- The *_enc columns are AES-GCM envelopes, `<key version>$<wrapped data key>$<ciphertext>`.
+-- New rows leave the plaintext columns NULL. `cargo run -- pii_backfill` seals what's already
+-- there. The old unsalted ssn_nacl digests can't be re-keyed without the SSN, so they stay until
+-- the rows age out of fraud screening and are then cleared by pii_backfill.
+ALTER TABLE applications ADD COLUMN IF NOT EXISTS ssn_hmac TEXT NULL;
+ALTER TABLE applications ADD COLUMN IF NOT EXISTS ssn_last4_enc TEXT NULL;
+ALTER TABLE applications ADD COLUMN IF NOT EXISTS dob_enc TEXT NULL;
+ALTER TABLE applications ADD COLUMN IF NOT EXISTS phone_enc TEXT NULL;
+ALTER TABLE applications ADD COLUMN IF NOT EXISTS address_one_enc TEXT NULL;
+ALTER TABLE applications ADD COLUMN IF NOT EXISTS address_two_enc TEXT NULL;
+ALTER TABLE applications ALTER COLUMN ssn_nacl DROP NOT NULL;
+ALTER TABLE applications ALTER COLUMN address_one DROP NOT NULL;
+
+ALTER TABLE co_borrowers ADD COLUMN IF NOT EXISTS ssn_hmac TEXT NULL;
+ALTER TABLE co_borrowers ADD COLUMN IF NOT EXISTS ssn_last4_enc TEXT NULL;
+ALTER TABLE co_borrowers ADD COLUMN IF NOT EXISTS dob_enc TEXT NULL;
+ALTER TABLE co_borrowers ALTER COLUMN ssn_nacl DROP NOT NULL;
+ALTER TABLE co_borrowers ALTER COLUMN dob DROP NOT NULL;
+
+CREATE INDEX IF NOT EXISTS applications_ssn_hmac_idx ON applications (ssn_hmac);
+CREATE INDEX IF NOT EXISTS co_borrowers_ssn_hmac_idx ON co_borrowers (ssn_hmac);
@@ -0,0 +1,8 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS review_notes;
+DROP INDEX IF EXISTS applications_consultant_idx;
+DROP INDEX IF EXISTS applications_status_idx;
+ALTER TABLE applications DROP COLUMN IF EXISTS claimed_at;
+ALTER TABLE applications DROP COLUMN IF EXISTS claimed_by;
+ALTER TABLE applications DROP COLUMN IF EXISTS consultant_id;
+DROP TABLE IF EXISTS consultants;
@@ -0,0 +1,49 @@
+-- Add up migration script here
+
+-- Users who review applications. New applications are assigned to the least loaded active
+-- consultant in the territory of the location they came in through, national consultants
+-- (territory 1) pick up territories without one.
+CREATE TABLE IF NOT EXISTS consultants (
+        consultant_id SERIAL PRIMARY KEY,
+        user_id INTEGER NOT NULL UNIQUE,
+        territory_id INTEGER NOT NULL DEFAULT 1,
+        active BOOLEAN NOT NULL DEFAULT TRUE,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        CONSTRAINT fk_user
+            FOREIGN KEY(user_id) 
+	            REFERENCES users(user_id),
+        CONSTRAINT fk_territory
+            FOREIGN KEY(territory_id) 
+	            REFERENCES territories(territory_id)
+    );
+
+-- The seeded consultant users
+INSERT INTO consultants (user_id, territory_id)
+SELECT user_id, territory_id
+FROM (VALUES (8, 1), (9, 2), (10, 3), (11, 4), (12, 5), (13, 4), (14, 5)) AS seed (user_id, territory_id)
+WHERE EXISTS (SELECT 1 FROM users u WHERE u.user_id = seed.user_id)
+ON CONFLICT (user_id) DO NOTHING;
+
+-- consultant_id is who the application is assigned to, claimed_by the user working it right now
+ALTER TABLE applications ADD COLUMN IF NOT EXISTS consultant_id INTEGER NULL REFERENCES consultants(consultant_id);
+ALTER TABLE applications ADD COLUMN IF NOT EXISTS claimed_by INTEGER NULL REFERENCES users(user_id);
+ALTER TABLE applications ADD COLUMN IF NOT EXISTS claimed_at TIMESTAMPTZ NULL;
+
+CREATE INDEX IF NOT EXISTS applications_status_idx ON applications (application_status, created_at);
+CREATE INDEX IF NOT EXISTS applications_consultant_idx ON applications (consultant_id, application_status);
+
+CREATE TABLE IF NOT EXISTS review_notes (
+        note_id SERIAL PRIMARY KEY,
+        application_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        note TEXT NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        CONSTRAINT fk_application
+            FOREIGN KEY(application_id) 
+	            REFERENCES applications(application_id),
+        CONSTRAINT fk_user
+            FOREIGN KEY(user_id) 
+	            REFERENCES users(user_id)
+    );
+
+CREATE INDEX IF NOT EXISTS review_notes_application_idx ON review_notes (application_id, note_id);
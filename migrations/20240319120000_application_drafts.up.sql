@@ -0,0 +1,22 @@
+-- Add up migration script here
+
+-- Saved progress through the application wizard. data_enc is the steps saved so far as JSON,
+-- sealed like the applications' PII columns. Once submitted the data is dropped and the draft
+-- points at the application made from it.
+CREATE TABLE IF NOT EXISTS application_drafts (
+        draft_id SERIAL PRIMARY KEY,
+        user_id INTEGER NOT NULL,
+        step INTEGER NOT NULL DEFAULT 1,
+        data_enc TEXT NULL,
+        application_id INTEGER NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        CONSTRAINT fk_user
+            FOREIGN KEY(user_id) 
+	            REFERENCES users(user_id),
+        CONSTRAINT fk_application
+            FOREIGN KEY(application_id) 
+	            REFERENCES applications(application_id)
+    );
+
+CREATE INDEX IF NOT EXISTS application_drafts_user_idx ON application_drafts (user_id, updated_at);
@@ -0,0 +1,29 @@
+-- Add up migration script here
+
+-- Income and identity documents uploaded against an application. The file itself is an
+-- attachments row whose path is the key in the document storage backend.
+CREATE TABLE IF NOT EXISTS application_documents (
+        document_id SERIAL PRIMARY KEY,
+        application_id INTEGER NOT NULL,
+        attachment_id INTEGER NOT NULL UNIQUE,
+        document_type INTEGER NOT NULL,
+        file_name TEXT NULL,
+        byte_size INTEGER NOT NULL,
+        sha256 TEXT NOT NULL,
+        verification_status INTEGER NOT NULL DEFAULT 1,
+        verified_by INTEGER NULL,
+        verified_at TIMESTAMPTZ NULL,
+        verification_note TEXT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        CONSTRAINT fk_application
+            FOREIGN KEY(application_id) 
+	            REFERENCES applications(application_id),
+        CONSTRAINT fk_attachment
+            FOREIGN KEY(attachment_id) 
+	            REFERENCES attachments(attachment_id),
+        CONSTRAINT fk_verified_by
+            FOREIGN KEY(verified_by) 
+	            REFERENCES users(user_id)
+    );
+
+CREATE INDEX IF NOT EXISTS application_documents_application_idx ON application_documents (application_id);
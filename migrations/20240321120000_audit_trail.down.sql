@@ -0,0 +1,7 @@
+-- Add down migration script here
+DROP TRIGGER IF EXISTS users_audit ON users;
+DROP TRIGGER IF EXISTS loans_audit ON loans;
+DROP TRIGGER IF EXISTS offers_audit ON offers;
+DROP TRIGGER IF EXISTS applications_audit ON applications;
+DROP FUNCTION IF EXISTS audit_row_change();
+DROP TABLE IF EXISTS audit_log;
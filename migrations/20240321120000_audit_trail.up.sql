@@ -0,0 +1,82 @@
+-- Add up migration script here
+
+-- Before/after diffs of every change to the audited tables. The acting user and request come from
+-- the transaction-local audit.user_id and audit.request_id settings the app begins its writes with,
+-- see libs::audit_trail::begin_audited. No foreign key on the actor, the log outlives the users it names.
+CREATE TABLE IF NOT EXISTS audit_log (
+        audit_id BIGSERIAL PRIMARY KEY,
+        table_name TEXT NOT NULL,
+        entity_id INTEGER NOT NULL,
+        action INTEGER NOT NULL,
+        changes JSONB NOT NULL,
+        actor_user_id INTEGER NULL,
+        request_id TEXT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+    );
+
+CREATE INDEX IF NOT EXISTS audit_log_entity_idx ON audit_log (table_name, entity_id, audit_id);
+CREATE INDEX IF NOT EXISTS audit_log_request_idx ON audit_log (request_id) WHERE request_id IS NOT NULL;
+
+-- TG_ARGV[0] is the primary key column, TG_ARGV[1] the columns whose values are never copied into
+-- the log, only that they changed. updated_at is left out, it changes with everything else.
+-- changes is {"column": {"old": .., "new": ..}} with only the columns that differ.
+CREATE or REPLACE FUNCTION audit_row_change() RETURNS trigger AS $$
+DECLARE
+  pk TEXT := TG_ARGV[0];
+  redacted TEXT[] := COALESCE(TG_ARGV[1], '{}')::TEXT[];
+  old_row JSONB := CASE WHEN TG_OP = 'INSERT' THEN '{}'::JSONB ELSE to_jsonb(OLD) END;
+  new_row JSONB := CASE WHEN TG_OP = 'DELETE' THEN '{}'::JSONB ELSE to_jsonb(NEW) END;
+  changes JSONB;
+BEGIN
+  SELECT jsonb_object_agg(k.key, jsonb_build_object(
+           'old', CASE WHEN k.key = ANY(redacted) AND COALESCE(old_row->k.key, 'null') <> 'null'
+                    THEN '"[redacted]"'::JSONB ELSE COALESCE(old_row->k.key, 'null') END,
+           'new', CASE WHEN k.key = ANY(redacted) AND COALESCE(new_row->k.key, 'null') <> 'null'
+                    THEN '"[redacted]"'::JSONB ELSE COALESCE(new_row->k.key, 'null') END))
+    INTO changes
+    FROM jsonb_object_keys(old_row || new_row) AS k(key)
+    WHERE k.key <> 'updated_at'
+      AND COALESCE(old_row->k.key, 'null') IS DISTINCT FROM COALESCE(new_row->k.key, 'null');
+  IF changes IS NULL THEN
+    RETURN NULL;
+  END IF;
+  INSERT INTO audit_log (table_name, entity_id, action, changes, actor_user_id, request_id)
+  VALUES (
+    TG_TABLE_NAME,
+    ((CASE WHEN TG_OP = 'DELETE' THEN old_row ELSE new_row END)->>pk)::INTEGER,
+    CASE TG_OP WHEN 'INSERT' THEN 1 WHEN 'UPDATE' THEN 2 ELSE 3 END,
+    changes,
+    NULLIF(current_setting('audit.user_id', true), '')::INTEGER,
+    NULLIF(current_setting('audit.request_id', true), '')
+  );
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS applications_audit ON applications;
+CREATE TRIGGER applications_audit
+    AFTER INSERT OR UPDATE OR DELETE
+    ON "applications"
+    FOR EACH ROW
+    EXECUTE PROCEDURE audit_row_change('application_id', '{first_name,last_name,address_one,address_two,phone,dob,ssn_nacl,ssn_hmac,ssn_last4_enc,dob_enc,phone_enc,address_one_enc,address_two_enc,ip_address}');
+
+DROP TRIGGER IF EXISTS offers_audit ON offers;
+CREATE TRIGGER offers_audit
+    AFTER INSERT OR UPDATE OR DELETE
+    ON "offers"
+    FOR EACH ROW
+    EXECUTE PROCEDURE audit_row_change('offer_id');
+
+DROP TRIGGER IF EXISTS loans_audit ON loans;
+CREATE TRIGGER loans_audit
+    AFTER INSERT OR UPDATE OR DELETE
+    ON "loans"
+    FOR EACH ROW
+    EXECUTE PROCEDURE audit_row_change('loan_id');
+
+DROP TRIGGER IF EXISTS users_audit ON users;
+CREATE TRIGGER users_audit
+    AFTER INSERT OR UPDATE OR DELETE
+    ON "users"
+    FOR EACH ROW
+    EXECUTE PROCEDURE audit_row_change('user_id', '{secret,password}');
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TRIGGER IF EXISTS event_outbox_notify ON event_outbox;
+DROP FUNCTION IF EXISTS event_outbox_notify();
+DROP TABLE IF EXISTS event_outbox;
@@ -0,0 +1,34 @@
+-- Add up migration script here
+
+-- Domain events, written in the same transaction as the change they describe and published
+-- by the outbox relay. delivered_to lists the sinks that already have the event so a retry
+-- only goes to the ones that failed. Rows that fail ten times stay undelivered with
+-- last_error for someone to look at.
+CREATE TABLE IF NOT EXISTS event_outbox (
+        event_id BIGSERIAL PRIMARY KEY,
+        event_type VARCHAR(64) NOT NULL,
+        aggregate_id INTEGER NOT NULL,
+        payload JSONB NOT NULL,
+        delivered_to TEXT[] NOT NULL DEFAULT '{}',
+        attempts INTEGER NOT NULL DEFAULT 0,
+        last_error TEXT NULL,
+        next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        delivered_at TIMESTAMPTZ NULL
+    );
+
+CREATE INDEX IF NOT EXISTS event_outbox_undelivered_idx ON event_outbox (event_id) WHERE delivered_at IS NULL;
+
+-- Wakes the relay once the inserting transaction commits, it polls as well in case this is missed
+CREATE OR REPLACE FUNCTION event_outbox_notify() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('outbox_event', '{}');
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER event_outbox_notify
+    AFTER INSERT
+    ON event_outbox
+    FOR EACH STATEMENT
+    EXECUTE PROCEDURE event_outbox_notify();
@@ -0,0 +1,5 @@
+-- Add down migration script here
+DROP TRIGGER IF EXISTS entry_types_table_update ON entry_types;
+DROP TRIGGER IF EXISTS states_table_update ON states;
+DROP TRIGGER IF EXISTS applications_table_update ON applications;
+DROP FUNCTION IF EXISTS table_update_notify();
@@ -0,0 +1,34 @@
+-- Add up migration script here
+
+-- Tells the app which cached row changed. TG_ARGV[0] is the primary key column, sent as id.
+CREATE OR REPLACE FUNCTION table_update_notify() RETURNS trigger AS $$
+DECLARE
+    row_data JSONB;
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        row_data = to_jsonb(OLD);
+    ELSE
+        row_data = to_jsonb(NEW);
+    END IF;
+    PERFORM pg_notify('table_update', json_build_object('table', TG_TABLE_NAME, 'action_type', TG_OP, 'id', (row_data ->> TG_ARGV[0])::INTEGER)::text);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER applications_table_update
+    AFTER INSERT OR UPDATE OR DELETE
+    ON applications
+    FOR EACH ROW
+    EXECUTE PROCEDURE table_update_notify('application_id');
+
+CREATE TRIGGER states_table_update
+    AFTER INSERT OR UPDATE OR DELETE
+    ON states
+    FOR EACH ROW
+    EXECUTE PROCEDURE table_update_notify('state_id');
+
+CREATE TRIGGER entry_types_table_update
+    AFTER INSERT OR UPDATE OR DELETE
+    ON entry_types
+    FOR EACH ROW
+    EXECUTE PROCEDURE table_update_notify('entry_type_id');
@@ -0,0 +1,5 @@
+-- Add down migration script here
+DROP TRIGGER IF EXISTS email_queue_sent ON email_queue;
+DROP FUNCTION IF EXISTS loan_notice_sent();
+
+ALTER TABLE loan_notices DROP COLUMN IF EXISTS email_id;
@@ -0,0 +1,21 @@
+-- Add up migration script here
+
+-- Notices go out through email_queue, queued with the status change. In-app delivery is the
+-- loan_status_changed outbox event, so only email notices are kept here now.
+ALTER TABLE loan_notices ADD COLUMN IF NOT EXISTS email_id INTEGER NULL
+    REFERENCES email_queue(email_id);
+DELETE FROM loan_notices WHERE channel = 'in_app';
+
+-- sent_at follows the email, set when the mail relay marks it sent
+CREATE OR REPLACE FUNCTION loan_notice_sent() RETURNS trigger AS $$
+BEGIN
+    UPDATE loan_notices SET sent_at = NEW.sent_at WHERE email_id = NEW.email_id AND sent_at IS NULL;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS email_queue_sent ON email_queue;
+CREATE TRIGGER email_queue_sent
+    AFTER UPDATE OF sent_at ON email_queue
+    FOR EACH ROW WHEN (NEW.sent_at IS NOT NULL AND OLD.sent_at IS NULL)
+    EXECUTE FUNCTION loan_notice_sent();
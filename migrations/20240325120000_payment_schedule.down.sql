@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE loan_payments DROP COLUMN IF EXISTS due_paid_before;
+ALTER TABLE loan_payments DROP COLUMN IF EXISTS installment_before;
+ALTER TABLE loan_payments DROP COLUMN IF EXISTS remaining_term_before;
@@ -0,0 +1,7 @@
+-- Add up migration script here
+
+-- The schedule each posting found, so reversing a payoff can put it back. NULL on postings made
+-- before these were recorded.
+ALTER TABLE loan_payments ADD COLUMN IF NOT EXISTS remaining_term_before INTEGER NULL;
+ALTER TABLE loan_payments ADD COLUMN IF NOT EXISTS installment_before BIGINT NULL;
+ALTER TABLE loan_payments ADD COLUMN IF NOT EXISTS due_paid_before BIGINT NULL;
@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TRIGGER IF EXISTS credit_file_changed ON credit_file;
+DROP FUNCTION IF EXISTS credit_file_changed_trigger();
@@ -0,0 +1,17 @@
+-- Add up migration script here
+
+-- Once per statement, so an import sends one notification per batch rather than per row.
+-- The app drops its cached credit file profile when it hears this.
+CREATE or REPLACE FUNCTION credit_file_changed_trigger() RETURNS trigger AS $$
+  BEGIN
+    PERFORM pg_notify('credit_file_changed', json_build_object('table', TG_TABLE_NAME, 'action_type', TG_OP)::text );
+    RETURN NULL;
+  END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS credit_file_changed ON credit_file;
+CREATE TRIGGER credit_file_changed
+    AFTER INSERT OR UPDATE OR DELETE
+    ON "credit_file"
+    FOR EACH STATEMENT
+    EXECUTE PROCEDURE credit_file_changed_trigger();
@@ -0,0 +1,2 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS credit_file_vectors_embedding_idx;
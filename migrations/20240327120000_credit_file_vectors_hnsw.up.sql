@@ -0,0 +1,6 @@
+-- Add up migration script here
+
+-- Nearest neighbour search orders by embedding <-> (L2), so the index uses the L2 opclass.
+-- Without it every similar borrowers query scans and sorts the whole table.
+CREATE INDEX IF NOT EXISTS credit_file_vectors_embedding_idx
+    ON credit_file_vectors USING hnsw (embedding vector_l2_ops);
@@ -1,5 +1,4 @@
 use chrono::{Datelike, NaiveDate, Utc};
-use csv::Reader;
 use fastembed::TextEmbedding;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
@@ -11,7 +10,8 @@ use std::{fs::File, io::Write};
 use tokio::sync::{broadcast, mpsc, oneshot};
 use tokio::time::{sleep, Duration};
 
-use crate::models::credit_file::CreditFile;
+use crate::error::AppError;
+use crate::libs::credit_file_import::{import_credit_file, ImportProgress, ImportReport};
 use crate::models::offer::Offer;
 
 pub struct Actor {
@@ -66,8 +66,12 @@ pub enum ActorMessage {
         // respond_to: Option<mpsc::Sender<ActorMessage>>,
     },
     PopulateDB {
-        text: String,
+        file_name: String,
         respond_to: Option<oneshot::Sender<ActorMessage>>,
+        pool: Option<PgPool>,
+        progress_tx: Option<broadcast::Sender<ImportProgress>>,
+        /// Set on the reply
+        report: Option<Result<ImportReport, AppError>>,
     },
 }
 
@@ -107,8 +111,8 @@ pub fn mock_offer(servicer_id: i32) -> Offer {
     Offer {
         offer_slug: Uuid::new_v4().to_string(),
         servicer_id: servicer_id,
-        max_amount: test_mins[rand::thread_rng().gen_range(0..test_mins.len())],
-        min_amount: test_maxes[rand::thread_rng().gen_range(0..test_maxes.len())],
+        max_amount: test_maxes[rand::thread_rng().gen_range(0..test_maxes.len())],
+        min_amount: test_mins[rand::thread_rng().gen_range(0..test_mins.len())],
         terms: terms[rand::thread_rng().gen_range(0..terms.len())],
         percent_fee: percent_fees[rand::thread_rng().gen_range(0..percent_fees.len())],
         apr: aprs[rand::thread_rng().gen_range(0..aprs.len())],
@@ -235,28 +239,31 @@ impl Actor {
                 // let msg = ActorMessage::RegularMessage { text: "Hey".to_owned() };
                 // let _ = respond_to.unwrap().send(msg);
             }
-            ActorMessage::PopulateDB { respond_to, text } => {
-                let file_name = "assets/data/____credit_file.csv";
-                // let file_contents = fs::read_to_string(file_name).expect("Cannot read file");
-                // let mut rdr = Reader::from_reader(file_contents.as_bytes());
-                let result = Reader::from_path(file_name);
-                if result.is_err() {
-                    println!("Error w/ CSV");
-                    std::process::exit(9);
+            ActorMessage::PopulateDB { respond_to, file_name, pool, progress_tx, .. } => {
+                tracing::info!(file_name, "Credit file import requested");
+                let result = match pool {
+                    Some(pool) => import_credit_file(&pool, &file_name, progress_tx).await,
+                    None => Err(AppError::GenericError("PopulateDB was sent without a pool".to_owned())),
+                };
+                match &result {
+                    Ok(report) => tracing::info!(
+                        file_name,
+                        inserted = report.progress.inserted,
+                        skipped = report.progress.skipped,
+                        failed = report.progress.failed,
+                        "Credit file imported"
+                    ),
+                    Err(err) => tracing::error!(file_name, error = ?err, "Credit file import failed"),
+                }
+                if let Some(sender) = respond_to {
+                    let _ = sender.send(ActorMessage::PopulateDB {
+                        file_name,
+                        respond_to: None,
+                        pool: None,
+                        progress_tx: None,
+                        report: Some(result),
+                    });
                 }
-                let mut rdr = result.unwrap();
-                // let mut rows = rdr.deserialize().map(|r| r.unwrap()).collect::<Vec<Review>>();
-                // for record in rdr.records() {
-                //     println!("First field is {}", record.unwrap().get(0).unwrap())
-                // }
-                let mut rows = rdr
-                    .deserialize()
-                    .map(|r| r.unwrap())
-                    .collect::<Vec<CreditFile>>();
-
-                rows.iter()
-                    .take(20)
-                    .for_each(|r| println!("{:?} & {:?}", r.emp_title, r.months_since_last_delinq));
             }
             ActorMessage::FetchSimilars { respond_to, embeddings, similars, pool } => {
                 println!("fetch huh");
@@ -0,0 +1,206 @@
+use std::path::Path;
+
+use chrono::{NaiveDate, Utc};
+
+use crate::{
+    libs::{
+        credit_file_import::{import_credit_file, DEFAULT_CREDIT_FILE_CSV},
+        credit_file_vector::build_credit_file_vectors,
+        credit_scorer::{train_credit_scorer, DEFAULT_MODEL_DIR},
+        delinquency::{run_delinquency, DelinquencyConfig},
+        loan_tape::{export_loan_tape, import_loan_tape},
+        pii::{backfill, key_file_path, rewrap_all, Keyring},
+    },
+    models::store::new_db_pool,
+};
+
+/// One-off jobs run as ```cargo run -- <command> [args]``` instead of serving.
+/// Returns `None` when `command` is not one of them.
+pub async fn run_command(
+    command: &str,
+    args: &[String],
+) -> Option<Result<(), Box<dyn std::error::Error>>> {
+    match command {
+        "import_credit_file" => Some(import_credit_file_cmd(args).await),
+        "build_credit_file_vectors" => Some(build_credit_file_vectors_cmd().await),
+        "train_credit_scorer" => Some(train_credit_scorer_cmd(args).await),
+        "run_delinquency" => Some(run_delinquency_cmd(args).await),
+        "import_loan_tape" => Some(import_loan_tape_cmd(args).await),
+        "export_loan_tape" => Some(export_loan_tape_cmd(args).await),
+        "pii_new_key" => Some(pii_new_key_cmd()),
+        "pii_rewrap" => Some(pii_rewrap_cmd().await),
+        "pii_backfill" => Some(pii_backfill_cmd().await),
+        _ => None,
+    }
+}
+
+async fn import_credit_file_cmd(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    dotenv::dotenv().ok();
+    let file_name = args.first().map(String::as_str).unwrap_or(DEFAULT_CREDIT_FILE_CSV);
+    let pool = new_db_pool().await?;
+    let report = import_credit_file(&pool, file_name, None).await?;
+    for err in &report.errors {
+        println!("row {} [{}]: {}", err.row, err.column, err.reason);
+    }
+    println!(
+        "Imported {} from {}: {} processed, {} inserted, {} skipped, {} failed",
+        report.import_id,
+        file_name,
+        report.progress.processed,
+        report.progress.inserted,
+        report.progress.skipped,
+        report.progress.failed
+    );
+    Ok(())
+}
+
+async fn build_credit_file_vectors_cmd() -> Result<(), Box<dyn std::error::Error>> {
+    dotenv::dotenv().ok();
+    let pool = new_db_pool().await?;
+    let (encoder_id, count) = build_credit_file_vectors(&pool).await?;
+    println!("Encoded {} credit files with encoder {}", count, encoder_id);
+    Ok(())
+}
+
+async fn train_credit_scorer_cmd(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    dotenv::dotenv().ok();
+    let dir = args
+        .first()
+        .map(String::as_str)
+        .unwrap_or(DEFAULT_MODEL_DIR);
+    let pool = new_db_pool().await?;
+    let (scorer, path) = train_credit_scorer(&pool, dir).await?;
+    println!(
+        "Trained credit scorer v{} on {} rows (bad rate {:.3}, log loss {:.4}, AUC {:.3}) -> {}",
+        scorer.version,
+        scorer.metrics.rows,
+        scorer.metrics.bad_rate,
+        scorer.metrics.log_loss,
+        scorer.metrics.auc,
+        path.display()
+    );
+    Ok(())
+}
+
+/// ```cargo run -- run_delinquency [YYYY-MM-DD]```, defaults to today.
+async fn run_delinquency_cmd(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    dotenv::dotenv().ok();
+    let as_of = match args.first() {
+        Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d")?,
+        None => Utc::now().date_naive(),
+    };
+    let config = DelinquencyConfig::from_env()?;
+    let pool = new_db_pool().await?;
+    let report = run_delinquency(&pool, as_of, &config).await?;
+    println!(
+        "Delinquency as of {}: {} loans, {} status changes, {} late fees (${:.2}), {} notices, {} failed",
+        report.as_of,
+        report.loans,
+        report.transitions,
+        report.late_fees,
+        report.late_fee_total as f64 / 100.0,
+        report.notices,
+        report.failed
+    );
+    Ok(())
+}
+
+/// ```cargo run -- import_loan_tape <file> [servicer_id]```, servicer defaults to 1.
+async fn import_loan_tape_cmd(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    dotenv::dotenv().ok();
+    let file_name = args.first().ok_or("import_loan_tape needs a file name")?;
+    let servicer_id = match args.get(1) {
+        Some(id) => id.parse()?,
+        None => 1,
+    };
+    let pool = new_db_pool().await?;
+    let report = import_loan_tape(&pool, file_name, servicer_id).await?;
+    for err in &report.errors {
+        println!("row {} [{}]: {}", err.row, err.column, err.reason);
+    }
+    for (column, values) in &report.unmapped {
+        for (value, count) in values {
+            println!("unmapped {} '{}': {} rows", column, value, count);
+        }
+    }
+    println!(
+        "Imported tape {} from {}: {} read, {} inserted, {} updated, {} failed",
+        report.import_id,
+        file_name,
+        report.rows_read,
+        report.inserted,
+        report.updated,
+        report.failed
+    );
+    println!(
+        "File totals {:?}\nLoaded totals {:?}\n{}",
+        report.file_totals,
+        report.loaded_totals,
+        if report.reconciled {
+            "Reconciled"
+        } else {
+            "NOT reconciled"
+        }
+    );
+    Ok(())
+}
+
+async fn export_loan_tape_cmd(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    dotenv::dotenv().ok();
+    let file_name = args.first().ok_or("export_loan_tape needs a file name")?;
+    let pool = new_db_pool().await?;
+    let export = export_loan_tape(&pool, file_name).await?;
+    for (loan_id, reason) in &export.skipped {
+        println!("skipped loan {}: {}", loan_id, reason);
+    }
+    println!("Exported {} loans to {}", export.rows, file_name);
+    Ok(())
+}
+
+/// ```cargo run -- pii_new_key```, creates the key file on a new install, otherwise adds a key
+/// and makes it current. Follow with pii_rewrap to move stored values onto it.
+fn pii_new_key_cmd() -> Result<(), Box<dyn std::error::Error>> {
+    dotenv::dotenv().ok();
+    let path = key_file_path();
+    let path = Path::new(&path);
+    let keyring = if path.exists() {
+        let mut keyring = Keyring::load(path)?;
+        keyring.rotate_key();
+        keyring
+    } else {
+        Keyring::generate()
+    };
+    keyring.save(path)?;
+    println!(
+        "PII key version {} is current in {}",
+        keyring.current_key(),
+        path.display()
+    );
+    Ok(())
+}
+
+async fn pii_rewrap_cmd() -> Result<(), Box<dyn std::error::Error>> {
+    dotenv::dotenv().ok();
+    let keyring = Keyring::load_active()?;
+    let pool = new_db_pool().await?;
+    let rewrapped = rewrap_all(&pool, &keyring).await?;
+    println!(
+        "Rewrapped {} values under key version {}",
+        rewrapped,
+        keyring.current_key()
+    );
+    Ok(())
+}
+
+/// ```cargo run -- pii_backfill```, seals plaintext PII left from before encryption
+async fn pii_backfill_cmd() -> Result<(), Box<dyn std::error::Error>> {
+    dotenv::dotenv().ok();
+    let keyring = Keyring::load_active()?;
+    let pool = new_db_pool().await?;
+    let report = backfill(&pool, &keyring).await?;
+    println!(
+        "Sealed {} applications and {} co-borrowers, cleared {} legacy SSN digests",
+        report.applications, report.co_borrowers, report.ssn_digests_cleared
+    );
+    Ok(())
+}
@@ -61,6 +61,7 @@ fn get_env(name: &'static str) -> Result<String, AppError> {
 }
 
 use crate::error::AppError;
+use crate::libs::address::StreetAddress;
 use crate::web::AppState;
 
 lazy_static! {
@@ -73,27 +74,6 @@ lazy_static! {
         r"^([a-z0-9_+]([a-z0-9_+.]*[a-z0-9_+])?)@([a-z0-9]+([\-\.]{1}[a-z0-9]+)*\.[a-z]{2,6})"
     )
     .unwrap();
-    pub static ref ACCEPTED_SECONDARIES: Vec<&'static str> = vec![
-        "Apt",
-        "Apt.",
-        "Ste",
-        "Ste.",
-        "Suite",
-        "Apartment",
-        "#",
-        "Pt.",
-        "No.",
-        "No",
-        "Unit",
-        "Ut",
-        "Un.",
-        "Un",
-        "Ut."
-    ];
-    pub static ref ACCEPTED_PRIMARIES: Vec<&'static str> = vec![
-        "St.", "St", "Street", "Ave.", "Av.", "Ave", "Avenue", "Parkway", "Pkwy", "Pkwy.", "Dr.",
-        "Dr", "Drive", "Ln", "Lane", "Ln."
-    ];
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -211,6 +191,22 @@ impl StringSelectOption {
     }
 }
 
+#[derive(Debug, Validate, Serialize, FromRow, Clone, Deserialize)]
+pub struct StringSelectOptionsVec {
+    pub vec: Vec<StringSelectOption>,
+}
+
+impl FromRedisValue for StringSelectOptionsVec {
+    fn from_redis_value(v: &Value) -> RedisResult<Self> {
+        let v: String = from_redis_value(v)?;
+        let result: Self = match serde_json::from_str::<Self>(&v) {
+            Ok(v) => v,
+            Err(_err) => return Err((ErrorKind::TypeError, "Parse to JSON Failed").into()),
+        };
+        Ok(result)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Todo {
     pub todo: String,
@@ -296,38 +292,52 @@ pub struct EntryType {
     entry_type_id: i32,
 }
 
+pub async fn fetch_state_options(pool: &Pool<Postgres>) -> Result<Vec<StringSelectOption>, sqlx::Error> {
+    let state_list = sqlx::query_as::<_, State>("SELECT state_name FROM states").fetch_all(pool).await?;
+    Ok(state_list
+        .iter()
+        .map(|state| StringSelectOption::new(&state.state_name, &state.state_name))
+        .collect::<Vec<StringSelectOption>>())
+}
+
 pub async fn get_state_options(pool: &Pool<Postgres>) -> Vec<StringSelectOption> {
-    match sqlx::query_as::<_, State>("SELECT state_name FROM states")
-        .fetch_all(pool)
-        .await
-    {
-        Ok(state_list) => state_list
-            .iter()
-            .map(|state| StringSelectOption::new(&state.state_name, &state.state_name))
-            .collect::<Vec<StringSelectOption>>(),
+    match fetch_state_options(pool).await {
+        Ok(state_options) => state_options,
         Err(err) => {
             dbg!(&err);
-            vec![StringSelectOption::new("Select One", "Select One")]
+            default_state_option()
         }
     }
 }
 
-pub async fn get_entry_type_options(pool: &Pool<Postgres>) -> Vec<SelectOption> {
-    match sqlx::query_as::<_, EntryType>("SELECT entry_type_id, entry_type_name FROM entry_types")
+pub fn default_state_option() -> Vec<StringSelectOption> {
+    vec![StringSelectOption::new("Select One", "Select One")]
+}
+
+pub async fn fetch_entry_type_options(pool: &Pool<Postgres>) -> Result<Vec<SelectOption>, sqlx::Error> {
+    let entry_type_list = sqlx::query_as::<_, EntryType>("SELECT entry_type_id, entry_type_name FROM entry_types")
         .fetch_all(pool)
-        .await
-    {
-        Ok(entry_type_list) => entry_type_list
-            .iter()
-            .map(|entry_type| SelectOption::from((entry_type.entry_type_id, entry_type.entry_type_name.clone())))
-            .collect::<Vec<SelectOption>>(),
+        .await?;
+    Ok(entry_type_list
+        .iter()
+        .map(|entry_type| SelectOption::from((entry_type.entry_type_id, entry_type.entry_type_name.clone())))
+        .collect::<Vec<SelectOption>>())
+}
+
+pub async fn get_entry_type_options(pool: &Pool<Postgres>) -> Vec<SelectOption> {
+    match fetch_entry_type_options(pool).await {
+        Ok(entry_type_options) => entry_type_options,
         Err(err) => {
             dbg!(&err);
-            vec![SelectOption::from((1, "Select One".to_owned()))]
+            default_entry_type_option()
         }
     }
 }
 
+pub fn default_entry_type_option() -> Vec<SelectOption> {
+    vec![SelectOption::from((1, "Select One".to_owned()))]
+}
+
 lazy_static! {
     static ref START_TIME: Instant = Instant::now();
 }
@@ -558,63 +568,31 @@ pub fn validate_email(email: &str) -> Result<(), ValidationError> {
     }
 }
 
+/// Street number, name and suffix, parsed by `libs::address`
 pub fn validate_primary_address(addr: &str) -> Result<(), ValidationError> {
-    if !addr.contains(" ") {
-        return Err(ValidationError {
-            code: std::borrow::Cow::Borrowed("contain"),
-            message: Some(Cow::from("Primary Address must contain a space.")),
+    match StreetAddress::parse_line(addr) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(ValidationError {
+            code: std::borrow::Cow::Borrowed("street"),
+            message: Some(Cow::from(err.to_string())),
             params: HashMap::new(),
-        });
-    }
-    let street_strings: Vec<&str> = addr.split(" ").collect::<Vec<&str>>().to_owned();
-    let ss_len = street_strings.len();
-    // Getting last two to account for 101 Hartford St. W etc..
-    if ACCEPTED_PRIMARIES.contains(&street_strings[ss_len - 1])
-        || ACCEPTED_PRIMARIES.contains(&street_strings[ss_len - 2])
-    {
-        Ok(())
-    } else {
-        Err(ValidationError {
-            code: std::borrow::Cow::Borrowed("identifier"),
-            message: Some(Cow::from(
-                "Primary Address must contain a valid Identifier (St., Ave, Lane ...)",
-            )),
-            params: HashMap::new(),
-        })
+        }),
     }
 }
 
 pub fn validate_secondary_address(addr_two: &str) -> Result<(), ValidationError> {
     // No input comes in as blank Some(""). These get turned into NULLs in DB.
-    if addr_two == "" {
+    if addr_two.trim().is_empty() {
         return Ok(());
     }
-    let len_range = 3..15;
-    if !len_range.contains(&addr_two.len()) {
-        Err(ValidationError {
-            code: std::borrow::Cow::Borrowed("length"),
-            message: Some(Cow::from("Secondary address must be 3 to 15 characters")),
+    // Any street will do, only the unit is checked
+    match StreetAddress::parse("1 Main St", addr_two) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(ValidationError {
+            code: std::borrow::Cow::Borrowed("unit"),
+            message: Some(Cow::from(err.to_string())),
             params: HashMap::new(),
-        })
-    } else {
-        let apt_ste: Vec<&str> = addr_two.split(" ").collect::<Vec<&str>>().to_owned();
-        let first = apt_ste[0];
-        dbg!(&first);
-        if ACCEPTED_SECONDARIES.contains(&first) {
-            Ok(())
-        } else {
-            Err(ValidationError {
-                code: std::borrow::Cow::Borrowed("identifier"),
-                message: Some(Cow::from(
-                    "Secondary Address must contain a valid Identifier (Unit, Apt, # ...)",
-                )),
-                params: HashMap::new(),
-            })
-            // See if I can impl From with a message
-            // Err(ValidationError::new(
-            //     "Secondary Address must contain a valid Identifier (Unit, Apt, # ...)",
-            // ))
-        }
+        }),
     }
 }
 
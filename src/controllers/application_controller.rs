@@ -0,0 +1,179 @@
+use axum::{
+    extract::Path,
+    response::{Html, IntoResponse, Response},
+    Extension, Json,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{
+    error::AppError,
+    libs::{
+        adverse_action::{
+            build_notice, issue_adverse_action_notice, issued_notice_html, StoredNotice,
+            DEFAULT_NOTICE_DIR,
+        },
+        application_lifecycle::{
+            application_owner, application_status, status_history, transition_outside_review,
+            ApplicationStatus, StatusChange,
+        },
+        fraud_screening::{application_flags, screen_application, FraudFlag},
+        joint_application::{co_borrower, CoBorrower},
+        review_queue::{ensure_active_consultant, ensure_application_access},
+    },
+    users::AuthSession,
+};
+
+#[derive(Debug, Serialize)]
+pub struct ApplicationStatusResponse {
+    pub application_id: i32,
+    pub status: ApplicationStatus,
+    pub allowed_next: Vec<ApplicationStatus>,
+    pub history: Vec<StatusChange>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TransitionInput {
+    pub to: ApplicationStatus,
+    /// Required when declining or moving back a step
+    pub reason: Option<String>,
+}
+
+pub async fn get_application_status(
+    Extension(pool): Extension<PgPool>,
+    Path(application_id): Path<i32>,
+) -> Result<Json<ApplicationStatusResponse>, AppError> {
+    let status = application_status(&pool, application_id).await?;
+    Ok(Json(ApplicationStatusResponse {
+        application_id,
+        status,
+        allowed_next: status.allowed_next().to_vec(),
+        history: status_history(&pool, application_id).await?,
+    }))
+}
+
+/// 404 for an individual application
+pub async fn get_co_borrower(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(application_id): Path<i32>,
+) -> Result<Json<CoBorrower>, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    ensure_application_access(&pool, application_id, user.user_id).await?;
+    co_borrower(&pool, application_id)
+        .await?
+        .map(Json)
+        .ok_or_else(|| {
+            AppError::NotFound(format!("Application {} has no co-borrower", application_id))
+        })
+}
+
+/// Consultants only, flags aren't shown to the applicant
+pub async fn get_fraud_flags(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(application_id): Path<i32>,
+) -> Result<Json<Vec<FraudFlag>>, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    ensure_active_consultant(&pool, user.user_id).await?;
+    Ok(Json(application_flags(&pool, application_id).await?))
+}
+
+/// Screens again, replacing the stored flags. For after a reviewer has had the details corrected.
+pub async fn create_fraud_screening(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(application_id): Path<i32>,
+) -> Result<Json<Vec<FraudFlag>>, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    ensure_active_consultant(&pool, user.user_id).await?;
+    Ok(Json(screen_application(&pool, application_id).await?))
+}
+
+/// Moves the application forward or back, recording the signed in user as the actor.
+/// Consultants make any move but a review decision, those go through the review queue.
+/// Applicants can only withdraw their own. A decline also sends the adverse action notice.
+pub async fn create_transition(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(application_id): Path<i32>,
+    Json(input): Json<TransitionInput>,
+) -> Result<Json<StatusChange>, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    if ensure_active_consultant(&pool, user.user_id).await.is_err() {
+        if input.to != ApplicationStatus::Withdrawn {
+            return Err(AppError::InvalidRequest(
+                "Applicants can only withdraw an application".to_owned(),
+            ));
+        }
+        if application_owner(&pool, application_id).await? != Some(user.user_id) {
+            return Err(AppError::NotFound(format!(
+                "Application {} not found",
+                application_id
+            )));
+        }
+    }
+    let change = transition_outside_review(
+        &pool,
+        application_id,
+        input.to,
+        Some(user.user_id),
+        input.reason.as_deref(),
+    )
+    .await?;
+    if change.to_status == ApplicationStatus::Declined {
+        // The decline stands either way, the notice can be reissued from its own endpoint
+        if let Err(err) =
+            issue_adverse_action_notice(&pool, application_id, user.user_id, DEFAULT_NOTICE_DIR)
+                .await
+        {
+            tracing::error!(application_id, error = ?err, "Adverse action notice not issued");
+        }
+    }
+    Ok(Json(change))
+}
+
+/// Consultants get a preview of the letter a declined application gets, the applicant gets the
+/// letter they were sent once it's issued.
+pub async fn get_adverse_action_notice(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(application_id): Path<i32>,
+) -> Result<Response, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    if ensure_active_consultant(&pool, user.user_id).await.is_ok() {
+        let (notice, _email) = build_notice(&pool, application_id, Utc::now().date_naive()).await?;
+        return Ok(Html(notice.to_html()?).into_response());
+    }
+    ensure_application_access(&pool, application_id, user.user_id).await?;
+    let html = issued_notice_html(&pool, application_id)
+        .await?
+        .ok_or_else(|| {
+            AppError::NotFound(format!(
+                "No adverse action notice for application {}",
+                application_id
+            ))
+        })?;
+    Ok(Html(html).into_response())
+}
+
+/// Issues the notice if it hasn't been, otherwise returns the one already sent. Consultants only.
+pub async fn create_adverse_action_notice(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(application_id): Path<i32>,
+) -> Result<Json<StoredNotice>, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    ensure_active_consultant(&pool, user.user_id).await?;
+    if application_status(&pool, application_id).await? != ApplicationStatus::Declined {
+        return Err(AppError::InvalidRequest(format!(
+            "Application {} is not declined",
+            application_id
+        )));
+    }
+    Ok(Json(
+        issue_adverse_action_notice(&pool, application_id, user.user_id, DEFAULT_NOTICE_DIR)
+            .await?,
+    ))
+}
@@ -0,0 +1,364 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use askama::Template;
+use askama_axum::IntoResponse;
+use axum::{
+    extract::{ConnectInfo, FromRequest, Path, Request, State},
+    http::{HeaderMap, StatusCode},
+    response::{Redirect, Response},
+    Extension, Form,
+};
+use deadpool_redis::Pool as RedisPool;
+use serde::de::DeserializeOwned;
+use sqlx::PgPool;
+use validator::{Validate, ValidationErrors};
+
+use crate::{
+    config::{
+        employment_options, get_validation_response, homeownership_options,
+        marital_status_options, purpose_options, FormErrorResponse, SelectOption,
+        StringSelectOption,
+    },
+    error::AppError,
+    libs::{
+        address::{merge_errors, validate_address},
+        application_draft::{
+            create_draft, delete_draft, load_draft, open_drafts, save_draft,
+            validate_history, AddressStep, DraftData, DraftStep, DraftSummary, HistoryStep,
+            LoanInfoStep, MAX_OPEN_DRAFTS,
+        },
+        joint_application::HouseholdForm,
+        lookup_cache::cached_state_options,
+    },
+    models::{application::Application, auth::CurrentUser},
+    users::{AuthSession, User},
+    web::{
+        api::{create_application, ApplicationInput, FormValidationTemplate},
+        SharedState,
+    },
+};
+
+#[derive(Debug, Template)]
+#[template(path = "application_drafts.html")]
+pub struct ApplicationDraftsTemplate {
+    pub user: Option<CurrentUser>,
+    pub drafts: Vec<DraftSummary>,
+    pub max_drafts: i64,
+}
+
+impl ApplicationDraftsTemplate {
+    pub fn can_start(&self) -> bool {
+        (self.drafts.len() as i64) < self.max_drafts
+    }
+}
+
+/// One step of the wizard. `entity` fills the step's form partial from what was saved.
+#[derive(Debug, Template)]
+#[template(path = "application_draft.html")]
+pub struct ApplicationDraftTemplate<'a> {
+    pub user: Option<CurrentUser>,
+    pub message: Option<String>,
+    pub validation_errors: FormErrorResponse,
+    pub location_options: Vec<SelectOption>,
+    pub purpose_options: Vec<SelectOption>,
+    pub marital_options: Vec<SelectOption>,
+    pub employment_options: Vec<SelectOption>,
+    pub homeownership_options: Vec<SelectOption>,
+    pub state_options: Vec<StringSelectOption>,
+    pub entity: Option<Application<'a>>,
+    pub draft_id: i32,
+    pub step: DraftStep,
+    pub data: &'a DraftData,
+}
+
+impl ApplicationDraftTemplate<'_> {
+    pub fn steps(&self) -> [DraftStep; 4] {
+        DraftStep::ALL
+    }
+
+    pub fn is_current(&self, step: &DraftStep) -> bool {
+        *step == self.step
+    }
+
+    /// Only steps already saved, and the one after them, can be visited
+    pub fn reachable(&self, step: &DraftStep) -> bool {
+        (*step as i32) <= (self.data.resume_step() as i32)
+    }
+
+    pub fn step_url(&self, step: &DraftStep) -> String {
+        format!("/apply/drafts/{}/{}", self.draft_id, step.slug())
+    }
+}
+
+/// The saved answers for `step` in the shape the form partials read. The partials take marital
+/// status and homeownership from `purpose_id` too, so it holds whichever one the step shows.
+fn step_entity(data: &DraftData, step: DraftStep) -> Option<Application<'_>> {
+    match step {
+        DraftStep::Address => data.address.as_ref().map(|address| Application {
+            first_name: &address.first_name,
+            last_name: &address.last_name,
+            address_one: &address.address_one,
+            address_two: &address.address_two,
+            city: &address.city,
+            state: &address.state,
+            zip: &address.zip,
+            phone: &address.phone,
+            ssn: &address.ssn,
+            dob: &address.dob,
+            purpose_id: address.marital_status,
+            ..Application::default()
+        }),
+        DraftStep::LoanInfo => data.loan_info.as_ref().map(|loan_info| Application {
+            desired_loan_amount: loan_info.desired_loan_amount,
+            purpose_id: loan_info.loan_purpose,
+            annual_income: loan_info.annual_income,
+            location_id: loan_info.location_id,
+            monthly_debt: &loan_info.monthly_debt,
+            ..Application::default()
+        }),
+        DraftStep::History => data.history.as_ref().map(|history| Application {
+            purpose_id: history.homeownership,
+            employment_status: history.employment_status,
+            emp_length: history.emp_length,
+            ..Application::default()
+        }),
+        DraftStep::Review => None,
+    }
+}
+
+fn step_redirect(draft_id: i32, step: DraftStep) -> Response {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "HX-Redirect",
+        format!("/apply/drafts/{}/{}", draft_id, step.slug())
+            .parse()
+            .unwrap(),
+    );
+    (StatusCode::OK, headers).into_response()
+}
+
+fn validation_failed(errors: ValidationErrors) -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        FormValidationTemplate {
+            form_response: get_validation_response(Err(errors)),
+        },
+    )
+        .into_response()
+}
+
+async fn step_form<T: DeserializeOwned>(request: Request) -> Result<T, AppError> {
+    let Form(step) = Form::<T>::from_request(request, &())
+        .await
+        .map_err(|rejection| AppError::InvalidRequest(rejection.body_text()))?;
+    Ok(step)
+}
+
+fn step_from_slug(draft_id: i32, slug: &str) -> Result<DraftStep, AppError> {
+    DraftStep::from_slug(slug)
+        .ok_or_else(|| AppError::NotFound(format!("Draft {} has no step {}", draft_id, slug)))
+}
+
+/// The user's applications in progress
+pub async fn get_application_drafts(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+) -> Result<Response, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    Ok(ApplicationDraftsTemplate {
+        drafts: open_drafts(&pool, user.user_id).await?,
+        user: Some(CurrentUser::new(&user.username, &user.email, user.user_id)),
+        max_drafts: MAX_OPEN_DRAFTS,
+    }
+    .into_response())
+}
+
+pub async fn create_application_draft(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+) -> Result<Redirect, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    let draft_id = create_draft(&pool, user.user_id).await?;
+    Ok(Redirect::to(&format!(
+        "/apply/drafts/{}/{}",
+        draft_id,
+        DraftStep::Address.slug()
+    )))
+}
+
+/// Picks the draft back up where the user left off
+pub async fn get_application_draft(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(draft_id): Path<i32>,
+) -> Result<Redirect, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    let draft = load_draft(&pool, draft_id, user.user_id).await?;
+    Ok(Redirect::to(&format!(
+        "/apply/drafts/{}/{}",
+        draft_id,
+        draft.data.resume_step().slug()
+    )))
+}
+
+pub async fn delete_application_draft(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(draft_id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    delete_draft(&pool, draft_id, user.user_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_draft_step(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Extension(r_pool): Extension<RedisPool>,
+    Path((draft_id, slug)): Path<(i32, String)>,
+) -> Result<Response, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    let step = step_from_slug(draft_id, &slug)?;
+    let draft = load_draft(&pool, draft_id, user.user_id).await?;
+    let resume_step = draft.data.resume_step();
+    // No skipping ahead of the first unsaved step
+    if (step as i32) > (resume_step as i32) {
+        return Ok(Redirect::to(&format!(
+            "/apply/drafts/{}/{}",
+            draft_id,
+            resume_step.slug()
+        ))
+        .into_response());
+    }
+    Ok(ApplicationDraftTemplate {
+        user: Some(CurrentUser::new(&user.username, &user.email, user.user_id)),
+        message: None,
+        validation_errors: FormErrorResponse { errors: None },
+        location_options: employment_options(),
+        purpose_options: purpose_options(),
+        marital_options: marital_status_options(),
+        employment_options: employment_options(),
+        homeownership_options: homeownership_options(),
+        state_options: cached_state_options(&pool, &r_pool).await,
+        entity: step_entity(&draft.data, step),
+        draft_id,
+        step,
+        data: &draft.data,
+    }
+    .into_response())
+}
+
+/// Validates and saves one step, then sends the browser on to the next unsaved one. Posting the
+/// review step submits the application.
+pub async fn save_draft_step(
+    auth_session: AuthSession,
+    State(state): State<Arc<Mutex<SharedState>>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(pool): Extension<PgPool>,
+    Path((draft_id, slug)): Path<(i32, String)>,
+    request: Request,
+) -> Result<Response, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    let step = step_from_slug(draft_id, &slug)?;
+    let mut draft = load_draft(&pool, draft_id, user.user_id).await?;
+    match step {
+        DraftStep::Address => {
+            let mut address: AddressStep = step_form(request).await?;
+            let checked = validate_address(
+                &pool,
+                &address.address_one,
+                &address.address_two,
+                &address.city,
+                &address.state,
+                &address.zip,
+            )
+            .await?;
+            let checked = match checked {
+                Ok(checked) => address.validate().map(|_| checked),
+                Err(errors) => merge_errors(address.validate(), errors),
+            };
+            match checked {
+                Ok(checked) => address.standardize_address(checked),
+                Err(errors) => return Ok(validation_failed(errors)),
+            }
+            draft.data.address = Some(address);
+        }
+        DraftStep::LoanInfo => {
+            let loan_info: LoanInfoStep = step_form(request).await?;
+            if let Err(errors) = loan_info.validate() {
+                return Ok(validation_failed(errors));
+            }
+            draft.data.loan_info = Some(loan_info);
+        }
+        DraftStep::History => {
+            let history: HistoryStep = step_form(request).await?;
+            if let Err(errors) = validate_history(&history, draft.data.address.as_ref()) {
+                return Ok(validation_failed(errors));
+            }
+            draft.data.history = Some(history);
+        }
+        DraftStep::Review => {
+            return submit_draft(state, pool, addr, user, draft.draft_id, draft.data).await
+        }
+    }
+    let next = save_draft(&pool, &draft).await?;
+    Ok(step_redirect(draft_id, next))
+}
+
+async fn submit_draft(
+    state: Arc<Mutex<SharedState>>,
+    pool: PgPool,
+    addr: SocketAddr,
+    user: User,
+    draft_id: i32,
+    data: DraftData,
+) -> Result<Response, AppError> {
+    let household_input = match data.household_input() {
+        Ok(Some(household_input)) => household_input,
+        Ok(None) => return Ok(step_redirect(draft_id, data.resume_step())),
+        Err(errors) => return Ok(validation_failed(errors)),
+    };
+    let (Some(address), Some(loan_info), Some(history)) =
+        (data.address, data.loan_info, data.history)
+    else {
+        return Ok(step_redirect(draft_id, DraftStep::Address));
+    };
+    let application = ApplicationInput {
+        location_id: loan_info.location_id,
+        first_name: address.first_name,
+        last_name: address.last_name,
+        address_one: address.address_one,
+        address_two: address.address_two,
+        city: address.city,
+        state: address.state,
+        zip: address.zip,
+        phone: address.phone,
+        ssn: address.ssn,
+        dob: address.dob,
+        annual_income: loan_info.annual_income,
+        marital_status: address.marital_status,
+        desired_loan_amount: loan_info.desired_loan_amount,
+        loan_purpose: loan_info.loan_purpose,
+        homeownership: history.homeownership,
+        employment_status: history.employment_status,
+        emp_length: history.emp_length,
+        household: HouseholdForm {
+            monthly_debt: Some(loan_info.monthly_debt),
+            ..history.household
+        },
+    };
+    let (_, response) = create_application(
+        state,
+        pool,
+        addr,
+        user,
+        application,
+        household_input,
+        Some(draft_id),
+    )
+    .await;
+    Ok(response)
+}
@@ -0,0 +1,137 @@
+use askama::Template;
+use askama_axum::IntoResponse;
+use axum::{extract::Path, response::Response, Extension, Json};
+use sqlx::PgPool;
+
+use crate::{
+    error::AppError,
+    libs::{
+        audit_trail::{entity_audit_trail, AuditEntry, AuditedEntity},
+        review_queue::ensure_active_consultant,
+    },
+    users::AuthSession,
+};
+
+/// Every recorded change to one entity, newest first
+#[derive(Debug, Template)]
+#[template(path = "audit_timeline.html")]
+pub struct AuditTimelineTemplate {
+    pub entity: AuditedEntity,
+    pub entity_id: i32,
+    pub entries: Vec<AuditEntry>,
+}
+
+impl AuditTimelineTemplate {
+    pub fn json_url(&self) -> String {
+        format!(
+            "/{}/{}/history.json",
+            self.entity.table_name(),
+            self.entity_id
+        )
+    }
+}
+
+/// Consultants only, the log shows who changed what
+async fn audit_trail(
+    auth_session: AuthSession,
+    pool: &PgPool,
+    entity: AuditedEntity,
+    entity_id: i32,
+) -> Result<Vec<AuditEntry>, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    ensure_active_consultant(pool, user.user_id).await?;
+    entity_audit_trail(pool, entity, entity_id).await
+}
+
+async fn timeline(
+    auth_session: AuthSession,
+    pool: &PgPool,
+    entity: AuditedEntity,
+    entity_id: i32,
+) -> Result<Response, AppError> {
+    Ok(AuditTimelineTemplate {
+        entries: audit_trail(auth_session, pool, entity, entity_id).await?,
+        entity,
+        entity_id,
+    }
+    .into_response())
+}
+
+pub async fn get_application_history(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(application_id): Path<i32>,
+) -> Result<Response, AppError> {
+    timeline(
+        auth_session,
+        &pool,
+        AuditedEntity::Application,
+        application_id,
+    )
+    .await
+}
+
+pub async fn get_application_history_json(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(application_id): Path<i32>,
+) -> Result<Json<Vec<AuditEntry>>, AppError> {
+    let entries = audit_trail(
+        auth_session,
+        &pool,
+        AuditedEntity::Application,
+        application_id,
+    );
+    Ok(Json(entries.await?))
+}
+
+pub async fn get_offer_history(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(offer_id): Path<i32>,
+) -> Result<Response, AppError> {
+    timeline(auth_session, &pool, AuditedEntity::Offer, offer_id).await
+}
+
+pub async fn get_offer_history_json(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(offer_id): Path<i32>,
+) -> Result<Json<Vec<AuditEntry>>, AppError> {
+    let entries = audit_trail(auth_session, &pool, AuditedEntity::Offer, offer_id);
+    Ok(Json(entries.await?))
+}
+
+pub async fn get_loan_history(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(loan_id): Path<i32>,
+) -> Result<Response, AppError> {
+    timeline(auth_session, &pool, AuditedEntity::Loan, loan_id).await
+}
+
+pub async fn get_loan_history_json(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(loan_id): Path<i32>,
+) -> Result<Json<Vec<AuditEntry>>, AppError> {
+    let entries = audit_trail(auth_session, &pool, AuditedEntity::Loan, loan_id);
+    Ok(Json(entries.await?))
+}
+
+pub async fn get_user_history(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(user_id): Path<i32>,
+) -> Result<Response, AppError> {
+    timeline(auth_session, &pool, AuditedEntity::User, user_id).await
+}
+
+pub async fn get_user_history_json(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(user_id): Path<i32>,
+) -> Result<Json<Vec<AuditEntry>>, AppError> {
+    let entries = audit_trail(auth_session, &pool, AuditedEntity::User, user_id);
+    Ok(Json(entries.await?))
+}
@@ -2,7 +2,7 @@ use axum::{Extension, Json};
 use serde_json::{json, Value};
 use sqlx::PgPool;
 
-use crate::{error::AppError, models};
+use crate::{error::AppError, libs::audit_trail::begin_audited, models};
 
 pub async fn register(
     Json(credentials): Json<models::auth::LoginUser>,
@@ -26,10 +26,16 @@ pub async fn register(
         return Err(AppError::UserAlreadyExists("test".to_owned()));
     }
 
+    let mut tx = begin_audited(&pool)
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
     let result = sqlx::query("INSERT INTO users (email, password) VALUES ($1,$2)")
         .bind(&credentials.email)
         .bind(&credentials.password)
-        .execute(&pool)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+    tx.commit()
         .await
         .map_err(|_| AppError::InternalServerError)?;
     if result.rows_affected() < 1 {
@@ -0,0 +1,76 @@
+use askama::Template;
+use askama_axum::IntoResponse;
+use axum::{
+    extract::{Path, Query},
+    response::Response,
+    Extension, Json,
+};
+use deadpool_redis::Pool as RedisPool;
+use hyper::StatusCode;
+use serde::Deserialize;
+use sqlx::PgPool;
+
+use crate::{
+    error::AppError,
+    libs::{
+        credit_file_profile::{cached_credit_file_profile, CreditFileProfile},
+        credit_file_vector::{find_credit_file, similar_borrowers},
+    },
+    models::credit_file::CreditFile,
+};
+
+const DEFAULT_NEIGHBORS: i64 = 10;
+const MAX_NEIGHBORS: i64 = 100;
+
+#[derive(Debug, Template)]
+#[template(path = "credit_file_profile.html")]
+pub struct CreditFileProfileTemplate {
+    pub profile: CreditFileProfile,
+}
+
+pub async fn get_credit_file_profile(
+    Extension(pool): Extension<PgPool>,
+    Extension(r_pool): Extension<RedisPool>,
+) -> Result<Response, AppError> {
+    let profile = cached_credit_file_profile(&pool, &r_pool).await?;
+    Ok(CreditFileProfileTemplate { profile }.into_response())
+}
+
+pub async fn get_credit_file_profile_json(
+    Extension(pool): Extension<PgPool>,
+    Extension(r_pool): Extension<RedisPool>,
+) -> Result<Json<CreditFileProfile>, AppError> {
+    let profile = cached_credit_file_profile(&pool, &r_pool).await?;
+    Ok(Json(profile))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SimilarParams {
+    pub k: Option<i64>,
+}
+
+impl SimilarParams {
+    fn k(&self) -> i64 {
+        self.k.unwrap_or(DEFAULT_NEIGHBORS).clamp(1, MAX_NEIGHBORS)
+    }
+}
+
+pub async fn get_similar_borrowers(
+    Extension(pool): Extension<PgPool>,
+    Path(borrower_id): Path<i32>,
+    Query(params): Query<SimilarParams>,
+) -> Result<Response, AppError> {
+    match find_credit_file(&pool, borrower_id).await? {
+        Some(file) => Ok(Json(similar_borrowers(&pool, &file, params.k()).await?).into_response()),
+        None => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
+
+/// kNN for a credit file that isn't stored yet, e.g. a new applicant being underwritten
+pub async fn post_similar_borrowers(
+    Extension(pool): Extension<PgPool>,
+    Query(params): Query<SimilarParams>,
+    Json(file): Json<CreditFile>,
+) -> Result<Response, AppError> {
+    Ok(Json(similar_borrowers(&pool, &file, params.k()).await?).into_response())
+}
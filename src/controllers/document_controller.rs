@@ -0,0 +1,180 @@
+use std::sync::Arc;
+
+use askama::Template;
+use askama_axum::IntoResponse;
+use axum::{
+    extract::{Multipart, Path},
+    http::header,
+    response::Response,
+    Extension, Json,
+};
+use serde::Deserialize;
+use sqlx::PgPool;
+
+use crate::{
+    error::AppError,
+    libs::{
+        application_document::{
+            application_documents, read_document, store_document, verify_document,
+            ApplicationDocument, DocumentType, DocumentUpload, VerificationStatus,
+        },
+        document_storage::DocumentStorage,
+        review_queue::ensure_application_access,
+    },
+    users::AuthSession,
+};
+
+#[derive(Debug, Template)]
+#[template(path = "application_documents.html")]
+pub struct ApplicationDocumentsTemplate {
+    pub application_id: i32,
+    pub documents: Vec<ApplicationDocument>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerificationInput {
+    pub status: VerificationStatus,
+    /// Required to reject
+    pub note: Option<String>,
+}
+
+fn multipart_err(err: axum::extract::multipart::MultipartError) -> AppError {
+    AppError::InvalidRequest(err.body_text())
+}
+
+/// Reads the upload form: `document_type` (1 income, 2 identity), an optional `short_desc` and
+/// the file itself in `upload`
+async fn read_upload(mut multipart: Multipart) -> Result<DocumentUpload, AppError> {
+    let mut document_type = None;
+    let mut short_desc = None;
+    let mut file = None;
+    while let Some(field) = multipart.next_field().await.map_err(multipart_err)? {
+        match field.name() {
+            Some("document_type") => {
+                let value = field.text().await.map_err(multipart_err)?;
+                document_type = value
+                    .trim()
+                    .parse::<i32>()
+                    .ok()
+                    .and_then(|value| DocumentType::try_from(value).ok());
+            }
+            Some("short_desc") => short_desc = Some(field.text().await.map_err(multipart_err)?),
+            Some("upload") => {
+                let file_name = field.file_name().map(str::to_owned);
+                let content_type = field.content_type().map(str::to_owned);
+                let bytes = field.bytes().await.map_err(multipart_err)?;
+                file = Some((file_name, content_type, bytes.to_vec()));
+            }
+            _ => {}
+        }
+    }
+    let document_type = document_type
+        .ok_or_else(|| AppError::InvalidRequest("Choose a document type".to_owned()))?;
+    let (file_name, content_type, bytes) =
+        file.ok_or_else(|| AppError::InvalidRequest("Choose a file to upload".to_owned()))?;
+    Ok(DocumentUpload {
+        document_type,
+        file_name,
+        content_type,
+        short_desc,
+        bytes,
+    })
+}
+
+pub async fn get_documents(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(application_id): Path<i32>,
+) -> Result<Response, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    ensure_application_access(&pool, application_id, user.user_id).await?;
+    Ok(ApplicationDocumentsTemplate {
+        application_id,
+        documents: application_documents(&pool, application_id).await?,
+    }
+    .into_response())
+}
+
+pub async fn get_documents_json(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(application_id): Path<i32>,
+) -> Result<Json<Vec<ApplicationDocument>>, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    ensure_application_access(&pool, application_id, user.user_id).await?;
+    Ok(Json(application_documents(&pool, application_id).await?))
+}
+
+pub async fn create_document(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Extension(storage): Extension<Arc<dyn DocumentStorage>>,
+    Path(application_id): Path<i32>,
+    multipart: Multipart,
+) -> Result<Json<ApplicationDocument>, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    ensure_application_access(&pool, application_id, user.user_id).await?;
+    let upload = read_upload(multipart).await?;
+    Ok(Json(
+        store_document(
+            &pool,
+            storage.as_ref(),
+            application_id,
+            user.user_id,
+            upload,
+        )
+        .await?,
+    ))
+}
+
+/// The file itself, always as a download so a browser never renders it inline
+pub async fn get_document(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Extension(storage): Extension<Arc<dyn DocumentStorage>>,
+    Path((application_id, document_id)): Path<(i32, i32)>,
+) -> Result<Response, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    ensure_application_access(&pool, application_id, user.user_id).await?;
+    let (document, bytes) =
+        read_document(&pool, storage.as_ref(), application_id, document_id).await?;
+    let extension = document
+        .storage_key
+        .rsplit_once('.')
+        .map_or("bin", |(_, extension)| extension);
+    Ok((
+        [
+            (header::CONTENT_TYPE, document.mime_type),
+            (
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"application-{}-document-{}.{}\"",
+                    application_id, document_id, extension
+                ),
+            ),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_owned()),
+        ],
+        bytes,
+    )
+        .into_response())
+}
+
+pub async fn create_verification(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path((application_id, document_id)): Path<(i32, i32)>,
+    Json(input): Json<VerificationInput>,
+) -> Result<Json<ApplicationDocument>, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    Ok(Json(
+        verify_document(
+            &pool,
+            application_id,
+            document_id,
+            user.user_id,
+            input.status,
+            input.note.as_deref(),
+        )
+        .await?,
+    ))
+}
@@ -0,0 +1,183 @@
+use axum::{
+    extract::{Path, Query},
+    http::header,
+    response::{Html, IntoResponse, Response},
+    Extension, Json,
+};
+use chrono::{NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{
+    error::AppError,
+    libs::{
+        loan_statement::{
+            build_statement, statement_period, store_statement, StatementFormat, StoredStatement,
+            DEFAULT_STATEMENT_DIR,
+        },
+        loan_tape::{load_loan_tape, write_loan_tape},
+        review_queue::ensure_active_consultant,
+        servicing::{
+            ensure_loan_access, load_loan_account, payment_ledger, post_payment, reverse_payment,
+            to_cents, Allocation, LedgerEntry, PaymentKind, PayoffQuote,
+        },
+    },
+    users::AuthSession,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct PaymentInput {
+    /// Dollars
+    pub amount: f64,
+    pub effective_date: Option<NaiveDate>,
+    pub prepayment: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PaymentResponse {
+    pub entry: LedgerEntry,
+    pub allocation: Allocation,
+}
+
+pub async fn get_payments(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(loan_id): Path<i32>,
+) -> Result<Json<Vec<LedgerEntry>>, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    ensure_loan_access(&pool, loan_id, user.user_id).await?;
+    Ok(Json(payment_ledger(&pool, loan_id).await?))
+}
+
+/// A borrower pays their own loan as of today, only consultants can post a back-dated payment
+pub async fn create_payment(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(loan_id): Path<i32>,
+    Json(input): Json<PaymentInput>,
+) -> Result<Json<PaymentResponse>, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    ensure_loan_access(&pool, loan_id, user.user_id).await?;
+    let today = Utc::now().date_naive();
+    let effective_date = input.effective_date.unwrap_or(today);
+    if effective_date < today {
+        ensure_active_consultant(&pool, user.user_id).await?;
+    }
+    let kind = if input.prepayment.unwrap_or(false) {
+        PaymentKind::Prepayment
+    } else {
+        PaymentKind::Regular
+    };
+    let (entry, allocation) =
+        post_payment(&pool, loan_id, to_cents(input.amount), effective_date, kind).await?;
+    Ok(Json(PaymentResponse { entry, allocation }))
+}
+
+pub async fn create_reversal(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path((loan_id, payment_id)): Path<(i32, i32)>,
+) -> Result<Json<LedgerEntry>, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    ensure_active_consultant(&pool, user.user_id).await?;
+    Ok(Json(reverse_payment(&pool, loan_id, payment_id).await?))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PayoffParams {
+    pub as_of: Option<NaiveDate>,
+}
+
+pub async fn get_payoff_quote(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(loan_id): Path<i32>,
+    Query(params): Query<PayoffParams>,
+) -> Result<Json<PayoffQuote>, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    ensure_loan_access(&pool, loan_id, user.user_id).await?;
+    let account = load_loan_account(&pool, loan_id).await?;
+    let as_of = params.as_of.unwrap_or_else(|| Utc::now().date_naive());
+    if as_of < account.last_accrual_date {
+        return Err(AppError::InvalidRequest(format!(
+            "Interest is already accrued through {}, quote a later date",
+            account.last_accrual_date
+        )));
+    }
+    Ok(Json(account.payoff_quote(as_of)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StatementParams {
+    pub format: Option<StatementFormat>,
+}
+
+/// `period` is YYYY-MM. HTML unless `?format=text` or `?format=csv`.
+pub async fn get_statement(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path((loan_id, period)): Path<(i32, String)>,
+    Query(params): Query<StatementParams>,
+) -> Result<Response, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    ensure_loan_access(&pool, loan_id, user.user_id).await?;
+    let (start, end) = statement_period(&period)?;
+    let statement = build_statement(&pool, loan_id, start, end, Utc::now().date_naive()).await?;
+    Ok(match params.format.unwrap_or(StatementFormat::Html) {
+        StatementFormat::Html => Html(statement.to_html()?).into_response(),
+        StatementFormat::Text => (
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            statement.to_text(),
+        )
+            .into_response(),
+        StatementFormat::Csv => (
+            [
+                (header::CONTENT_TYPE, "text/csv; charset=utf-8".to_owned()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{}.csv\"", statement.file_stem()),
+                ),
+            ],
+            statement.to_csv()?,
+        )
+            .into_response(),
+    })
+}
+
+/// Renders the statement and saves it as attachments owned by the signed in user.
+pub async fn create_statement(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path((loan_id, period)): Path<(i32, String)>,
+) -> Result<Json<StoredStatement>, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    ensure_loan_access(&pool, loan_id, user.user_id).await?;
+    let (start, end) = statement_period(&period)?;
+    let statement = build_statement(&pool, loan_id, start, end, Utc::now().date_naive()).await?;
+    Ok(Json(
+        store_statement(&pool, &statement, user.user_id, DEFAULT_STATEMENT_DIR).await?,
+    ))
+}
+
+/// The whole loan book as a tape, same format `import_loan_tape` reads. Consultants only.
+pub async fn get_loan_tape(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+) -> Result<Response, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    ensure_active_consultant(&pool, user.user_id).await?;
+    let (rows, _skipped) = load_loan_tape(&pool).await?;
+    let mut csv = Vec::new();
+    write_loan_tape(&rows, &mut csv)?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"loan_tape.csv\"",
+            ),
+        ],
+        csv,
+    )
+        .into_response())
+}
@@ -2,3 +2,12 @@ pub mod auth_controller;
 pub mod offer_controller;
 pub mod ticker_controller;
 pub mod metrics_controller;
+pub mod credit_file_controller;
+pub mod loan_controller;
+pub mod portfolio_controller;
+pub mod application_controller;
+pub mod underwriting_controller;
+pub mod review_controller;
+pub mod application_draft_controller;
+pub mod document_controller;
+pub mod audit_controller;
@@ -4,15 +4,31 @@ use std::ops::Deref;
 
 use crate::models::credit_file::CreditFile;
 use crate::{
-    actors::actor::{ActorHandle, ActorMessage},
+    actors::actor::{get_mock_offers, ActorHandle, ActorMessage},
     error::AppError,
+    libs::{
+        affordability::{
+            policy_max_dti, AffordabilityProfile, AffordabilityQuote, OfferAffordability,
+        },
+        lookup_cache::cached_application,
+        review_queue::ensure_application_access,
+        underwriting::{application_underwriting_input, RuleSet},
+    },
     models::{self, offer::Offer},
+    users::AuthSession,
 };
 use askama::Template;
 use askama_axum::IntoResponse;
-use axum::{debug_handler, response::Response, Extension, Json};
+use axum::{
+    debug_handler,
+    extract::{Path, Query},
+    response::Response,
+    Extension, Json,
+};
 use csv::Reader;
+use deadpool_redis::Pool as RedisPool;
 use hyper::StatusCode;
+use serde::Deserialize;
 use serde_json::{json, Value};
 use sqlx::PgPool;
 use tokio::sync::oneshot;
@@ -26,6 +42,83 @@ pub struct OffersTemplate<'a> {
     pub message: Option<String>,
 }
 
+/// Offers for one application with what each would do to the applicant's DTI
+#[derive(Debug, Template)]
+#[template(path = "application_offers.html")]
+pub struct ApplicationOffersTemplate {
+    pub application_id: i32,
+    pub first_name: String,
+    pub desired_loan_amount: i32,
+    pub profile: AffordabilityProfile,
+    pub offers: Vec<OfferAffordability>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AffordabilityQuery {
+    pub term: i32,
+    /// As a percentage, e.g. 9.6
+    pub apr: f64,
+    /// Defaults to the amount on the application
+    pub amount: Option<i32>,
+}
+
+async fn application_profile(
+    pool: &PgPool,
+    application_id: i32,
+) -> Result<(AffordabilityProfile, i32), AppError> {
+    let input = application_underwriting_input(pool, application_id).await?;
+    let rule_set = RuleSet::load_active().ok();
+    Ok((
+        AffordabilityProfile::new(&input, policy_max_dti(rule_set.as_ref())),
+        input.desired_loan_amount,
+    ))
+}
+
+/// Offers within the DTI limit come first, the rest are shown flagged with what would fit
+pub async fn get_application_offers(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Extension(r_pool): Extension<RedisPool>,
+    Path(application_id): Path<i32>,
+) -> Result<Response, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    ensure_application_access(&pool, application_id, user.user_id).await?;
+    let application = cached_application(&pool, &r_pool, application_id).await?;
+    let (profile, desired_loan_amount) = application_profile(&pool, application_id).await?;
+    let mut offers = profile.assess_offers(&get_mock_offers(3), desired_loan_amount);
+    offers.sort_by_key(|offer| !offer.within_policy);
+    Ok(ApplicationOffersTemplate {
+        application_id,
+        first_name: application.first_name,
+        desired_loan_amount,
+        profile,
+        offers,
+    }
+    .into_response())
+}
+
+/// The most the applicant can afford at a term and APR, e.g. ?term=36&apr=9.6
+pub async fn get_affordability(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(application_id): Path<i32>,
+    Query(query): Query<AffordabilityQuery>,
+) -> Result<Json<AffordabilityQuote>, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    ensure_application_access(&pool, application_id, user.user_id).await?;
+    if query.term < 1 || query.apr < 0.0 {
+        return Err(AppError::InvalidRequest(
+            "Term must be at least a month and APR can't be negative".to_owned(),
+        ));
+    }
+    let (profile, desired_loan_amount) = application_profile(&pool, application_id).await?;
+    Ok(Json(profile.quote(
+        query.amount.unwrap_or(desired_loan_amount),
+        query.apr,
+        query.term,
+    )))
+}
+
 #[debug_handler]
 pub async fn get_offers(
     // Json(application): Json<models::Application>,
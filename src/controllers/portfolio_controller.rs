@@ -0,0 +1,31 @@
+use askama::Template;
+use askama_axum::IntoResponse;
+use axum::{response::Response, Extension, Json};
+use deadpool_redis::Pool as RedisPool;
+use sqlx::PgPool;
+
+use crate::{
+    error::AppError,
+    libs::portfolio_analytics::{cached_portfolio_analytics, PortfolioAnalytics},
+};
+
+#[derive(Debug, Template)]
+#[template(path = "portfolio.html")]
+pub struct PortfolioTemplate {
+    pub analytics: PortfolioAnalytics,
+}
+
+pub async fn get_portfolio(
+    Extension(pool): Extension<PgPool>,
+    Extension(r_pool): Extension<RedisPool>,
+) -> Result<Response, AppError> {
+    let analytics = cached_portfolio_analytics(&pool, &r_pool).await?;
+    Ok(PortfolioTemplate { analytics }.into_response())
+}
+
+pub async fn get_portfolio_json(
+    Extension(pool): Extension<PgPool>,
+    Extension(r_pool): Extension<RedisPool>,
+) -> Result<Json<PortfolioAnalytics>, AppError> {
+    Ok(Json(cached_portfolio_analytics(&pool, &r_pool).await?))
+}
@@ -0,0 +1,177 @@
+use askama::Template;
+use askama_axum::IntoResponse;
+use axum::{
+    extract::{Path, Query},
+    response::Response,
+    Extension, Json,
+};
+use serde::Deserialize;
+use sqlx::PgPool;
+use url::form_urlencoded;
+
+use crate::{
+    config::FilterOptions,
+    error::AppError,
+    libs::{
+        adverse_action::{issue_adverse_action_notice, DEFAULT_NOTICE_DIR},
+        application_lifecycle::{ApplicationStatus, StatusChange},
+        review_queue::{
+            add_review_note, claim_application, ensure_active_consultant, release_application,
+            review_application, review_notes, review_queue, Claim, QueueEntry, QueueQuery,
+            ReviewAction, ReviewNote, ReviewQueue,
+        },
+    },
+    users::AuthSession,
+};
+
+#[derive(Debug, Template)]
+#[template(path = "review_queue.html")]
+pub struct ReviewQueueTemplate {
+    pub queue: ReviewQueue,
+    pub filter: FilterOptions,
+    pub user_id: i32,
+}
+
+impl ReviewQueueTemplate {
+    pub fn claimed_by_me(&self, entry: &QueueEntry) -> bool {
+        entry.claimed_by == Some(self.user_id)
+    }
+
+    /// The queue URL `offset` pages away, keeping the current search, sort and date filters.
+    /// None past either end.
+    pub fn page_link(&self, offset: isize) -> Option<String> {
+        let page = self
+            .queue
+            .page
+            .checked_add_signed(offset)
+            .filter(|page| (1..=self.queue.pages()).contains(page))?;
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        let filter = &self.filter;
+        for (name, value) in [
+            ("search", filter.search.clone()),
+            ("key", filter.key.clone()),
+            ("dir", filter.dir.clone()),
+            ("year", filter.year.map(|year| year.to_string())),
+            ("month", filter.month.map(|month| month.to_string())),
+            ("limit", filter.limit.map(|limit| limit.to_string())),
+        ] {
+            if let Some(value) = value.filter(|value| !value.is_empty()) {
+                query.append_pair(name, &value);
+            }
+        }
+        query.append_pair("page", &page.to_string());
+        Some(format!("/review-queue?{}", query.finish()))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NoteInput {
+    pub note: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReviewInput {
+    pub action: ReviewAction,
+    /// Required to decline
+    pub reason: Option<String>,
+}
+
+/// Applications under review, filtered and paged by `FilterOptions`
+pub async fn get_review_queue(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Query(filter): Query<FilterOptions>,
+) -> Result<Response, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    ensure_active_consultant(&pool, user.user_id).await?;
+    let queue = review_queue(&pool, &QueueQuery::from_filter(&filter)?).await?;
+    Ok(ReviewQueueTemplate {
+        queue,
+        filter,
+        user_id: user.user_id,
+    }
+    .into_response())
+}
+
+pub async fn get_review_queue_json(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Query(filter): Query<FilterOptions>,
+) -> Result<Json<ReviewQueue>, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    ensure_active_consultant(&pool, user.user_id).await?;
+    Ok(Json(
+        review_queue(&pool, &QueueQuery::from_filter(&filter)?).await?,
+    ))
+}
+
+pub async fn create_claim(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(application_id): Path<i32>,
+) -> Result<Json<Claim>, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    Ok(Json(
+        claim_application(&pool, application_id, user.user_id).await?,
+    ))
+}
+
+pub async fn delete_claim(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(application_id): Path<i32>,
+) -> Result<Json<()>, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    release_application(&pool, application_id, user.user_id).await?;
+    Ok(Json(()))
+}
+
+pub async fn get_review_notes(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(application_id): Path<i32>,
+) -> Result<Json<Vec<ReviewNote>>, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    ensure_active_consultant(&pool, user.user_id).await?;
+    Ok(Json(review_notes(&pool, application_id).await?))
+}
+
+pub async fn create_review_note(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(application_id): Path<i32>,
+    Json(input): Json<NoteInput>,
+) -> Result<Json<ReviewNote>, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    Ok(Json(
+        add_review_note(&pool, application_id, user.user_id, &input.note).await?,
+    ))
+}
+
+/// Approves or declines a claimed application. A decline also sends the adverse action notice.
+pub async fn create_review(
+    auth_session: AuthSession,
+    Extension(pool): Extension<PgPool>,
+    Path(application_id): Path<i32>,
+    Json(input): Json<ReviewInput>,
+) -> Result<Json<StatusChange>, AppError> {
+    let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+    let change = review_application(
+        &pool,
+        application_id,
+        user.user_id,
+        input.action,
+        input.reason.as_deref(),
+    )
+    .await?;
+    if change.to_status == ApplicationStatus::Declined {
+        // The decline stands either way, the notice can be reissued from its own endpoint
+        if let Err(err) =
+            issue_adverse_action_notice(&pool, application_id, user.user_id, DEFAULT_NOTICE_DIR)
+                .await
+        {
+            tracing::error!(application_id, error = ?err, "Adverse action notice not issued");
+        }
+    }
+    Ok(Json(change))
+}
@@ -0,0 +1,43 @@
+use axum::{extract::Path, Extension, Json};
+use serde::Deserialize;
+use sqlx::PgPool;
+
+use crate::{
+    error::AppError,
+    libs::underwriting::{
+        application_decisions, RuleSet, StoredDecision, UnderwritingDecision, UnderwritingInput,
+    },
+};
+
+#[derive(Debug, Deserialize)]
+pub struct DryRunInput {
+    #[serde(flatten)]
+    pub input: UnderwritingInput,
+    /// Rules to try instead of the active set, e.g. a draft of the next version
+    pub rules: Option<RuleSet>,
+}
+
+pub async fn get_underwriting_rules() -> Result<Json<RuleSet>, AppError> {
+    Ok(Json(RuleSet::load_active()?))
+}
+
+/// Evaluates without storing anything
+pub async fn post_underwriting_dry_run(
+    Json(dry_run): Json<DryRunInput>,
+) -> Result<Json<UnderwritingDecision>, AppError> {
+    let rule_set = match dry_run.rules {
+        Some(rule_set) => {
+            rule_set.validate()?;
+            rule_set
+        }
+        None => RuleSet::load_active()?,
+    };
+    Ok(Json(rule_set.evaluate(&dry_run.input)))
+}
+
+pub async fn get_application_decisions(
+    Extension(pool): Extension<PgPool>,
+    Path(application_id): Path<i32>,
+) -> Result<Json<Vec<StoredDecision>>, AppError> {
+    Ok(Json(application_decisions(&pool, application_id).await?))
+}
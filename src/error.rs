@@ -12,6 +12,8 @@ pub enum AppError {
     TokenCreation,
     UserDoesNotExist,
     UserAlreadyExists(String),
+    InvalidRequest(String),
+    NotFound(String),
 }
 
 impl std::fmt::Display for AppError {
@@ -26,6 +28,8 @@ impl std::fmt::Display for AppError {
             Self::TokenCreation => write!(f, "Failed to create a token"),
             Self::UserDoesNotExist => write!(f, "No user found"),
             Self::UserAlreadyExists(s) => write!(f, "User already exists"),
+            Self::InvalidRequest(s) => write!(f, "{}", s),
+            Self::NotFound(s) => write!(f, "{}", s),
         }
     }
 }
@@ -47,11 +51,25 @@ impl IntoResponse for AppError {
             ),
             Self::UserDoesNotExist => (StatusCode::UNAUTHORIZED, "No user found"),
             Self::UserAlreadyExists(s) => (StatusCode::BAD_REQUEST, "User already exists"),
+            Self::InvalidRequest(s) => {
+                return (StatusCode::BAD_REQUEST, Json(json!({ "error": s }))).into_response()
+            }
+            Self::NotFound(s) => {
+                return (StatusCode::NOT_FOUND, Json(json!({ "error": s }))).into_response()
+            }
         };
         (status, Json(json!({ "error": err_msg }))).into_response()
     }
 }
 
+/// Logged here and kept out of the response, so `?` on a query is all a handler needs
+impl From<sqlx::Error> for AppError {
+    fn from(err: sqlx::Error) -> Self {
+        tracing::error!(error = ?err, "Database error");
+        Self::InternalServerError
+    }
+}
+
 impl std::error::Error for AppError {
     fn description(&self) -> &str {
         match self {
@@ -64,6 +82,8 @@ impl std::error::Error for AppError {
             Self::TokenCreation => "Failed to create a token",
             Self::UserDoesNotExist => "No user found",
             Self::UserAlreadyExists(s) => "User already exists",
+            Self::InvalidRequest(s) => "Invalid request",
+            Self::NotFound(s) => "Not found",
         }
     }
 }
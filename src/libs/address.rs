@@ -0,0 +1,738 @@
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use validator::{ValidationError, ValidationErrors};
+
+use crate::{error::AppError, libs::pii::Redacted};
+
+/// USPS Publication 28 street suffixes (C1): every common spelling maps to the standard
+/// abbreviation, which maps to itself.
+const STREET_SUFFIXES: [(&str, &str); 103] = [
+    ("ALLEY", "ALY"),
+    ("ALLEE", "ALY"),
+    ("ALLY", "ALY"),
+    ("ALY", "ALY"),
+    ("ANNEX", "ANX"),
+    ("ANX", "ANX"),
+    ("ARCADE", "ARC"),
+    ("ARC", "ARC"),
+    ("AVENUE", "AVE"),
+    ("AVEN", "AVE"),
+    ("AVENU", "AVE"),
+    ("AVN", "AVE"),
+    ("AV", "AVE"),
+    ("AVE", "AVE"),
+    ("BAYOU", "BYU"),
+    ("BYU", "BYU"),
+    ("BEACH", "BCH"),
+    ("BCH", "BCH"),
+    ("BEND", "BND"),
+    ("BND", "BND"),
+    ("BLUFF", "BLF"),
+    ("BLF", "BLF"),
+    ("BOULEVARD", "BLVD"),
+    ("BOUL", "BLVD"),
+    ("BLVD", "BLVD"),
+    ("BRANCH", "BR"),
+    ("BR", "BR"),
+    ("BRIDGE", "BRG"),
+    ("BRG", "BRG"),
+    ("BYPASS", "BYP"),
+    ("BYP", "BYP"),
+    ("CAUSEWAY", "CSWY"),
+    ("CSWY", "CSWY"),
+    ("CENTER", "CTR"),
+    ("CENTRE", "CTR"),
+    ("CTR", "CTR"),
+    ("CIRCLE", "CIR"),
+    ("CIRC", "CIR"),
+    ("CIR", "CIR"),
+    ("COURT", "CT"),
+    ("CT", "CT"),
+    ("COVE", "CV"),
+    ("CV", "CV"),
+    ("CREEK", "CRK"),
+    ("CRK", "CRK"),
+    ("CROSSING", "XING"),
+    ("XING", "XING"),
+    ("DRIVE", "DR"),
+    ("DRIV", "DR"),
+    ("DRV", "DR"),
+    ("DR", "DR"),
+    ("EXPRESSWAY", "EXPY"),
+    ("EXPY", "EXPY"),
+    ("FREEWAY", "FWY"),
+    ("FWY", "FWY"),
+    ("HEIGHTS", "HTS"),
+    ("HTS", "HTS"),
+    ("HIGHWAY", "HWY"),
+    ("HIWAY", "HWY"),
+    ("HWY", "HWY"),
+    ("HILL", "HL"),
+    ("HL", "HL"),
+    ("HOLLOW", "HOLW"),
+    ("HOLW", "HOLW"),
+    ("JUNCTION", "JCT"),
+    ("JCT", "JCT"),
+    ("LANE", "LN"),
+    ("LN", "LN"),
+    ("LOOP", "LOOP"),
+    ("MEADOWS", "MDWS"),
+    ("MDWS", "MDWS"),
+    ("PARKWAY", "PKWY"),
+    ("PARKWY", "PKWY"),
+    ("PKY", "PKWY"),
+    ("PKWY", "PKWY"),
+    ("PIKE", "PIKE"),
+    ("PLACE", "PL"),
+    ("PL", "PL"),
+    ("PLAZA", "PLZ"),
+    ("PLZ", "PLZ"),
+    ("POINT", "PT"),
+    ("PT", "PT"),
+    ("ROAD", "RD"),
+    ("RD", "RD"),
+    ("ROUTE", "RTE"),
+    ("RTE", "RTE"),
+    ("SQUARE", "SQ"),
+    ("SQ", "SQ"),
+    ("STREET", "ST"),
+    ("STR", "ST"),
+    ("STRT", "ST"),
+    ("ST", "ST"),
+    ("TERRACE", "TER"),
+    ("TER", "TER"),
+    ("TRAIL", "TRL"),
+    ("TRL", "TRL"),
+    ("TURNPIKE", "TPKE"),
+    ("TPKE", "TPKE"),
+    ("VIEW", "VW"),
+    ("VW", "VW"),
+    ("WAY", "WAY"),
+    ("WY", "WAY"),
+    ("WALK", "WALK"),
+];
+
+/// USPS secondary unit designators (C2). The bool is whether a unit number has to follow.
+const UNIT_DESIGNATORS: [(&str, &str, bool); 28] = [
+    ("APARTMENT", "APT", true),
+    ("APT", "APT", true),
+    ("BASEMENT", "BSMT", false),
+    ("BSMT", "BSMT", false),
+    ("BUILDING", "BLDG", true),
+    ("BLDG", "BLDG", true),
+    ("DEPARTMENT", "DEPT", true),
+    ("DEPT", "DEPT", true),
+    ("FLOOR", "FL", true),
+    ("FL", "FL", true),
+    ("FRONT", "FRNT", false),
+    ("FRNT", "FRNT", false),
+    ("LOT", "LOT", true),
+    ("OFFICE", "OFC", false),
+    ("OFC", "OFC", false),
+    ("PENTHOUSE", "PH", false),
+    ("PH", "PH", false),
+    ("REAR", "REAR", false),
+    ("ROOM", "RM", true),
+    ("RM", "RM", true),
+    ("SPACE", "SPC", true),
+    ("SPC", "SPC", true),
+    ("SUITE", "STE", true),
+    ("STE", "STE", true),
+    ("TRAILER", "TRLR", true),
+    ("TRLR", "TRLR", true),
+    ("UNIT", "UNIT", true),
+    ("#", "#", true),
+];
+
+const DIRECTIONALS: [(&str, &str); 16] = [
+    ("NORTH", "N"),
+    ("SOUTH", "S"),
+    ("EAST", "E"),
+    ("WEST", "W"),
+    ("NORTHEAST", "NE"),
+    ("NORTHWEST", "NW"),
+    ("SOUTHEAST", "SE"),
+    ("SOUTHWEST", "SW"),
+    ("N", "N"),
+    ("S", "S"),
+    ("E", "E"),
+    ("W", "W"),
+    ("NE", "NE"),
+    ("NW", "NW"),
+    ("SE", "SE"),
+    ("SW", "SW"),
+];
+
+/// USPS ZIP prefix ranges, first three digits. Military prefixes are left out, so they never
+/// match a state.
+const ZIP_PREFIXES: [(u16, u16, &str); 64] = [
+    (5, 5, "NY"),
+    (6, 7, "PR"),
+    (8, 8, "VI"),
+    (9, 9, "PR"),
+    (10, 27, "MA"),
+    (28, 29, "RI"),
+    (30, 38, "NH"),
+    (39, 49, "ME"),
+    (50, 59, "VT"),
+    (60, 69, "CT"),
+    (70, 89, "NJ"),
+    (100, 149, "NY"),
+    (150, 196, "PA"),
+    (197, 199, "DE"),
+    (200, 200, "DC"),
+    (201, 201, "VA"),
+    (202, 205, "DC"),
+    (206, 219, "MD"),
+    (220, 246, "VA"),
+    (247, 268, "WV"),
+    (270, 289, "NC"),
+    (290, 299, "SC"),
+    (300, 319, "GA"),
+    (320, 339, "FL"),
+    (341, 349, "FL"),
+    (350, 369, "AL"),
+    (370, 385, "TN"),
+    (386, 397, "MS"),
+    (398, 399, "GA"),
+    (400, 427, "KY"),
+    (430, 459, "OH"),
+    (460, 479, "IN"),
+    (480, 499, "MI"),
+    (500, 528, "IA"),
+    (530, 549, "WI"),
+    (550, 567, "MN"),
+    (569, 569, "DC"),
+    (570, 577, "SD"),
+    (580, 588, "ND"),
+    (590, 599, "MT"),
+    (600, 629, "IL"),
+    (630, 658, "MO"),
+    (660, 679, "KS"),
+    (680, 693, "NE"),
+    (700, 714, "LA"),
+    (716, 729, "AR"),
+    (730, 732, "OK"),
+    (733, 733, "TX"),
+    (734, 749, "OK"),
+    (750, 799, "TX"),
+    (800, 816, "CO"),
+    (820, 831, "WY"),
+    (832, 838, "ID"),
+    (840, 847, "UT"),
+    (850, 865, "AZ"),
+    (870, 884, "NM"),
+    (885, 885, "TX"),
+    (889, 898, "NV"),
+    (900, 961, "CA"),
+    (967, 968, "HI"),
+    (969, 969, "GU"),
+    (970, 979, "OR"),
+    (980, 994, "WA"),
+    (995, 999, "AK"),
+];
+
+fn lookup<'a>(table: &[(&str, &'a str)], word: &str) -> Option<&'a str> {
+    table
+        .iter()
+        .find(|(spelling, _)| *spelling == word)
+        .map(|(_, standard)| *standard)
+}
+
+fn unit_designator(word: &str) -> Option<(&'static str, bool)> {
+    UNIT_DESIGNATORS
+        .iter()
+        .find(|(spelling, _, _)| *spelling == word)
+        .map(|(_, standard, needs_number)| (*standard, *needs_number))
+}
+
+/// A street address split into its USPS parts, each already standardized and uppercase
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StreetAddress {
+    pub number: String,
+    pub predirectional: Option<String>,
+    pub street_name: String,
+    pub suffix: Option<String>,
+    pub postdirectional: Option<String>,
+    pub unit_designator: Option<String>,
+    pub unit: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AddressError {
+    Empty,
+    MissingNumber,
+    MissingStreetName,
+    MissingUnit(String),
+    InvalidUnit,
+}
+
+impl std::fmt::Display for AddressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AddressError::Empty => write!(f, "Address is required"),
+            AddressError::MissingNumber => write!(f, "Address must start with a street number"),
+            AddressError::MissingStreetName => write!(f, "Address must include a street name"),
+            AddressError::MissingUnit(designator) => {
+                write!(f, "{} needs a number, such as {} 4", designator, designator)
+            }
+            AddressError::InvalidUnit => write!(
+                f,
+                "Secondary address must be a unit such as Apt 4, Ste 200 or #4"
+            ),
+        }
+    }
+}
+
+/// Uppercase words with periods dropped, and `#4` split into `#` and `4`
+fn tokens(line: &str) -> Vec<String> {
+    line.split(|c: char| c.is_whitespace() || c == ',')
+        .flat_map(|word| match word.strip_prefix('#') {
+            Some(rest) => vec!["#".to_owned(), rest.to_owned()],
+            None => vec![word.to_owned()],
+        })
+        .map(|word| word.replace('.', "").to_uppercase())
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+/// The unit part from `words`, which start at its designator. A bare unit number is kept
+/// under `#`, as USPS does when the designator isn't known.
+fn parse_unit(words: &[String]) -> Result<(String, Option<String>), AddressError> {
+    let Some((first, rest)) = words.split_first() else {
+        return Err(AddressError::InvalidUnit);
+    };
+    match unit_designator(first) {
+        Some((designator, needs_number)) => {
+            if rest.is_empty() && needs_number {
+                return Err(AddressError::MissingUnit(designator.to_owned()));
+            }
+            let unit = Some(rest.join(" ")).filter(|unit| !unit.is_empty());
+            Ok((designator.to_owned(), unit))
+        }
+        None if words.len() == 1 && first.chars().any(|c| c.is_ascii_digit()) => {
+            Ok(("#".to_owned(), Some(first.clone())))
+        }
+        None => Err(AddressError::InvalidUnit),
+    }
+}
+
+impl StreetAddress {
+    /// Parses one line such as "4483 South 87th Street, Apt. 2"
+    pub fn parse_line(line: &str) -> Result<StreetAddress, AddressError> {
+        let words = tokens(line);
+        let Some((number, rest)) = words.split_first() else {
+            return Err(AddressError::Empty);
+        };
+        if !number.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(AddressError::MissingNumber);
+        }
+        // The unit starts at the first designator after at least one street word. One that takes
+        // no number has to be the last word, so "Old Front St" stays a street.
+        let unit_at = rest
+            .iter()
+            .enumerate()
+            .skip(1)
+            .find(|(i, word)| match unit_designator(word) {
+                Some((_, needs_number)) => needs_number || *i == rest.len() - 1,
+                None => false,
+            })
+            .map(|(i, _)| i);
+        let (mut street, unit) = match unit_at {
+            Some(i) => (rest[..i].to_vec(), Some(parse_unit(&rest[i..])?)),
+            None => (rest.to_vec(), None),
+        };
+
+        let mut address = StreetAddress {
+            number: number.clone(),
+            ..StreetAddress::default()
+        };
+        if street.len() > 1 {
+            if let Some(direction) = lookup(&DIRECTIONALS, &street[street.len() - 1]) {
+                address.postdirectional = Some(direction.to_owned());
+                street.pop();
+            }
+        }
+        if street.len() > 1 {
+            if let Some(suffix) = lookup(&STREET_SUFFIXES, &street[street.len() - 1]) {
+                address.suffix = Some(suffix.to_owned());
+                street.pop();
+            }
+        }
+        if street.len() > 1 {
+            if let Some(direction) = lookup(&DIRECTIONALS, &street[0]) {
+                address.predirectional = Some(direction.to_owned());
+                street.remove(0);
+            }
+        }
+        if street.is_empty() {
+            return Err(AddressError::MissingStreetName);
+        }
+        address.street_name = street.join(" ");
+        if let Some((designator, unit)) = unit {
+            address.unit_designator = Some(designator);
+            address.unit = unit;
+        }
+        Ok(address)
+    }
+
+    /// Parses the two address lines of the application form. A unit on the first line is kept
+    /// unless the second line has one too.
+    pub fn parse(address_one: &str, address_two: &str) -> Result<StreetAddress, AddressError> {
+        let mut address = StreetAddress::parse_line(address_one)?;
+        let words = tokens(address_two);
+        if !words.is_empty() {
+            let (designator, unit) = parse_unit(&words)?;
+            address.unit_designator = Some(designator);
+            address.unit = unit;
+        }
+        Ok(address)
+    }
+
+    /// Number, directionals, street and suffix, e.g. "4483 S 87TH ST"
+    pub fn line_one(&self) -> String {
+        [
+            Some(&self.number),
+            self.predirectional.as_ref(),
+            Some(&self.street_name),
+            self.suffix.as_ref(),
+            self.postdirectional.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(" ")
+    }
+
+    /// The unit, e.g. "APT 2", or None
+    pub fn line_two(&self) -> Option<String> {
+        let designator = self.unit_designator.as_ref()?;
+        Some(match &self.unit {
+            Some(unit) => format!("{} {}", designator, unit),
+            None => designator.clone(),
+        })
+    }
+
+    pub fn standardized(&self) -> String {
+        match self.line_two() {
+            Some(line_two) => format!("{} {}", self.line_one(), line_two),
+            None => self.line_one(),
+        }
+    }
+}
+
+/// The standardized form for comparing addresses, so "4483 South 87th Street, Apt. 2" and
+/// "4483 S 87th St Apt 2" are equal. Addresses that don't parse, like some seed data, are still
+/// compared word by word with the suffixes and directionals abbreviated.
+pub fn normalize_address(address: &str) -> String {
+    match StreetAddress::parse_line(address) {
+        Ok(address) => address.standardized(),
+        Err(_) => tokens(address)
+            .iter()
+            .map(|word| {
+                lookup(&STREET_SUFFIXES, word)
+                    .or_else(|| lookup(&DIRECTIONALS, word))
+                    .or_else(|| unit_designator(word).map(|(designator, _)| designator))
+                    .unwrap_or(word)
+            })
+            .collect::<Vec<_>>()
+            .join(" "),
+    }
+}
+
+/// The state a ZIP code belongs to, None for a malformed ZIP or one outside the table
+pub fn zip_state(zip: &str) -> Option<&'static str> {
+    let zip = zip.trim();
+    if zip.len() < 5 || !zip.chars().take(5).all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let prefix: u16 = zip[..3].parse().ok()?;
+    ZIP_PREFIXES
+        .iter()
+        .find(|(from, to, _)| (*from..=*to).contains(&prefix))
+        .map(|(_, _, state)| *state)
+}
+
+/// Five digits, or ZIP+4 with a hyphen
+pub fn valid_zip_format(zip: &str) -> bool {
+    let (zip, plus_four) = match zip.split_once('-') {
+        Some((zip, plus_four)) => (zip, Some(plus_four)),
+        None => (zip, None),
+    };
+    let digits = |s: &str, len: usize| s.len() == len && s.chars().all(|c| c.is_ascii_digit());
+    digits(zip, 5) && plus_four.is_none_or(|plus_four| digits(plus_four, 4))
+}
+
+/// A checked address, ready to store
+#[derive(Clone, PartialEq)]
+pub struct ValidatedAddress {
+    pub street: StreetAddress,
+    pub city: String,
+    pub state: String,
+    pub zip: String,
+}
+
+impl std::fmt::Debug for ValidatedAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ValidatedAddress")
+            .field("street", &Redacted)
+            .field("city", &self.city)
+            .field("state", &self.state)
+            .field("zip", &self.zip)
+            .finish()
+    }
+}
+
+impl ValidatedAddress {
+    pub fn address_one(&self) -> String {
+        self.street.line_one()
+    }
+
+    /// Empty without a unit, as the form sends it
+    pub fn address_two(&self) -> String {
+        self.street.line_two().unwrap_or_default()
+    }
+}
+
+fn field_error(
+    errors: &mut ValidationErrors,
+    field: &'static str,
+    code: &'static str,
+    message: String,
+) {
+    let mut error = ValidationError::new(code);
+    error.message = Some(message.into());
+    errors.add(field, error);
+}
+
+/// Everything but the state's existence, which needs the database. Errors are keyed by form
+/// field like validator's own.
+pub fn check_address(
+    address_one: &str,
+    address_two: &str,
+    city: &str,
+    state: &str,
+    zip: &str,
+) -> Result<ValidatedAddress, ValidationErrors> {
+    let mut errors = ValidationErrors::new();
+    let street = match StreetAddress::parse_line(address_one) {
+        Ok(mut street) => {
+            match tokens(address_two).as_slice() {
+                [] => {}
+                words => match parse_unit(words) {
+                    Ok((designator, unit)) => {
+                        street.unit_designator = Some(designator);
+                        street.unit = unit;
+                    }
+                    Err(err) => field_error(&mut errors, "address_two", "unit", err.to_string()),
+                },
+            }
+            Some(street)
+        }
+        Err(err) => {
+            field_error(&mut errors, "address_one", "street", err.to_string());
+            None
+        }
+    };
+    let city = city.split_whitespace().collect::<Vec<_>>().join(" ");
+    if city.is_empty() {
+        field_error(
+            &mut errors,
+            "city",
+            "required",
+            "City is required".to_owned(),
+        );
+    }
+    let state = state.trim().to_uppercase();
+    let zip = zip.trim().to_owned();
+    if !valid_zip_format(&zip) {
+        field_error(
+            &mut errors,
+            "zip",
+            "format",
+            "ZIP must be 5 digits, or ZIP+4 as 00000-0000".to_owned(),
+        );
+    } else {
+        match zip_state(&zip) {
+            Some(zip_state) if zip_state != state => field_error(
+                &mut errors,
+                "zip",
+                "state",
+                format!("ZIP {} is in {}, not {}", zip, zip_state, state),
+            ),
+            Some(_) => {}
+            None => field_error(
+                &mut errors,
+                "zip",
+                "unknown",
+                format!("ZIP {} isn't a known US ZIP code", zip),
+            ),
+        }
+    }
+    match street {
+        Some(street) if errors.is_empty() => Ok(ValidatedAddress {
+            street,
+            city,
+            state,
+            zip,
+        }),
+        _ => Err(errors),
+    }
+}
+
+pub async fn state_exists(pool: &PgPool, state: &str) -> Result<bool, AppError> {
+    sqlx::query_scalar::<_, bool>("SELECT EXISTS (SELECT 1 FROM states WHERE state_name = $1)")
+        .bind(state.trim().to_uppercase())
+        .fetch_one(pool)
+        .await
+        .map_err(AppError::from)
+}
+
+/// `check_address` plus the state against the states table. The outer error is the database
+/// failing, the inner one the address.
+pub async fn validate_address(
+    pool: &PgPool,
+    address_one: &str,
+    address_two: &str,
+    city: &str,
+    state: &str,
+    zip: &str,
+) -> Result<Result<ValidatedAddress, ValidationErrors>, AppError> {
+    let checked = check_address(address_one, address_two, city, state, zip);
+    if state_exists(pool, state).await? {
+        return Ok(checked);
+    }
+    let mut errors = checked.err().unwrap_or_default();
+    field_error(
+        &mut errors,
+        "state",
+        "unknown",
+        format!("{} isn't a US state", state.trim()),
+    );
+    Ok(Err(errors))
+}
+
+/// Adds `errors` to the outcome of the form's own validation, so one response lists them all
+pub fn merge_errors<T, U>(
+    validated: Result<T, ValidationErrors>,
+    errors: ValidationErrors,
+) -> Result<U, ValidationErrors> {
+    let mut merged = match validated {
+        Ok(_) => ValidationErrors::new(),
+        Err(merged) => merged,
+    };
+    for (field, field_errors) in errors.field_errors() {
+        for error in field_errors {
+            merged.add(field, error.clone());
+        }
+    }
+    Err(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn street(line: &str) -> String {
+        StreetAddress::parse_line(line).unwrap().standardized()
+    }
+
+    #[test]
+    fn parses_and_abbreviates_to_usps() {
+        let address = StreetAddress::parse_line("4483 South 87th Street, Apt. 2").unwrap();
+        assert_eq!(
+            address,
+            StreetAddress {
+                number: "4483".to_owned(),
+                predirectional: Some("S".to_owned()),
+                street_name: "87TH".to_owned(),
+                suffix: Some("ST".to_owned()),
+                postdirectional: None,
+                unit_designator: Some("APT".to_owned()),
+                unit: Some("2".to_owned()),
+            }
+        );
+        assert_eq!(address.line_one(), "4483 S 87TH ST");
+        assert_eq!(address.line_two().as_deref(), Some("APT 2"));
+        assert_eq!(street("101 Hartford St. W"), "101 HARTFORD ST W");
+        assert_eq!(street("7724 pine circle"), "7724 PINE CIR");
+        assert_eq!(street("12 Elm Boulevard #4B"), "12 ELM BLVD # 4B");
+        assert_eq!(street("500 Main Street Suite 200"), "500 MAIN ST STE 200");
+        assert_eq!(street("9 Oak Ln Rear"), "9 OAK LN REAR");
+        assert_eq!(street("100 Old Front Street"), "100 OLD FRONT ST");
+    }
+
+    #[test]
+    fn directionals_and_suffixes_can_be_the_street_name() {
+        assert_eq!(street("123 North St"), "123 NORTH ST");
+        assert_eq!(street("45 West Avenue"), "45 WEST AVE");
+        assert_eq!(street("300 Broadway"), "300 BROADWAY");
+        assert_eq!(street("77 Avenue B"), "77 AVENUE B");
+    }
+
+    #[test]
+    fn rejects_what_isnt_a_street_address() {
+        assert_eq!(StreetAddress::parse_line("  "), Err(AddressError::Empty));
+        assert_eq!(
+            StreetAddress::parse_line("Pine Circle"),
+            Err(AddressError::MissingNumber)
+        );
+        assert_eq!(
+            StreetAddress::parse_line("7724"),
+            Err(AddressError::MissingStreetName)
+        );
+        assert_eq!(
+            StreetAddress::parse_line("7724 Pine Cir Apt"),
+            Err(AddressError::MissingUnit("APT".to_owned()))
+        );
+        assert_eq!(
+            StreetAddress::parse("7724 Pine Cir", "Around back"),
+            Err(AddressError::InvalidUnit)
+        );
+    }
+
+    #[test]
+    fn second_line_is_the_unit() {
+        let address = StreetAddress::parse("7724 Pine Cir", "Apartment 3").unwrap();
+        assert_eq!(address.standardized(), "7724 PINE CIR APT 3");
+        let address = StreetAddress::parse("7724 Pine Cir", "3").unwrap();
+        assert_eq!(address.standardized(), "7724 PINE CIR # 3");
+        let address = StreetAddress::parse("7724 Pine Cir", "").unwrap();
+        assert_eq!(address.line_two(), None);
+    }
+
+    #[test]
+    fn normalizes_for_comparison() {
+        assert_eq!(
+            normalize_address("4483 South 87th Street, Apt. 2"),
+            normalize_address("4483 S 87th St  apt 2")
+        );
+        assert_eq!(normalize_address("PO Box 12"), "PO BOX 12");
+        assert_eq!(normalize_address("Rural Route Street"), "RURAL RTE ST");
+    }
+
+    #[test]
+    fn checks_zip_format_and_state() {
+        assert!(valid_zip_format("68124"));
+        assert!(valid_zip_format("68124-0126"));
+        assert!(!valid_zip_format("6812"));
+        assert!(!valid_zip_format("68124-12"));
+        assert_eq!(zip_state("68124"), Some("NE"));
+        assert_eq!(zip_state("00501"), Some("NY"));
+        assert_eq!(zip_state("09012"), None);
+
+        let address = check_address("7724 Pine Cir", "", " Omaha ", "ne", "68124").unwrap();
+        assert_eq!(address.state, "NE");
+        assert_eq!(address.city, "Omaha");
+        let errors = check_address("Pine Cir", "Out back", "", "IA", "68124").unwrap_err();
+        let mut fields: Vec<_> = errors.field_errors().into_keys().collect();
+        fields.sort();
+        assert_eq!(fields, ["address_one", "city", "zip"]);
+        let errors =
+            check_address("7724 Pine Cir", "Out back", "Omaha", "NE", "09012").unwrap_err();
+        let mut fields: Vec<_> = errors.field_errors().into_keys().collect();
+        fields.sort();
+        assert_eq!(fields, ["address_two", "zip"]);
+    }
+}
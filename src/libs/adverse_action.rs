@@ -0,0 +1,471 @@
+use std::{env, fs, path::Path};
+
+use askama::Template;
+use chrono::{NaiveDate, Utc};
+use serde::Serialize;
+use sqlx::{FromRow, PgPool};
+
+use crate::{
+    error::AppError,
+    libs::{
+        application_lifecycle::ApplicationStatus,
+        loan_statement::{insert_attachment, HTML_MIME_TYPE_ID},
+        mailer::queue_email,
+        pii::keyring,
+        underwriting::{Decision, RuleSet},
+    },
+};
+
+pub const DEFAULT_NOTICE_DIR: &str = "notices";
+const NOTICE_CHANNEL: &str = "AdverseAction";
+/// Reg B: more than four principal reasons isn't likely to be helpful to the applicant
+const MAX_REASONS: usize = 4;
+/// Code used when a reviewer declines outside the rules, with their reason as the explanation
+pub const REVIEWER_DECLINE_CODE: &str = "REVIEWER_DECLINE";
+/// Pricing declines, after the rules approved: no offer is priced for the applicant's score,
+/// or none they're offered keeps their DTI under the policy max
+pub const CREDIT_SCORE_DECLINE_CODE: &str = "CREDIT_SCORE_TOO_LOW";
+pub const DTI_DECLINE_CODE: &str = "DTI_TOO_HIGH";
+
+/// The applicant facing statement for a decline reason code, None for codes without one.
+pub fn explanation(code: &str) -> Option<&'static str> {
+    let s = match code {
+        "INCOME_BELOW_MINIMUM" => "Income insufficient for amount of credit requested",
+        "LOAN_TO_INCOME_TOO_HIGH" => "Amount of credit requested is too large relative to income",
+        "STATE_NOT_ELIGIBLE" => "We do not grant credit to residents of your state",
+        "DTI_TOO_HIGH" => "Excessive obligations in relation to income",
+        "BANKRUPTCY" => "Bankruptcy",
+        "RECENT_DELINQUENCY" => "Delinquent past or present credit obligations with others",
+        "DELINQUENCY_TO_REVIEW" => "Delinquent past or present credit obligations with others",
+        "TAX_LIENS" => "Tax lien on your credit report",
+        "CREDIT_SCORE_TOO_LOW" => "Credit score below our minimum requirement",
+        _ => return None,
+    };
+    Some(s)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NoticeReason {
+    pub code: String,
+    pub explanation: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AdverseActionNotice {
+    pub application_id: i32,
+    pub decision_id: Option<i32>,
+    pub applicant_name: String,
+    pub address_lines: Vec<String>,
+    pub notice_date: NaiveDate,
+    pub lender_name: String,
+    pub reasons: Vec<NoticeReason>,
+    /// The credit scorer's output counted against the applicant, which adds the score disclosure
+    pub credit_score_used: bool,
+}
+
+#[derive(Debug, Template)]
+#[template(path = "adverse_action_notice.html")]
+pub struct AdverseActionNoticeTemplate<'a> {
+    pub notice: &'a AdverseActionNotice,
+}
+
+impl AdverseActionNotice {
+    pub fn subject(&self) -> String {
+        format!("Your application #{}", self.application_id)
+    }
+
+    pub fn to_html(&self) -> Result<String, AppError> {
+        AdverseActionNoticeTemplate { notice: self }
+            .render()
+            .map_err(|err| AppError::GenericError(err.to_string()))
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!(
+            "{}\n\n{}\n{}\n\nDear {},\n\n",
+            self.notice_date.format("%B %-d, %Y"),
+            self.applicant_name,
+            self.address_lines.join("\n"),
+            self.applicant_name
+        );
+        text.push_str(&format!(
+            "Thank you for your application #{}. We are unable to approve it at this time. \
+            The principal reasons for our decision are:\n\n",
+            self.application_id
+        ));
+        for reason in &self.reasons {
+            text.push_str(&format!("  - {}\n", reason.explanation));
+        }
+        if self.credit_score_used {
+            text.push_str(&format!("\n{}\n", CREDIT_SCORE_DISCLOSURE));
+        }
+        text.push_str(&format!(
+            "\n{}\n\nSincerely,\n{}\n",
+            ECOA_NOTICE, self.lender_name
+        ));
+        text
+    }
+
+    pub fn file_stem(&self) -> String {
+        format!("adverse_action_{}", self.application_id)
+    }
+}
+
+pub const ECOA_NOTICE: &str = "The federal Equal Credit Opportunity Act prohibits creditors from \
+    discriminating against credit applicants on the basis of race, color, religion, national origin, \
+    sex, marital status, age (provided the applicant has the capacity to enter into a binding contract); \
+    because all or part of the applicant's income derives from any public assistance program; or because \
+    the applicant has in good faith exercised any right under the Consumer Credit Protection Act. \
+    The federal agency that administers compliance with this law concerning this creditor is the \
+    Consumer Financial Protection Bureau, 1700 G Street NW, Washington, DC 20552.";
+
+pub const CREDIT_SCORE_DISCLOSURE: &str = "Our decision was based in whole or in part on a credit \
+    score we calculated from the information in your application.";
+
+/// Principal reasons in decision order, capped at MAX_REASONS. Codes without a standard
+/// explanation fall back to the rule's own description, then to the code itself.
+pub fn notice_reasons(codes: &[String], rule_set: Option<&RuleSet>) -> Vec<NoticeReason> {
+    codes
+        .iter()
+        .take(MAX_REASONS)
+        .map(|code| {
+            let description = rule_set.and_then(|rules| {
+                rules
+                    .rules
+                    .iter()
+                    .find(|rule| &rule.code == code)
+                    .map(|rule| rule.description.clone())
+            });
+            NoticeReason {
+                code: code.clone(),
+                explanation: explanation(code)
+                    .map(str::to_owned)
+                    .or(description)
+                    .unwrap_or_else(|| code.clone()),
+            }
+        })
+        .collect()
+}
+
+/// Reasons for a decline only the status history records, a pricing decline's code or a
+/// reviewer's own words
+fn history_reasons(reason: String) -> Vec<NoticeReason> {
+    match explanation(&reason) {
+        Some(_) => notice_reasons(&[reason], None),
+        None => vec![NoticeReason {
+            code: REVIEWER_DECLINE_CODE.to_owned(),
+            explanation: reason,
+        }],
+    }
+}
+
+/// Whether the credit scorer's output is among the reasons, which needs the score disclosure
+fn credit_score_used(reasons: &[NoticeReason]) -> bool {
+    reasons.iter().any(|r| r.code == CREDIT_SCORE_DECLINE_CODE)
+}
+
+#[derive(Debug, FromRow)]
+struct Applicant {
+    first_name: String,
+    last_name: String,
+    address_one: Option<String>,
+    address_one_enc: Option<String>,
+    address_two: Option<String>,
+    address_two_enc: Option<String>,
+    city: String,
+    state: Option<String>,
+    zip: Option<String>,
+    /// Email of the user who submitted the application
+    email: Option<String>,
+}
+
+#[derive(Debug, FromRow)]
+struct DeclineDecision {
+    decision_id: i32,
+    reason_codes: Vec<String>,
+    rules: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct StoredNotice {
+    pub notice_id: i32,
+    pub application_id: i32,
+    pub decision_id: Option<i32>,
+    pub attachment_id: i32,
+    pub email_id: Option<i32>,
+    pub reason_codes: Vec<String>,
+}
+
+/// Builds the notice from the latest declining underwriting decision, or for a pricing or
+/// reviewer's decline, the reason recorded with it.
+pub async fn build_notice(
+    pool: &PgPool,
+    application_id: i32,
+    notice_date: NaiveDate,
+) -> Result<(AdverseActionNotice, Option<String>), AppError> {
+    let applicant = sqlx::query_as::<_, Applicant>(
+        "SELECT a.first_name, a.last_name, a.address_one, a.address_one_enc, a.address_two, a.address_two_enc, a.city, a.state, a.zip, u.email
+        FROM applications a
+        LEFT JOIN application_status_history h ON h.application_id = a.application_id AND h.from_status IS NULL
+        LEFT JOIN users u ON u.user_id = h.actor_user_id
+        WHERE a.application_id = $1",
+    )
+    .bind(application_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Application {} not found", application_id)))?;
+
+    let decision = sqlx::query_as::<_, DeclineDecision>(
+        "SELECT d.decision_id, d.reason_codes, r.rules
+        FROM underwriting_decisions d JOIN underwriting_rule_sets r ON r.version = d.rule_set_version
+        WHERE d.application_id = $1 AND d.decision = $2
+        ORDER BY d.decision_id DESC LIMIT 1",
+    )
+    .bind(application_id)
+    .bind(Decision::Decline)
+    .fetch_optional(pool)
+    .await?;
+
+    let (decision_id, reasons) = match decision {
+        Some(decision) => {
+            let rule_set: Option<RuleSet> = serde_json::from_value(decision.rules).ok();
+            (
+                Some(decision.decision_id),
+                notice_reasons(&decision.reason_codes, rule_set.as_ref()),
+            )
+        }
+        None => {
+            let reason: Option<String> = sqlx::query_scalar(
+                "SELECT reason FROM application_status_history
+                WHERE application_id = $1 AND to_status = $2 AND reason IS NOT NULL
+                ORDER BY history_id DESC LIMIT 1",
+            )
+            .bind(application_id)
+            .bind(ApplicationStatus::Declined)
+            .fetch_optional(pool)
+            .await?;
+            let reason = reason.ok_or_else(|| {
+                AppError::InvalidRequest(format!(
+                    "Application {} has no decline reasons to give",
+                    application_id
+                ))
+            })?;
+            (None, history_reasons(reason))
+        }
+    };
+
+    let city_line = [
+        Some(applicant.city.trim().to_owned()),
+        applicant.state.map(|s| s.trim().to_owned()),
+        applicant.zip.map(|z| z.trim().to_owned()),
+    ]
+    .into_iter()
+    .flatten()
+    .filter(|part| !part.is_empty())
+    .collect::<Vec<_>>()
+    .join(" ");
+    let keyring = keyring()?;
+    let address_lines = [
+        keyring.open_or(
+            "applications.address_one",
+            applicant.address_one_enc.as_deref(),
+            applicant.address_one,
+        )?,
+        keyring.open_or(
+            "applications.address_two",
+            applicant.address_two_enc.as_deref(),
+            applicant.address_two,
+        )?,
+        Some(city_line),
+    ]
+    .into_iter()
+    .flatten()
+    .filter(|line| !line.trim().is_empty())
+    .collect();
+
+    let notice = AdverseActionNotice {
+        application_id,
+        decision_id,
+        applicant_name: format!("{} {}", applicant.first_name, applicant.last_name),
+        address_lines,
+        notice_date,
+        lender_name: env::var("LENDER_NAME").unwrap_or_else(|_| "Tokio Actors Lending".to_owned()),
+        credit_score_used: credit_score_used(&reasons),
+        reasons,
+    };
+    Ok((notice, applicant.email))
+}
+
+/// Renders the letter under `dir`, stores it as an attachment owned by `user_id` and queues it for
+/// the applicant. Issuing again for the same application returns the notice already sent.
+pub async fn issue_adverse_action_notice(
+    pool: &PgPool,
+    application_id: i32,
+    user_id: i32,
+    dir: &str,
+) -> Result<StoredNotice, AppError> {
+    let existing = sqlx::query_as::<_, StoredNotice>(
+        "SELECT notice_id, application_id, decision_id, attachment_id, email_id, reason_codes
+        FROM adverse_action_notices WHERE application_id = $1",
+    )
+    .bind(application_id)
+    .fetch_optional(pool)
+    .await?;
+    if let Some(existing) = existing {
+        return Ok(existing);
+    }
+
+    let (notice, email) = build_notice(pool, application_id, Utc::now().date_naive()).await?;
+    let html = notice.to_html()?;
+    let io_err = |err: std::io::Error| AppError::GenericError(err.to_string());
+    fs::create_dir_all(dir).map_err(io_err)?;
+    let path = Path::new(dir).join(format!("{}.html", notice.file_stem()));
+    fs::write(&path, &html).map_err(io_err)?;
+
+    let mut tx = pool.begin().await?;
+    let short_desc = format!("Adverse action notice, application {}", application_id);
+    let attachment_id = insert_attachment(
+        &mut tx,
+        &path,
+        HTML_MIME_TYPE_ID,
+        user_id,
+        NOTICE_CHANNEL,
+        &short_desc,
+    )
+    .await?;
+    let email_id = match &email {
+        Some(to) => Some(
+            queue_email(
+                &mut tx,
+                to,
+                &notice.subject(),
+                &html,
+                &notice.to_text(),
+                Some(attachment_id),
+            )
+            .await?,
+        ),
+        None => {
+            tracing::warn!(
+                application_id,
+                "No email for the applicant, notice stored but not sent"
+            );
+            None
+        }
+    };
+    let reason_codes: Vec<&str> = notice.reasons.iter().map(|r| r.code.as_str()).collect();
+    let stored = sqlx::query_as::<_, StoredNotice>(
+        "INSERT INTO adverse_action_notices (application_id, decision_id, attachment_id, email_id, reason_codes)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING notice_id, application_id, decision_id, attachment_id, email_id, reason_codes",
+    )
+    .bind(application_id)
+    .bind(notice.decision_id)
+    .bind(attachment_id)
+    .bind(email_id)
+    .bind(&reason_codes)
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(stored)
+}
+
+/// The letter as it was sent, None until the notice is issued
+pub async fn issued_notice_html(
+    pool: &PgPool,
+    application_id: i32,
+) -> Result<Option<String>, AppError> {
+    let path: Option<String> = sqlx::query_scalar(
+        "SELECT a.path FROM adverse_action_notices n
+        JOIN attachments a ON a.attachment_id = n.attachment_id
+        WHERE n.application_id = $1",
+    )
+    .bind(application_id)
+    .fetch_optional(pool)
+    .await?;
+    path.map(|path| fs::read_to_string(path).map_err(|err| AppError::GenericError(err.to_string())))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::libs::underwriting::DEFAULT_RULES_PATH;
+
+    fn notice(codes: &[&str]) -> AdverseActionNotice {
+        let codes: Vec<String> = codes.iter().map(|c| c.to_string()).collect();
+        let reasons = notice_reasons(&codes, None);
+        AdverseActionNotice {
+            application_id: 42,
+            decision_id: Some(3),
+            applicant_name: "Jimbo Smith".to_owned(),
+            address_lines: vec!["7724 Pine Cir".to_owned(), "Omaha NE 68124".to_owned()],
+            notice_date: NaiveDate::from_ymd_opt(2024, 3, 14).unwrap(),
+            lender_name: "Test Lending".to_owned(),
+            credit_score_used: credit_score_used(&reasons),
+            reasons,
+        }
+    }
+
+    #[test]
+    fn every_checked_in_decline_code_has_an_explanation() {
+        let rules = RuleSet::load(Path::new(DEFAULT_RULES_PATH)).unwrap();
+        for rule in &rules.rules {
+            assert!(explanation(&rule.code).is_some(), "{}", rule.code);
+        }
+    }
+
+    #[test]
+    fn reasons_fall_back_and_cap_at_four() {
+        let codes: Vec<String> = [
+            "BANKRUPTCY",
+            "SOMETHING_NEW",
+            "DTI_TOO_HIGH",
+            "TAX_LIENS",
+            "CREDIT_SCORE_TOO_LOW",
+        ]
+        .iter()
+        .map(|c| c.to_string())
+        .collect();
+        let reasons = notice_reasons(&codes, None);
+        assert_eq!(reasons.len(), MAX_REASONS);
+        assert_eq!(reasons[0].explanation, "Bankruptcy");
+        assert_eq!(reasons[1].explanation, "SOMETHING_NEW");
+    }
+
+    #[test]
+    fn pricing_declines_keep_their_code() {
+        let reasons = history_reasons(CREDIT_SCORE_DECLINE_CODE.to_owned());
+        assert_eq!(
+            reasons[0].explanation,
+            "Credit score below our minimum requirement"
+        );
+        assert!(credit_score_used(&reasons));
+
+        let reasons = history_reasons(DTI_DECLINE_CODE.to_owned());
+        assert_eq!(reasons[0].code, DTI_DECLINE_CODE);
+        assert!(!credit_score_used(&reasons));
+
+        let reasons = history_reasons("Pay stubs don't match the stated income".to_owned());
+        assert_eq!(reasons[0].code, REVIEWER_DECLINE_CODE);
+        assert_eq!(
+            reasons[0].explanation,
+            "Pay stubs don't match the stated income"
+        );
+    }
+
+    #[test]
+    fn letter_lists_reasons_and_disclosures() {
+        let letter = notice(&["INCOME_BELOW_MINIMUM", "CREDIT_SCORE_TOO_LOW"]);
+        let html = letter.to_html().unwrap();
+        assert!(html.contains("Income insufficient for amount of credit requested"));
+        assert!(html.contains("Equal Credit Opportunity Act"));
+        assert!(html.contains("credit score"));
+        let text = letter.to_text();
+        assert!(text.starts_with("March 14, 2024\n\nJimbo Smith\n7724 Pine Cir\nOmaha NE 68124\n"));
+        assert!(text.contains("  - Credit score below our minimum requirement\n"));
+
+        let without_score = notice(&["BANKRUPTCY"]).to_text();
+        assert!(!without_score.contains(CREDIT_SCORE_DISCLOSURE));
+    }
+}
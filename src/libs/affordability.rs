@@ -0,0 +1,400 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actors::actor::mock_offer,
+    libs::{
+        credit_scorer::CreditScore,
+        underwriting::{Condition, RuleSet, UnderwritingInput},
+    },
+    models::offer::Offer,
+};
+
+/// The DTI cap when the rule set has no max rule on qualifying_dti
+pub const DEFAULT_MAX_DTI: f64 = 40.0;
+
+/// The lender's own offer, priced off the credit score. None when the score is too risky to
+/// price, before any cut for affordability.
+pub fn comp_offer(score: Option<&CreditScore>) -> Option<Offer> {
+    let mut offer = mock_offer(1);
+    match score {
+        Some(score) if !score.approvable() => return None,
+        Some(score) => offer.apr = score.apr(),
+        // No model trained yet, keep the mock pricing
+        None => {}
+    }
+    Some(offer)
+}
+
+/// Where the existing monthly debts came from
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DebtSource {
+    /// What the applicant, and co-borrower, said they pay
+    Stated,
+    /// Worked back from the credit file's DTI
+    CreditFile,
+    /// Neither, projections count the new payment alone
+    Unknown,
+}
+
+/// What the applicant can take on, on the same income and debts underwriting looks at
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AffordabilityProfile {
+    pub annual_income: i32,
+    /// Set on joint applications
+    pub joint_income: Option<i32>,
+    pub qualifying_income: i32,
+    pub existing_monthly_debt: Option<f64>,
+    pub debt_source: DebtSource,
+    /// Before the new loan
+    pub current_dti: Option<f64>,
+    pub max_dti: f64,
+}
+
+/// One offer priced for the applicant
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OfferAffordability {
+    pub offer: Offer,
+    /// The requested amount, held within the offer's range
+    pub amount: i32,
+    pub monthly_payment: f64,
+    /// None without income to divide by
+    pub projected_dti: Option<f64>,
+    /// The most the offer's term and APR allow under max_dti, up to the offer's maximum
+    pub max_affordable_amount: i32,
+    pub within_policy: bool,
+}
+
+/// The most the applicant can borrow at one term and APR, and where the amount they asked for
+/// would leave them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AffordabilityQuote {
+    pub profile: AffordabilityProfile,
+    pub term_months: i32,
+    pub apr: f64,
+    pub max_monthly_payment: f64,
+    pub max_affordable_amount: i32,
+    pub desired_loan_amount: i32,
+    pub desired_monthly_payment: f64,
+    pub desired_projected_dti: Option<f64>,
+    pub desired_within_policy: bool,
+}
+
+/// The max DTI rule on qualifying_dti, the one the DTI_TOO_HIGH decline comes from
+pub fn policy_max_dti(rule_set: Option<&RuleSet>) -> f64 {
+    rule_set
+        .and_then(|rule_set| {
+            rule_set.rules.iter().find_map(|rule| match rule.condition {
+                Condition::Max { value } if rule.field == "qualifying_dti" => Some(value),
+                _ => None,
+            })
+        })
+        .unwrap_or(DEFAULT_MAX_DTI)
+}
+
+fn round_cents(amount: f64) -> f64 {
+    (amount * 100.0).round() / 100.0
+}
+
+/// The level payment that pays off `principal` over `term_months`, APR as a percentage
+pub fn monthly_payment(principal: f64, apr: f64, term_months: i32) -> f64 {
+    if principal <= 0.0 || term_months <= 0 {
+        return 0.0;
+    }
+    let rate = apr / 100.0 / 12.0;
+    if rate <= 0.0 {
+        return principal / term_months as f64;
+    }
+    principal * rate / (1.0 - (1.0 + rate).powi(-term_months))
+}
+
+/// The principal a monthly `payment` pays off over `term_months`, the inverse of monthly_payment
+pub fn max_principal(payment: f64, apr: f64, term_months: i32) -> f64 {
+    if payment <= 0.0 || term_months <= 0 {
+        return 0.0;
+    }
+    let rate = apr / 100.0 / 12.0;
+    if rate <= 0.0 {
+        return payment * term_months as f64;
+    }
+    payment * (1.0 - (1.0 + rate).powi(-term_months)) / rate
+}
+
+impl AffordabilityProfile {
+    /// Stated debts win over the credit file, as they do for the qualifying_dti rule. The file's
+    /// joint DTI is used on joint applications.
+    pub fn new(input: &UnderwritingInput, max_dti: f64) -> Self {
+        let household = input.household();
+        let qualifying_income = household.qualifying_income();
+        let joint = input.co_borrower.is_some();
+        let file_dti = input.credit_file.as_ref().and_then(|file| match joint {
+            true => file.debt_to_income_joint,
+            false => file.debt_to_income,
+        });
+        let (existing_monthly_debt, debt_source) =
+            match (household.qualifying_monthly_debt(), file_dti) {
+                (Some(debt), _) => (Some(debt as f64), DebtSource::Stated),
+                (None, Some(dti)) => (
+                    Some(round_cents(
+                        dti as f64 / 100.0 * qualifying_income as f64 / 12.0,
+                    )),
+                    DebtSource::CreditFile,
+                ),
+                (None, None) => (None, DebtSource::Unknown),
+            };
+        let mut profile = AffordabilityProfile {
+            annual_income: input.annual_income,
+            joint_income: input.co_borrower.map(|_| qualifying_income),
+            qualifying_income,
+            existing_monthly_debt,
+            debt_source,
+            current_dti: None,
+            max_dti,
+        };
+        profile.current_dti = existing_monthly_debt.and_then(|debt| profile.dti(debt));
+        profile
+    }
+
+    fn monthly_income(&self) -> f64 {
+        self.qualifying_income as f64 / 12.0
+    }
+
+    /// Same scale as debt_to_income, a percentage to two places
+    fn dti(&self, monthly_debt: f64) -> Option<f64> {
+        (self.qualifying_income > 0)
+            .then(|| (monthly_debt / self.monthly_income() * 10000.0).round() / 100.0)
+    }
+
+    /// DTI once the new payment is added to the existing debts
+    pub fn projected_dti(&self, monthly_payment: f64) -> Option<f64> {
+        self.dti(self.existing_monthly_debt.unwrap_or(0.0) + monthly_payment)
+    }
+
+    /// The payment room left under max_dti
+    pub fn max_monthly_payment(&self) -> f64 {
+        let room = self.monthly_income() * self.max_dti / 100.0
+            - self.existing_monthly_debt.unwrap_or(0.0);
+        round_cents(room.max(0.0))
+    }
+
+    /// The largest whole-dollar loan whose payment fits under max_dti
+    pub fn max_affordable_amount(&self, apr: f64, term_months: i32) -> i32 {
+        max_principal(self.max_monthly_payment(), apr, term_months)
+            .floor()
+            .min(i32::MAX as f64) as i32
+    }
+
+    pub fn assess_offer(&self, offer: &Offer, desired_amount: i32) -> OfferAffordability {
+        let apr = offer.apr as f64;
+        let amount = desired_amount.clamp(offer.min_amount, offer.max_amount.max(offer.min_amount));
+        let payment = round_cents(monthly_payment(amount as f64, apr, offer.terms));
+        let projected_dti = self.projected_dti(payment);
+        OfferAffordability {
+            offer: offer.clone(),
+            amount,
+            monthly_payment: payment,
+            projected_dti,
+            max_affordable_amount: self
+                .max_affordable_amount(apr, offer.terms)
+                .min(offer.max_amount),
+            within_policy: projected_dti.is_some_and(|dti| dti <= self.max_dti),
+        }
+    }
+
+    pub fn assess_offers(&self, offers: &[Offer], desired_amount: i32) -> Vec<OfferAffordability> {
+        offers
+            .iter()
+            .map(|offer| self.assess_offer(offer, desired_amount))
+            .collect()
+    }
+
+    pub fn quote(self, desired_amount: i32, apr: f64, term_months: i32) -> AffordabilityQuote {
+        let desired_monthly_payment =
+            round_cents(monthly_payment(desired_amount as f64, apr, term_months));
+        let desired_projected_dti = self.projected_dti(desired_monthly_payment);
+        AffordabilityQuote {
+            term_months,
+            apr,
+            max_monthly_payment: self.max_monthly_payment(),
+            max_affordable_amount: self.max_affordable_amount(apr, term_months),
+            desired_loan_amount: desired_amount,
+            desired_monthly_payment,
+            desired_projected_dti,
+            desired_within_policy: desired_projected_dti.is_some_and(|dti| dti <= self.max_dti),
+            profile: self,
+        }
+    }
+
+    /// The offer cut down to what fits under max_dti, None when even its minimum doesn't
+    pub fn affordable_offer(&self, offer: &Offer) -> Option<Offer> {
+        let max_amount = self.max_affordable_amount(offer.apr as f64, offer.terms);
+        (max_amount >= offer.min_amount).then(|| Offer {
+            max_amount: offer.max_amount.min(max_amount),
+            ..offer.clone()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+    use crate::{
+        libs::joint_application::CoBorrowerIncome,
+        models::credit_file::{CreditFile, HomeOwnership},
+    };
+
+    fn input() -> UnderwritingInput {
+        UnderwritingInput {
+            state: "NE".to_owned(),
+            annual_income: 60000,
+            desired_loan_amount: 15000,
+            emp_length: 5,
+            loan_purpose: 1,
+            homeownership: 1,
+            credit_file: None,
+            credit_score_pd: None,
+            monthly_debt: Some(1000),
+            co_borrower: None,
+        }
+    }
+
+    fn offer(min_amount: i32, max_amount: i32, terms: i32, apr: f32) -> Offer {
+        Offer {
+            offer_slug: "offer".to_owned(),
+            servicer_id: 1,
+            max_amount,
+            min_amount,
+            terms,
+            percent_fee: 2.5,
+            apr,
+            expires: NaiveDate::from_ymd_opt(2030, 1, 1).unwrap(),
+        }
+    }
+
+    #[test]
+    fn payments_amortize_and_invert() {
+        let payment = monthly_payment(10000.0, 6.0, 36);
+        assert!((payment - 304.22).abs() < 0.01);
+        assert!((max_principal(payment, 6.0, 36) - 10000.0).abs() < 0.01);
+        assert_eq!(monthly_payment(1200.0, 0.0, 12), 100.0);
+        assert_eq!(max_principal(100.0, 0.0, 12), 1200.0);
+        assert_eq!(monthly_payment(1200.0, 6.0, 0), 0.0);
+    }
+
+    #[test]
+    fn projects_dti_from_stated_debts() {
+        let profile = AffordabilityProfile::new(&input(), 40.0);
+        assert_eq!(profile.debt_source, DebtSource::Stated);
+        assert_eq!(profile.current_dti, Some(20.0));
+        // 40% of 5000 a month, less the 1000 already owed
+        assert_eq!(profile.max_monthly_payment(), 1000.0);
+        assert_eq!(profile.max_affordable_amount(0.0, 36), 36000);
+
+        let fits = profile.assess_offer(&offer(2000, 20000, 36, 0.0), 15000);
+        assert_eq!(fits.monthly_payment, 416.67);
+        assert_eq!(fits.projected_dti, Some(28.33));
+        assert!(fits.within_policy);
+        assert_eq!(fits.max_affordable_amount, 20000);
+
+        let too_much = profile.assess_offer(&offer(30000, 75000, 12, 9.6), 15000);
+        assert_eq!(too_much.amount, 30000);
+        assert!(!too_much.within_policy);
+
+        let quote = profile.quote(40000, 0.0, 36);
+        assert_eq!(quote.max_affordable_amount, 36000);
+        assert_eq!(quote.desired_monthly_payment, 1111.11);
+        assert!(!quote.desired_within_policy);
+    }
+
+    #[test]
+    fn falls_back_to_the_credit_file_and_counts_joint_income() {
+        let mut file = CreditFile::applicant("NE", 60000, HomeOwnership::Rent, 5);
+        file.debt_to_income = Some(10.0);
+        file.debt_to_income_joint = Some(15.0);
+        let from_file = AffordabilityProfile::new(
+            &UnderwritingInput {
+                monthly_debt: None,
+                credit_file: Some(file.clone()),
+                ..input()
+            },
+            40.0,
+        );
+        assert_eq!(from_file.debt_source, DebtSource::CreditFile);
+        assert_eq!(from_file.existing_monthly_debt, Some(500.0));
+
+        let joint = AffordabilityProfile::new(
+            &UnderwritingInput {
+                monthly_debt: None,
+                credit_file: Some(file),
+                co_borrower: Some(CoBorrowerIncome {
+                    annual_income: 30000,
+                    emp_length: 2,
+                    monthly_debt: None,
+                }),
+                ..input()
+            },
+            40.0,
+        );
+        assert_eq!(joint.joint_income, Some(90000));
+        assert_eq!(joint.existing_monthly_debt, Some(1125.0));
+        assert_eq!(joint.current_dti, Some(15.0));
+
+        let unknown = AffordabilityProfile::new(
+            &UnderwritingInput {
+                monthly_debt: None,
+                ..input()
+            },
+            40.0,
+        );
+        assert_eq!(unknown.debt_source, DebtSource::Unknown);
+        assert_eq!(unknown.current_dti, None);
+        assert_eq!(unknown.projected_dti(500.0), Some(10.0));
+    }
+
+    #[test]
+    fn offers_are_cut_to_what_fits_or_dropped() {
+        let profile = AffordabilityProfile::new(&input(), 40.0);
+        let capped = profile
+            .affordable_offer(&offer(2000, 75000, 36, 0.0))
+            .unwrap();
+        assert_eq!(capped.max_amount, 36000);
+        assert!(profile
+            .affordable_offer(&offer(40000, 75000, 36, 0.0))
+            .is_none());
+
+        let broke = AffordabilityProfile::new(
+            &UnderwritingInput {
+                monthly_debt: Some(2500),
+                ..input()
+            },
+            40.0,
+        );
+        assert_eq!(broke.max_monthly_payment(), 0.0);
+        assert!(broke
+            .affordable_offer(&offer(2000, 75000, 36, 6.0))
+            .is_none());
+    }
+
+    #[test]
+    fn max_dti_comes_from_the_rules() {
+        let rule_set = RuleSet::from_toml(
+            r#"
+            version = 1
+            name = "test"
+
+            [[rules]]
+            code = "DTI_TOO_HIGH"
+            description = "Excessive obligations in relation to income"
+            field = "qualifying_dti"
+            op = "max"
+            value = 36
+            action = "decline"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(policy_max_dti(Some(&rule_set)), 36.0);
+        assert_eq!(policy_max_dti(None), DEFAULT_MAX_DTI);
+    }
+}
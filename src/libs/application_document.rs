@@ -0,0 +1,435 @@
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{types::Uuid, FromRow, PgPool};
+
+use crate::{
+    error::AppError,
+    libs::{
+        document_storage::DocumentStorage, loan_statement::insert_attachment,
+        review_queue::ensure_active_consultant,
+    },
+};
+
+/// Largest document accepted, scans of a few pages fit comfortably
+pub const MAX_DOCUMENT_BYTES: usize = 10 * 1024 * 1024;
+const DOCUMENT_CHANNEL: &str = "ApplicationDocument";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[repr(i32)]
+pub enum DocumentType {
+    /// Pay stubs, W-2s, tax returns, bank statements
+    Income = 1,
+    /// Driver's license, passport, state ID
+    Identity = 2,
+}
+
+impl std::fmt::Display for DocumentType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            DocumentType::Income => "Income",
+            DocumentType::Identity => "Identity",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl TryFrom<i32> for DocumentType {
+    type Error = &'static str;
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(DocumentType::Income),
+            2 => Ok(DocumentType::Identity),
+            _ => Err("Invalid DocumentType value"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[repr(i32)]
+pub enum VerificationStatus {
+    Pending = 1,
+    Verified = 2,
+    /// Unreadable, expired or not what it claims to be. Needs a note saying why.
+    Rejected = 3,
+}
+
+impl std::fmt::Display for VerificationStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            VerificationStatus::Pending => "Pending",
+            VerificationStatus::Verified => "Verified",
+            VerificationStatus::Rejected => "Rejected",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl TryFrom<i32> for VerificationStatus {
+    type Error = &'static str;
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(VerificationStatus::Pending),
+            2 => Ok(VerificationStatus::Verified),
+            3 => Ok(VerificationStatus::Rejected),
+            _ => Err("Invalid VerificationStatus value"),
+        }
+    }
+}
+
+/// What an upload actually is, going by its leading bytes rather than its name or the
+/// Content-Type the browser sent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DocumentFormat {
+    Pdf,
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl DocumentFormat {
+    pub fn sniff(bytes: &[u8]) -> Option<DocumentFormat> {
+        match bytes {
+            [b'%', b'P', b'D', b'F', b'-', ..] => Some(DocumentFormat::Pdf),
+            [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => Some(DocumentFormat::Png),
+            [0xFF, 0xD8, 0xFF, ..] => Some(DocumentFormat::Jpeg),
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => {
+                Some(DocumentFormat::Webp)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            DocumentFormat::Pdf => "application/pdf",
+            DocumentFormat::Png => "image/png",
+            DocumentFormat::Jpeg => "image/jpeg",
+            DocumentFormat::Webp => "image/webp",
+        }
+    }
+
+    /// Its row in mime_types
+    pub fn mime_type_id(&self) -> i32 {
+        match self {
+            DocumentFormat::Pdf => 13,
+            DocumentFormat::Png => 1,
+            DocumentFormat::Jpeg => 2,
+            DocumentFormat::Webp => 4,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            DocumentFormat::Pdf => "pdf",
+            DocumentFormat::Png => "png",
+            DocumentFormat::Jpeg => "jpg",
+            DocumentFormat::Webp => "webp",
+        }
+    }
+}
+
+/// A file as it came in from the upload form
+pub struct DocumentUpload {
+    pub document_type: DocumentType,
+    pub file_name: Option<String>,
+    /// The part's Content-Type, only used to catch a mislabelled file
+    pub content_type: Option<String>,
+    pub short_desc: Option<String>,
+    pub bytes: Vec<u8>,
+}
+
+/// Enforces the size limit and the accepted formats. A file labelled as one accepted format
+/// but holding another is refused rather than stored under either.
+pub fn check_upload(upload: &DocumentUpload) -> Result<DocumentFormat, AppError> {
+    if upload.bytes.is_empty() {
+        return Err(AppError::InvalidRequest(
+            "The uploaded file is empty".to_owned(),
+        ));
+    }
+    if upload.bytes.len() > MAX_DOCUMENT_BYTES {
+        return Err(AppError::InvalidRequest(format!(
+            "Documents can be at most {} MB",
+            MAX_DOCUMENT_BYTES / (1024 * 1024)
+        )));
+    }
+    let format = DocumentFormat::sniff(&upload.bytes).ok_or_else(|| {
+        AppError::InvalidRequest("Only PDF, PNG, JPEG and WebP documents are accepted".to_owned())
+    })?;
+    let declared = upload
+        .content_type
+        .as_deref()
+        .map(|content_type| content_type.split(';').next().unwrap_or("").trim())
+        .filter(|content_type| *content_type != "application/octet-stream");
+    if let Some(declared) = declared {
+        if !declared.eq_ignore_ascii_case(format.mime_type()) {
+            return Err(AppError::InvalidRequest(format!(
+                "The file was sent as {} but is {}",
+                declared,
+                format.mime_type()
+            )));
+        }
+    }
+    Ok(format)
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct ApplicationDocument {
+    pub document_id: i32,
+    pub application_id: i32,
+    pub attachment_id: i32,
+    pub document_type: DocumentType,
+    pub file_name: Option<String>,
+    pub short_desc: String,
+    pub mime_type: String,
+    pub byte_size: i32,
+    pub sha256: String,
+    pub uploaded_by: i32,
+    pub verification_status: VerificationStatus,
+    pub verified_by: Option<i32>,
+    pub verified_at: Option<DateTime<Utc>>,
+    pub verification_note: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// Where the storage backend has it, attachments.path
+    #[serde(skip)]
+    pub storage_key: String,
+}
+
+const DOCUMENT_SELECT: &str = "SELECT d.document_id, d.application_id, d.attachment_id,
+        d.document_type, d.file_name, at.short_desc, m.mime_type_name AS mime_type, d.byte_size,
+        d.sha256, at.user_id AS uploaded_by, d.verification_status, d.verified_by, d.verified_at,
+        d.verification_note, d.created_at, at.path AS storage_key
+    FROM application_documents d
+    JOIN attachments at ON at.attachment_id = d.attachment_id
+    JOIN mime_types m ON m.mime_type_id = at.mime_type_id";
+
+/// Checks and stores the file, then records it against the application. The file is removed
+/// again if it can't be recorded.
+pub async fn store_document(
+    pool: &PgPool,
+    storage: &dyn DocumentStorage,
+    application_id: i32,
+    user_id: i32,
+    upload: DocumentUpload,
+) -> Result<ApplicationDocument, AppError> {
+    let format = check_upload(&upload)?;
+    let exists = sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS (SELECT 1 FROM applications WHERE application_id = $1)",
+    )
+    .bind(application_id)
+    .fetch_one(pool)
+    .await?;
+    if !exists {
+        return Err(AppError::NotFound(format!(
+            "Application {} not found",
+            application_id
+        )));
+    }
+
+    let key = format!(
+        "applications/{}/{}.{}",
+        application_id,
+        Uuid::new_v4().simple(),
+        format.extension()
+    );
+    storage.put(&key, &upload.bytes, format.mime_type()).await?;
+    let short_desc = upload
+        .short_desc
+        .filter(|desc| !desc.trim().is_empty())
+        .unwrap_or_else(|| format!("{} document", upload.document_type));
+    let recorded = async {
+        let mut tx = pool.begin().await?;
+        let attachment_id = insert_attachment(
+            &mut tx,
+            Path::new(&key),
+            format.mime_type_id(),
+            user_id,
+            DOCUMENT_CHANNEL,
+            &short_desc,
+        )
+        .await?;
+        let document_id = sqlx::query_scalar::<_, i32>(
+            "INSERT INTO application_documents
+                (application_id, attachment_id, document_type, file_name, byte_size, sha256)
+            VALUES ($1, $2, $3, $4, $5, $6) RETURNING document_id",
+        )
+        .bind(application_id)
+        .bind(attachment_id)
+        .bind(upload.document_type)
+        .bind(&upload.file_name)
+        .bind(upload.bytes.len() as i32)
+        .bind(hex::encode(Sha256::digest(&upload.bytes)))
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok::<_, AppError>(document_id)
+    }
+    .await;
+    match recorded {
+        Ok(document_id) => application_document(pool, application_id, document_id).await,
+        Err(err) => {
+            if let Err(delete_err) = storage.delete(&key).await {
+                tracing::error!(key, error = ?delete_err, "Unrecorded document not removed");
+            }
+            Err(err)
+        }
+    }
+}
+
+pub async fn application_documents(
+    pool: &PgPool,
+    application_id: i32,
+) -> Result<Vec<ApplicationDocument>, AppError> {
+    sqlx::query_as::<_, ApplicationDocument>(&format!(
+        "{} WHERE d.application_id = $1 ORDER BY d.document_id",
+        DOCUMENT_SELECT
+    ))
+    .bind(application_id)
+    .fetch_all(pool)
+    .await
+    .map_err(AppError::from)
+}
+
+pub async fn application_document(
+    pool: &PgPool,
+    application_id: i32,
+    document_id: i32,
+) -> Result<ApplicationDocument, AppError> {
+    sqlx::query_as::<_, ApplicationDocument>(&format!(
+        "{} WHERE d.application_id = $1 AND d.document_id = $2",
+        DOCUMENT_SELECT
+    ))
+    .bind(application_id)
+    .bind(document_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| {
+        AppError::NotFound(format!(
+            "Document {} not found on application {}",
+            document_id, application_id
+        ))
+    })
+}
+
+/// The document and its contents
+pub async fn read_document(
+    pool: &PgPool,
+    storage: &dyn DocumentStorage,
+    application_id: i32,
+    document_id: i32,
+) -> Result<(ApplicationDocument, Vec<u8>), AppError> {
+    let document = application_document(pool, application_id, document_id).await?;
+    let bytes = storage.get(&document.storage_key).await?;
+    Ok((document, bytes))
+}
+
+/// Sets the document's verification status. Only active consultants can, and rejecting needs
+/// a note for the applicant. Moving back to Pending clears who verified it.
+pub async fn verify_document(
+    pool: &PgPool,
+    application_id: i32,
+    document_id: i32,
+    user_id: i32,
+    status: VerificationStatus,
+    note: Option<&str>,
+) -> Result<ApplicationDocument, AppError> {
+    ensure_active_consultant(pool, user_id).await?;
+    let note = note.map(str::trim).filter(|note| !note.is_empty());
+    if status == VerificationStatus::Rejected && note.is_none() {
+        return Err(AppError::InvalidRequest(
+            "A rejected document needs a note saying why".to_owned(),
+        ));
+    }
+    let verified_by = (status != VerificationStatus::Pending).then_some(user_id);
+    let updated = sqlx::query(
+        "UPDATE application_documents
+        SET verification_status = $3, verified_by = $4, verification_note = $5,
+            verified_at = CASE WHEN $4::INTEGER IS NULL THEN NULL ELSE NOW() END
+        WHERE application_id = $1 AND document_id = $2",
+    )
+    .bind(application_id)
+    .bind(document_id)
+    .bind(status)
+    .bind(verified_by)
+    .bind(note)
+    .execute(pool)
+    .await?
+    .rows_affected();
+    if updated == 0 {
+        return Err(AppError::NotFound(format!(
+            "Document {} not found on application {}",
+            document_id, application_id
+        )));
+    }
+    application_document(pool, application_id, document_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upload(bytes: &[u8], content_type: Option<&str>) -> DocumentUpload {
+        DocumentUpload {
+            document_type: DocumentType::Income,
+            file_name: Some("pay_stub.pdf".to_owned()),
+            content_type: content_type.map(str::to_owned),
+            short_desc: None,
+            bytes: bytes.to_vec(),
+        }
+    }
+
+    #[test]
+    fn sniffs_accepted_formats_from_leading_bytes() {
+        assert_eq!(
+            DocumentFormat::sniff(b"%PDF-1.7\n%\xE2\xE3"),
+            Some(DocumentFormat::Pdf)
+        );
+        assert_eq!(
+            DocumentFormat::sniff(b"\x89PNG\r\n\x1A\n\0\0\0\rIHDR"),
+            Some(DocumentFormat::Png)
+        );
+        assert_eq!(
+            DocumentFormat::sniff(b"\xFF\xD8\xFF\xE0\0\x10JFIF"),
+            Some(DocumentFormat::Jpeg)
+        );
+        assert_eq!(
+            DocumentFormat::sniff(b"RIFF\x24\0\0\0WEBPVP8 "),
+            Some(DocumentFormat::Webp)
+        );
+        // WAV is RIFF too
+        assert_eq!(DocumentFormat::sniff(b"RIFF\x24\0\0\0WAVEfmt "), None);
+        assert_eq!(DocumentFormat::sniff(b"<html><script>"), None);
+        assert_eq!(DocumentFormat::sniff(b"%PD"), None);
+    }
+
+    #[test]
+    fn checks_size_format_and_label() {
+        assert_eq!(
+            check_upload(&upload(b"%PDF-1.7", Some("application/pdf"))).unwrap(),
+            DocumentFormat::Pdf
+        );
+        // Browsers that don't know the type send octet-stream, or nothing
+        assert!(check_upload(&upload(b"%PDF-1.7", Some("application/octet-stream"))).is_ok());
+        assert!(check_upload(&upload(b"%PDF-1.7", None)).is_ok());
+        assert!(matches!(
+            check_upload(&upload(b"%PDF-1.7", Some("image/png"))),
+            Err(AppError::InvalidRequest(_))
+        ));
+        assert!(matches!(
+            check_upload(&upload(b"", None)),
+            Err(AppError::InvalidRequest(_))
+        ));
+        assert!(matches!(
+            check_upload(&upload(b"MZ\x90\0", Some("application/pdf"))),
+            Err(AppError::InvalidRequest(_))
+        ));
+        let mut too_big = b"%PDF-1.7".to_vec();
+        too_big.resize(MAX_DOCUMENT_BYTES + 1, b' ');
+        assert!(matches!(
+            check_upload(&upload(&too_big, None)),
+            Err(AppError::InvalidRequest(_))
+        ));
+    }
+}
@@ -0,0 +1,540 @@
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool, Postgres, Transaction};
+use validator::{Validate, ValidationError, ValidationErrors};
+
+use crate::{
+    error::AppError,
+    libs::{
+        address::ValidatedAddress,
+        joint_application::{HouseholdForm, HouseholdInput},
+        pii::{keyring, Redacted},
+    },
+};
+
+/// In-progress drafts a user can have at once
+pub const MAX_OPEN_DRAFTS: i64 = 5;
+const MIN_APPLICANT_AGE: i32 = 18;
+const DRAFT_CONTEXT: &str = "application_drafts.data";
+
+lazy_static! {
+    static ref RE_STATE: Regex = Regex::new(r"^[A-Z]{2}$").unwrap();
+    static ref RE_ZIP: Regex = Regex::new(r"^[0-9]{5}$").unwrap();
+    static ref RE_SSN: Regex = Regex::new(r"^[0-9]{3}-?[0-9]{2}-?[0-9]{4}$").unwrap();
+    static ref RE_PHONE: Regex = Regex::new(r"^([0-9]{3}-[0-9]{3}-[0-9]{4})?$").unwrap();
+    static ref RE_AMOUNT: Regex = Regex::new(r"^[0-9]{0,6}$").unwrap();
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[repr(i32)]
+pub enum DraftStep {
+    Address = 1,
+    LoanInfo = 2,
+    History = 3,
+    /// Every step saved, waiting on the applicant to submit
+    Review = 4,
+}
+
+impl DraftStep {
+    pub const ALL: [DraftStep; 4] = [
+        DraftStep::Address,
+        DraftStep::LoanInfo,
+        DraftStep::History,
+        DraftStep::Review,
+    ];
+
+    pub fn next(&self) -> DraftStep {
+        match self {
+            DraftStep::Address => DraftStep::LoanInfo,
+            DraftStep::LoanInfo => DraftStep::History,
+            DraftStep::History | DraftStep::Review => DraftStep::Review,
+        }
+    }
+
+    /// The step's part of the draft URL
+    pub fn slug(&self) -> &'static str {
+        match self {
+            DraftStep::Address => "address",
+            DraftStep::LoanInfo => "loan-info",
+            DraftStep::History => "history",
+            DraftStep::Review => "review",
+        }
+    }
+
+    pub fn from_slug(slug: &str) -> Option<DraftStep> {
+        DraftStep::ALL.into_iter().find(|step| step.slug() == slug)
+    }
+}
+
+impl std::fmt::Display for DraftStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            DraftStep::Address => "Address",
+            DraftStep::LoanInfo => "Loan Info",
+            DraftStep::History => "History",
+            DraftStep::Review => "Review",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl TryFrom<i32> for DraftStep {
+    type Error = &'static str;
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(DraftStep::Address),
+            2 => Ok(DraftStep::LoanInfo),
+            3 => Ok(DraftStep::History),
+            4 => Ok(DraftStep::Review),
+            _ => Err("Invalid DraftStep value"),
+        }
+    }
+}
+
+fn validate_dob(dob: &str) -> Result<(), ValidationError> {
+    let mut error = ValidationError::new("dob");
+    let Ok(dob) = NaiveDate::parse_from_str(dob, "%Y-%m-%d") else {
+        error.message = Some("Date of birth must be YYYY-MM-DD".into());
+        return Err(error);
+    };
+    let today = Utc::now().date_naive();
+    let adult_on = dob.with_year(dob.year() + MIN_APPLICANT_AGE);
+    // Feb 29 birthdays come of age on Mar 1
+    let adult_on =
+        adult_on.or_else(|| NaiveDate::from_ymd_opt(dob.year() + MIN_APPLICANT_AGE, 3, 1));
+    if adult_on.is_none_or(|adult_on| adult_on > today) {
+        error.message = Some("Applicants must be 18 or older".into());
+        return Err(error);
+    }
+    Ok(())
+}
+
+/// Step one, form/address.html
+#[derive(Clone, Default, Serialize, Deserialize, Validate)]
+pub struct AddressStep {
+    #[validate(length(min = 1, max = 40, message = "First name must be 1 to 40 characters"))]
+    pub first_name: String,
+    #[validate(length(min = 1, max = 40, message = "Last name must be 1 to 40 characters"))]
+    pub last_name: String,
+    #[validate(length(min = 1, max = 100, message = "Address must be 1 to 100 characters"))]
+    pub address_one: String,
+    #[serde(default)]
+    #[validate(length(max = 10, message = "Address line two cannot exceed 10 characters"))]
+    pub address_two: String,
+    #[validate(length(min = 1, max = 28, message = "City must be 1 to 28 characters"))]
+    pub city: String,
+    #[validate(regex(path = "RE_STATE", message = "Choose a state"))]
+    pub state: String,
+    #[validate(regex(path = "RE_ZIP", message = "ZIP must be 5 digits"))]
+    pub zip: String,
+    #[serde(default)]
+    #[validate(regex(path = "RE_PHONE", message = "Phone must be 000-000-0000"))]
+    pub phone: String,
+    #[validate(regex(path = "RE_SSN", message = "SSN must be 000-00-0000"))]
+    pub ssn: String,
+    #[validate(custom = "validate_dob")]
+    pub dob: String,
+    #[validate(range(min = 1, max = 4, message = "Choose a marital status"))]
+    pub marital_status: i32,
+}
+
+impl std::fmt::Debug for AddressStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AddressStep")
+            .field("first_name", &Redacted)
+            .field("last_name", &Redacted)
+            .field("address_one", &Redacted)
+            .field("address_two", &Redacted)
+            .field("city", &self.city)
+            .field("state", &self.state)
+            .field("zip", &self.zip)
+            .field("phone", &Redacted)
+            .field("ssn", &Redacted)
+            .field("dob", &Redacted)
+            .field("marital_status", &self.marital_status)
+            .finish()
+    }
+}
+
+impl AddressStep {
+    /// Saves the address the way USPS writes it, as the application will be
+    pub fn standardize_address(&mut self, address: ValidatedAddress) {
+        self.address_one = address.address_one();
+        self.address_two = address.address_two();
+        self.city = address.city;
+        self.state = address.state;
+        self.zip = address.zip;
+    }
+}
+
+/// Step two, form/loan_info.html
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Validate)]
+pub struct LoanInfoStep {
+    #[validate(range(
+        min = 1,
+        max = 999999,
+        message = "Enter the amount you'd like to borrow"
+    ))]
+    pub desired_loan_amount: i32,
+    #[validate(range(min = 1, max = 6, message = "Choose a loan purpose"))]
+    pub loan_purpose: i32,
+    #[validate(range(min = 0, max = 999999, message = "Annual income must be 0 to 999999"))]
+    pub annual_income: i32,
+    #[validate(range(min = 1, message = "Choose a location"))]
+    pub location_id: i32,
+    #[serde(default)]
+    #[validate(regex(
+        path = "RE_AMOUNT",
+        message = "Monthly debt must be a whole dollar amount"
+    ))]
+    pub monthly_debt: String,
+}
+
+/// Step three, form/history.html with the co-borrower section
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Validate)]
+pub struct HistoryStep {
+    #[validate(range(min = 1, max = 4, message = "Choose a homeownership status"))]
+    pub homeownership: i32,
+    #[validate(range(min = 1, max = 3, message = "Choose an employment status"))]
+    pub employment_status: i32,
+    #[validate(range(min = 0, max = 40, message = "Employment length must be 0 to 40 years"))]
+    pub emp_length: i32,
+    #[serde(flatten)]
+    pub household: HouseholdForm,
+}
+
+/// The steps saved so far. Only steps that validated are kept.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DraftData {
+    pub address: Option<AddressStep>,
+    pub loan_info: Option<LoanInfoStep>,
+    pub history: Option<HistoryStep>,
+}
+
+impl DraftData {
+    /// Where the applicant left off, the first step not yet saved
+    pub fn resume_step(&self) -> DraftStep {
+        if self.address.is_none() {
+            DraftStep::Address
+        } else if self.loan_info.is_none() {
+            DraftStep::LoanInfo
+        } else if self.history.is_none() {
+            DraftStep::History
+        } else {
+            DraftStep::Review
+        }
+    }
+
+    pub fn has_step(&self, step: DraftStep) -> bool {
+        match step {
+            DraftStep::Address => self.address.is_some(),
+            DraftStep::LoanInfo => self.loan_info.is_some(),
+            DraftStep::History => self.history.is_some(),
+            DraftStep::Review => self.resume_step() == DraftStep::Review,
+        }
+    }
+
+    /// The applicant's household from the saved steps, checked again as a whole: the co-borrower
+    /// is compared against the applicant's SSN, which may have changed since step three.
+    pub fn household_input(&self) -> Result<Option<HouseholdInput>, ValidationErrors> {
+        let (Some(address), Some(loan_info), Some(history)) =
+            (&self.address, &self.loan_info, &self.history)
+        else {
+            return Ok(None);
+        };
+        let household = HouseholdForm {
+            monthly_debt: Some(loan_info.monthly_debt.clone()),
+            ..history.household.clone()
+        };
+        household.parse(&address.ssn).map(Some)
+    }
+}
+
+/// Checks `step` on its own, plus the co-borrower section against the applicant for step three
+pub fn validate_history(
+    history: &HistoryStep,
+    address: Option<&AddressStep>,
+) -> Result<(), ValidationErrors> {
+    history.validate()?;
+    history
+        .household
+        .parse(address.map_or("", |address| address.ssn.as_str()))
+        .map(|_| ())
+}
+
+/// One row of application_drafts, the data opened
+#[derive(Debug, Clone)]
+pub struct ApplicationDraft {
+    pub draft_id: i32,
+    pub user_id: i32,
+    pub step: DraftStep,
+    pub data: DraftData,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A draft as listed, without the data
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct DraftSummary {
+    pub draft_id: i32,
+    pub step: DraftStep,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, FromRow)]
+struct DraftRow {
+    draft_id: i32,
+    user_id: i32,
+    step: DraftStep,
+    data_enc: Option<String>,
+    application_id: Option<i32>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+fn not_found(draft_id: i32) -> AppError {
+    AppError::NotFound(format!("Draft {} not found", draft_id))
+}
+
+/// The user's drafts not yet submitted, most recently worked on first
+pub async fn open_drafts(pool: &PgPool, user_id: i32) -> Result<Vec<DraftSummary>, AppError> {
+    sqlx::query_as::<_, DraftSummary>(
+        "SELECT draft_id, step, created_at, updated_at FROM application_drafts
+        WHERE user_id = $1 AND application_id IS NULL ORDER BY updated_at DESC",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+    .map_err(AppError::from)
+}
+
+pub async fn create_draft(pool: &PgPool, user_id: i32) -> Result<i32, AppError> {
+    let open = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM application_drafts WHERE user_id = $1 AND application_id IS NULL",
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+    if open >= MAX_OPEN_DRAFTS {
+        return Err(AppError::InvalidRequest(format!(
+            "You already have {} applications in progress, finish or delete one first",
+            open
+        )));
+    }
+    sqlx::query_scalar(
+        "INSERT INTO application_drafts (user_id, step) VALUES ($1, $2) RETURNING draft_id",
+    )
+    .bind(user_id)
+    .bind(DraftStep::Address)
+    .fetch_one(pool)
+    .await
+    .map_err(AppError::from)
+}
+
+/// The user's draft. Someone else's, or one already submitted, is not found.
+pub async fn load_draft(
+    pool: &PgPool,
+    draft_id: i32,
+    user_id: i32,
+) -> Result<ApplicationDraft, AppError> {
+    let row = sqlx::query_as::<_, DraftRow>(
+        "SELECT draft_id, user_id, step, data_enc, application_id, created_at, updated_at
+        FROM application_drafts WHERE draft_id = $1 AND user_id = $2",
+    )
+    .bind(draft_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?
+    .filter(|row| row.application_id.is_none())
+    .ok_or_else(|| not_found(draft_id))?;
+    let data = match &row.data_enc {
+        Some(sealed) => serde_json::from_str(&keyring()?.open(DRAFT_CONTEXT, sealed)?)
+            .map_err(|err| AppError::GenericError(format!("Unreadable draft data: {}", err)))?,
+        None => DraftData::default(),
+    };
+    Ok(ApplicationDraft {
+        draft_id: row.draft_id,
+        user_id: row.user_id,
+        step: row.step,
+        data,
+        created_at: row.created_at,
+        updated_at: row.updated_at,
+    })
+}
+
+/// Seals and stores the data, moving the draft on to wherever the applicant now resumes
+pub async fn save_draft(pool: &PgPool, draft: &ApplicationDraft) -> Result<DraftStep, AppError> {
+    let json = serde_json::to_string(&draft.data).map_err(|err| {
+        AppError::GenericError(format!("Unable to serialize draft data: {}", err))
+    })?;
+    let step = draft.data.resume_step();
+    sqlx::query(
+        "UPDATE application_drafts SET step = $3, data_enc = $4, updated_at = NOW()
+        WHERE draft_id = $1 AND user_id = $2 AND application_id IS NULL",
+    )
+    .bind(draft.draft_id)
+    .bind(draft.user_id)
+    .bind(step)
+    .bind(keyring()?.seal(DRAFT_CONTEXT, &json)?)
+    .execute(pool)
+    .await?;
+    Ok(step)
+}
+
+/// Links the draft to the application made from it and drops the data, the application has its
+/// own copy. Run in the transaction inserting the application, so a draft that was already
+/// submitted, by a double click or another tab, rolls the second application back.
+pub async fn claim_draft(
+    tx: &mut Transaction<'_, Postgres>,
+    draft_id: i32,
+    user_id: i32,
+    application_id: i32,
+) -> Result<(), AppError> {
+    let claimed = sqlx::query(
+        "UPDATE application_drafts SET application_id = $3, data_enc = NULL, updated_at = NOW()
+        WHERE draft_id = $1 AND user_id = $2 AND application_id IS NULL",
+    )
+    .bind(draft_id)
+    .bind(user_id)
+    .bind(application_id)
+    .execute(&mut **tx)
+    .await?
+    .rows_affected();
+    if claimed == 0 {
+        return Err(AppError::InvalidRequest(format!(
+            "Draft {} has already been submitted",
+            draft_id
+        )));
+    }
+    Ok(())
+}
+
+pub async fn delete_draft(pool: &PgPool, draft_id: i32, user_id: i32) -> Result<(), AppError> {
+    let deleted = sqlx::query(
+        "DELETE FROM application_drafts WHERE draft_id = $1 AND user_id = $2 AND application_id IS NULL",
+    )
+    .bind(draft_id)
+    .bind(user_id)
+    .execute(pool)
+    .await?
+    .rows_affected();
+    if deleted == 0 {
+        return Err(not_found(draft_id));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address() -> AddressStep {
+        AddressStep {
+            first_name: "Jimbo".to_owned(),
+            last_name: "Smith".to_owned(),
+            address_one: "7724 Pine Cir".to_owned(),
+            address_two: String::new(),
+            city: "Omaha".to_owned(),
+            state: "NE".to_owned(),
+            zip: "68124".to_owned(),
+            phone: "402-392-0126".to_owned(),
+            ssn: "666-66-6666".to_owned(),
+            dob: "1987-07-24".to_owned(),
+            marital_status: 1,
+        }
+    }
+
+    fn fields(errors: ValidationErrors) -> Vec<&'static str> {
+        let mut fields: Vec<_> = errors.field_errors().into_keys().collect();
+        fields.sort();
+        fields
+    }
+
+    #[test]
+    fn steps_validate_on_their_own() {
+        assert!(address().validate().is_ok());
+        let bad = AddressStep {
+            zip: "6812".to_owned(),
+            ssn: "666-66-666".to_owned(),
+            dob: "07-24-1987".to_owned(),
+            phone: String::new(),
+            ..address()
+        };
+        assert_eq!(fields(bad.validate().unwrap_err()), ["dob", "ssn", "zip"]);
+
+        let minor = AddressStep {
+            dob: Utc::now().date_naive().to_string(),
+            ..address()
+        };
+        assert_eq!(fields(minor.validate().unwrap_err()), ["dob"]);
+
+        let loan_info = LoanInfoStep {
+            desired_loan_amount: 0,
+            loan_purpose: 1,
+            annual_income: 75000,
+            location_id: 1,
+            monthly_debt: "12.50".to_owned(),
+        };
+        assert_eq!(
+            fields(loan_info.validate().unwrap_err()),
+            ["desired_loan_amount", "monthly_debt"]
+        );
+    }
+
+    #[test]
+    fn resumes_at_the_first_missing_step() {
+        let mut data = DraftData::default();
+        assert_eq!(data.resume_step(), DraftStep::Address);
+        data.address = Some(address());
+        assert_eq!(data.resume_step(), DraftStep::LoanInfo);
+        // A later step saved out of order doesn't skip the missing one
+        data.history = Some(HistoryStep::default());
+        assert_eq!(data.resume_step(), DraftStep::LoanInfo);
+        assert!(!data.has_step(DraftStep::Review));
+        data.loan_info = Some(LoanInfoStep::default());
+        assert_eq!(data.resume_step(), DraftStep::Review);
+    }
+
+    #[test]
+    fn co_borrower_is_checked_against_the_applicant() {
+        let history = HistoryStep {
+            homeownership: 3,
+            employment_status: 1,
+            emp_length: 4,
+            household: HouseholdForm {
+                joint: Some("true".to_owned()),
+                co_first_name: Some("Pat".to_owned()),
+                co_last_name: Some("Smith".to_owned()),
+                co_ssn: Some("666-66-6666".to_owned()),
+                co_dob: Some("1988-04-12".to_owned()),
+                co_annual_income: Some("40000".to_owned()),
+                co_employment_status: Some("1".to_owned()),
+                co_emp_length: Some("6".to_owned()),
+                ..HouseholdForm::default()
+            },
+        };
+        assert!(validate_history(&history, None).is_ok());
+        let errors = validate_history(&history, Some(&address())).unwrap_err();
+        assert_eq!(fields(errors), ["co_ssn"]);
+    }
+
+    #[test]
+    fn steps_round_trip_through_slugs() {
+        for step in DraftStep::ALL {
+            assert_eq!(DraftStep::from_slug(step.slug()), Some(step));
+            assert_eq!(DraftStep::try_from(step as i32), Ok(step));
+        }
+        assert_eq!(DraftStep::from_slug("submit"), None);
+    }
+
+    #[test]
+    fn debug_output_is_redacted() {
+        let debug = format!("{:?}", address());
+        assert!(!debug.contains("666-66-6666"));
+        assert!(!debug.contains("Pine"));
+        assert!(debug.contains("68124"));
+    }
+}
@@ -0,0 +1,375 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool, Postgres, Transaction};
+
+use crate::{
+    error::AppError,
+    libs::{
+        audit_trail::begin_audited,
+        outbox::{record_event, EventType},
+    },
+};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, sqlx::Type)]
+#[repr(i32)]
+pub enum ApplicationStatus {
+    Draft = 1,
+    Submitted = 2,
+    UnderReview = 3,
+    OffersPresented = 4,
+    OfferAccepted = 5,
+    Funded = 6,
+    Declined = 7,
+    Withdrawn = 8,
+}
+
+use ApplicationStatus::*;
+
+impl ApplicationStatus {
+    /// Where an application can go from here. Moving back a step (e.g. OffersPresented to
+    /// UnderReview when the offers need repricing) is allowed but needs a reason.
+    pub fn allowed_next(&self) -> &'static [ApplicationStatus] {
+        match self {
+            Draft => &[Submitted, Withdrawn],
+            Submitted => &[UnderReview, Draft, Declined, Withdrawn],
+            UnderReview => &[OffersPresented, Submitted, Declined, Withdrawn],
+            OffersPresented => &[OfferAccepted, UnderReview, Declined, Withdrawn],
+            OfferAccepted => &[Funded, OffersPresented, Withdrawn],
+            Funded | Declined | Withdrawn => &[],
+        }
+    }
+
+    pub fn can_transition_to(&self, to: ApplicationStatus) -> bool {
+        self.allowed_next().contains(&to)
+    }
+
+    pub fn is_terminal(&self) -> bool {
+        self.allowed_next().is_empty()
+    }
+
+    /// Back along the Draft..Funded path. Declined and Withdrawn are never backward.
+    pub fn is_backward(&self, to: ApplicationStatus) -> bool {
+        !matches!(to, Declined | Withdrawn) && (to as i32) < (*self as i32)
+    }
+
+    /// Declines and backward moves have to say why
+    pub fn requires_reason(&self, to: ApplicationStatus) -> bool {
+        to == Declined || self.is_backward(to)
+    }
+
+    /// Approving or declining an application under review. Those go through the review queue,
+    /// made by the reviewer holding the claim.
+    pub fn is_review_decision(&self, to: ApplicationStatus) -> bool {
+        *self == UnderReview && matches!(to, OffersPresented | Declined)
+    }
+}
+
+impl std::fmt::Display for ApplicationStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Draft => "Draft",
+            Submitted => "Submitted",
+            UnderReview => "Under Review",
+            OffersPresented => "Offers Presented",
+            OfferAccepted => "Offer Accepted",
+            Funded => "Funded",
+            Declined => "Declined",
+            Withdrawn => "Withdrawn",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl TryFrom<i32> for ApplicationStatus {
+    type Error = &'static str;
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Draft),
+            2 => Ok(Submitted),
+            3 => Ok(UnderReview),
+            4 => Ok(OffersPresented),
+            5 => Ok(OfferAccepted),
+            6 => Ok(Funded),
+            7 => Ok(Declined),
+            8 => Ok(Withdrawn),
+            _ => Err("Invalid ApplicationStatus value"),
+        }
+    }
+}
+
+/// One row of application_status_history
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct StatusChange {
+    pub history_id: i32,
+    pub application_id: i32,
+    pub from_status: Option<ApplicationStatus>,
+    pub to_status: ApplicationStatus,
+    /// None when the system made the change
+    pub actor_user_id: Option<i32>,
+    pub reason: Option<String>,
+    pub changed_at: DateTime<Utc>,
+}
+
+/// Checks a requested move against the workflow, without touching the database.
+pub fn validate_transition(
+    application_id: i32,
+    from: ApplicationStatus,
+    to: ApplicationStatus,
+    reason: Option<&str>,
+) -> Result<(), AppError> {
+    if !from.can_transition_to(to) {
+        return Err(AppError::InvalidRequest(format!(
+            "Application {} cannot move from {} to {}",
+            application_id, from, to
+        )));
+    }
+    if from.requires_reason(to) && reason.is_none_or(|reason| reason.trim().is_empty()) {
+        return Err(AppError::InvalidRequest(format!(
+            "A reason is required to move application {} from {} to {}",
+            application_id, from, to
+        )));
+    }
+    Ok(())
+}
+
+async fn insert_status_change(
+    tx: &mut Transaction<'_, Postgres>,
+    application_id: i32,
+    from: Option<ApplicationStatus>,
+    to: ApplicationStatus,
+    actor_user_id: Option<i32>,
+    reason: Option<&str>,
+) -> Result<StatusChange, AppError> {
+    sqlx::query("UPDATE applications SET application_status = $1, updated_at = NOW() WHERE application_id = $2")
+        .bind(to)
+        .bind(application_id)
+        .execute(&mut **tx)
+        .await?;
+    let change = sqlx::query_as::<_, StatusChange>(
+        "INSERT INTO application_status_history (application_id, from_status, to_status, actor_user_id, reason)
+        VALUES ($1, $2, $3, $4, NULLIF(TRIM($5), ''))
+        RETURNING history_id, application_id, from_status, to_status, actor_user_id, reason, changed_at",
+    )
+    .bind(application_id)
+    .bind(from)
+    .bind(to)
+    .bind(actor_user_id)
+    .bind(reason)
+    .fetch_one(&mut **tx)
+    .await?;
+    record_event(tx, EventType::ApplicationStatusChanged, application_id, &change).await?;
+    Ok(change)
+}
+
+/// First history row for a new application. `apply` inserts straight into Submitted.
+pub async fn record_created(
+    pool: &PgPool,
+    application_id: i32,
+    status: ApplicationStatus,
+    actor_user_id: Option<i32>,
+) -> Result<StatusChange, AppError> {
+    let mut tx = begin_audited(pool).await?;
+    let change = record_created_in(&mut tx, application_id, status, actor_user_id).await?;
+    tx.commit().await?;
+    Ok(change)
+}
+
+/// `record_created` inside the transaction that inserts the application, so it never exists
+/// without the history row that says who it belongs to.
+pub async fn record_created_in(
+    tx: &mut Transaction<'_, Postgres>,
+    application_id: i32,
+    status: ApplicationStatus,
+    actor_user_id: Option<i32>,
+) -> Result<StatusChange, AppError> {
+    insert_status_change(tx, application_id, None, status, actor_user_id, None).await
+}
+
+/// Moves an application along the workflow. The row is locked so two reviewers can't both
+/// move it from the same status.
+pub async fn transition_application(
+    pool: &PgPool,
+    application_id: i32,
+    to: ApplicationStatus,
+    actor_user_id: Option<i32>,
+    reason: Option<&str>,
+) -> Result<StatusChange, AppError> {
+    let mut tx = begin_audited(pool).await?;
+    let change =
+        transition_application_in(&mut tx, application_id, to, actor_user_id, reason).await?;
+    tx.commit().await?;
+    Ok(change)
+}
+
+/// `transition_application` inside the caller's transaction, for changes that have to commit
+/// along with the move.
+pub async fn transition_application_in(
+    tx: &mut Transaction<'_, Postgres>,
+    application_id: i32,
+    to: ApplicationStatus,
+    actor_user_id: Option<i32>,
+    reason: Option<&str>,
+) -> Result<StatusChange, AppError> {
+    let from = lock_application_status(tx, application_id).await?;
+    validate_transition(application_id, from, to, reason)?;
+    let change =
+        insert_status_change(tx, application_id, Some(from), to, actor_user_id, reason).await?;
+    tracing::info!(application_id, %from, %to, "Application status changed");
+    Ok(change)
+}
+
+/// The application's status, with the row locked until the transaction ends
+pub async fn lock_application_status(
+    tx: &mut Transaction<'_, Postgres>,
+    application_id: i32,
+) -> Result<ApplicationStatus, AppError> {
+    sqlx::query_scalar::<_, ApplicationStatus>(
+        "SELECT application_status FROM applications WHERE application_id = $1 FOR UPDATE",
+    )
+    .bind(application_id)
+    .fetch_optional(&mut **tx)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Application {} not found", application_id)))
+}
+
+/// A move made by staff or the applicant directly, which can't be a review decision
+pub async fn transition_outside_review(
+    pool: &PgPool,
+    application_id: i32,
+    to: ApplicationStatus,
+    actor_user_id: Option<i32>,
+    reason: Option<&str>,
+) -> Result<StatusChange, AppError> {
+    let mut tx = begin_audited(pool).await?;
+    let from = lock_application_status(&mut tx, application_id).await?;
+    if from.is_review_decision(to) {
+        return Err(AppError::InvalidRequest(format!(
+            "Application {} is under review, claim it and review it to move it to {}",
+            application_id, to
+        )));
+    }
+    let change =
+        transition_application_in(&mut tx, application_id, to, actor_user_id, reason).await?;
+    tx.commit().await?;
+    Ok(change)
+}
+
+/// Who submitted the application, the actor on its first status
+pub async fn application_owner(
+    pool: &PgPool,
+    application_id: i32,
+) -> Result<Option<i32>, AppError> {
+    sqlx::query_scalar::<_, Option<i32>>(
+        "SELECT actor_user_id FROM application_status_history
+        WHERE application_id = $1 AND from_status IS NULL ORDER BY history_id LIMIT 1",
+    )
+    .bind(application_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(AppError::from)
+    .map(Option::flatten)
+}
+
+pub async fn application_status(
+    pool: &PgPool,
+    application_id: i32,
+) -> Result<ApplicationStatus, AppError> {
+    sqlx::query_scalar::<_, ApplicationStatus>(
+        "SELECT application_status FROM applications WHERE application_id = $1",
+    )
+    .bind(application_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Application {} not found", application_id)))
+}
+
+pub async fn status_history(
+    pool: &PgPool,
+    application_id: i32,
+) -> Result<Vec<StatusChange>, AppError> {
+    sqlx::query_as::<_, StatusChange>(
+        "SELECT history_id, application_id, from_status, to_status, actor_user_id, reason, changed_at
+        FROM application_status_history WHERE application_id = $1 ORDER BY history_id",
+    )
+    .bind(application_id)
+    .fetch_all(pool)
+    .await
+    .map_err(AppError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [ApplicationStatus; 8] = [
+        Draft,
+        Submitted,
+        UnderReview,
+        OffersPresented,
+        OfferAccepted,
+        Funded,
+        Declined,
+        Withdrawn,
+    ];
+
+    #[test]
+    fn happy_path_is_allowed() {
+        let path = [
+            Draft,
+            Submitted,
+            UnderReview,
+            OffersPresented,
+            OfferAccepted,
+            Funded,
+        ];
+        for step in path.windows(2) {
+            assert!(validate_transition(1, step[0], step[1], None).is_ok());
+        }
+    }
+
+    #[test]
+    fn terminal_statuses_go_nowhere() {
+        for status in [Funded, Declined, Withdrawn] {
+            assert!(status.is_terminal());
+            assert!(ALL.iter().all(|to| !status.can_transition_to(*to)));
+        }
+        // Funded is past the point of withdrawing
+        assert!(!OfferAccepted.is_terminal());
+        assert!(validate_transition(1, Funded, Withdrawn, Some("changed mind")).is_err());
+    }
+
+    #[test]
+    fn skipping_steps_is_rejected() {
+        assert!(validate_transition(1, Submitted, OffersPresented, None).is_err());
+        assert!(validate_transition(1, Draft, Funded, None).is_err());
+        assert!(validate_transition(1, OffersPresented, Funded, None).is_err());
+    }
+
+    #[test]
+    fn backward_moves_and_declines_need_a_reason() {
+        assert!(OffersPresented.is_backward(UnderReview));
+        assert!(!OffersPresented.is_backward(Declined));
+        assert!(validate_transition(1, OffersPresented, UnderReview, None).is_err());
+        assert!(validate_transition(1, OffersPresented, UnderReview, Some("  ")).is_err());
+        assert!(validate_transition(1, OffersPresented, UnderReview, Some("Reprice")).is_ok());
+        assert!(validate_transition(1, UnderReview, Declined, None).is_err());
+        assert!(validate_transition(1, UnderReview, Withdrawn, None).is_ok());
+    }
+
+    #[test]
+    fn review_decisions_are_approve_and_decline_from_review() {
+        assert!(UnderReview.is_review_decision(OffersPresented));
+        assert!(UnderReview.is_review_decision(Declined));
+        assert!(!UnderReview.is_review_decision(Withdrawn));
+        assert!(!UnderReview.is_review_decision(Submitted));
+        assert!(!OffersPresented.is_review_decision(Declined));
+    }
+
+    #[test]
+    fn codes_round_trip() {
+        for status in ALL {
+            assert_eq!(ApplicationStatus::try_from(status as i32), Ok(status));
+        }
+        assert!(ApplicationStatus::try_from(0).is_err());
+    }
+}
@@ -0,0 +1,299 @@
+use std::future::Future;
+
+use axum::{extract::Request, http::HeaderValue, middleware::Next, response::Response};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{types::Uuid, FromRow, PgPool, Postgres, Transaction};
+
+use crate::{error::AppError, users::AuthSession};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Who is making the change and for which request, set on each audited transaction so the audit
+/// triggers can record it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AuditContext {
+    pub user_id: Option<i32>,
+    pub request_id: Option<String>,
+}
+
+tokio::task_local! {
+    static AUDIT_CONTEXT: AuditContext;
+}
+
+/// The context of the request this task is serving, None outside one
+pub fn current_audit_context() -> Option<AuditContext> {
+    AUDIT_CONTEXT.try_with(Clone::clone).ok()
+}
+
+/// Runs `f` with `context`. Spawned tasks don't inherit it, a task doing work for a request
+/// should be wrapped in this with `current_audit_context()`.
+pub async fn in_audit_context<F: Future>(context: Option<AuditContext>, f: F) -> F::Output {
+    match context {
+        Some(context) => AUDIT_CONTEXT.scope(context, f).await,
+        None => f.await,
+    }
+}
+
+/// Begins a transaction with this task's audit context in audit.user_id and audit.request_id for
+/// the audit triggers. They're transaction-local, so nothing carries over to the connection's
+/// next user and outside a request there's nothing to set. Writes to an audited table go through
+/// one of these to be put down to the request.
+pub async fn begin_audited(pool: &PgPool) -> Result<Transaction<'static, Postgres>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    if let Some(context) = current_audit_context() {
+        sqlx::query(
+            "SELECT set_config('audit.user_id', $1, true), set_config('audit.request_id', $2, true)",
+        )
+        .bind(
+            context
+                .user_id
+                .map(|user_id| user_id.to_string())
+                .unwrap_or_default(),
+        )
+        .bind(context.request_id.unwrap_or_default())
+        .execute(&mut *tx)
+        .await?;
+    }
+    Ok(tx)
+}
+
+/// Keeps a caller's request ID if it's a sane one, so a request can be followed across services
+fn valid_request_id(request_id: &&str) -> bool {
+    (1..=64).contains(&request_id.len())
+        && request_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Middleware giving each request an ID, echoed back in x-request-id, and running the handler
+/// with the signed in user as the audit context. Has to sit inside the auth layer.
+pub async fn audit_context(auth_session: AuthSession, request: Request, next: Next) -> Response {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(valid_request_id)
+        .map(str::to_owned)
+        .unwrap_or_else(|| Uuid::new_v4().simple().to_string());
+    let context = AuditContext {
+        user_id: auth_session.user.map(|user| user.user_id),
+        request_id: Some(request_id.clone()),
+    };
+    let mut response = AUDIT_CONTEXT.scope(context, next.run(request)).await;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+/// The tables with an audit trigger, see the audit_trail migration
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum AuditedEntity {
+    Application,
+    Offer,
+    Loan,
+    User,
+}
+
+impl AuditedEntity {
+    pub fn table_name(&self) -> &'static str {
+        match self {
+            AuditedEntity::Application => "applications",
+            AuditedEntity::Offer => "offers",
+            AuditedEntity::Loan => "loans",
+            AuditedEntity::User => "users",
+        }
+    }
+}
+
+impl std::fmt::Display for AuditedEntity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            AuditedEntity::Application => "Application",
+            AuditedEntity::Offer => "Offer",
+            AuditedEntity::Loan => "Loan",
+            AuditedEntity::User => "User",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[repr(i32)]
+pub enum AuditAction {
+    Insert = 1,
+    Update = 2,
+    Delete = 3,
+}
+
+impl std::fmt::Display for AuditAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            AuditAction::Insert => "Created",
+            AuditAction::Update => "Updated",
+            AuditAction::Delete => "Deleted",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl TryFrom<i32> for AuditAction {
+    type Error = &'static str;
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(AuditAction::Insert),
+            2 => Ok(AuditAction::Update),
+            3 => Ok(AuditAction::Delete),
+            _ => Err("Invalid AuditAction value"),
+        }
+    }
+}
+
+/// One column's before and after. Null on the side of an insert or delete, "[redacted]" for
+/// columns the trigger doesn't copy.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct AuditChange {
+    pub field: String,
+    pub old: Value,
+    pub new: Value,
+}
+
+fn display_value(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+impl AuditChange {
+    pub fn old_text(&self) -> String {
+        display_value(&self.old)
+    }
+
+    pub fn new_text(&self) -> String {
+        display_value(&self.new)
+    }
+}
+
+/// The trigger's {"column": {"old": .., "new": ..}}, by column name
+pub fn parse_changes(changes: &Value) -> Vec<AuditChange> {
+    let Some(columns) = changes.as_object() else {
+        return vec![];
+    };
+    let mut changes: Vec<AuditChange> = columns
+        .iter()
+        .map(|(field, change)| AuditChange {
+            field: field.clone(),
+            old: change.get("old").cloned().unwrap_or(Value::Null),
+            new: change.get("new").cloned().unwrap_or(Value::Null),
+        })
+        .collect();
+    changes.sort_by(|x, y| x.field.cmp(&y.field));
+    changes
+}
+
+#[derive(Debug, FromRow)]
+struct AuditRow {
+    audit_id: i64,
+    action: AuditAction,
+    changes: Value,
+    actor_user_id: Option<i32>,
+    actor_username: Option<String>,
+    request_id: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEntry {
+    pub audit_id: i64,
+    pub action: AuditAction,
+    pub changes: Vec<AuditChange>,
+    /// None for changes made outside a request, e.g. the nightly jobs
+    pub actor_user_id: Option<i32>,
+    pub actor_username: Option<String>,
+    pub request_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<AuditRow> for AuditEntry {
+    fn from(row: AuditRow) -> Self {
+        AuditEntry {
+            audit_id: row.audit_id,
+            action: row.action,
+            changes: parse_changes(&row.changes),
+            actor_user_id: row.actor_user_id,
+            actor_username: row.actor_username,
+            request_id: row.request_id,
+            created_at: row.created_at,
+        }
+    }
+}
+
+/// Every recorded change to one row, newest first
+pub async fn entity_audit_trail(
+    pool: &PgPool,
+    entity: AuditedEntity,
+    entity_id: i32,
+) -> Result<Vec<AuditEntry>, AppError> {
+    let rows = sqlx::query_as::<_, AuditRow>(
+        "SELECT a.audit_id, a.action, a.changes, a.actor_user_id, u.username AS actor_username, a.request_id, a.created_at
+        FROM audit_log a LEFT JOIN users u ON u.user_id = a.actor_user_id
+        WHERE a.table_name = $1 AND a.entity_id = $2
+        ORDER BY a.audit_id DESC",
+    )
+    .bind(entity.table_name())
+    .bind(entity_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.into_iter().map(AuditEntry::from).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn changes_parse_by_column() {
+        let changes = parse_changes(&json!({
+            "first_name": {"old": "[redacted]", "new": "[redacted]"},
+            "annual_income": {"old": 75000, "new": 75001},
+            "consultant_id": {"old": null, "new": 3},
+        }));
+        let fields: Vec<&str> = changes.iter().map(|change| change.field.as_str()).collect();
+        assert_eq!(fields, ["annual_income", "consultant_id", "first_name"]);
+        assert_eq!(changes[0].new_text(), "75001");
+        assert_eq!(changes[1].old_text(), "");
+        assert_eq!(changes[2].old_text(), "[redacted]");
+        assert!(parse_changes(&json!([])).is_empty());
+    }
+
+    #[test]
+    fn request_ids_are_checked() {
+        assert!(valid_request_id(&"7f3c2a9e-41d2"));
+        assert!(!valid_request_id(&""));
+        assert!(!valid_request_id(&"drop table; --"));
+        assert!(!valid_request_id(&"x".repeat(65).as_str()));
+    }
+
+    #[tokio::test]
+    async fn context_follows_the_task_it_was_set_on() {
+        assert_eq!(current_audit_context(), None);
+        let context = AuditContext {
+            user_id: Some(7),
+            request_id: Some("abc".to_owned()),
+        };
+        let seen = in_audit_context(Some(context.clone()), async {
+            let spawned = tokio::spawn(async { current_audit_context() })
+                .await
+                .unwrap();
+            (current_audit_context(), spawned)
+        })
+        .await;
+        assert_eq!(seen, (Some(context), None));
+    }
+}
@@ -29,6 +29,19 @@ impl std::str::FromStr for HomeOwnership {
     }
 }
 
+// Same codes as config::homeownership_options, which the application form posts
+impl TryFrom<i32> for HomeOwnership {
+    type Error = &'static str;
+    fn try_from(code: i32) -> Result<Self, Self::Error> {
+        match code {
+            1 => Ok(HomeOwnership::Own),
+            2 => Ok(HomeOwnership::Mortgage),
+            3 => Ok(HomeOwnership::Rent),
+            _ => Err("Invalid HomeOwnership code"),
+        }
+    }
+}
+
 impl std::str::FromStr for IncomeVerification {
     type Err = &'static str;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -50,9 +63,22 @@ pub fn convert_income_verification(iv_str: &str) -> Result<IncomeVerification, &
 }
 
 pub fn handle_na_col(col_val: &str) -> Result<Option<i32>, &'static str> {
-    match col_val {
-        "NA" => Ok(None),
-        _ => Ok(Some(col_val.parse::<i32>().unwrap())),
+    match col_val.trim() {
+        "NA" | "" => Ok(None),
+        val => val
+            .parse::<i32>()
+            .map(Some)
+            .map_err(|_| "Invalid integer value"),
+    }
+}
+
+pub fn handle_na_float_col(col_val: &str) -> Result<Option<f32>, &'static str> {
+    match col_val.trim() {
+        "NA" | "" => Ok(None),
+        val => val
+            .parse::<f32>()
+            .map(Some)
+            .map_err(|_| "Invalid decimal value"),
     }
 }
 
@@ -99,4 +125,12 @@ mod tests {
         let converted_ho = convert_income_verification(TEST_STR).unwrap();
         assert_eq!(converted_ho, IncomeVerification::SourceVerified);
     }
+
+    #[test]
+    fn test_handle_na_col() {
+        assert_eq!(handle_na_col("NA"), Ok(None));
+        assert_eq!(handle_na_col(""), Ok(None));
+        assert_eq!(handle_na_col("12"), Ok(Some(12)));
+        assert!(handle_na_col("twelve").is_err());
+    }
 }
@@ -0,0 +1,561 @@
+use std::{collections::HashMap, env};
+
+use csv::{Reader, StringRecord};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool, Postgres, QueryBuilder, Transaction};
+use tokio::sync::broadcast;
+
+use crate::{
+    error::AppError,
+    libs::credit_file_enums::{
+        convert_homeownership, convert_income_verification, handle_na_col, handle_na_float_col,
+    },
+    models::credit_file::{CreditFile, HomeOwnership, IncomeVerification},
+};
+
+pub const DEFAULT_CREDIT_FILE_CSV: &str = "assets/data/____credit_file.csv";
+// 39 binds per row. Keeps us well under the 65535 bind limit
+const BATCH_SIZE: usize = 500;
+
+const CREDIT_FILE_COLUMNS: [&str; 39] = [
+    "borrower_id",
+    "emp_title",
+    "emp_length",
+    "state",
+    "homeownership",
+    "annual_income",
+    "verified_income",
+    "debt_to_income",
+    "annual_income_joint",
+    "verification_income_joint",
+    "debt_to_income_joint",
+    "delinq_2y",
+    "months_since_last_delinq",
+    "earliest_credit_line",
+    "inquiries_last_12m",
+    "total_credit_lines",
+    "open_credit_lines",
+    "total_credit_limit",
+    "total_credit_utilized",
+    "num_collections_last_12m",
+    "num_historical_failed_to_pay",
+    "months_since_90d_late",
+    "current_accounts_delinq",
+    "total_collection_amount_ever",
+    "current_installment_accounts",
+    "accounts_opened_24m",
+    "months_since_last_credit_inquiry",
+    "num_satisfactory_accounts",
+    "num_accounts_120d_past_due",
+    "num_accounts_30d_past_due",
+    "num_active_debit_accounts",
+    "total_debit_limit",
+    "num_total_cc_accounts",
+    "num_open_cc_accounts",
+    "num_cc_carrying_balance",
+    "num_mort_accounts",
+    "account_never_delinq_percent",
+    "tax_liens",
+    "public_record_bankrupt",
+];
+
+/// One bad cell. `row` is 1-based and does not count the header.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ImportError {
+    pub row: i32,
+    pub column: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ImportProgress {
+    pub file_name: String,
+    pub processed: i32,
+    pub inserted: i32,
+    pub skipped: i32,
+    pub failed: i32,
+    pub done: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ImportReport {
+    pub import_id: i32,
+    pub progress: ImportProgress,
+    pub errors: Vec<ImportError>,
+}
+
+#[derive(Debug, FromRow)]
+struct ImportCheckpoint {
+    import_id: i32,
+    rows_committed: i32,
+    rows_inserted: i32,
+    rows_failed: i32,
+}
+
+/// Reads one CSV record by header name, collecting every bad cell instead of bailing on the first.
+pub(crate) struct RowReader<'r> {
+    row: i32,
+    columns: &'r HashMap<String, usize>,
+    record: &'r StringRecord,
+    pub(crate) errors: Vec<ImportError>,
+}
+
+impl<'r> RowReader<'r> {
+    pub(crate) fn new(row: i32, columns: &'r HashMap<String, usize>, record: &'r StringRecord) -> Self {
+        RowReader {
+            row,
+            columns,
+            record,
+            errors: vec![],
+        }
+    }
+
+    pub(crate) fn raw(&self, column: &str) -> &'r str {
+        self.columns
+            .get(column)
+            .and_then(|idx| self.record.get(*idx))
+            .unwrap_or("")
+            .trim()
+    }
+
+    pub(crate) fn error(&mut self, column: &str, reason: &str) {
+        self.errors.push(ImportError {
+            row: self.row,
+            column: column.to_owned(),
+            reason: reason.to_owned(),
+        });
+    }
+
+    pub(crate) fn opt_int(&mut self, column: &str) -> Option<i32> {
+        match handle_na_col(self.raw(column)) {
+            Ok(val) => val,
+            Err(reason) => {
+                let reason = format!("{}: '{}'", reason, self.raw(column));
+                self.error(column, &reason);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn int(&mut self, column: &str) -> i32 {
+        let raw = self.raw(column);
+        if raw.is_empty() || raw == "NA" {
+            self.error(column, "Required value is missing");
+            return 0;
+        }
+        let val = self.opt_int(column).unwrap_or(0);
+        if val < 0 {
+            self.error(column, "Value cannot be negative");
+        }
+        val
+    }
+
+    pub(crate) fn opt_float(&mut self, column: &str) -> Option<f32> {
+        match handle_na_float_col(self.raw(column)) {
+            Ok(val) => val,
+            Err(reason) => {
+                let reason = format!("{}: '{}'", reason, self.raw(column));
+                self.error(column, &reason);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn float(&mut self, column: &str) -> f32 {
+        let raw = self.raw(column);
+        if raw.is_empty() || raw == "NA" {
+            self.error(column, "Required value is missing");
+            return 0.0;
+        }
+        self.opt_float(column).unwrap_or(0.0)
+    }
+
+    pub(crate) fn opt_text(&self, column: &str) -> Option<String> {
+        match self.raw(column) {
+            "" | "NA" => None,
+            val => Some(val.to_owned()),
+        }
+    }
+
+    /// A required value run through one of the `*_enums` converters.
+    pub(crate) fn converted<T>(
+        &mut self,
+        column: &str,
+        convert: fn(&str) -> Result<T, &'static str>,
+    ) -> Option<T> {
+        match convert(self.raw(column)) {
+            Ok(val) => Some(val),
+            Err(reason) => {
+                let reason = format!("{}: '{}'", reason, self.raw(column));
+                self.error(column, &reason);
+                None
+            }
+        }
+    }
+
+    fn homeownership(&mut self, column: &str) -> HomeOwnership {
+        match convert_homeownership(self.raw(column)) {
+            Ok(ho) => ho,
+            Err(reason) => {
+                let reason = format!("{}: '{}'", reason, self.raw(column));
+                self.error(column, &reason);
+                HomeOwnership::Rent
+            }
+        }
+    }
+
+    fn income_verification(&mut self, column: &str) -> IncomeVerification {
+        // Unknown values fall back to IncomeVerification::Empty, which is valid for joint columns
+        convert_income_verification(self.raw(column)).unwrap_or(IncomeVerification::Empty)
+    }
+}
+
+/// Validates one CSV record into a `CreditFile`. Every bad cell is reported, not just the first.
+pub fn parse_credit_file_row(
+    row: i32,
+    columns: &HashMap<String, usize>,
+    record: &StringRecord,
+) -> Result<CreditFile, Vec<ImportError>> {
+    let mut r = RowReader::new(row, columns, record);
+    let state = r.raw("state").to_uppercase();
+    if state.len() != 2 {
+        r.error("state", "State must be a 2 letter abbreviation");
+    }
+    let credit_file = CreditFile {
+        borrower_id: r.int("borrower_id"),
+        emp_title: r.opt_text("emp_title"),
+        emp_length: r.opt_int("emp_length"),
+        state,
+        homeownership: r.homeownership("homeownership"),
+        annual_income: r.int("annual_income"),
+        verified_income: r.income_verification("verified_income"),
+        debt_to_income: r.opt_float("debt_to_income"),
+        annual_income_joint: r.opt_int("annual_income_joint"),
+        verification_income_joint: r.income_verification("verification_income_joint"),
+        debt_to_income_joint: r.opt_float("debt_to_income_joint"),
+        delinq_2y: r.int("delinq_2y"),
+        months_since_last_delinq: r.opt_int("months_since_last_delinq"),
+        earliest_credit_line: r.int("earliest_credit_line"),
+        inquiries_last_12m: r.int("inquiries_last_12m"),
+        total_credit_lines: r.opt_int("total_credit_lines"),
+        open_credit_lines: r.int("open_credit_lines"),
+        total_credit_limit: r.int("total_credit_limit"),
+        total_credit_utilized: r.int("total_credit_utilized"),
+        num_collections_last_12m: r.int("num_collections_last_12m"),
+        num_historical_failed_to_pay: r.int("num_historical_failed_to_pay"),
+        months_since_90d_late: r.opt_int("months_since_90d_late"),
+        current_accounts_delinq: r.int("current_accounts_delinq"),
+        total_collection_amount_ever: r.int("total_collection_amount_ever"),
+        current_installment_accounts: r.int("current_installment_accounts"),
+        accounts_opened_24m: r.int("accounts_opened_24m"),
+        months_since_last_credit_inquiry: r.opt_int("months_since_last_credit_inquiry"),
+        num_satisfactory_accounts: r.int("num_satisfactory_accounts"),
+        num_accounts_120d_past_due: r.opt_int("num_accounts_120d_past_due"),
+        num_accounts_30d_past_due: r.int("num_accounts_30d_past_due"),
+        num_active_debit_accounts: r.int("num_active_debit_accounts"),
+        total_debit_limit: r.int("total_debit_limit"),
+        num_total_cc_accounts: r.int("num_total_cc_accounts"),
+        num_open_cc_accounts: r.int("num_open_cc_accounts"),
+        num_cc_carrying_balance: r.int("num_cc_carrying_balance"),
+        num_mort_accounts: r.int("num_mort_accounts"),
+        account_never_delinq_percent: r.float("account_never_delinq_percent"),
+        tax_liens: r.int("tax_liens"),
+        public_record_bankrupt: r.int("public_record_bankrupt"),
+    };
+    if !(0.0..=100.0).contains(&credit_file.account_never_delinq_percent) {
+        r.error("account_never_delinq_percent", "Percent must be between 0 and 100");
+    }
+    if r.errors.is_empty() {
+        Ok(credit_file)
+    } else {
+        Err(r.errors)
+    }
+}
+
+/// Maps header names to their index and checks every `credit_file` column is present.
+pub fn column_index(headers: &StringRecord) -> Result<HashMap<String, usize>, AppError> {
+    required_columns(headers, &CREDIT_FILE_COLUMNS, "Credit file")
+}
+
+/// Maps header names to their index and checks every `required` column is present.
+pub(crate) fn required_columns(
+    headers: &StringRecord,
+    required: &[&str],
+    what: &str,
+) -> Result<HashMap<String, usize>, AppError> {
+    let columns = headers
+        .iter()
+        .enumerate()
+        .map(|(idx, name)| (name.trim().to_owned(), idx))
+        .collect::<HashMap<String, usize>>();
+    let missing = required
+        .iter()
+        .filter(|col| !columns.contains_key(**col))
+        .copied()
+        .collect::<Vec<&str>>();
+    if missing.is_empty() {
+        Ok(columns)
+    } else {
+        Err(AppError::GenericError(format!(
+            "{} CSV is missing columns: {}",
+            what,
+            missing.join(", ")
+        )))
+    }
+}
+
+async fn insert_batch(
+    tx: &mut Transaction<'_, Postgres>,
+    batch: Vec<CreditFile>,
+) -> Result<u64, sqlx::Error> {
+    let mut query_builder = QueryBuilder::new(format!(
+        "INSERT INTO credit_file ({}) ",
+        CREDIT_FILE_COLUMNS.join(", ")
+    ));
+    query_builder.push_values(batch, |mut b, cf| {
+        b.push_bind(cf.borrower_id)
+            .push_bind(cf.emp_title)
+            .push_bind(cf.emp_length)
+            .push_bind(cf.state)
+            .push_bind(cf.homeownership as i32)
+            .push_bind(cf.annual_income)
+            .push_bind(cf.verified_income as i32)
+            .push_bind(cf.debt_to_income)
+            .push_bind(cf.annual_income_joint)
+            .push_bind(cf.verification_income_joint as i32)
+            .push_bind(cf.debt_to_income_joint)
+            .push_bind(cf.delinq_2y)
+            .push_bind(cf.months_since_last_delinq)
+            .push_bind(cf.earliest_credit_line)
+            .push_bind(cf.inquiries_last_12m)
+            .push_bind(cf.total_credit_lines)
+            .push_bind(cf.open_credit_lines)
+            .push_bind(cf.total_credit_limit)
+            .push_bind(cf.total_credit_utilized)
+            .push_bind(cf.num_collections_last_12m)
+            .push_bind(cf.num_historical_failed_to_pay)
+            .push_bind(cf.months_since_90d_late)
+            .push_bind(cf.current_accounts_delinq)
+            .push_bind(cf.total_collection_amount_ever)
+            .push_bind(cf.current_installment_accounts)
+            .push_bind(cf.accounts_opened_24m)
+            .push_bind(cf.months_since_last_credit_inquiry)
+            .push_bind(cf.num_satisfactory_accounts)
+            .push_bind(cf.num_accounts_120d_past_due)
+            .push_bind(cf.num_accounts_30d_past_due)
+            .push_bind(cf.num_active_debit_accounts)
+            .push_bind(cf.total_debit_limit)
+            .push_bind(cf.num_total_cc_accounts)
+            .push_bind(cf.num_open_cc_accounts)
+            .push_bind(cf.num_cc_carrying_balance)
+            .push_bind(cf.num_mort_accounts)
+            .push_bind(cf.account_never_delinq_percent)
+            .push_bind(cf.tax_liens)
+            .push_bind(cf.public_record_bankrupt);
+    });
+    query_builder.push(" ON CONFLICT (borrower_id) DO NOTHING");
+    let result = query_builder.build().execute(&mut **tx).await?;
+    Ok(result.rows_affected())
+}
+
+async fn insert_errors(
+    tx: &mut Transaction<'_, Postgres>,
+    import_id: i32,
+    errors: &[ImportError],
+) -> Result<(), sqlx::Error> {
+    if errors.is_empty() {
+        return Ok(());
+    }
+    let mut query_builder = QueryBuilder::new(
+        "INSERT INTO credit_file_import_errors (import_id, row_num, column_name, reason) ",
+    );
+    query_builder.push_values(errors, |mut b, err| {
+        b.push_bind(import_id)
+            .push_bind(err.row)
+            .push_bind(&err.column)
+            .push_bind(&err.reason);
+    });
+    query_builder.build().execute(&mut **tx).await?;
+    Ok(())
+}
+
+/// Commits a batch of rows, its errors & the checkpoint together so a crash can resume after `last_row`.
+async fn commit_batch(
+    pool: &PgPool,
+    import_id: i32,
+    batch: Vec<CreditFile>,
+    errors: &[ImportError],
+    last_row: i32,
+    progress: &mut ImportProgress,
+) -> Result<(), sqlx::Error> {
+    let batch_len = batch.len() as i32;
+    let mut tx = pool.begin().await?;
+    let inserted = if batch.is_empty() {
+        0
+    } else {
+        insert_batch(&mut tx, batch).await? as i32
+    };
+    insert_errors(&mut tx, import_id, errors).await?;
+    progress.inserted += inserted;
+    progress.skipped += batch_len - inserted;
+    sqlx::query(
+        "UPDATE credit_file_imports SET rows_committed = $2, rows_inserted = $3, rows_failed = $4 WHERE import_id = $1",
+    )
+    .bind(import_id)
+    .bind(last_row)
+    .bind(progress.inserted)
+    .bind(progress.failed)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await
+}
+
+fn send_progress(progress_tx: &Option<broadcast::Sender<ImportProgress>>, progress: &ImportProgress) {
+    if let Some(sender) = progress_tx {
+        // No SSE subscribers is fine
+        let _ = sender.send(progress.clone());
+    }
+}
+
+/// The file the web import loads, `CREDIT_FILE_CSV` or the checked in one
+pub fn configured_credit_file_csv() -> String {
+    env::var("CREDIT_FILE_CSV").unwrap_or_else(|_| DEFAULT_CREDIT_FILE_CSV.to_owned())
+}
+
+/// Streams `file_name` into `credit_file` in batches. Re-running the same file resumes after
+/// the last committed row, and `ON CONFLICT` skips any borrower that is already loaded.
+pub async fn import_credit_file(
+    pool: &PgPool,
+    file_name: &str,
+    progress_tx: Option<broadcast::Sender<ImportProgress>>,
+) -> Result<ImportReport, AppError> {
+    let mut rdr = Reader::from_path(file_name)
+        .map_err(|err| AppError::GenericError(format!("Unable to open {}: {}", file_name, err)))?;
+    let headers = rdr
+        .headers()
+        .map_err(|err| AppError::GenericError(format!("Unable to read CSV headers: {}", err)))?
+        .clone();
+    let columns = column_index(&headers)?;
+
+    let checkpoint = sqlx::query_as::<_, ImportCheckpoint>(
+        "INSERT INTO credit_file_imports (file_name) VALUES ($1)
+            ON CONFLICT (file_name) DO UPDATE SET status = 'running', finished_at = NULL
+            RETURNING import_id, rows_committed, rows_inserted, rows_failed",
+    )
+    .bind(file_name)
+    .fetch_one(pool)
+    .await?;
+
+    let mut progress = ImportProgress {
+        file_name: file_name.to_owned(),
+        processed: checkpoint.rows_committed,
+        inserted: checkpoint.rows_inserted,
+        failed: checkpoint.rows_failed,
+        ..Default::default()
+    };
+    let mut report_errors: Vec<ImportError> = vec![];
+    let mut batch: Vec<CreditFile> = Vec::with_capacity(BATCH_SIZE);
+    let mut batch_errors: Vec<ImportError> = vec![];
+    let mut row: i32 = 0;
+
+    for result in rdr.records() {
+        row += 1;
+        // Already committed by a previous run
+        if row <= checkpoint.rows_committed {
+            continue;
+        }
+        match result {
+            Ok(record) => match parse_credit_file_row(row, &columns, &record) {
+                Ok(credit_file) => batch.push(credit_file),
+                Err(errors) => {
+                    progress.failed += 1;
+                    batch_errors.extend(errors);
+                }
+            },
+            Err(err) => {
+                progress.failed += 1;
+                batch_errors.push(ImportError {
+                    row,
+                    column: "*".to_owned(),
+                    reason: err.to_string(),
+                });
+            }
+        }
+        progress.processed = row;
+        if batch.len() >= BATCH_SIZE {
+            commit_batch(pool, checkpoint.import_id, std::mem::take(&mut batch), &batch_errors, row, &mut progress)
+                .await?;
+            report_errors.append(&mut batch_errors);
+            send_progress(&progress_tx, &progress);
+        }
+    }
+    commit_batch(pool, checkpoint.import_id, batch, &batch_errors, row.max(checkpoint.rows_committed), &mut progress)
+        .await?;
+    report_errors.append(&mut batch_errors);
+
+    let _ = sqlx::query(
+        "UPDATE credit_file_imports SET status = 'complete', finished_at = NOW() WHERE import_id = $1",
+    )
+    .bind(checkpoint.import_id)
+    .execute(pool)
+    .await;
+
+    progress.done = true;
+    send_progress(&progress_tx, &progress);
+
+    Ok(ImportReport {
+        import_id: checkpoint.import_id,
+        progress,
+        errors: report_errors,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers() -> StringRecord {
+        StringRecord::from(CREDIT_FILE_COLUMNS.to_vec())
+    }
+
+    fn valid_row() -> Vec<&'static str> {
+        vec![
+            "7", "president", "3", "ne", "MORTGAGE", "90000", "Verified", "18.01", "NA", "", "NA",
+            "0", "NA", "2001", "6", "28", "10", "70795", "38767", "0", "0", "NA", "0", "0", "2",
+            "5", "NA", "0", "NA", "0", "2", "11100", "14", "8", "6", "1", "92.9", "0", "0",
+        ]
+    }
+
+    #[test]
+    fn valid_row_parses_with_na_as_none() {
+        let columns = column_index(&headers()).unwrap();
+        let record = StringRecord::from(valid_row());
+        let cf = parse_credit_file_row(1, &columns, &record).unwrap();
+        assert_eq!(cf.borrower_id, 7);
+        assert_eq!(cf.state, "NE");
+        assert_eq!(cf.homeownership, HomeOwnership::Mortgage);
+        assert_eq!(cf.months_since_last_delinq, None);
+        assert_eq!(cf.num_accounts_120d_past_due, None);
+        assert_eq!(cf.verification_income_joint, IncomeVerification::Empty);
+    }
+
+    #[test]
+    fn invalid_row_reports_every_bad_column() {
+        let columns = column_index(&headers()).unwrap();
+        let mut row = valid_row();
+        row[4] = "CASTLE";
+        row[5] = "lots";
+        row[36] = "140.0";
+        let record = StringRecord::from(row);
+        let errors = parse_credit_file_row(3, &columns, &record).unwrap_err();
+        let cols = errors.iter().map(|e| e.column.as_str()).collect::<Vec<&str>>();
+        assert_eq!(cols, vec!["homeownership", "annual_income", "account_never_delinq_percent"]);
+        assert!(errors.iter().all(|e| e.row == 3));
+    }
+
+    #[test]
+    fn missing_header_is_rejected() {
+        let headers = StringRecord::from(vec!["borrower_id", "state"]);
+        assert!(column_index(&headers).is_err());
+    }
+}
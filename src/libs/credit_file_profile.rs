@@ -0,0 +1,432 @@
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use chrono::{Datelike, Utc};
+use deadpool_redis::{redis::cmd, Pool as RedisPool};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use struct_iterable::Iterable;
+
+use crate::{
+    error::AppError,
+    libs::pg_notify_handle::TableUpdate,
+    models::credit_file::{
+        CreditFile, HomeOwnership, IncomeVerification, DECODABLE_CREDIT_FILES, SELECT_CREDIT_FILES,
+    },
+};
+
+const PERCENTILES: [f64; 4] = [0.25, 0.5, 0.75, 0.95];
+pub const PROFILE_CACHE_KEY: &str = "credit_file:profile";
+/// Backstop in case a `credit_file_changed` notification is missed
+const PROFILE_CACHE_TTL_SECONDS: u64 = 60 * 60;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum ColumnKind {
+    Numeric,
+    Text,
+    Categorical,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct NumericStats {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub p25: f64,
+    pub p50: f64,
+    pub p75: f64,
+    pub p95: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ColumnProfile {
+    pub column: String,
+    pub kind: ColumnKind,
+    pub nulls: usize,
+    pub null_rate: f64,
+    pub distinct: usize,
+    pub stats: Option<NumericStats>,
+    /// Value counts, most frequent first. Only filled for categorical columns.
+    pub distribution: Vec<(String, usize)>,
+    pub out_of_range: usize,
+    pub expected: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CreditFileProfile {
+    pub rows: usize,
+    pub columns: Vec<ColumnProfile>,
+}
+
+/// Enum columns whose stored code doesn't map to a variant. These rows can't be
+/// decoded into a CreditFile, so they are counted in SQL instead.
+#[derive(Debug, Default, FromRow)]
+pub struct InvalidEnumCodes {
+    pub homeownership: i64,
+    pub verified_income: i64,
+    pub verification_income_joint: i64,
+}
+
+enum Cell {
+    Number(Option<f64>),
+    Text(Option<String>),
+    Category(Option<String>),
+}
+
+fn read_cell(column: &str, value: &dyn Any) -> Option<Cell> {
+    if let Some(v) = value.downcast_ref::<i32>() {
+        return Some(Cell::Number(Some(*v as f64)));
+    }
+    if let Some(v) = value.downcast_ref::<Option<i32>>() {
+        return Some(Cell::Number(v.map(|v| v as f64)));
+    }
+    if let Some(v) = value.downcast_ref::<f32>() {
+        return Some(Cell::Number(Some(*v as f64)));
+    }
+    if let Some(v) = value.downcast_ref::<Option<f32>>() {
+        return Some(Cell::Number(v.map(|v| v as f64)));
+    }
+    if let Some(v) = value.downcast_ref::<String>() {
+        return Some(Cell::Text(Some(v.clone()).filter(|v| !v.trim().is_empty())));
+    }
+    if let Some(v) = value.downcast_ref::<Option<String>>() {
+        return Some(Cell::Text(v.clone().filter(|v| !v.trim().is_empty())));
+    }
+    if let Some(v) = value.downcast_ref::<HomeOwnership>() {
+        return Some(Cell::Category(Some(format!("{:?}", v))));
+    }
+    if let Some(v) = value.downcast_ref::<IncomeVerification>() {
+        // Joint verification is only set on joint files, Empty there just means not joint
+        if column == "verification_income_joint" && *v == IncomeVerification::Empty {
+            return Some(Cell::Category(None));
+        }
+        return Some(Cell::Category(Some(format!("{:?}", v))));
+    }
+    None
+}
+
+/// Numeric columns of a file in struct order, None where the value is missing.
+pub fn numeric_cells(file: &CreditFile) -> Vec<(&'static str, Option<f64>)> {
+    file.iter()
+        .filter_map(|(column, value)| match read_cell(column, value) {
+            Some(Cell::Number(v)) => Some((column, v)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Inclusive bounds a numeric column should fall within, None meaning unbounded.
+fn expected_range(column: &str) -> (Option<f64>, Option<f64>) {
+    match column {
+        "borrower_id" => (Some(1.0), None),
+        "emp_length" => (Some(0.0), Some(10.0)),
+        "account_never_delinq_percent" => (Some(0.0), Some(100.0)),
+        "earliest_credit_line" => (Some(1900.0), Some(Utc::now().year() as f64)),
+        col if col.starts_with("months_since") => (Some(0.0), Some(1200.0)),
+        // Everything else is a count, an amount or a ratio
+        _ => (Some(0.0), None),
+    }
+}
+
+fn describe_range(range: (Option<f64>, Option<f64>)) -> Option<String> {
+    match range {
+        (Some(lo), Some(hi)) => Some(format!("{} to {}", lo, hi)),
+        (Some(lo), None) => Some(format!(">= {}", lo)),
+        (None, Some(hi)) => Some(format!("<= {}", hi)),
+        (None, None) => None,
+    }
+}
+
+fn valid_category(column: &str, label: &str) -> bool {
+    // Every file should have its primary income verification status set
+    !(column == "verified_income" && label == "Empty")
+}
+
+/// Nearest-rank percentile over already sorted values.
+pub fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+pub fn numeric_stats(values: &[f64]) -> Option<NumericStats> {
+    if values.is_empty() {
+        return None;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let [p25, p50, p75, p95] = PERCENTILES.map(|p| percentile(&sorted, p));
+    Some(NumericStats {
+        min: sorted[0],
+        max: sorted[sorted.len() - 1],
+        mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+        p25,
+        p50,
+        p75,
+        p95,
+    })
+}
+
+struct ColumnAccumulator {
+    kind: ColumnKind,
+    nulls: usize,
+    numbers: Vec<f64>,
+    distinct: HashSet<String>,
+    categories: BTreeMap<String, usize>,
+    out_of_range: usize,
+}
+
+impl ColumnAccumulator {
+    fn new(kind: ColumnKind) -> Self {
+        ColumnAccumulator {
+            kind,
+            nulls: 0,
+            numbers: Vec::new(),
+            distinct: HashSet::new(),
+            categories: BTreeMap::new(),
+            out_of_range: 0,
+        }
+    }
+
+    fn push(&mut self, column: &str, cell: Cell) {
+        match cell {
+            Cell::Number(Some(v)) => {
+                let (lo, hi) = expected_range(column);
+                if lo.is_some_and(|lo| v < lo) || hi.is_some_and(|hi| v > hi) {
+                    self.out_of_range += 1;
+                }
+                self.distinct.insert(v.to_string());
+                self.numbers.push(v);
+            }
+            Cell::Text(Some(v)) => {
+                self.distinct.insert(v);
+            }
+            Cell::Category(Some(v)) => {
+                if !valid_category(column, &v) {
+                    self.out_of_range += 1;
+                }
+                *self.categories.entry(v.clone()).or_insert(0) += 1;
+                self.distinct.insert(v);
+            }
+            Cell::Number(None) | Cell::Text(None) | Cell::Category(None) => self.nulls += 1,
+        }
+    }
+
+    fn finish(self, column: &str, rows: usize) -> ColumnProfile {
+        let mut distribution: Vec<(String, usize)> = self.categories.into_iter().collect();
+        distribution.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        ColumnProfile {
+            column: column.to_owned(),
+            kind: self.kind,
+            nulls: self.nulls,
+            null_rate: if rows == 0 { 0.0 } else { self.nulls as f64 / rows as f64 },
+            distinct: self.distinct.len(),
+            stats: numeric_stats(&self.numbers),
+            distribution,
+            out_of_range: self.out_of_range,
+            expected: match self.kind {
+                ColumnKind::Numeric => describe_range(expected_range(column)),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Profiles every column of CreditFile, in struct order.
+pub fn profile_credit_files(files: &[CreditFile]) -> CreditFileProfile {
+    let mut order: Vec<&'static str> = Vec::new();
+    let mut columns: HashMap<&'static str, ColumnAccumulator> = HashMap::new();
+
+    for file in files {
+        for (column, value) in file.iter() {
+            let Some(cell) = read_cell(column, value) else {
+                continue;
+            };
+            let acc = columns.entry(column).or_insert_with(|| {
+                order.push(column);
+                ColumnAccumulator::new(match cell {
+                    Cell::Number(_) => ColumnKind::Numeric,
+                    Cell::Text(_) => ColumnKind::Text,
+                    Cell::Category(_) => ColumnKind::Categorical,
+                })
+            });
+            acc.push(column, cell);
+        }
+    }
+
+    CreditFileProfile {
+        rows: files.len(),
+        columns: order
+            .into_iter()
+            .filter_map(|col| columns.remove(col).map(|acc| acc.finish(col, files.len())))
+            .collect(),
+    }
+}
+
+async fn count_invalid_enum_codes(pool: &PgPool) -> Result<InvalidEnumCodes, AppError> {
+    sqlx::query_as::<_, InvalidEnumCodes>(
+        "SELECT COUNT(*) FILTER (WHERE homeownership NOT BETWEEN 1 AND 3) AS homeownership,
+                COUNT(*) FILTER (WHERE verified_income NOT BETWEEN 0 AND 3) AS verified_income,
+                COUNT(*) FILTER (WHERE verification_income_joint NOT BETWEEN 0 AND 3) AS verification_income_joint
+         FROM credit_file",
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(AppError::from)
+}
+
+/// Profiles the credit_file table. Rows with enum codes we can't decode are left out of
+/// the column stats but still reported as out of range on their enum column.
+pub async fn profile_credit_file_table(pool: &PgPool) -> Result<CreditFileProfile, AppError> {
+    let invalid = count_invalid_enum_codes(pool).await?;
+    let sql = format!("{} WHERE {}", SELECT_CREDIT_FILES, DECODABLE_CREDIT_FILES);
+    let files = sqlx::query_as::<_, CreditFile>(&sql)
+        .fetch_all(pool)
+        .await?;
+
+    let mut profile = profile_credit_files(&files);
+    let skipped = [
+        ("homeownership", invalid.homeownership),
+        ("verified_income", invalid.verified_income),
+        ("verification_income_joint", invalid.verification_income_joint),
+    ];
+    for column in profile.columns.iter_mut() {
+        if let Some((_, count)) = skipped.iter().find(|(name, _)| *name == column.column) {
+            column.out_of_range += *count as usize;
+        }
+    }
+    Ok(profile)
+}
+
+/// Serves from Redis when it can, the profile reads the whole table. Redis being down only
+/// costs the cache.
+pub async fn cached_credit_file_profile(
+    pool: &PgPool,
+    r_pool: &RedisPool,
+) -> Result<CreditFileProfile, AppError> {
+    let mut con = match r_pool.get().await {
+        Ok(con) => Some(con),
+        Err(err) => {
+            tracing::warn!(error = ?err, "Redis unavailable, profiling the credit file table");
+            None
+        }
+    };
+    if let Some(con) = con.as_mut() {
+        let cached: Option<String> = cmd("GET")
+            .arg(PROFILE_CACHE_KEY)
+            .query_async(con)
+            .await
+            .unwrap_or(None);
+        if let Some(profile) = cached.and_then(|json| serde_json::from_str(&json).ok()) {
+            return Ok(profile);
+        }
+    }
+
+    let profile = profile_credit_file_table(pool).await?;
+    if let (Some(con), Ok(json)) = (con.as_mut(), serde_json::to_string(&profile)) {
+        let stored = cmd("SET")
+            .arg(PROFILE_CACHE_KEY)
+            .arg(json)
+            .arg("EX")
+            .arg(PROFILE_CACHE_TTL_SECONDS)
+            .query_async::<_, ()>(con)
+            .await;
+        if let Err(err) = stored {
+            tracing::warn!(error = ?err, "Unable to cache the credit file profile");
+        }
+    }
+    Ok(profile)
+}
+
+pub async fn invalidate_profile_cache(r_pool: &RedisPool) -> Result<(), AppError> {
+    let mut con = r_pool.get().await.map_err(|err| {
+        AppError::GenericError(format!("Unable to get a Redis connection: {}", err))
+    })?;
+    cmd("DEL")
+        .arg(PROFILE_CACHE_KEY)
+        .query_async::<_, ()>(&mut con)
+        .await
+        .map_err(|err| AppError::GenericError(format!("Unable to invalidate cache: {}", err)))
+}
+
+/// The credit_file_changed handler, an import batch or any other write drops the cached profile.
+pub async fn invalidate_on_credit_file_changes(r_pool: RedisPool, update: TableUpdate) {
+    if let Err(err) = invalidate_profile_cache(&r_pool).await {
+        tracing::warn!(?update, error = ?err, "Credit file profile cache not invalidated");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::credit_file::mock_credit_file;
+
+    fn column<'a>(profile: &'a CreditFileProfile, name: &str) -> &'a ColumnProfile {
+        profile.columns.iter().find(|c| c.column == name).unwrap()
+    }
+
+    #[test]
+    fn test_percentile() {
+        let sorted = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0];
+        assert_eq!(percentile(&sorted, 0.25), 3.0);
+        assert_eq!(percentile(&sorted, 0.5), 5.0);
+        assert_eq!(percentile(&sorted, 0.95), 10.0);
+        assert_eq!(percentile(&[], 0.5), 0.0);
+    }
+
+    #[test]
+    fn test_numeric_stats() {
+        let stats = numeric_stats(&[4.0, 1.0, 3.0, 2.0]).unwrap();
+        assert_eq!(stats.min, 1.0);
+        assert_eq!(stats.max, 4.0);
+        assert_eq!(stats.mean, 2.5);
+        assert_eq!(stats.p50, 2.0);
+        assert!(numeric_stats(&[]).is_none());
+    }
+
+    #[test]
+    fn test_profile_credit_files() {
+        let mut first = mock_credit_file();
+        first.emp_title = None;
+        first.homeownership = HomeOwnership::Rent;
+        first.verified_income = IncomeVerification::Empty;
+        first.verification_income_joint = IncomeVerification::Empty;
+        first.account_never_delinq_percent = 120.0;
+        let mut second = mock_credit_file();
+        second.homeownership = HomeOwnership::Rent;
+        second.verified_income = IncomeVerification::Verified;
+        second.verification_income_joint = IncomeVerification::Verified;
+
+        let profile = profile_credit_files(&[first, second]);
+        assert_eq!(profile.rows, 2);
+        assert_eq!(profile.columns.len(), 39);
+        assert_eq!(profile.columns[0].column, "borrower_id");
+
+        let emp_title = column(&profile, "emp_title");
+        assert_eq!(emp_title.kind, ColumnKind::Text);
+        assert_eq!(emp_title.nulls, 1);
+        assert_eq!(emp_title.null_rate, 0.5);
+
+        let homeownership = column(&profile, "homeownership");
+        assert_eq!(homeownership.kind, ColumnKind::Categorical);
+        assert_eq!(homeownership.distinct, 1);
+        assert_eq!(homeownership.distribution, vec![("Rent".to_owned(), 2)]);
+
+        assert_eq!(column(&profile, "verified_income").out_of_range, 1);
+        assert_eq!(column(&profile, "verification_income_joint").nulls, 1);
+
+        let never_delinq = column(&profile, "account_never_delinq_percent");
+        assert_eq!(never_delinq.out_of_range, 1);
+        assert_eq!(never_delinq.stats.as_ref().unwrap().max, 120.0);
+    }
+
+    #[test]
+    fn profile_round_trips_through_the_cache_format() {
+        let profile = profile_credit_files(&[mock_credit_file(), mock_credit_file()]);
+        let json = serde_json::to_string(&profile).unwrap();
+        let cached: CreditFileProfile = serde_json::from_str(&json).unwrap();
+        assert_eq!(cached, profile);
+    }
+}
@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+
+use pgvector::Vector;
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, FromRow, PgPool, Postgres, QueryBuilder};
+
+use crate::{
+    error::AppError,
+    libs::credit_file_profile::{numeric_cells, percentile},
+    models::{
+        credit_file::{
+            CreditFile, HomeOwnership, IncomeVerification, DECODABLE_CREDIT_FILES,
+            SELECT_CREDIT_FILES,
+        },
+        loan::LoanStatus,
+    },
+};
+
+/// 33 numerics + 3 HomeOwnership + 4 verified_income + 4 verification_income_joint.
+/// Must match the vector(44) column in credit_file_vectors.
+pub const FEATURE_DIMS: usize = 44;
+const BATCH_SIZE: usize = 500;
+// Numerics are scaled between these percentiles so a few huge incomes don't flatten the rest
+const SCALE_LOW: f64 = 0.01;
+const SCALE_HIGH: f64 = 0.99;
+
+const HOMEOWNERSHIP: [HomeOwnership; 3] = [
+    HomeOwnership::Own,
+    HomeOwnership::Mortgage,
+    HomeOwnership::Rent,
+];
+const INCOME_VERIFICATION: [IncomeVerification; 4] = [
+    IncomeVerification::Verified,
+    IncomeVerification::SourceVerified,
+    IncomeVerification::NotVerified,
+    IncomeVerification::Empty,
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct NumericScale {
+    pub column: String,
+    pub low: f64,
+    pub high: f64,
+    /// Imputed when the value is NA
+    pub median: f64,
+}
+
+impl NumericScale {
+    fn scale(&self, value: Option<f64>) -> f32 {
+        let value = value.unwrap_or(self.median);
+        if self.high > self.low {
+            ((value - self.low) / (self.high - self.low)).clamp(0.0, 1.0) as f32
+        } else {
+            0.0
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreditFileEncoder {
+    pub encoder_id: Option<i32>,
+    pub scales: Vec<NumericScale>,
+}
+
+impl CreditFileEncoder {
+    pub fn fit(files: &[CreditFile]) -> Self {
+        let mut order: Vec<&'static str> = Vec::new();
+        let mut values: HashMap<&'static str, Vec<f64>> = HashMap::new();
+        for file in files {
+            for (column, value) in numeric_cells(file) {
+                if column == "borrower_id" {
+                    continue;
+                }
+                let column_values = values.entry(column).or_insert_with(|| {
+                    order.push(column);
+                    Vec::new()
+                });
+                if let Some(v) = value {
+                    column_values.push(v);
+                }
+            }
+        }
+
+        let scales = order
+            .into_iter()
+            .map(|column| {
+                let mut sorted = values.remove(column).unwrap_or_default();
+                sorted.sort_by(|a, b| a.total_cmp(b));
+                NumericScale {
+                    column: column.to_owned(),
+                    low: percentile(&sorted, SCALE_LOW),
+                    high: percentile(&sorted, SCALE_HIGH),
+                    median: percentile(&sorted, 0.5),
+                }
+            })
+            .collect();
+        CreditFileEncoder {
+            encoder_id: None,
+            scales,
+        }
+    }
+
+    /// Name of each position in `encode`, one-hots as `column=Variant`.
+    pub fn feature_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .scales
+            .iter()
+            .map(|scale| scale.column.clone())
+            .collect();
+        names.extend(
+            HOMEOWNERSHIP
+                .iter()
+                .map(|v| format!("homeownership={:?}", v)),
+        );
+        names.extend(
+            INCOME_VERIFICATION
+                .iter()
+                .map(|v| format!("verified_income={:?}", v)),
+        );
+        names.extend(
+            INCOME_VERIFICATION
+                .iter()
+                .map(|v| format!("verification_income_joint={:?}", v)),
+        );
+        names
+    }
+
+    pub fn encode(&self, file: &CreditFile) -> Vec<f32> {
+        let numerics: HashMap<&'static str, Option<f64>> = numeric_cells(file).into_iter().collect();
+        let mut features: Vec<f32> = self
+            .scales
+            .iter()
+            .map(|scale| scale.scale(numerics.get(scale.column.as_str()).copied().flatten()))
+            .collect();
+
+        features.extend(one_hot(&HOMEOWNERSHIP, &file.homeownership));
+        features.extend(one_hot(&INCOME_VERIFICATION, &file.verified_income));
+        features.extend(one_hot(&INCOME_VERIFICATION, &file.verification_income_joint));
+        features
+    }
+}
+
+fn one_hot<T: PartialEq>(variants: &[T], value: &T) -> Vec<f32> {
+    variants
+        .iter()
+        .map(|variant| if variant == value { 1.0 } else { 0.0 })
+        .collect()
+}
+
+#[derive(Debug, FromRow)]
+struct EncoderRow {
+    encoder_id: i32,
+    scaler: Json<Vec<NumericScale>>,
+}
+
+#[derive(Debug, FromRow)]
+struct SimilarRow {
+    borrower_id: i32,
+    distance: f64,
+    loan_status: Option<i32>,
+    grade: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SimilarBorrower {
+    pub borrower_id: i32,
+    pub distance: f64,
+    /// None when the borrower has no loan on file
+    pub loan_status: Option<LoanStatus>,
+    pub grade: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SimilarBorrowers {
+    pub encoder_id: i32,
+    pub neighbors: Vec<SimilarBorrower>,
+    /// Neighbor count per LoanStatus, "NoLoan" for those without one
+    pub outcomes: HashMap<String, usize>,
+}
+
+async fn load_credit_files(pool: &PgPool) -> Result<Vec<CreditFile>, AppError> {
+    let sql = format!("{} WHERE {}", SELECT_CREDIT_FILES, DECODABLE_CREDIT_FILES);
+    sqlx::query_as::<_, CreditFile>(&sql)
+        .fetch_all(pool)
+        .await
+        .map_err(AppError::from)
+}
+
+pub async fn find_credit_file(pool: &PgPool, borrower_id: i32) -> Result<Option<CreditFile>, AppError> {
+    let sql = format!(
+        "{} WHERE borrower_id = $1 AND {}",
+        SELECT_CREDIT_FILES, DECODABLE_CREDIT_FILES
+    );
+    sqlx::query_as::<_, CreditFile>(&sql)
+        .bind(borrower_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(AppError::from)
+}
+
+/// Fits a new encoder over credit_file and re-encodes every file with it.
+/// Returns the new encoder_id and the number of vectors written.
+pub async fn build_credit_file_vectors(pool: &PgPool) -> Result<(i32, usize), AppError> {
+    let files = load_credit_files(pool).await?;
+    let mut encoder = CreditFileEncoder::fit(&files);
+
+    let mut tx = pool.begin().await?;
+    let encoder_id: i32 = sqlx::query_scalar(
+        "INSERT INTO credit_file_encoders (dims, scaler) VALUES ($1, $2) RETURNING encoder_id",
+    )
+    .bind(FEATURE_DIMS as i32)
+    .bind(Json(&encoder.scales))
+    .fetch_one(&mut *tx)
+    .await?;
+    encoder.encoder_id = Some(encoder_id);
+
+    for batch in files.chunks(BATCH_SIZE) {
+        let mut query_builder: QueryBuilder<Postgres> =
+            QueryBuilder::new("INSERT INTO credit_file_vectors (borrower_id, encoder_id, embedding) ");
+        query_builder.push_values(batch, |mut b, file| {
+            b.push_bind(file.borrower_id)
+                .push_bind(encoder_id)
+                .push_bind(Vector::from(encoder.encode(file)));
+        });
+        query_builder.push(
+            " ON CONFLICT (borrower_id) DO UPDATE SET encoder_id = EXCLUDED.encoder_id, \
+             embedding = EXCLUDED.embedding, created_at = NOW()",
+        );
+        query_builder.build().execute(&mut *tx).await?;
+    }
+
+    // Files that no longer decode would otherwise keep a vector from an old scaler
+    sqlx::query("DELETE FROM credit_file_vectors WHERE encoder_id <> $1")
+        .bind(encoder_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok((encoder_id, files.len()))
+}
+
+pub async fn latest_encoder(pool: &PgPool) -> Result<CreditFileEncoder, AppError> {
+    let row = sqlx::query_as::<_, EncoderRow>(
+        "SELECT encoder_id, scaler FROM credit_file_encoders ORDER BY encoder_id DESC LIMIT 1",
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    match row {
+        Some(row) => Ok(CreditFileEncoder {
+            encoder_id: Some(row.encoder_id),
+            scales: row.scaler.0,
+        }),
+        None => Err(AppError::GenericError(
+            "Credit file vectors have not been built".to_owned(),
+        )),
+    }
+}
+
+/// The `k` historical borrowers closest to `file`, with the outcome of their latest loan.
+pub async fn similar_borrowers(
+    pool: &PgPool,
+    file: &CreditFile,
+    k: i64,
+) -> Result<SimilarBorrowers, AppError> {
+    let encoder = latest_encoder(pool).await?;
+    let encoder_id = encoder.encoder_id.unwrap_or_default();
+    let embedding = Vector::from(encoder.encode(file));
+
+    let rows = sqlx::query_as::<_, SimilarRow>(
+        "SELECT v.borrower_id, v.embedding <-> $1 AS distance, l.loan_status, l.grade::TEXT AS grade
+        FROM credit_file_vectors v
+        LEFT JOIN LATERAL (
+            SELECT loan_status, grade FROM loans WHERE loans.borrower_id = v.borrower_id ORDER BY loan_id DESC LIMIT 1
+        ) l ON TRUE
+        WHERE v.encoder_id = $2 AND v.borrower_id <> $3
+        ORDER BY v.embedding <-> $1 LIMIT $4",
+    )
+    .bind(embedding)
+    .bind(encoder_id)
+    .bind(file.borrower_id)
+    .bind(k)
+    .fetch_all(pool)
+    .await?;
+
+    let neighbors: Vec<SimilarBorrower> = rows
+        .into_iter()
+        .map(|row| SimilarBorrower {
+            borrower_id: row.borrower_id,
+            distance: row.distance,
+            loan_status: row.loan_status.and_then(|code| LoanStatus::try_from(code).ok()),
+            grade: row.grade,
+        })
+        .collect();
+
+    let mut outcomes: HashMap<String, usize> = HashMap::new();
+    for neighbor in &neighbors {
+        let outcome = match &neighbor.loan_status {
+            Some(status) => format!("{:?}", status),
+            None => "NoLoan".to_owned(),
+        };
+        *outcomes.entry(outcome).or_default() += 1;
+    }
+
+    Ok(SimilarBorrowers {
+        encoder_id,
+        neighbors,
+        outcomes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::credit_file::mock_credit_file;
+
+    #[test]
+    fn encoded_length_matches_dims() {
+        let files = vec![mock_credit_file(), mock_credit_file()];
+        let encoder = CreditFileEncoder::fit(&files);
+        assert_eq!(encoder.scales.len(), FEATURE_DIMS - 11);
+        assert_eq!(encoder.encode(&files[0]).len(), FEATURE_DIMS);
+        assert_eq!(encoder.feature_names().len(), FEATURE_DIMS);
+    }
+
+    #[test]
+    fn scales_imputes_and_one_hots() {
+        let mut low = mock_credit_file();
+        low.annual_income = 10000;
+        low.homeownership = HomeOwnership::Rent;
+        low.verified_income = IncomeVerification::Verified;
+        low.verification_income_joint = IncomeVerification::Empty;
+        let mut high = low.clone();
+        high.annual_income = 210000;
+        let mut missing = low.clone();
+        missing.months_since_last_delinq = None;
+        low.months_since_last_delinq = Some(0);
+        high.months_since_last_delinq = Some(100);
+
+        let encoder = CreditFileEncoder::fit(&[low.clone(), high.clone()]);
+        let income = encoder.scales.iter().position(|s| s.column == "annual_income").unwrap();
+        assert_eq!(encoder.encode(&low)[income], 0.0);
+        assert_eq!(encoder.encode(&high)[income], 1.0);
+
+        // NA falls back to the fitted median
+        let delinq = encoder.scales.iter().position(|s| s.column == "months_since_last_delinq").unwrap();
+        assert_eq!(encoder.encode(&missing)[delinq], encoder.encode(&low)[delinq]);
+
+        let tail = &encoder.encode(&low)[FEATURE_DIMS - 11..];
+        assert_eq!(tail, [0.0, 0.0, 1.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0]);
+    }
+}
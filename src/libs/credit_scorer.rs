@@ -0,0 +1,480 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, FromRow, PgPool};
+
+use crate::{
+    error::AppError,
+    libs::credit_file_vector::CreditFileEncoder,
+    models::{
+        credit_file::{CreditFile, DECODABLE_CREDIT_FILES, SELECT_CREDIT_FILES},
+        loan::LoanStatus,
+    },
+};
+
+pub const DEFAULT_MODEL_DIR: &str = "model_files";
+const MODEL_PREFIX: &str = "credit_scorer_v";
+const EPOCHS: usize = 500;
+const LEARNING_RATE: f64 = 0.5;
+const L2_PENALTY: f64 = 0.001;
+const TOP_FEATURES: usize = 5;
+/// Applications scoring above this are declined rather than priced
+pub const MAX_OFFER_PD: f64 = 0.30;
+
+// Upper PD bound for each grade, each band split evenly into five sub-grades
+const GRADE_BANDS: [(char, f64); 7] = [
+    ('A', 0.03),
+    ('B', 0.06),
+    ('C', 0.10),
+    ('D', 0.15),
+    ('E', 0.22),
+    ('F', 0.30),
+    ('G', 1.0),
+];
+const BASE_APR: f32 = 6.0;
+const APR_STEP: f32 = 0.75;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TrainingMetrics {
+    pub rows: usize,
+    pub bad_rate: f64,
+    pub log_loss: f64,
+    pub auc: f64,
+}
+
+/// Logistic regression over CreditFileEncoder features. Written to
+/// `model_files/credit_scorer_v{version}.json` by the `train_credit_scorer` command.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreditScorer {
+    pub version: u32,
+    pub trained_at: DateTime<Utc>,
+    pub encoder: CreditFileEncoder,
+    pub feature_names: Vec<String>,
+    pub weights: Vec<f64>,
+    pub bias: f64,
+    /// Training mean of each feature, contributions are measured against these
+    pub feature_means: Vec<f64>,
+    pub metrics: TrainingMetrics,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FeatureContribution {
+    pub feature: String,
+    pub value: f32,
+    /// Change in log-odds vs. an average borrower, positive raises the PD
+    pub contribution: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CreditScore {
+    pub model_version: u32,
+    pub pd: f64,
+    pub grade: String,
+    pub sub_grade: String,
+    pub top_features: Vec<FeatureContribution>,
+}
+
+impl CreditScore {
+    pub fn approvable(&self) -> bool {
+        self.pd <= MAX_OFFER_PD
+    }
+
+    pub fn apr(&self) -> f32 {
+        apr_for_sub_grade(&self.sub_grade)
+    }
+}
+
+fn sigmoid(z: f64) -> f64 {
+    1.0 / (1.0 + (-z).exp())
+}
+
+/// Grade letter and sub-grade (e.g. "B3") for a probability of default.
+pub fn grade_for_pd(pd: f64) -> (String, String) {
+    let mut low = 0.0;
+    for (grade, high) in GRADE_BANDS {
+        if pd < high || grade == 'G' {
+            let step = ((pd - low) / (high - low) * 5.0).floor().clamp(0.0, 4.0) as usize + 1;
+            return (grade.to_string(), format!("{}{}", grade, step));
+        }
+        low = high;
+    }
+    unreachable!()
+}
+
+/// A1 prices at BASE_APR and every sub-grade below adds APR_STEP.
+pub fn apr_for_sub_grade(sub_grade: &str) -> f32 {
+    let mut chars = sub_grade.chars();
+    let grade = chars
+        .next()
+        .and_then(|g| GRADE_BANDS.iter().position(|(band, _)| *band == g));
+    let step = chars.next().and_then(|s| s.to_digit(10));
+    match (grade, step) {
+        (Some(grade), Some(step @ 1..=5)) => {
+            BASE_APR + (grade as u32 * 5 + step - 1) as f32 * APR_STEP
+        }
+        _ => BASE_APR + 34.0 * APR_STEP,
+    }
+}
+
+/// Area under the ROC curve via the rank-sum of the bad outcomes.
+fn auc(scores: &[f64], labels: &[bool]) -> f64 {
+    let mut ranked: Vec<(f64, bool)> = scores.iter().copied().zip(labels.iter().copied()).collect();
+    ranked.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let bads = labels.iter().filter(|bad| **bad).count() as f64;
+    let goods = labels.len() as f64 - bads;
+    if bads == 0.0 || goods == 0.0 {
+        return 0.5;
+    }
+    let rank_sum: f64 = ranked
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, bad))| *bad)
+        .map(|(i, _)| (i + 1) as f64)
+        .sum();
+    (rank_sum - bads * (bads + 1.0) / 2.0) / (bads * goods)
+}
+
+impl CreditScorer {
+    /// Fits by batch gradient descent. `labels` are true for adverse outcomes.
+    pub fn train(files: &[CreditFile], labels: &[bool], version: u32) -> Self {
+        let encoder = CreditFileEncoder::fit(files);
+        let rows: Vec<Vec<f64>> = files
+            .iter()
+            .map(|file| encoder.encode(file).into_iter().map(f64::from).collect())
+            .collect();
+        let n = rows.len().max(1) as f64;
+        let dims = encoder.feature_names().len();
+
+        let mut feature_means = vec![0.0; dims];
+        for row in &rows {
+            for (mean, x) in feature_means.iter_mut().zip(row) {
+                *mean += x / n;
+            }
+        }
+
+        let ys: Vec<f64> = labels
+            .iter()
+            .map(|bad| if *bad { 1.0 } else { 0.0 })
+            .collect();
+        let bad_rate = ys.iter().sum::<f64>() / n;
+        let mut weights = vec![0.0; dims];
+        // Start at the base rate so early epochs aren't spent learning the intercept
+        let mut bias =
+            (bad_rate.clamp(1e-6, 1.0 - 1e-6) / (1.0 - bad_rate.clamp(1e-6, 1.0 - 1e-6))).ln();
+
+        for _ in 0..EPOCHS {
+            let mut grad = vec![0.0; dims];
+            let mut grad_bias = 0.0;
+            for (row, y) in rows.iter().zip(&ys) {
+                let z = bias + row.iter().zip(&weights).map(|(x, w)| x * w).sum::<f64>();
+                let err = sigmoid(z) - y;
+                grad_bias += err;
+                for (g, x) in grad.iter_mut().zip(row) {
+                    *g += err * x;
+                }
+            }
+            for (w, g) in weights.iter_mut().zip(&grad) {
+                *w -= LEARNING_RATE * (g / n + L2_PENALTY * *w);
+            }
+            bias -= LEARNING_RATE * grad_bias / n;
+        }
+
+        let pds: Vec<f64> = rows
+            .iter()
+            .map(|row| sigmoid(bias + row.iter().zip(&weights).map(|(x, w)| x * w).sum::<f64>()))
+            .collect();
+        let log_loss = pds
+            .iter()
+            .zip(&ys)
+            .map(|(p, y)| {
+                let p = p.clamp(1e-12, 1.0 - 1e-12);
+                -(y * p.ln() + (1.0 - y) * (1.0 - p).ln())
+            })
+            .sum::<f64>()
+            / n;
+
+        CreditScorer {
+            version,
+            trained_at: Utc::now(),
+            feature_names: encoder.feature_names(),
+            encoder,
+            weights,
+            bias,
+            feature_means,
+            metrics: TrainingMetrics {
+                rows: files.len(),
+                bad_rate,
+                log_loss,
+                auc: auc(&pds, labels),
+            },
+        }
+    }
+
+    pub fn score(&self, file: &CreditFile) -> CreditScore {
+        self.score_features(self.encoder.encode(file), |_| true)
+    }
+
+    /// Scores with only the `known` columns. Everything else is held at the training
+    /// mean, so it neither raises nor lowers the PD. Used for applications, which
+    /// carry a handful of credit file columns and no bureau data.
+    pub fn score_partial(&self, file: &CreditFile, known: &[&str]) -> CreditScore {
+        self.score_features(self.encoder.encode(file), |feature| {
+            let column = feature.split('=').next().unwrap_or(feature);
+            known.contains(&column)
+        })
+    }
+
+    fn score_features(&self, features: Vec<f32>, is_known: impl Fn(&str) -> bool) -> CreditScore {
+        let mut contributions: Vec<FeatureContribution> = Vec::new();
+        let mut z = self.bias;
+        for (i, name) in self.feature_names.iter().enumerate() {
+            let mean = self.feature_means[i];
+            let value = if is_known(name) {
+                features[i] as f64
+            } else {
+                mean
+            };
+            z += self.weights[i] * value;
+            let contribution = self.weights[i] * (value - mean);
+            if contribution != 0.0 {
+                contributions.push(FeatureContribution {
+                    feature: name.clone(),
+                    value: value as f32,
+                    contribution,
+                });
+            }
+        }
+        contributions.sort_by(|a, b| b.contribution.abs().total_cmp(&a.contribution.abs()));
+        contributions.truncate(TOP_FEATURES);
+
+        let pd = sigmoid(z);
+        let (grade, sub_grade) = grade_for_pd(pd);
+        CreditScore {
+            model_version: self.version,
+            pd,
+            grade,
+            sub_grade,
+            top_features: contributions,
+        }
+    }
+
+    pub fn save(&self, dir: &str) -> std::io::Result<PathBuf> {
+        fs::create_dir_all(dir)?;
+        let path = Path::new(dir).join(format!("{}{}.json", MODEL_PREFIX, self.version));
+        fs::write(&path, serde_json::to_string_pretty(self)?)?;
+        Ok(path)
+    }
+
+    /// The highest model version in `dir`, None if nothing has been trained yet.
+    pub fn load_latest(dir: &str) -> std::io::Result<Option<Self>> {
+        match latest_version(dir)? {
+            Some(version) => {
+                let path = Path::new(dir).join(format!("{}{}.json", MODEL_PREFIX, version));
+                let scorer = serde_json::from_str(&fs::read_to_string(path)?)?;
+                Ok(Some(scorer))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+pub fn latest_version(dir: &str) -> std::io::Result<Option<u32>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    Ok(entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            name.strip_prefix(MODEL_PREFIX)?
+                .strip_suffix(".json")?
+                .parse::<u32>()
+                .ok()
+        })
+        .max())
+}
+
+#[derive(Debug, FromRow)]
+struct TrainingRow {
+    #[sqlx(flatten)]
+    file: CreditFile,
+    loan_status: i32,
+}
+
+/// Credit files joined to the status of the borrower's latest loan.
+pub async fn load_training_set(pool: &PgPool) -> Result<(Vec<CreditFile>, Vec<bool>), AppError> {
+    let sql = format!(
+        "SELECT cf.*, l.loan_status FROM ({} WHERE {}) cf
+        JOIN LATERAL (
+            SELECT loan_status FROM loans WHERE loans.borrower_id = cf.borrower_id ORDER BY loan_id DESC LIMIT 1
+        ) l ON TRUE",
+        SELECT_CREDIT_FILES, DECODABLE_CREDIT_FILES
+    );
+    let rows = sqlx::query_as::<_, TrainingRow>(&sql)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            let status = LoanStatus::try_from(row.loan_status).ok()?;
+            Some((row.file, status.is_adverse()))
+        })
+        .unzip())
+}
+
+/// Trains the next model version from the database and writes it to `dir`.
+pub async fn train_credit_scorer(
+    pool: &PgPool,
+    dir: &str,
+) -> Result<(CreditScorer, PathBuf), AppError> {
+    let (files, labels) = load_training_set(pool).await?;
+    if files.is_empty() {
+        return Err(AppError::GenericError(
+            "No credit files with a loan outcome to train on".to_owned(),
+        ));
+    }
+    let version = latest_version(dir)
+        .map_err(|err| AppError::GenericError(err.to_string()))?
+        .unwrap_or(0)
+        + 1;
+    let scorer = CreditScorer::train(&files, &labels, version);
+    let path = scorer
+        .save(dir)
+        .map_err(|err| AppError::GenericError(err.to_string()))?;
+    Ok((scorer, path))
+}
+
+pub async fn save_credit_score(
+    pool: &PgPool,
+    application_id: i32,
+    score: &CreditScore,
+) -> Result<(), AppError> {
+    sqlx::query(
+        "INSERT INTO credit_scores (application_id, model_version, pd, grade, sub_grade, top_features)
+        VALUES ($1, $2, $3, $4, $5, $6)",
+    )
+    .bind(application_id)
+    .bind(score.model_version as i32)
+    .bind(score.pd)
+    .bind(&score.grade)
+    .bind(&score.sub_grade)
+    .bind(Json(&score.top_features))
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[derive(Debug, FromRow)]
+struct CreditScoreRow {
+    model_version: i32,
+    pd: f64,
+    grade: String,
+    sub_grade: String,
+    top_features: Json<Vec<FeatureContribution>>,
+}
+
+/// The most recent score saved for the application, None if it was never scored
+pub async fn latest_credit_score(
+    pool: &PgPool,
+    application_id: i32,
+) -> Result<Option<CreditScore>, AppError> {
+    let row = sqlx::query_as::<_, CreditScoreRow>(
+        "SELECT model_version, pd, grade, sub_grade, top_features FROM credit_scores
+        WHERE application_id = $1 ORDER BY credit_score_id DESC LIMIT 1",
+    )
+    .bind(application_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|row| CreditScore {
+        model_version: row.model_version as u32,
+        pd: row.pd,
+        grade: row.grade,
+        sub_grade: row.sub_grade,
+        top_features: row.top_features.0,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::credit_file::mock_credit_file;
+
+    fn training_set() -> (Vec<CreditFile>, Vec<bool>) {
+        // Delinquencies drive the bad outcomes, nothing else varies with the label
+        (0..200)
+            .map(|i| {
+                let mut file = mock_credit_file();
+                let bad = i % 4 == 0;
+                file.delinq_2y = if bad { 5 + i % 3 } else { i % 2 };
+                file.num_historical_failed_to_pay = if bad { 3 } else { 0 };
+                (file, bad)
+            })
+            .unzip()
+    }
+
+    #[test]
+    fn test_grade_for_pd() {
+        assert_eq!(grade_for_pd(0.0), ("A".to_owned(), "A1".to_owned()));
+        assert_eq!(grade_for_pd(0.029), ("A".to_owned(), "A5".to_owned()));
+        assert_eq!(grade_for_pd(0.08), ("C".to_owned(), "C3".to_owned()));
+        assert_eq!(grade_for_pd(0.99), ("G".to_owned(), "G5".to_owned()));
+    }
+
+    #[test]
+    fn test_apr_for_sub_grade() {
+        assert_eq!(apr_for_sub_grade("A1"), 6.0);
+        assert_eq!(apr_for_sub_grade("B1"), 9.75);
+        assert_eq!(apr_for_sub_grade("G5"), 31.5);
+        assert_eq!(apr_for_sub_grade("Z9"), 31.5);
+    }
+
+    #[test]
+    fn trained_model_separates_outcomes() {
+        let (files, labels) = training_set();
+        let scorer = CreditScorer::train(&files, &labels, 1);
+        assert_eq!(scorer.metrics.rows, 200);
+        assert_eq!(scorer.metrics.bad_rate, 0.25);
+        assert!(scorer.metrics.auc > 0.9);
+
+        let good = scorer.score(&files[1]);
+        let bad = scorer.score(&files[0]);
+        assert!(bad.pd > good.pd);
+        assert!(bad
+            .top_features
+            .iter()
+            .any(|f| f.feature == "delinq_2y" && f.contribution > 0.0));
+    }
+
+    #[test]
+    fn partial_scores_ignore_unknown_columns() {
+        let (files, labels) = training_set();
+        let scorer = CreditScorer::train(&files, &labels, 1);
+        let bad = scorer.score_partial(&files[0], &["annual_income"]);
+        let good = scorer.score_partial(&files[1], &["annual_income"]);
+        assert!((bad.pd - good.pd).abs() < 0.05);
+        assert!(bad
+            .top_features
+            .iter()
+            .all(|f| f.feature == "annual_income"));
+    }
+
+    #[test]
+    fn save_and_load_latest() {
+        let dir = std::env::temp_dir().join(format!("credit_scorer_{}", std::process::id()));
+        let dir = dir.to_str().unwrap();
+        assert!(CreditScorer::load_latest(dir).unwrap().is_none());
+
+        let (files, labels) = training_set();
+        CreditScorer::train(&files, &labels, 1).save(dir).unwrap();
+        CreditScorer::train(&files, &labels, 2).save(dir).unwrap();
+        let latest = CreditScorer::load_latest(dir).unwrap().unwrap();
+        assert_eq!(latest.version, 2);
+        assert_eq!(latest.score(&files[0]).model_version, 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,591 @@
+use std::{env, str::FromStr, time::Duration};
+
+use chrono::{Months, NaiveDate, NaiveDateTime, Utc};
+use serde::Serialize;
+use sqlx::{PgPool, Postgres, Transaction};
+
+use crate::{
+    error::AppError,
+    libs::{
+        audit_trail::begin_audited,
+        mailer::queue_email,
+        outbox::{record_event, EventType},
+        servicing::{
+            insert_ledger_entry, loan_borrower_user, lock_loan_account, save_loan_account,
+            Allocation, LoanAccount, PaymentKind,
+        },
+    },
+    models::loan::LoanStatus,
+};
+
+/// loan_notices.channel of the email a borrower gets when their loan enters one of `notify_on`.
+/// In-app, the loan_status_changed outbox event is forwarded to the borrower's `/sse` stream.
+pub const NOTICE_CHANNEL: &str = "email";
+
+/// Where each delinquency band ends, in days past due, plus the late fee and which
+/// statuses send a notice. Every field can be overridden with a `DELINQUENCY_*` env var.
+#[derive(Debug, Clone)]
+pub struct DelinquencyConfig {
+    /// Last day past due that is still InGracePeriod
+    pub grace_period_days: i64,
+    pub late_1_to_15_days: i64,
+    pub late_16_to_30_days: i64,
+    /// Past this a loan is ChargedOff
+    pub charge_off_days: i64,
+    /// Cents. The fee is the greater of this and `late_fee_rate` of the installment
+    pub late_fee_flat: i64,
+    pub late_fee_rate: f64,
+    pub notify_on: Vec<LoanStatus>,
+    /// Hour of the day (UTC) the nightly run starts
+    pub run_hour: u32,
+}
+
+impl Default for DelinquencyConfig {
+    fn default() -> Self {
+        Self {
+            grace_period_days: 5,
+            late_1_to_15_days: 15,
+            late_16_to_30_days: 30,
+            charge_off_days: 120,
+            late_fee_flat: 1_500,
+            late_fee_rate: 0.05,
+            notify_on: vec![
+                LoanStatus::Late16to30,
+                LoanStatus::Late31to120,
+                LoanStatus::ChargedOff,
+            ],
+            run_hour: 2,
+        }
+    }
+}
+
+fn env_or<T: FromStr>(name: &'static str, default: T) -> Result<T, AppError> {
+    match env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map_err(|_| AppError::GenericError(format!("Invalid {}: {}", name, value))),
+        Err(_) => Ok(default),
+    }
+}
+
+impl DelinquencyConfig {
+    pub fn from_env() -> Result<Self, AppError> {
+        let default = Self::default();
+        let notify_on = match env::var("DELINQUENCY_NOTIFY_ON") {
+            // Comma separated status names as they appear in the loan data, e.g. "Late (16-30 days)"
+            Ok(names) => names
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(|name| {
+                    LoanStatus::from_str(name).map_err(|_| {
+                        AppError::GenericError(format!("Invalid DELINQUENCY_NOTIFY_ON: {}", name))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            Err(_) => default.notify_on,
+        };
+        let config = Self {
+            grace_period_days: env_or("DELINQUENCY_GRACE_PERIOD_DAYS", default.grace_period_days)?,
+            late_1_to_15_days: env_or("DELINQUENCY_LATE_1_TO_15_DAYS", default.late_1_to_15_days)?,
+            late_16_to_30_days: env_or(
+                "DELINQUENCY_LATE_16_TO_30_DAYS",
+                default.late_16_to_30_days,
+            )?,
+            charge_off_days: env_or("DELINQUENCY_CHARGE_OFF_DAYS", default.charge_off_days)?,
+            late_fee_flat: env_or("DELINQUENCY_LATE_FEE_FLAT", default.late_fee_flat)?,
+            late_fee_rate: env_or("DELINQUENCY_LATE_FEE_RATE", default.late_fee_rate)?,
+            notify_on,
+            run_hour: env_or("DELINQUENCY_RUN_HOUR", default.run_hour)?,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), AppError> {
+        let ascending = 0 <= self.grace_period_days
+            && self.grace_period_days <= self.late_1_to_15_days
+            && self.late_1_to_15_days <= self.late_16_to_30_days
+            && self.late_16_to_30_days <= self.charge_off_days;
+        if !ascending {
+            return Err(AppError::GenericError(
+                "Delinquency thresholds must be ascending".to_owned(),
+            ));
+        }
+        if self.run_hour > 23 {
+            return Err(AppError::GenericError(
+                "DELINQUENCY_RUN_HOUR must be 0-23".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn status_for(&self, days_past_due: i64) -> LoanStatus {
+        match days_past_due {
+            d if d <= 0 => LoanStatus::Current,
+            d if d <= self.grace_period_days => LoanStatus::InGracePeriod,
+            d if d <= self.late_1_to_15_days => LoanStatus::Late1to15,
+            d if d <= self.late_16_to_30_days => LoanStatus::Late16to30,
+            d if d <= self.charge_off_days => LoanStatus::Late31to120,
+            _ => LoanStatus::ChargedOff,
+        }
+    }
+
+    pub fn late_fee(&self, installment: i64) -> i64 {
+        self.late_fee_flat
+            .max((installment as f64 * self.late_fee_rate).round() as i64)
+    }
+}
+
+/// What the job decided for one loan on one day.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Assessment {
+    pub days_past_due: i64,
+    pub status: LoanStatus,
+    /// Cents, zero when no fee is due
+    pub late_fee: i64,
+    /// Due date of the latest installment `late_fee` covers, None when no fee is due
+    pub late_fee_due_date: Option<NaiveDate>,
+}
+
+pub fn days_past_due(account: &LoanAccount, as_of: NaiveDate) -> i64 {
+    if account.paid_off() {
+        return 0;
+    }
+    (as_of - account.next_due_date).num_days().max(0)
+}
+
+/// Due dates of the installments unpaid as of `as_of`, oldest first. Nothing is paid past
+/// `next_due_date`, so it's that one and every monthly one after it that has come due, up to
+/// the end of the term.
+pub fn missed_due_dates(account: &LoanAccount, as_of: NaiveDate) -> Vec<NaiveDate> {
+    (0..account.remaining_term.max(1) as u32)
+        .map_while(|months| {
+            account
+                .next_due_date
+                .checked_add_months(Months::new(months))
+        })
+        .take_while(|due_date| *due_date < as_of)
+        .collect()
+}
+
+/// `late_fee_due_date` is the due date of the latest installment a late fee was charged for.
+/// One fee per missed installment, charged once its grace period is over, none on charge off.
+/// Installments that went past grace since the last run are all charged, so a loan missing
+/// several in a row pays for each.
+pub fn assess(
+    account: &LoanAccount,
+    late_fee_due_date: Option<NaiveDate>,
+    as_of: NaiveDate,
+    config: &DelinquencyConfig,
+) -> Assessment {
+    if account.paid_off() {
+        return Assessment {
+            days_past_due: 0,
+            status: LoanStatus::FullyPaid,
+            late_fee: 0,
+            late_fee_due_date: None,
+        };
+    }
+    let days_past_due = days_past_due(account, as_of);
+    let status = config.status_for(days_past_due);
+    let fees_due = if status == LoanStatus::ChargedOff {
+        Vec::new()
+    } else {
+        missed_due_dates(account, as_of)
+            .into_iter()
+            .filter(|due_date| (as_of - *due_date).num_days() > config.grace_period_days)
+            .filter(|due_date| late_fee_due_date.is_none_or(|charged| *due_date > charged))
+            .collect()
+    };
+    Assessment {
+        days_past_due,
+        late_fee: config.late_fee(account.installment) * fees_due.len() as i64,
+        late_fee_due_date: fees_due.last().copied(),
+        status,
+    }
+}
+
+/// Recorded in the outbox with the status change it describes, for in-app delivery.
+#[derive(Debug, Clone, Serialize)]
+pub struct LoanStatusChange {
+    pub history_id: i32,
+    pub loan_id: i32,
+    pub from: LoanStatus,
+    pub to: LoanStatus,
+    pub days_past_due: i64,
+    pub as_of: NaiveDate,
+    /// A notice email was queued for it
+    pub notify: bool,
+    /// Whose `/sse` stream it goes to, None if the borrower hasn't signed up
+    pub borrower_user_id: Option<i32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DelinquencyReport {
+    pub as_of: NaiveDate,
+    pub loans: usize,
+    pub transitions: usize,
+    pub late_fees: usize,
+    /// Cents
+    pub late_fee_total: i64,
+    pub notices: usize,
+    pub failed: usize,
+}
+
+async fn insert_status_change(
+    tx: &mut Transaction<'_, Postgres>,
+    loan_id: i32,
+    from: &LoanStatus,
+    assessment: &Assessment,
+    as_of: NaiveDate,
+) -> Result<i32, AppError> {
+    sqlx::query_scalar(
+        "INSERT INTO loan_status_history (loan_id, from_status, to_status, days_past_due, as_of)
+        VALUES ($1, $2, $3, $4, $5) RETURNING history_id",
+    )
+    .bind(loan_id)
+    .bind(from.clone() as i32)
+    .bind(assessment.status.clone() as i32)
+    .bind(assessment.days_past_due as i32)
+    .bind(as_of)
+    .fetch_one(&mut **tx)
+    .await
+    .map_err(AppError::from)
+}
+
+/// Subject, text and HTML of the email sent when a loan enters `assessment.status`
+pub fn notice_email(loan_id: i32, assessment: &Assessment) -> (String, String, String) {
+    let subject = format!("Your loan {} is now {}", loan_id, assessment.status);
+    let text = format!(
+        "Your loan {} is {} days past due and its status is now {}. Please make a payment or contact us to avoid further fees.",
+        loan_id, assessment.days_past_due, assessment.status
+    );
+    let html = format!("<p>{}</p>", text);
+    (subject, text, html)
+}
+
+/// Queues the notice email to the borrower and records the notice against it. Returns whether
+/// there was an address to send to.
+async fn insert_notice(
+    tx: &mut Transaction<'_, Postgres>,
+    loan_id: i32,
+    history_id: i32,
+    assessment: &Assessment,
+) -> Result<bool, AppError> {
+    let email: Option<String> = sqlx::query_scalar(
+        "SELECT b.email FROM loans l JOIN borrowers b ON b.borrower_id = l.borrower_id
+        WHERE l.loan_id = $1",
+    )
+    .bind(loan_id)
+    .fetch_optional(&mut **tx)
+    .await?;
+    let email_id = match &email {
+        Some(to) => {
+            let (subject, text, html) = notice_email(loan_id, assessment);
+            Some(queue_email(tx, to, &subject, &html, &text, None).await?)
+        }
+        None => {
+            tracing::warn!(
+                loan_id,
+                "No email for the borrower, notice stored but not sent"
+            );
+            None
+        }
+    };
+    sqlx::query(
+        "INSERT INTO loan_notices (loan_id, history_id, channel, loan_status, days_past_due, email_id)
+        VALUES ($1, $2, $3, $4, $5, $6)",
+    )
+    .bind(loan_id)
+    .bind(history_id)
+    .bind(NOTICE_CHANNEL)
+    .bind(assessment.status.clone() as i32)
+    .bind(assessment.days_past_due as i32)
+    .bind(email_id)
+    .execute(&mut **tx)
+    .await?;
+    Ok(email_id.is_some())
+}
+
+/// Assesses one loan in its own transaction. Running it twice for the same day is a no-op.
+pub async fn assess_loan(
+    pool: &PgPool,
+    loan_id: i32,
+    as_of: NaiveDate,
+    config: &DelinquencyConfig,
+) -> Result<(Assessment, Option<LoanStatusChange>), AppError> {
+    let mut tx = begin_audited(pool).await?;
+    let mut account = lock_loan_account(&mut tx, loan_id).await?;
+    let (status_code, late_fee_due_date): (i32, Option<NaiveDate>) =
+        sqlx::query_as("SELECT loan_status, late_fee_due_date FROM loans WHERE loan_id = $1")
+            .bind(loan_id)
+            .fetch_one(&mut *tx)
+            .await?;
+    let from = LoanStatus::try_from(status_code).map_err(|err| {
+        AppError::GenericError(format!("Loan {}: {} {}", loan_id, err, status_code))
+    })?;
+    let assessment = assess(&account, late_fee_due_date, as_of, config);
+
+    if assessment.late_fee > 0 {
+        let before = account.clone();
+        account.assess_late_fee(assessment.late_fee);
+        save_loan_account(&mut tx, &account, &Allocation::default()).await?;
+        let charged = Allocation {
+            fees: assessment.late_fee,
+            ..Default::default()
+        };
+        insert_ledger_entry(
+            &mut tx,
+            &before,
+            &account,
+            PaymentKind::LateFee,
+            as_of,
+            assessment.late_fee,
+            &charged,
+            None,
+        )
+        .await?;
+    }
+
+    sqlx::query(
+        "UPDATE loans SET loan_status = $2, days_past_due = $3,
+            late_fee_due_date = COALESCE($4, late_fee_due_date)
+        WHERE loan_id = $1",
+    )
+    .bind(loan_id)
+    .bind(assessment.status.clone() as i32)
+    .bind(assessment.days_past_due as i32)
+    .bind(assessment.late_fee_due_date)
+    .execute(&mut *tx)
+    .await?;
+
+    let mut change = None;
+    if assessment.status != from {
+        let history_id = insert_status_change(&mut tx, loan_id, &from, &assessment, as_of).await?;
+        let notify = config.notify_on.contains(&assessment.status)
+            && insert_notice(&mut tx, loan_id, history_id, &assessment).await?;
+        let status_change = LoanStatusChange {
+            history_id,
+            loan_id,
+            from,
+            to: assessment.status.clone(),
+            days_past_due: assessment.days_past_due,
+            as_of,
+            notify,
+            borrower_user_id: loan_borrower_user(&mut *tx, loan_id).await?,
+        };
+        record_event(
+            &mut tx,
+            EventType::LoanStatusChanged,
+            loan_id,
+            &status_change,
+        )
+        .await?;
+        change = Some(status_change);
+    }
+    tx.commit().await?;
+    Ok((assessment, change))
+}
+
+/// Assesses every serviced loan that isn't closed as of `as_of`. A loan that fails is
+/// counted and skipped so one bad row doesn't hold up the rest.
+pub async fn run_delinquency(
+    pool: &PgPool,
+    as_of: NaiveDate,
+    config: &DelinquencyConfig,
+) -> Result<DelinquencyReport, AppError> {
+    // Loans without a next_due_date were never serviced here, their status came with the data
+    let loan_ids: Vec<i32> = sqlx::query_scalar(
+        "SELECT loan_id FROM loans
+        WHERE next_due_date IS NOT NULL AND loan_status NOT IN ($1, $2) ORDER BY loan_id",
+    )
+    .bind(LoanStatus::FullyPaid as i32)
+    .bind(LoanStatus::ChargedOff as i32)
+    .fetch_all(pool)
+    .await?;
+
+    let mut report = DelinquencyReport {
+        as_of,
+        loans: loan_ids.len(),
+        transitions: 0,
+        late_fees: 0,
+        late_fee_total: 0,
+        notices: 0,
+        failed: 0,
+    };
+    for loan_id in loan_ids {
+        match assess_loan(pool, loan_id, as_of, config).await {
+            Ok((assessment, change)) => {
+                if assessment.late_fee > 0 {
+                    report.late_fees += 1;
+                    report.late_fee_total += assessment.late_fee;
+                }
+                if let Some(change) = change {
+                    report.transitions += 1;
+                    if change.notify {
+                        report.notices += 1;
+                    }
+                }
+            }
+            Err(err) => {
+                tracing::error!(loan_id, error = ?err, "Delinquency assessment failed");
+                report.failed += 1;
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Time from `now` until the next `hour`:00 UTC.
+pub fn until_next_run(now: NaiveDateTime, hour: u32) -> Duration {
+    let today = now.date().and_hms_opt(hour, 0, 0).unwrap_or(now);
+    let next = if today > now {
+        today
+    } else {
+        today + chrono::Duration::days(1)
+    };
+    (next - now).to_std().unwrap_or_default()
+}
+
+/// Runs the job once a day at `config.run_hour` for as long as the app is up.
+pub async fn run_nightly(pool: PgPool, config: DelinquencyConfig) {
+    loop {
+        tokio::time::sleep(until_next_run(Utc::now().naive_utc(), config.run_hour)).await;
+        let as_of = Utc::now().date_naive();
+        match run_delinquency(&pool, as_of, &config).await {
+            Ok(report) => tracing::info!(?report, "Delinquency run finished"),
+            Err(err) => tracing::error!(error = ?err, "Delinquency run failed"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::libs::servicing::{amortized_installment, AccrualMethod};
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn account() -> LoanAccount {
+        LoanAccount {
+            loan_id: 1,
+            principal: 1_200_000,
+            interest_due: 0,
+            fees_due: 0,
+            due_paid: 0,
+            installment: amortized_installment(1_200_000, 12.0, 12),
+            interest_rate: 12.0,
+            remaining_term: 12,
+            accrual_method: AccrualMethod::Monthly,
+            last_accrual_date: date(2024, 1, 1),
+            next_due_date: date(2024, 2, 1),
+        }
+    }
+
+    #[test]
+    fn statuses_follow_thresholds() {
+        let config = DelinquencyConfig::default();
+        let cases = [
+            (0, LoanStatus::Current),
+            (1, LoanStatus::InGracePeriod),
+            (5, LoanStatus::InGracePeriod),
+            (6, LoanStatus::Late1to15),
+            (16, LoanStatus::Late16to30),
+            (31, LoanStatus::Late31to120),
+            (120, LoanStatus::Late31to120),
+            (121, LoanStatus::ChargedOff),
+        ];
+        for (days, status) in cases {
+            assert_eq!(config.status_for(days), status, "{} days", days);
+        }
+        let bad = DelinquencyConfig {
+            late_1_to_15_days: 40,
+            ..DelinquencyConfig::default()
+        };
+        assert!(bad.validate().is_err());
+    }
+
+    #[test]
+    fn one_late_fee_per_missed_installment() {
+        let config = DelinquencyConfig::default();
+        let loan = account();
+
+        let early = assess(&loan, None, date(2024, 2, 4), &config);
+        assert_eq!(early.status, LoanStatus::InGracePeriod);
+        assert_eq!(early.late_fee, 0);
+        assert_eq!(early.late_fee_due_date, None);
+
+        let late = assess(&loan, None, date(2024, 2, 7), &config);
+        assert_eq!(late.days_past_due, 6);
+        assert_eq!(late.status, LoanStatus::Late1to15);
+        // 5% of a $1,066.19 installment beats the $15 flat fee
+        assert_eq!(late.late_fee, 5_331);
+        assert_eq!(late.late_fee_due_date, Some(date(2024, 2, 1)));
+
+        let rerun = assess(&loan, late.late_fee_due_date, date(2024, 2, 7), &config);
+        assert_eq!(rerun.late_fee, 0);
+
+        // Still nothing paid, so March's installment is missed too and gets its own fee
+        let in_grace = assess(&loan, late.late_fee_due_date, date(2024, 3, 5), &config);
+        assert_eq!(in_grace.late_fee, 0);
+        let second = assess(&loan, late.late_fee_due_date, date(2024, 3, 10), &config);
+        assert_eq!(second.status, LoanStatus::Late31to120);
+        assert_eq!(second.late_fee, 5_331);
+        assert_eq!(second.late_fee_due_date, Some(date(2024, 3, 1)));
+
+        // A job that didn't run for a while catches up on every installment it missed
+        let caught_up = assess(&loan, None, date(2024, 4, 10), &config);
+        assert_eq!(caught_up.late_fee, 3 * 5_331);
+        assert_eq!(caught_up.late_fee_due_date, Some(date(2024, 4, 1)));
+
+        let charged_off = assess(&loan, None, date(2024, 7, 1), &config);
+        assert_eq!(charged_off.status, LoanStatus::ChargedOff);
+        assert_eq!(charged_off.late_fee, 0);
+    }
+
+    #[test]
+    fn missed_installments_stop_at_the_end_of_the_term() {
+        let mut loan = account();
+        loan.remaining_term = 2;
+        assert_eq!(
+            missed_due_dates(&loan, date(2024, 6, 1)),
+            [date(2024, 2, 1), date(2024, 3, 1)]
+        );
+        assert!(missed_due_dates(&loan, date(2024, 2, 1)).is_empty());
+    }
+
+    #[test]
+    fn notice_email_names_the_status() {
+        let assessment = Assessment {
+            days_past_due: 20,
+            status: LoanStatus::Late16to30,
+            late_fee: 0,
+            late_fee_due_date: None,
+        };
+        let (subject, text, html) = notice_email(7, &assessment);
+        assert_eq!(subject, "Your loan 7 is now Late (16-30 days)");
+        assert!(text.contains("20 days past due"));
+        assert_eq!(html, format!("<p>{}</p>", text));
+    }
+
+    #[test]
+    fn paid_off_loans_are_fully_paid() {
+        let mut loan = account();
+        loan.principal = 0;
+        let assessment = assess(&loan, None, date(2024, 6, 1), &DelinquencyConfig::default());
+        assert_eq!(assessment.status, LoanStatus::FullyPaid);
+        assert_eq!(assessment.days_past_due, 0);
+    }
+
+    #[test]
+    fn next_run_is_today_or_tomorrow() {
+        let now = date(2024, 2, 1).and_hms_opt(1, 30, 0).unwrap();
+        assert_eq!(until_next_run(now, 2), Duration::from_secs(30 * 60));
+        let now = date(2024, 2, 1).and_hms_opt(2, 0, 0).unwrap();
+        assert_eq!(until_next_run(now, 2), Duration::from_secs(24 * 60 * 60));
+    }
+}
@@ -0,0 +1,364 @@
+use std::{
+    env, fs,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::{Client, Method, StatusCode};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::error::AppError;
+
+pub const DEFAULT_DOCUMENT_DIR: &str = "documents";
+const DEFAULT_S3_REGION: &str = "us-east-1";
+
+/// Where uploaded documents live. Keys are relative, slash separated paths such as
+/// `applications/12/3f2a….pdf`, and are what gets recorded in `attachments.path`.
+#[async_trait]
+pub trait DocumentStorage: Send + Sync {
+    async fn put(&self, key: &str, bytes: &[u8], content_type: &str) -> Result<(), AppError>;
+    async fn get(&self, key: &str) -> Result<Vec<u8>, AppError>;
+    async fn delete(&self, key: &str) -> Result<(), AppError>;
+}
+
+fn io_err(err: std::io::Error) -> AppError {
+    AppError::GenericError(err.to_string())
+}
+
+/// Only plain relative paths, so a key can't climb out of the storage root or bucket
+fn check_key(key: &str) -> Result<(), AppError> {
+    let path = Path::new(key);
+    if key.is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(AppError::InvalidRequest(format!(
+            "Invalid document key {}",
+            key
+        )));
+    }
+    Ok(())
+}
+
+/// Files under `root` on the local disk
+pub struct LocalStorage {
+    pub root: PathBuf,
+}
+
+impl LocalStorage {
+    fn path_for(&self, key: &str) -> Result<PathBuf, AppError> {
+        check_key(key)?;
+        Ok(self.root.join(key))
+    }
+}
+
+#[async_trait]
+impl DocumentStorage for LocalStorage {
+    async fn put(&self, key: &str, bytes: &[u8], _content_type: &str) -> Result<(), AppError> {
+        let path = self.path_for(key)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(io_err)?;
+        }
+        fs::write(path, bytes).map_err(io_err)
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, AppError> {
+        fs::read(self.path_for(key)?).map_err(|err| match err.kind() {
+            std::io::ErrorKind::NotFound => {
+                AppError::NotFound(format!("Document {} not found", key))
+            }
+            _ => io_err(err),
+        })
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), AppError> {
+        match fs::remove_file(self.path_for(key)?) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(io_err(err)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// An S3 compatible bucket, addressed path style so a local MinIO works the same as AWS.
+/// Requests are signed with AWS Signature Version 4.
+pub struct S3Storage {
+    pub endpoint: Url,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    client: Client,
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// RFC 3986 encoding of one path segment, as SigV4 wants it
+fn uri_encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let date_key = hmac_sha256(format!("AWS4{}", secret_key).as_bytes(), date);
+    let region_key = hmac_sha256(&date_key, region);
+    let service_key = hmac_sha256(&region_key, service);
+    hmac_sha256(&service_key, "aws4_request")
+}
+
+impl S3Storage {
+    pub fn new(
+        endpoint: Url,
+        bucket: String,
+        region: String,
+        access_key: String,
+        secret_key: String,
+    ) -> Self {
+        Self {
+            endpoint,
+            bucket,
+            region,
+            access_key,
+            secret_key,
+            client: Client::new(),
+        }
+    }
+
+    fn object_path(&self, key: &str) -> String {
+        let base = self.endpoint.path().trim_end_matches('/');
+        let key: Vec<String> = key.split('/').map(uri_encode).collect();
+        format!("{}/{}/{}", base, uri_encode(&self.bucket), key.join("/"))
+    }
+
+    fn host(&self) -> String {
+        let host = self.endpoint.host_str().unwrap_or_default();
+        match self.endpoint.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_owned(),
+        }
+    }
+
+    /// The Authorization header for a request with no query string, signing host, the
+    /// payload hash and the date.
+    fn authorization(
+        &self,
+        method: &Method,
+        path: &str,
+        payload_hash: &str,
+        now: DateTime<Utc>,
+    ) -> String {
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method,
+            path,
+            self.host(),
+            payload_hash,
+            amz_date,
+            signed_headers,
+            payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            sha256_hex(canonical_request.as_bytes())
+        );
+        let signature = hex::encode(hmac_sha256(
+            &signing_key(&self.secret_key, &date, &self.region, "s3"),
+            &string_to_sign,
+        ));
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature
+        )
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        body: Vec<u8>,
+        content_type: Option<&str>,
+    ) -> Result<reqwest::Response, AppError> {
+        check_key(key)?;
+        let path = self.object_path(key);
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        let payload_hash = sha256_hex(&body);
+        let now = Utc::now();
+        let mut request = self
+            .client
+            .request(method.clone(), url)
+            .header("x-amz-content-sha256", &payload_hash)
+            .header("x-amz-date", now.format("%Y%m%dT%H%M%SZ").to_string())
+            .header(
+                "authorization",
+                self.authorization(&method, &path, &payload_hash, now),
+            );
+        if let Some(content_type) = content_type {
+            request = request.header("content-type", content_type);
+        }
+        request
+            .body(body)
+            .send()
+            .await
+            .map_err(|err| AppError::GenericError(format!("S3 request failed: {}", err)))
+    }
+}
+
+async fn s3_status(response: reqwest::Response, key: &str) -> Result<reqwest::Response, AppError> {
+    match response.status() {
+        status if status.is_success() => Ok(response),
+        StatusCode::NOT_FOUND => Err(AppError::NotFound(format!("Document {} not found", key))),
+        status => {
+            let body = response.text().await.unwrap_or_default();
+            Err(AppError::GenericError(format!(
+                "S3 returned {}: {}",
+                status, body
+            )))
+        }
+    }
+}
+
+#[async_trait]
+impl DocumentStorage for S3Storage {
+    async fn put(&self, key: &str, bytes: &[u8], content_type: &str) -> Result<(), AppError> {
+        let response = self
+            .send(Method::PUT, key, bytes.to_vec(), Some(content_type))
+            .await?;
+        s3_status(response, key).await.map(|_| ())
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, AppError> {
+        let response = self.send(Method::GET, key, Vec::new(), None).await?;
+        let bytes = s3_status(response, key)
+            .await?
+            .bytes()
+            .await
+            .map_err(|err| AppError::GenericError(format!("S3 read failed: {}", err)))?;
+        Ok(bytes.to_vec())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), AppError> {
+        let response = self.send(Method::DELETE, key, Vec::new(), None).await?;
+        s3_status(response, key).await.map(|_| ())
+    }
+}
+
+fn required_env(name: &str) -> Result<String, AppError> {
+    env::var(name).map_err(|_| AppError::ConfigMissingEnv(name.to_owned()))
+}
+
+/// DOCUMENT_STORAGE=s3 stores in S3_BUCKET at S3_ENDPOINT (a MinIO in docker-compose) with
+/// S3_ACCESS_KEY and S3_SECRET_KEY. Anything else writes under DOCUMENT_DIR.
+pub fn storage_from_env() -> Result<Arc<dyn DocumentStorage>, AppError> {
+    match env::var("DOCUMENT_STORAGE").as_deref() {
+        Ok("s3") => {
+            let endpoint = required_env("S3_ENDPOINT")?;
+            let endpoint = Url::parse(&endpoint)
+                .map_err(|err| AppError::GenericError(format!("Invalid S3_ENDPOINT: {}", err)))?;
+            Ok(Arc::new(S3Storage::new(
+                endpoint,
+                required_env("S3_BUCKET")?,
+                env::var("S3_REGION").unwrap_or_else(|_| DEFAULT_S3_REGION.to_owned()),
+                required_env("S3_ACCESS_KEY")?,
+                required_env("S3_SECRET_KEY")?,
+            )))
+        }
+        _ => Ok(Arc::new(LocalStorage {
+            root: env::var("DOCUMENT_DIR")
+                .unwrap_or_else(|_| DEFAULT_DOCUMENT_DIR.to_owned())
+                .into(),
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn signing_key_matches_the_aws_example() {
+        // From the AWS Signature Version 4 documentation
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex::encode(key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[test]
+    fn signs_path_style_requests() {
+        let storage = S3Storage::new(
+            Url::parse("http://localhost:9000").unwrap(),
+            "documents".to_owned(),
+            "us-east-1".to_owned(),
+            "minioadmin".to_owned(),
+            "minioadmin".to_owned(),
+        );
+        let path = storage.object_path("applications/12/pay stub.pdf");
+        assert_eq!(path, "/documents/applications/12/pay%20stub.pdf");
+        assert_eq!(storage.host(), "localhost:9000");
+        let now = Utc.with_ymd_and_hms(2024, 3, 20, 12, 0, 0).unwrap();
+        let authorization =
+            storage.authorization(&Method::PUT, &path, &sha256_hex(b"%PDF-1.7"), now);
+        assert_eq!(
+            authorization,
+            "AWS4-HMAC-SHA256 Credential=minioadmin/20240320/us-east-1/s3/aws4_request, \
+            SignedHeaders=host;x-amz-content-sha256;x-amz-date, \
+            Signature=2e7e5285805ebb7a207448b194bed1638364e47411ca976a44f1906e46ad7b56"
+        );
+    }
+
+    #[tokio::test]
+    async fn local_storage_round_trips_and_rejects_escaping_keys() {
+        let root = env::temp_dir().join(format!("document_storage_{}", std::process::id()));
+        let storage = LocalStorage { root: root.clone() };
+        storage
+            .put("applications/1/a.pdf", b"%PDF-1.7", "application/pdf")
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.get("applications/1/a.pdf").await.unwrap(),
+            b"%PDF-1.7"
+        );
+        storage.delete("applications/1/a.pdf").await.unwrap();
+        assert!(matches!(
+            storage.get("applications/1/a.pdf").await,
+            Err(AppError::NotFound(_))
+        ));
+        for key in ["../secrets", "/etc/passwd", "applications/../../x", ""] {
+            assert!(matches!(
+                storage.put(key, b"", "text/plain").await,
+                Err(AppError::InvalidRequest(_))
+            ));
+        }
+        let _ = fs::remove_dir_all(root);
+    }
+}
@@ -0,0 +1,540 @@
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+
+use crate::{
+    error::AppError,
+    libs::{
+        address::{normalize_address, zip_state},
+        pii::{keyring, Keyring},
+    },
+};
+
+/// How far back the same SSN counts against a new application
+pub(crate) const SSN_LOOKBACK_DAYS: i32 = 90;
+const VELOCITY_WINDOW_HOURS: i64 = 24;
+/// Applications from one IP or address within the window, counting the new one, before it's flagged
+const VELOCITY_LIMIT: i64 = 3;
+const NEAR_DUPLICATE_LOOKBACK_DAYS: i32 = 30;
+/// Addresses this similar but not the same are near duplicates, 1.0 is identical
+const NEAR_DUPLICATE_SIMILARITY: f64 = 0.8;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[repr(i32)]
+pub enum FraudFlagKind {
+    /// Same SSN on a recent application under a different name or date of birth
+    SsnIdentityMismatch = 1,
+    IpVelocity = 2,
+    AddressVelocity = 3,
+    StateZipMismatch = 4,
+    NearDuplicateAddress = 5,
+}
+
+impl std::fmt::Display for FraudFlagKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            FraudFlagKind::SsnIdentityMismatch => "SSN Identity Mismatch",
+            FraudFlagKind::IpVelocity => "IP Velocity",
+            FraudFlagKind::AddressVelocity => "Address Velocity",
+            FraudFlagKind::StateZipMismatch => "State/ZIP Mismatch",
+            FraudFlagKind::NearDuplicateAddress => "Near Duplicate Address",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl TryFrom<i32> for FraudFlagKind {
+    type Error = &'static str;
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(FraudFlagKind::SsnIdentityMismatch),
+            2 => Ok(FraudFlagKind::IpVelocity),
+            3 => Ok(FraudFlagKind::AddressVelocity),
+            4 => Ok(FraudFlagKind::StateZipMismatch),
+            5 => Ok(FraudFlagKind::NearDuplicateAddress),
+            _ => Err("Invalid FraudFlagKind value"),
+        }
+    }
+}
+
+/// One row of fraud_flags
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct FraudFlag {
+    pub flag_id: i32,
+    pub application_id: i32,
+    pub flag_kind: FraudFlagKind,
+    pub detail: String,
+    pub related_application_id: Option<i32>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewFlag {
+    pub flag_kind: FraudFlagKind,
+    pub detail: String,
+    pub related_application_id: Option<i32>,
+}
+
+/// Who a prior application with the same SSN said they were
+#[derive(Debug, Clone, FromRow)]
+pub struct Identity {
+    pub application_id: i32,
+    pub first_name: String,
+    pub last_name: String,
+    pub dob: Option<NaiveDate>,
+}
+
+fn same_name(x: &str, y: &str) -> bool {
+    x.trim().eq_ignore_ascii_case(y.trim())
+}
+
+impl Identity {
+    /// A missing DOB doesn't count as a mismatch, the seed data and co-borrowers may not have one
+    pub fn mismatches(&self, other: &Identity) -> Option<&'static str> {
+        let names = !same_name(&self.first_name, &other.first_name)
+            || !same_name(&self.last_name, &other.last_name);
+        let dobs = matches!((self.dob, other.dob), (Some(x), Some(y)) if x != y);
+        match (names, dobs) {
+            (true, true) => Some("a different name and date of birth"),
+            (true, false) => Some("a different name"),
+            (false, true) => Some("a different date of birth"),
+            (false, false) => None,
+        }
+    }
+}
+
+fn levenshtein(x: &str, y: &str) -> usize {
+    let y: Vec<char> = y.chars().collect();
+    let mut row: Vec<usize> = (0..=y.len()).collect();
+    for (i, x_char) in x.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, y_char) in y.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if x_char == *y_char {
+                diagonal
+            } else {
+                1 + diagonal.min(above).min(row[j])
+            };
+            diagonal = above;
+        }
+    }
+    row[y.len()]
+}
+
+/// 1.0 for the same normalized address, falling towards 0.0 with the edit distance
+pub fn address_similarity(x: &str, y: &str) -> f64 {
+    let (x, y) = (normalize_address(x), normalize_address(y));
+    let longest = x.chars().count().max(y.chars().count());
+    if longest == 0 {
+        return 1.0;
+    }
+    1.0 - levenshtein(&x, &y) as f64 / longest as f64
+}
+
+/// Sealed columns come with their plaintext ones, rows from before encryption only have the latter
+#[derive(Debug, Clone, FromRow)]
+struct IdentityRow {
+    application_id: i32,
+    first_name: String,
+    last_name: String,
+    dob: Option<NaiveDate>,
+    dob_enc: Option<String>,
+}
+
+impl IdentityRow {
+    fn open(&self, keyring: &Keyring, table: &str) -> Result<Identity, AppError> {
+        Ok(Identity {
+            application_id: self.application_id,
+            first_name: self.first_name.clone(),
+            last_name: self.last_name.clone(),
+            dob: keyring.open_date(&format!("{}.dob", table), self.dob_enc.as_deref(), self.dob)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+struct AddressRow {
+    application_id: i32,
+    address_one: Option<String>,
+    address_one_enc: Option<String>,
+    address_two: Option<String>,
+    address_two_enc: Option<String>,
+    /// ssn_hmac, or the legacy ssn_nacl digest
+    ssn_key: Option<String>,
+    created_at: Option<DateTime<Utc>>,
+}
+
+impl AddressRow {
+    fn address(&self, keyring: &Keyring) -> Result<String, AppError> {
+        let address_one = keyring.open_or(
+            "applications.address_one",
+            self.address_one_enc.as_deref(),
+            self.address_one.clone(),
+        )?;
+        let address_two = keyring.open_or(
+            "applications.address_two",
+            self.address_two_enc.as_deref(),
+            self.address_two.clone(),
+        )?;
+        Ok(full_address(
+            address_one.as_deref().unwrap_or_default(),
+            address_two.as_deref(),
+        ))
+    }
+
+    fn neighbour(&self, keyring: &Keyring) -> Result<Neighbour, AppError> {
+        Ok(Neighbour {
+            application_id: self.application_id,
+            address: self.address(keyring)?,
+            ssn_key: self.ssn_key.clone(),
+            created_at: self.created_at,
+        })
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+struct ScreenedApplication {
+    #[sqlx(flatten)]
+    identity: IdentityRow,
+    #[sqlx(flatten)]
+    address: AddressRow,
+    state: Option<String>,
+    zip: Option<String>,
+    ip_address: Option<String>,
+}
+
+fn full_address(address_one: &str, address_two: Option<&str>) -> String {
+    match address_two {
+        Some(address_two) => format!("{} {}", address_one, address_two),
+        None => address_one.to_owned(),
+    }
+}
+
+/// A recent application in the same ZIP
+#[derive(Debug, Clone)]
+pub struct Neighbour {
+    pub application_id: i32,
+    pub address: String,
+    /// None for legacy rows whose digest has been cleared, which count as a different applicant
+    pub ssn_key: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+pub fn state_zip_flag(state: &str, zip: &str) -> Option<NewFlag> {
+    let zip_state = zip_state(zip)?;
+    let state = state.trim().to_uppercase();
+    (zip_state != state).then(|| NewFlag {
+        flag_kind: FraudFlagKind::StateZipMismatch,
+        detail: format!("ZIP {} is in {}, not {}", zip.trim(), zip_state, state),
+        related_application_id: None,
+    })
+}
+
+/// Exact repeats of the address within the velocity window, and different applicants at
+/// addresses that are almost but not quite the same.
+pub fn address_flags(
+    address: &str,
+    ssn_key: Option<&str>,
+    neighbours: &[Neighbour],
+    now: DateTime<Utc>,
+) -> Vec<NewFlag> {
+    let normalized = normalize_address(address);
+    let window_start = now - Duration::hours(VELOCITY_WINDOW_HOURS);
+    let mut flags = Vec::new();
+    let repeats = neighbours
+        .iter()
+        .filter(|neighbour| {
+            neighbour.created_at.is_some_and(|at| at > window_start)
+                && normalize_address(&neighbour.address) == normalized
+        })
+        .count() as i64;
+    if repeats + 1 >= VELOCITY_LIMIT {
+        flags.push(NewFlag {
+            flag_kind: FraudFlagKind::AddressVelocity,
+            detail: format!(
+                "{} applications from this address in the last {} hours",
+                repeats + 1,
+                VELOCITY_WINDOW_HOURS
+            ),
+            related_application_id: None,
+        });
+    }
+    for neighbour in neighbours
+        .iter()
+        .filter(|n| ssn_key.is_none() || n.ssn_key.as_deref() != ssn_key)
+    {
+        let similarity = address_similarity(address, &neighbour.address);
+        if similarity >= NEAR_DUPLICATE_SIMILARITY
+            && normalize_address(&neighbour.address) != normalized
+        {
+            flags.push(NewFlag {
+                flag_kind: FraudFlagKind::NearDuplicateAddress,
+                detail: format!(
+                    "Address is {:.0}% similar to application {} from a different applicant",
+                    similarity * 100.0,
+                    neighbour.application_id
+                ),
+                related_application_id: Some(neighbour.application_id),
+            });
+        }
+    }
+    flags
+}
+
+/// Lookup keys only match under the same pepper, so after a pepper change this only sees
+/// applications written since.
+async fn ssn_flags(
+    pool: &PgPool,
+    keyring: &Keyring,
+    app: &ScreenedApplication,
+) -> Result<Vec<NewFlag>, AppError> {
+    let Some(ssn_key) = &app.address.ssn_key else {
+        return Ok(Vec::new());
+    };
+    let application_id = app.identity.application_id;
+    let mut others = Vec::new();
+    for table in ["applications", "co_borrowers"] {
+        let rows = sqlx::query_as::<_, IdentityRow>(&format!(
+            "SELECT application_id, first_name, last_name, dob, dob_enc FROM {table}
+            WHERE COALESCE(ssn_hmac, ssn_nacl) = $1 AND application_id <> $2
+            AND created_at > NOW() - make_interval(days => $3)
+            ORDER BY application_id"
+        ))
+        .bind(ssn_key)
+        .bind(application_id)
+        .bind(SSN_LOOKBACK_DAYS)
+        .fetch_all(pool)
+        .await?;
+        for row in rows {
+            others.push(row.open(keyring, table)?);
+        }
+    }
+    let identity = app.identity.open(keyring, "applications")?;
+    Ok(others
+        .iter()
+        .filter_map(|other| {
+            identity.mismatches(other).map(|mismatch| NewFlag {
+                flag_kind: FraudFlagKind::SsnIdentityMismatch,
+                detail: format!(
+                    "SSN was used on application {} with {}",
+                    other.application_id, mismatch
+                ),
+                related_application_id: Some(other.application_id),
+            })
+        })
+        .collect())
+}
+
+async fn ip_flags(pool: &PgPool, app: &ScreenedApplication) -> Result<Vec<NewFlag>, AppError> {
+    let Some(ip_address) = &app.ip_address else {
+        return Ok(Vec::new());
+    };
+    let others: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM applications
+        WHERE ip_address = $1 AND application_id <> $2 AND created_at > NOW() - make_interval(hours => $3)",
+    )
+    .bind(ip_address)
+    .bind(app.identity.application_id)
+    .bind(VELOCITY_WINDOW_HOURS as i32)
+    .fetch_one(pool)
+    .await?;
+    Ok((others + 1 >= VELOCITY_LIMIT)
+        .then(|| NewFlag {
+            flag_kind: FraudFlagKind::IpVelocity,
+            detail: format!(
+                "{} applications from {} in the last {} hours",
+                others + 1,
+                ip_address,
+                VELOCITY_WINDOW_HOURS
+            ),
+            related_application_id: None,
+        })
+        .into_iter()
+        .collect())
+}
+
+/// Runs every check against the application and replaces its flags with what was found, so
+/// screening again after a fix clears the old flags.
+pub async fn screen_application(
+    pool: &PgPool,
+    application_id: i32,
+) -> Result<Vec<FraudFlag>, AppError> {
+    let keyring = keyring()?;
+    let app = sqlx::query_as::<_, ScreenedApplication>(
+        "SELECT application_id, first_name, last_name, dob, dob_enc, address_one, address_one_enc, address_two,
+            address_two_enc, COALESCE(ssn_hmac, ssn_nacl) AS ssn_key, created_at, state, zip, ip_address
+        FROM applications WHERE application_id = $1",
+    )
+    .bind(application_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Application {} not found", application_id)))?;
+
+    let mut flags = ssn_flags(pool, keyring, &app).await?;
+    flags.extend(ip_flags(pool, &app).await?);
+    if let (Some(state), Some(zip)) = (&app.state, &app.zip) {
+        flags.extend(state_zip_flag(state, zip));
+    }
+    if let Some(zip) = &app.zip {
+        let neighbours = sqlx::query_as::<_, AddressRow>(
+            "SELECT application_id, address_one, address_one_enc, address_two, address_two_enc,
+                COALESCE(ssn_hmac, ssn_nacl) AS ssn_key, created_at FROM applications
+            WHERE zip = $1 AND application_id <> $2 AND created_at > NOW() - make_interval(days => $3)",
+        )
+        .bind(zip)
+        .bind(application_id)
+        .bind(NEAR_DUPLICATE_LOOKBACK_DAYS)
+        .fetch_all(pool)
+        .await?
+        .iter()
+        .map(|row| row.neighbour(keyring))
+        .collect::<Result<Vec<_>, _>>()?;
+        flags.extend(address_flags(
+            &app.address.address(keyring)?,
+            app.address.ssn_key.as_deref(),
+            &neighbours,
+            Utc::now(),
+        ));
+    }
+
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM fraud_flags WHERE application_id = $1")
+        .bind(application_id)
+        .execute(&mut *tx)
+        .await?;
+    let mut stored = Vec::with_capacity(flags.len());
+    for flag in flags {
+        stored.push(
+            sqlx::query_as::<_, FraudFlag>(
+                "INSERT INTO fraud_flags (application_id, flag_kind, detail, related_application_id)
+                VALUES ($1, $2, $3, $4)
+                RETURNING flag_id, application_id, flag_kind, detail, related_application_id, created_at",
+            )
+            .bind(application_id)
+            .bind(flag.flag_kind)
+            .bind(&flag.detail)
+            .bind(flag.related_application_id)
+            .fetch_one(&mut *tx)
+            .await?,
+        );
+    }
+    tx.commit().await?;
+    if !stored.is_empty() {
+        tracing::warn!(
+            application_id,
+            flags = stored.len(),
+            "Application flagged by fraud screening"
+        );
+    }
+    Ok(stored)
+}
+
+pub async fn application_flags(
+    pool: &PgPool,
+    application_id: i32,
+) -> Result<Vec<FraudFlag>, AppError> {
+    sqlx::query_as::<_, FraudFlag>(
+        "SELECT flag_id, application_id, flag_kind, detail, related_application_id, created_at
+        FROM fraud_flags WHERE application_id = $1 ORDER BY flag_id",
+    )
+    .bind(application_id)
+    .fetch_all(pool)
+    .await
+    .map_err(AppError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(first_name: &str, dob: Option<NaiveDate>) -> Identity {
+        Identity {
+            application_id: 1,
+            first_name: first_name.to_owned(),
+            last_name: "Jones".to_owned(),
+            dob,
+        }
+    }
+
+    fn neighbour(application_id: i32, address: &str, ssn_key: &str, hours_ago: i64) -> Neighbour {
+        Neighbour {
+            application_id,
+            address: address.to_owned(),
+            ssn_key: Some(ssn_key.to_owned()),
+            created_at: Some(Utc::now() - Duration::hours(hours_ago)),
+        }
+    }
+
+    #[test]
+    fn identity_mismatches_ignore_case_and_missing_dobs() {
+        let dob = NaiveDate::from_ymd_opt(1987, 1, 1);
+        let tim = identity("Tim", dob);
+        assert_eq!(tim.mismatches(&identity(" tim ", None)), None);
+        assert_eq!(
+            tim.mismatches(&identity("Tom", dob)),
+            Some("a different name")
+        );
+        assert_eq!(
+            tim.mismatches(&identity("Tom", NaiveDate::from_ymd_opt(1990, 5, 5))),
+            Some("a different name and date of birth")
+        );
+    }
+
+    #[test]
+    fn addresses_normalize_and_compare() {
+        assert_eq!(
+            normalize_address("4483 South 87th Street, Apt. 2"),
+            normalize_address("4483 S 87th St  apt 2")
+        );
+        assert_eq!(address_similarity("7724 Pine Cir", "7724 Pine Circle"), 1.0);
+        assert!(address_similarity("7724 Pine Cir", "7742 Pine Cir") >= NEAR_DUPLICATE_SIMILARITY);
+        assert!(address_similarity("7724 Pine Cir", "12 Elm St") < NEAR_DUPLICATE_SIMILARITY);
+    }
+
+    #[test]
+    fn zips_map_to_states() {
+        assert_eq!(zip_state("68124"), Some("NE"));
+        assert_eq!(zip_state("20110"), Some("VA"));
+        assert_eq!(zip_state("73301"), Some("TX"));
+        assert_eq!(zip_state("09001"), None);
+        assert_eq!(zip_state("6812"), None);
+        assert!(state_zip_flag("ne", "68124").is_none());
+        let flag = state_zip_flag("MN", "68124").unwrap();
+        assert_eq!(flag.detail, "ZIP 68124 is in NE, not MN");
+    }
+
+    #[test]
+    fn address_velocity_and_near_duplicates() {
+        let neighbours = [
+            neighbour(1, "7724 Pine Circle", "a", 2),
+            neighbour(2, "7724 pine cir.", "b", 5),
+            // Outside the velocity window
+            neighbour(3, "7724 Pine Cir", "c", 48),
+            neighbour(4, "7742 Pine Cir", "d", 72),
+            // Same applicant again
+            neighbour(5, "7742 Pine Cir", "me", 1),
+        ];
+        let flags = address_flags("7724 Pine Cir", Some("me"), &neighbours, Utc::now());
+        let kinds: Vec<FraudFlagKind> = flags.iter().map(|flag| flag.flag_kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                FraudFlagKind::AddressVelocity,
+                FraudFlagKind::NearDuplicateAddress
+            ]
+        );
+        assert_eq!(flags[1].related_application_id, Some(4));
+
+        let quiet = address_flags("7724 Pine Cir", Some("me"), &neighbours[2..3], Utc::now());
+        assert!(quiet.is_empty());
+    }
+
+    #[test]
+    fn codes_round_trip() {
+        for code in 1..=5 {
+            assert_eq!(FraudFlagKind::try_from(code).unwrap() as i32, code);
+        }
+        assert!(FraudFlagKind::try_from(6).is_err());
+    }
+}
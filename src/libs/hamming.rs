@@ -1,13 +1,17 @@
 use struct_iterable::Iterable;
 
+use super::record_diff::{diff_fields, DiffOptions};
+
+/// Number of fields that differ between `x` and `y`, matched by field name.
+/// None if the records don't have the same number of fields.
 pub fn hamming_distance(x: impl Iterable, y: impl Iterable) -> Option<usize> {
-    let x_ct = x.iter().count();
-    let y_ct = y.iter().count();
+    let x_fields = x.iter().collect::<Vec<_>>();
+    let y_fields = y.iter().collect::<Vec<_>>();
 
-    if x_ct != y_ct {
+    if x_fields.len() != y_fields.len() {
         None
     } else {
-        Some(2)
+        Some(diff_fields(x_fields, y_fields, &DiffOptions::new()).distance())
     }
 }
 
@@ -50,6 +54,21 @@ mod tests {
         let res = hamming_distance(x_eg, y_long_eg);
         assert_eq!(res, None);
     }
+    #[test]
+    fn counts_differing_fields() {
+        let x_eg: Record = Record {
+            name: "Name".to_owned(),
+            count: 44,
+            quote: "What".to_owned(),
+        };
+        let y_eg: Record = Record {
+            name: "Jim".to_owned(),
+            count: 44,
+            quote: "Huh".to_owned(),
+        };
+        assert_eq!(hamming_distance(x_eg.clone(), x_eg.clone()), Some(0));
+        assert_eq!(hamming_distance(x_eg, y_eg), Some(2));
+    }
     // #[test]
     // fn test_date_convert() {
     //     let converted_date = convert_date(DATE_STR).unwrap();
@@ -0,0 +1,587 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool, Postgres, Transaction};
+use validator::{ValidationError, ValidationErrors};
+
+use crate::{
+    error::AppError,
+    libs::pii::{keyring, ssn_last4, Keyring, Redacted},
+    models::{
+        credit_file::{CreditFile, HomeOwnership, IncomeVerification},
+        loan::ApplicationType,
+    },
+};
+
+/// Credit file columns an application fills in, the rest wait on a bureau pull
+pub const APPLICATION_CREDIT_COLUMNS: [&str; 3] = ["annual_income", "homeownership", "emp_length"];
+
+/// The monthly debt and co-borrower part of the application form. Everything comes in as an
+/// optional string: the co-borrower section is hidden, and blank, unless the joint box is ticked.
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct HouseholdForm {
+    #[serde(default)]
+    pub monthly_debt: Option<String>,
+    #[serde(default)]
+    pub joint: Option<String>,
+    #[serde(default)]
+    pub co_first_name: Option<String>,
+    #[serde(default)]
+    pub co_last_name: Option<String>,
+    #[serde(default)]
+    pub co_ssn: Option<String>,
+    #[serde(default)]
+    pub co_dob: Option<String>,
+    #[serde(default)]
+    pub co_annual_income: Option<String>,
+    #[serde(default)]
+    pub co_employment_status: Option<String>,
+    #[serde(default)]
+    pub co_emp_length: Option<String>,
+    #[serde(default)]
+    pub co_monthly_debt: Option<String>,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct CoBorrowerInput {
+    pub first_name: String,
+    pub last_name: String,
+    /// Digits only
+    pub ssn: String,
+    pub dob: NaiveDate,
+    pub annual_income: i32,
+    pub employment_status: i32,
+    pub emp_length: i32,
+    pub monthly_debt: Option<i32>,
+}
+
+/// The applicant's stated monthly debt and, on a joint application, the co-borrower.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct HouseholdInput {
+    pub monthly_debt: Option<i32>,
+    pub co_borrower: Option<CoBorrowerInput>,
+}
+
+impl std::fmt::Debug for HouseholdForm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HouseholdForm")
+            .field("monthly_debt", &self.monthly_debt)
+            .field("joint", &self.joint)
+            .field("co_first_name", &Redacted)
+            .field("co_last_name", &Redacted)
+            .field("co_ssn", &Redacted)
+            .field("co_dob", &Redacted)
+            .field("co_annual_income", &self.co_annual_income)
+            .field("co_employment_status", &self.co_employment_status)
+            .field("co_emp_length", &self.co_emp_length)
+            .field("co_monthly_debt", &self.co_monthly_debt)
+            .finish()
+    }
+}
+
+impl std::fmt::Debug for CoBorrowerInput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CoBorrowerInput")
+            .field("first_name", &Redacted)
+            .field("last_name", &Redacted)
+            .field("ssn", &Redacted)
+            .field("dob", &Redacted)
+            .field("annual_income", &self.annual_income)
+            .field("employment_status", &self.employment_status)
+            .field("emp_length", &self.emp_length)
+            .field("monthly_debt", &self.monthly_debt)
+            .finish()
+    }
+}
+
+/// A co_borrowers row, with the date of birth opened. The SSN lookup key never leaves the
+/// database.
+#[derive(Debug, Clone, Serialize)]
+pub struct CoBorrower {
+    pub co_borrower_id: i32,
+    pub application_id: i32,
+    pub first_name: String,
+    pub last_name: String,
+    pub dob: Option<NaiveDate>,
+    pub annual_income: i32,
+    pub employment_status: i32,
+    pub emp_length: i32,
+    pub monthly_debt: Option<i32>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// What underwriting and pricing need to know about the co-borrower
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct CoBorrowerIncome {
+    pub annual_income: i32,
+    pub emp_length: i32,
+    #[serde(default)]
+    pub monthly_debt: Option<i32>,
+}
+
+impl From<&CoBorrower> for CoBorrowerIncome {
+    fn from(co_borrower: &CoBorrower) -> Self {
+        CoBorrowerIncome {
+            annual_income: co_borrower.annual_income,
+            emp_length: co_borrower.emp_length,
+            monthly_debt: co_borrower.monthly_debt,
+        }
+    }
+}
+
+impl From<&CoBorrowerInput> for CoBorrowerIncome {
+    fn from(co_borrower: &CoBorrowerInput) -> Self {
+        CoBorrowerIncome {
+            annual_income: co_borrower.annual_income,
+            emp_length: co_borrower.emp_length,
+            monthly_debt: co_borrower.monthly_debt,
+        }
+    }
+}
+
+fn field_error(
+    errors: &mut ValidationErrors,
+    field: &'static str,
+    code: &'static str,
+    message: &'static str,
+) {
+    let mut error = ValidationError::new(code);
+    error.message = Some(message.into());
+    errors.add(field, error);
+}
+
+fn filled(value: &Option<String>) -> Option<&str> {
+    value
+        .as_deref()
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+/// Blank is None. Anything else has to be a whole number of dollars.
+fn parse_amount(
+    value: &Option<String>,
+    errors: &mut ValidationErrors,
+    field: &'static str,
+    message: &'static str,
+) -> Option<i32> {
+    let value = filled(value)?;
+    match value.parse::<i32>() {
+        Ok(amount) if amount >= 0 => Some(amount),
+        _ => {
+            field_error(errors, field, "amount", message);
+            None
+        }
+    }
+}
+
+fn required<'a>(
+    value: &'a Option<String>,
+    errors: &mut ValidationErrors,
+    field: &'static str,
+    message: &'static str,
+) -> Option<&'a str> {
+    let value = filled(value);
+    if value.is_none() {
+        field_error(errors, field, "required", message);
+    }
+    value
+}
+
+impl HouseholdForm {
+    pub fn is_joint(&self) -> bool {
+        self.joint.as_deref() == Some("true")
+    }
+
+    /// Checks the section against the applicant's own SSN, so the applicant can't also be
+    /// their co-borrower. Errors are keyed by form field like validator's own.
+    pub fn parse(&self, applicant_ssn: &str) -> Result<HouseholdInput, ValidationErrors> {
+        let mut errors = ValidationErrors::new();
+        let monthly_debt = parse_amount(
+            &self.monthly_debt,
+            &mut errors,
+            "monthly_debt",
+            "Monthly debt payments must be a whole number of dollars",
+        );
+        let co_borrower = if self.is_joint() {
+            self.parse_co_borrower(applicant_ssn, &mut errors)
+        } else {
+            None
+        };
+        if errors.is_empty() {
+            Ok(HouseholdInput {
+                monthly_debt,
+                co_borrower,
+            })
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn parse_co_borrower(
+        &self,
+        applicant_ssn: &str,
+        errors: &mut ValidationErrors,
+    ) -> Option<CoBorrowerInput> {
+        let first_name = required(
+            &self.co_first_name,
+            errors,
+            "co_first_name",
+            "Co-borrower first name is required",
+        );
+        let last_name = required(
+            &self.co_last_name,
+            errors,
+            "co_last_name",
+            "Co-borrower last name is required",
+        );
+        let ssn = required(
+            &self.co_ssn,
+            errors,
+            "co_ssn",
+            "Co-borrower SSN is required",
+        )
+        .map(|ssn| ssn.replace('-', ""));
+        let ssn = match ssn {
+            Some(ssn) if ssn.len() != 9 || !ssn.chars().all(|c| c.is_ascii_digit()) => {
+                field_error(
+                    errors,
+                    "co_ssn",
+                    "ssn",
+                    "Co-borrower SSN must be in the form 000-00-0000",
+                );
+                None
+            }
+            Some(ssn) if ssn == applicant_ssn.replace('-', "") => {
+                field_error(
+                    errors,
+                    "co_ssn",
+                    "same_person",
+                    "The co-borrower can't be the applicant",
+                );
+                None
+            }
+            ssn => ssn,
+        };
+        let dob = required(
+            &self.co_dob,
+            errors,
+            "co_dob",
+            "Co-borrower date of birth is required",
+        )
+        .and_then(|dob| match NaiveDate::parse_from_str(dob, "%Y-%m-%d") {
+            Ok(dob) => Some(dob),
+            Err(_) => {
+                field_error(
+                    errors,
+                    "co_dob",
+                    "date",
+                    "Co-borrower date of birth is not a valid date",
+                );
+                None
+            }
+        });
+        let annual_income = required(
+            &self.co_annual_income,
+            errors,
+            "co_annual_income",
+            "Co-borrower annual income is required",
+        )
+        .and(parse_amount(
+            &self.co_annual_income,
+            errors,
+            "co_annual_income",
+            "Co-borrower annual income must be a whole number of dollars",
+        ));
+        let employment_status =
+            filled(&self.co_employment_status).and_then(|status| status.parse::<i32>().ok());
+        if employment_status.is_none() {
+            field_error(
+                errors,
+                "co_employment_status",
+                "required",
+                "Co-borrower employment status is required",
+            );
+        }
+        let emp_length = filled(&self.co_emp_length)
+            .and_then(|years| years.parse::<i32>().ok())
+            .filter(|years| (0..=40).contains(years));
+        if emp_length.is_none() {
+            field_error(
+                errors,
+                "co_emp_length",
+                "range",
+                "Co-borrower years employed must be between 0 and 40",
+            );
+        }
+        let monthly_debt = parse_amount(
+            &self.co_monthly_debt,
+            errors,
+            "co_monthly_debt",
+            "Co-borrower monthly debt payments must be a whole number of dollars",
+        );
+
+        Some(CoBorrowerInput {
+            first_name: first_name?.to_owned(),
+            last_name: last_name?.to_owned(),
+            ssn: ssn?,
+            dob: dob?,
+            annual_income: annual_income?,
+            employment_status: employment_status?,
+            emp_length: emp_length?,
+            monthly_debt,
+        })
+    }
+}
+
+/// Yearly debt payments as a percentage of income, the same scale as the credit file's
+/// debt_to_income. None when the debt is unknown or there's no income to divide by.
+pub fn debt_to_income(monthly_debt: Option<i32>, annual_income: i32) -> Option<f64> {
+    let monthly_debt = monthly_debt?;
+    (annual_income > 0)
+        .then(|| (monthly_debt as f64 * 12.0 / annual_income as f64 * 10000.0).round() / 100.0)
+}
+
+/// Whoever is on the hook for the loan, taken together.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Household {
+    pub annual_income: i32,
+    pub monthly_debt: Option<i32>,
+    pub co_borrower: Option<CoBorrowerIncome>,
+}
+
+impl Household {
+    pub fn application_type(&self) -> ApplicationType {
+        match self.co_borrower {
+            Some(_) => ApplicationType::Joint,
+            None => ApplicationType::Individual,
+        }
+    }
+
+    /// Income the loan is underwritten and priced on, both incomes on a joint application
+    pub fn qualifying_income(&self) -> i32 {
+        self.co_borrower.map_or(self.annual_income, |co_borrower| {
+            self.annual_income.saturating_add(co_borrower.annual_income)
+        })
+    }
+
+    /// None if either party left their debts blank, a partial total would understate the DTI
+    pub fn qualifying_monthly_debt(&self) -> Option<i32> {
+        match self.co_borrower {
+            Some(co_borrower) => Some(self.monthly_debt? + co_borrower.monthly_debt?),
+            None => self.monthly_debt,
+        }
+    }
+
+    pub fn debt_to_income(&self) -> Option<f64> {
+        debt_to_income(self.monthly_debt, self.annual_income)
+    }
+
+    pub fn qualifying_debt_to_income(&self) -> Option<f64> {
+        debt_to_income(self.qualifying_monthly_debt(), self.qualifying_income())
+    }
+
+    /// The applicant's credit file for scoring, with the columns the application actually filled
+    /// in. A joint household fills the joint income and DTI columns, so pricing sees both parties.
+    pub fn credit_file(
+        &self,
+        state: &str,
+        homeownership: HomeOwnership,
+        emp_length: i32,
+    ) -> (CreditFile, Vec<&'static str>) {
+        let mut file = CreditFile::applicant(state, self.annual_income, homeownership, emp_length);
+        let mut known = APPLICATION_CREDIT_COLUMNS.to_vec();
+        if let Some(dti) = self.debt_to_income() {
+            file.debt_to_income = Some(dti as f32);
+            known.push("debt_to_income");
+        }
+        if self.co_borrower.is_some() {
+            file.annual_income_joint = Some(self.qualifying_income());
+            file.verification_income_joint = IncomeVerification::NotVerified;
+            known.extend(["annual_income_joint", "verification_income_joint"]);
+            if let Some(dti) = self.qualifying_debt_to_income() {
+                file.debt_to_income_joint = Some(dti as f32);
+                known.push("debt_to_income_joint");
+            }
+        }
+        (file, known)
+    }
+}
+
+impl HouseholdInput {
+    pub fn household(&self, annual_income: i32) -> Household {
+        Household {
+            annual_income,
+            monthly_debt: self.monthly_debt,
+            co_borrower: self.co_borrower.as_ref().map(CoBorrowerIncome::from),
+        }
+    }
+}
+
+/// Inserted in the same transaction as the application, a joint application is never
+/// without its co-borrower.
+pub async fn insert_co_borrower(
+    tx: &mut Transaction<'_, Postgres>,
+    keyring: &Keyring,
+    application_id: i32,
+    co_borrower: &CoBorrowerInput,
+) -> Result<i32, AppError> {
+    sqlx::query_scalar(
+        "INSERT INTO co_borrowers (application_id, first_name, last_name, ssn_hmac, ssn_last4_enc, dob_enc, annual_income, employment_status, emp_length, monthly_debt)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) RETURNING co_borrower_id",
+    )
+    .bind(application_id)
+    .bind(&co_borrower.first_name)
+    .bind(&co_borrower.last_name)
+    .bind(keyring.lookup_key(&co_borrower.ssn))
+    .bind(keyring.seal("co_borrowers.ssn_last4", &ssn_last4(&co_borrower.ssn))?)
+    .bind(keyring.seal("co_borrowers.dob", &co_borrower.dob.to_string())?)
+    .bind(co_borrower.annual_income)
+    .bind(co_borrower.employment_status)
+    .bind(co_borrower.emp_length)
+    .bind(co_borrower.monthly_debt)
+    .fetch_one(&mut **tx)
+    .await
+    .map_err(AppError::from)
+}
+
+#[derive(Debug, FromRow)]
+struct CoBorrowerRow {
+    co_borrower_id: i32,
+    application_id: i32,
+    first_name: String,
+    last_name: String,
+    dob: Option<NaiveDate>,
+    dob_enc: Option<String>,
+    annual_income: i32,
+    employment_status: i32,
+    emp_length: i32,
+    monthly_debt: Option<i32>,
+    created_at: DateTime<Utc>,
+}
+
+pub async fn co_borrower(
+    pool: &PgPool,
+    application_id: i32,
+) -> Result<Option<CoBorrower>, AppError> {
+    let Some(row) = sqlx::query_as::<_, CoBorrowerRow>(
+        "SELECT co_borrower_id, application_id, first_name, last_name, dob, dob_enc, annual_income, employment_status, emp_length, monthly_debt, created_at
+        FROM co_borrowers WHERE application_id = $1",
+    )
+    .bind(application_id)
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+    Ok(Some(CoBorrower {
+        co_borrower_id: row.co_borrower_id,
+        application_id: row.application_id,
+        first_name: row.first_name,
+        last_name: row.last_name,
+        dob: keyring()?.open_date("co_borrowers.dob", row.dob_enc.as_deref(), row.dob)?,
+        annual_income: row.annual_income,
+        employment_status: row.employment_status,
+        emp_length: row.emp_length,
+        monthly_debt: row.monthly_debt,
+        created_at: row.created_at,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn joint_form() -> HouseholdForm {
+        let some = |s: &str| Some(s.to_owned());
+        HouseholdForm {
+            monthly_debt: some("1000"),
+            joint: some("true"),
+            co_first_name: some("Pat"),
+            co_last_name: some("Jones"),
+            co_ssn: some("123-45-6789"),
+            co_dob: some("1988-04-12"),
+            co_annual_income: some("40000"),
+            co_employment_status: some("1"),
+            co_emp_length: some("6"),
+            co_monthly_debt: some("500"),
+        }
+    }
+
+    #[test]
+    fn individual_ignores_the_hidden_section() {
+        let form = HouseholdForm {
+            monthly_debt: Some(" ".to_owned()),
+            co_first_name: Some(String::new()),
+            ..HouseholdForm::default()
+        };
+        assert_eq!(form.parse("000-00-0000"), Ok(HouseholdInput::default()));
+    }
+
+    #[test]
+    fn joint_section_is_validated() {
+        let input = joint_form().parse("000-00-0000").unwrap();
+        let co_borrower = input.co_borrower.unwrap();
+        assert_eq!(co_borrower.ssn, "123456789");
+        assert_eq!(co_borrower.monthly_debt, Some(500));
+
+        let form = HouseholdForm {
+            co_last_name: None,
+            co_dob: Some("04/12/1988".to_owned()),
+            co_annual_income: Some("lots".to_owned()),
+            ..joint_form()
+        };
+        let errors = form.parse("000-00-0000").unwrap_err();
+        let mut fields: Vec<&str> = errors.field_errors().into_keys().collect();
+        fields.sort();
+        assert_eq!(fields, ["co_annual_income", "co_dob", "co_last_name"]);
+
+        let errors = joint_form().parse("123456789").unwrap_err();
+        assert!(errors.field_errors().contains_key("co_ssn"));
+    }
+
+    #[test]
+    fn joint_household_combines_income_and_debt() {
+        let input = joint_form().parse("000-00-0000").unwrap();
+        let household = input.household(60000);
+        assert_eq!(household.application_type(), ApplicationType::Joint);
+        assert_eq!(household.qualifying_income(), 100000);
+        assert_eq!(household.qualifying_monthly_debt(), Some(1500));
+        assert_eq!(household.debt_to_income(), Some(20.0));
+        assert_eq!(household.qualifying_debt_to_income(), Some(18.0));
+
+        // A blank on either side leaves the combined DTI unknown
+        let household = Household {
+            monthly_debt: None,
+            ..household
+        };
+        assert_eq!(household.qualifying_monthly_debt(), None);
+        assert_eq!(household.qualifying_debt_to_income(), None);
+    }
+
+    #[test]
+    fn joint_credit_file_fills_the_joint_columns() {
+        let individual = Household {
+            annual_income: 60000,
+            monthly_debt: None,
+            co_borrower: None,
+        };
+        let (file, known) = individual.credit_file("NE", HomeOwnership::Rent, 4);
+        assert_eq!(file.annual_income_joint, None);
+        assert_eq!(known, APPLICATION_CREDIT_COLUMNS);
+
+        let joint = Household {
+            monthly_debt: Some(1000),
+            co_borrower: Some(CoBorrowerIncome {
+                annual_income: 40000,
+                emp_length: 6,
+                monthly_debt: Some(500),
+            }),
+            ..individual
+        };
+        let (file, known) = joint.credit_file("NE", HomeOwnership::Rent, 4);
+        assert_eq!(file.annual_income, 60000);
+        assert_eq!(file.annual_income_joint, Some(100000));
+        assert_eq!(file.debt_to_income_joint, Some(18.0));
+        assert!(known.contains(&"annual_income_joint"));
+        assert!(known.contains(&"debt_to_income_joint"));
+    }
+}
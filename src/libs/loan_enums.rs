@@ -72,6 +72,35 @@ impl std::str::FromStr for LoanStatus {
     }
 }
 
+impl LoanStatus {
+    /// Outcomes counted as bad when training the credit scorer
+    pub fn is_adverse(&self) -> bool {
+        matches!(
+            self,
+            LoanStatus::Late1to15
+                | LoanStatus::Late16to30
+                | LoanStatus::Late31to120
+                | LoanStatus::ChargedOff
+        )
+    }
+}
+
+impl TryFrom<i32> for LoanStatus {
+    type Error = &'static str;
+    fn try_from(code: i32) -> Result<Self, Self::Error> {
+        match code {
+            1 => Ok(LoanStatus::Current),
+            2 => Ok(LoanStatus::FullyPaid),
+            3 => Ok(LoanStatus::InGracePeriod),
+            4 => Ok(LoanStatus::Late1to15),
+            5 => Ok(LoanStatus::Late16to30),
+            6 => Ok(LoanStatus::Late31to120),
+            7 => Ok(LoanStatus::ChargedOff),
+            _ => Err("Invalid LoanStatus code"),
+        }
+    }
+}
+
 impl std::str::FromStr for DisbursementMethod {
     type Err = &'static str;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -87,8 +116,9 @@ impl std::str::FromStr for ApplicationType {
     type Err = &'static str;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
-            "Individual" => Ok(ApplicationType::Individual),
-            "Joint" => Ok(ApplicationType::Joint),
+            // Loan tapes use the lowercase form
+            "Individual" | "individual" => Ok(ApplicationType::Individual),
+            "Joint" | "joint" => Ok(ApplicationType::Joint),
             _ => Err("Invalid ApplicationType value"),
         }
     }
@@ -97,11 +127,129 @@ impl std::str::FromStr for ApplicationType {
 impl std::str::FromStr for InitialListingStatus {
     type Err = &'static str;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        dbg!(s);
         match s {
             "whole" => Ok(InitialListingStatus::Whole),
             "fractional" => Ok(InitialListingStatus::Fractional),
-            e => Err("Invalid InitialListingStatus value {e}"),
+            _ => Err("Invalid InitialListingStatus value"),
+        }
+    }
+}
+
+// Display writes the same strings FromStr reads, so a loan tape round trips.
+
+impl std::fmt::Display for LoanPurpose {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            LoanPurpose::Moving => "moving",
+            LoanPurpose::Medical => "medical",
+            LoanPurpose::DebtConsolidation => "debt_consolidation",
+            LoanPurpose::CreditCard => "credit_card",
+            LoanPurpose::HomeImprovement => "home_improvement",
+            LoanPurpose::Car => "car",
+            LoanPurpose::House => "house",
+            LoanPurpose::MajorPurchase => "major_purchase",
+            LoanPurpose::Vacation => "vacation",
+            LoanPurpose::SmallBusiness => "small_business",
+            LoanPurpose::Other => "other",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl std::fmt::Display for LoanStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            LoanStatus::Current => "Current",
+            LoanStatus::FullyPaid => "Fully Paid",
+            LoanStatus::InGracePeriod => "In Grace Period",
+            LoanStatus::Late1to15 => "Late (1-15 days)",
+            LoanStatus::Late16to30 => "Late (16-30 days)",
+            LoanStatus::Late31to120 => "Late (31-120 days)",
+            LoanStatus::ChargedOff => "Charged Off",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl std::fmt::Display for DisbursementMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            DisbursementMethod::Cash => "Cash",
+            DisbursementMethod::DirectPay => "DirectPay",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl std::fmt::Display for ApplicationType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            ApplicationType::Individual => "individual",
+            ApplicationType::Joint => "joint",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl std::fmt::Display for InitialListingStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            InitialListingStatus::Whole => "whole",
+            InitialListingStatus::Fractional => "fractional",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl TryFrom<i32> for LoanPurpose {
+    type Error = &'static str;
+    fn try_from(code: i32) -> Result<Self, Self::Error> {
+        match code {
+            1 => Ok(LoanPurpose::Moving),
+            2 => Ok(LoanPurpose::Medical),
+            3 => Ok(LoanPurpose::DebtConsolidation),
+            4 => Ok(LoanPurpose::CreditCard),
+            5 => Ok(LoanPurpose::HomeImprovement),
+            6 => Ok(LoanPurpose::Car),
+            7 => Ok(LoanPurpose::House),
+            8 => Ok(LoanPurpose::MajorPurchase),
+            9 => Ok(LoanPurpose::Vacation),
+            10 => Ok(LoanPurpose::SmallBusiness),
+            11 => Ok(LoanPurpose::Other),
+            _ => Err("Invalid LoanPurpose code"),
+        }
+    }
+}
+
+impl TryFrom<i32> for DisbursementMethod {
+    type Error = &'static str;
+    fn try_from(code: i32) -> Result<Self, Self::Error> {
+        match code {
+            1 => Ok(DisbursementMethod::Cash),
+            2 => Ok(DisbursementMethod::DirectPay),
+            _ => Err("Invalid DisbursementMethod code"),
+        }
+    }
+}
+
+impl TryFrom<i32> for ApplicationType {
+    type Error = &'static str;
+    fn try_from(code: i32) -> Result<Self, Self::Error> {
+        match code {
+            1 => Ok(ApplicationType::Individual),
+            2 => Ok(ApplicationType::Joint),
+            _ => Err("Invalid ApplicationType code"),
+        }
+    }
+}
+
+impl TryFrom<i32> for InitialListingStatus {
+    type Error = &'static str;
+    fn try_from(code: i32) -> Result<Self, Self::Error> {
+        match code {
+            1 => Ok(InitialListingStatus::Whole),
+            2 => Ok(InitialListingStatus::Fractional),
+            _ => Err("Invalid InitialListingStatus code"),
         }
     }
 }
@@ -141,6 +289,8 @@ mod tests {
         const TEST_STR: &str = "In Grace Period";
         let converted_str = convert_loan_status(TEST_STR).unwrap();
         assert_eq!(converted_str, LoanStatus::InGracePeriod);
+        assert_eq!(LoanStatus::try_from(7), Ok(LoanStatus::ChargedOff));
+        assert!(LoanStatus::try_from(0).is_err());
     }
 
     #[test]
@@ -163,4 +313,28 @@ mod tests {
         let converted_str = convert_initial_listing_status(TEST_STR).unwrap();
         assert_eq!(converted_str, InitialListingStatus::Fractional);
     }
+
+    #[test]
+    fn test_display_round_trips() {
+        for code in 1..=7 {
+            let status = LoanStatus::try_from(code).unwrap();
+            assert_eq!(convert_loan_status(&status.to_string()), Ok(status));
+        }
+        for code in 1..=11 {
+            let purpose = LoanPurpose::try_from(code).unwrap();
+            assert_eq!(convert_loan_purpose(&purpose.to_string()), Ok(purpose));
+        }
+        assert_eq!(
+            convert_application_type(&ApplicationType::Joint.to_string()),
+            Ok(ApplicationType::Joint)
+        );
+        assert_eq!(
+            convert_disbursement_method(&DisbursementMethod::DirectPay.to_string()),
+            Ok(DisbursementMethod::DirectPay)
+        );
+        assert_eq!(
+            convert_initial_listing_status(&InitialListingStatus::Whole.to_string()),
+            Ok(InitialListingStatus::Whole)
+        );
+    }
 }
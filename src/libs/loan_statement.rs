@@ -0,0 +1,629 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use askama::Template;
+use chrono::{Datelike, Months, NaiveDate};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+
+use crate::{
+    error::AppError,
+    libs::{
+        delinquency::days_past_due,
+        servicing::{load_loan_account, payment_ledger, LedgerEntry, LoanAccount, PaymentKind},
+    },
+    models::loan::LoanStatus,
+};
+
+pub const DEFAULT_STATEMENT_DIR: &str = "statements";
+
+pub(crate) const HTML_MIME_TYPE_ID: i32 = 15;
+const CSV_MIME_TYPE_ID: i32 = 14;
+const STATEMENT_CHANNEL: &str = "Statement";
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatementFormat {
+    Html,
+    Text,
+    Csv,
+}
+
+/// One ledger row as it reads on a statement. Amounts in cents.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StatementLine {
+    pub payment_id: i32,
+    pub date: NaiveDate,
+    pub description: String,
+    pub amount: i64,
+    pub fees: i64,
+    pub interest: i64,
+    pub principal: i64,
+    /// Principal after this row
+    pub balance: i64,
+}
+
+/// A month of activity on a loan plus what is due next. Amounts in cents.
+#[derive(Debug, Clone, Serialize)]
+pub struct LoanStatement {
+    pub loan_id: i32,
+    pub borrower_name: String,
+    pub loan_status: LoanStatus,
+    pub period_start: NaiveDate,
+    pub period_end: NaiveDate,
+    pub generated_on: NaiveDate,
+    pub opening_balance: i64,
+    pub closing_balance: i64,
+    pub lines: Vec<StatementLine>,
+    /// Applied to the loan, net of reversals
+    pub payments: i64,
+    pub principal_paid: i64,
+    pub interest_paid: i64,
+    pub fees_paid: i64,
+    pub interest_accrued: i64,
+    /// Late fees assessed net of waivers
+    pub fees_charged: i64,
+    pub interest_due: i64,
+    pub fees_due: i64,
+    pub amount_due: i64,
+    pub next_due_date: NaiveDate,
+    pub days_past_due: i64,
+}
+
+/// First and last day of the month `period` ("YYYY-MM") names.
+pub fn statement_period(period: &str) -> Result<(NaiveDate, NaiveDate), AppError> {
+    let start = NaiveDate::parse_from_str(&format!("{}-01", period), "%Y-%m-%d").map_err(|_| {
+        AppError::InvalidRequest(format!("Statement period must be YYYY-MM, got {}", period))
+    })?;
+    let end = start
+        .checked_add_months(Months::new(1))
+        .and_then(|next| next.pred_opt())
+        .unwrap_or(start);
+    Ok((start, end))
+}
+
+fn describe(entry: &LedgerEntry, reversed_kind: Option<PaymentKind>) -> String {
+    match (entry.kind, reversed_kind) {
+        (PaymentKind::Regular, _) => "Payment".to_owned(),
+        (PaymentKind::Prepayment, _) => "Principal prepayment".to_owned(),
+        (PaymentKind::LateFee, _) => "Late fee".to_owned(),
+        (PaymentKind::Reversal, Some(PaymentKind::LateFee)) => "Late fee waived".to_owned(),
+        (PaymentKind::Reversal, _) => format!(
+            "Reversal of payment #{}",
+            entry.reverses_payment_id.unwrap_or_default()
+        ),
+    }
+}
+
+/// Builds the statement for `period_start..=period_end` from the loan's whole ledger.
+/// Balances come from the ledger, what's due comes from the account as it is now.
+pub fn statement_from_ledger(
+    borrower_name: String,
+    loan_status: LoanStatus,
+    account: &LoanAccount,
+    ledger: &[LedgerEntry],
+    period_start: NaiveDate,
+    period_end: NaiveDate,
+    generated_on: NaiveDate,
+) -> LoanStatement {
+    let kinds: HashMap<i32, PaymentKind> = ledger
+        .iter()
+        .map(|entry| (entry.payment_id, entry.kind))
+        .collect();
+    let mut entries: Vec<&LedgerEntry> = ledger.iter().collect();
+    entries.sort_by_key(|entry| (entry.effective_date, entry.payment_id));
+
+    let opening_balance = entries
+        .iter()
+        .rev()
+        .find(|entry| entry.effective_date < period_start)
+        .map(|entry| entry.principal_after)
+        .or_else(|| {
+            entries
+                .first()
+                .map(|entry| entry.principal_after + entry.principal)
+        })
+        .unwrap_or(account.principal);
+
+    let mut statement = LoanStatement {
+        loan_id: account.loan_id,
+        borrower_name,
+        loan_status,
+        period_start,
+        period_end,
+        generated_on,
+        opening_balance,
+        closing_balance: opening_balance,
+        lines: Vec::new(),
+        payments: 0,
+        principal_paid: 0,
+        interest_paid: 0,
+        fees_paid: 0,
+        interest_accrued: 0,
+        fees_charged: 0,
+        interest_due: account.interest_due,
+        fees_due: account.fees_due,
+        amount_due: if account.paid_off() {
+            0
+        } else {
+            (account.installment - account.due_paid).max(0) + account.fees_due
+        },
+        next_due_date: account.next_due_date,
+        days_past_due: days_past_due(account, generated_on),
+    };
+
+    for entry in entries
+        .into_iter()
+        .filter(|entry| period_start <= entry.effective_date && entry.effective_date <= period_end)
+    {
+        let reversed_kind = entry
+            .reverses_payment_id
+            .and_then(|payment_id| kinds.get(&payment_id).copied());
+        let is_fee =
+            entry.kind == PaymentKind::LateFee || reversed_kind == Some(PaymentKind::LateFee);
+        if is_fee {
+            statement.fees_charged += entry.fees;
+        } else {
+            statement.payments += entry.fees + entry.interest + entry.principal;
+            statement.principal_paid += entry.principal;
+            statement.interest_paid += entry.interest;
+            statement.fees_paid += entry.fees;
+            statement.interest_accrued += entry.accrued;
+        }
+        statement.closing_balance = entry.principal_after;
+        statement.lines.push(StatementLine {
+            payment_id: entry.payment_id,
+            date: entry.effective_date,
+            description: describe(entry, reversed_kind),
+            amount: entry.amount,
+            fees: entry.fees,
+            interest: entry.interest,
+            principal: entry.principal,
+            balance: entry.principal_after,
+        });
+    }
+    statement
+}
+
+pub fn dollars(cents: i64) -> String {
+    let sign = if cents < 0 { "-" } else { "" };
+    format!("{}${}.{:02}", sign, cents.abs() / 100, cents.abs() % 100)
+}
+
+mod filters {
+    pub fn dollars(cents: &i64) -> ::askama::Result<String> {
+        Ok(super::dollars(*cents))
+    }
+}
+
+#[derive(Debug, Template)]
+#[template(path = "loan_statement.html")]
+pub struct LoanStatementTemplate<'a> {
+    pub statement: &'a LoanStatement,
+}
+
+impl LoanStatement {
+    pub fn to_html(&self) -> Result<String, AppError> {
+        LoanStatementTemplate { statement: self }
+            .render()
+            .map_err(|err| AppError::GenericError(err.to_string()))
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!(
+            "Loan {} statement for {}\n{} to {}, generated {}\n\n",
+            self.loan_id, self.borrower_name, self.period_start, self.period_end, self.generated_on
+        );
+        text.push_str(&format!(
+            "{:<12}{:<28}{:>12}{:>12}{:>12}{:>12}{:>14}\n",
+            "Date", "Description", "Amount", "Fees", "Interest", "Principal", "Balance"
+        ));
+        for line in &self.lines {
+            text.push_str(&format!(
+                "{:<12}{:<28}{:>12}{:>12}{:>12}{:>12}{:>14}\n",
+                line.date.to_string(),
+                line.description,
+                dollars(line.amount),
+                dollars(line.fees),
+                dollars(line.interest),
+                dollars(line.principal),
+                dollars(line.balance)
+            ));
+        }
+        if self.lines.is_empty() {
+            text.push_str("No activity this period\n");
+        }
+        let summary = [
+            ("Opening balance", self.opening_balance),
+            ("Payments", self.payments),
+            ("  Principal", self.principal_paid),
+            ("  Interest", self.interest_paid),
+            ("  Fees", self.fees_paid),
+            ("Interest accrued", self.interest_accrued),
+            ("Fees charged", self.fees_charged),
+            ("Closing balance", self.closing_balance),
+            ("Interest due", self.interest_due),
+            ("Fees due", self.fees_due),
+            ("Amount due", self.amount_due),
+        ];
+        text.push('\n');
+        for (label, cents) in summary {
+            text.push_str(&format!("{:<20}{:>14}\n", label, dollars(cents)));
+        }
+        text.push_str(&format!(
+            "{:<20}{:>14}\n",
+            "Next due date", self.next_due_date
+        ));
+        if self.days_past_due > 0 {
+            text.push_str(&format!(
+                "{:<20}{:>14}\n",
+                "Days past due", self.days_past_due
+            ));
+        }
+        text
+    }
+
+    /// One row per statement line, amounts in dollars.
+    pub fn to_csv(&self) -> Result<String, AppError> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        let csv_err = |err: csv::Error| AppError::GenericError(err.to_string());
+        writer
+            .write_record([
+                "payment_id",
+                "date",
+                "description",
+                "amount",
+                "fees",
+                "interest",
+                "principal",
+                "balance",
+            ])
+            .map_err(csv_err)?;
+        let amount = |cents: i64| format!("{:.2}", cents as f64 / 100.0);
+        for line in &self.lines {
+            writer
+                .write_record([
+                    line.payment_id.to_string(),
+                    line.date.to_string(),
+                    line.description.clone(),
+                    amount(line.amount),
+                    amount(line.fees),
+                    amount(line.interest),
+                    amount(line.principal),
+                    amount(line.balance),
+                ])
+                .map_err(csv_err)?;
+        }
+        let bytes = writer
+            .into_inner()
+            .map_err(|err| AppError::GenericError(err.to_string()))?;
+        String::from_utf8(bytes).map_err(|err| AppError::GenericError(err.to_string()))
+    }
+
+    /// File name stem, e.g. `loan_12_2024-02`.
+    pub fn file_stem(&self) -> String {
+        format!(
+            "loan_{}_{}-{:02}",
+            self.loan_id,
+            self.period_start.year(),
+            self.period_start.month()
+        )
+    }
+}
+
+#[derive(Debug, FromRow)]
+struct StatementHeader {
+    borrower_name: String,
+    loan_status: i32,
+}
+
+pub async fn build_statement(
+    pool: &PgPool,
+    loan_id: i32,
+    period_start: NaiveDate,
+    period_end: NaiveDate,
+    generated_on: NaiveDate,
+) -> Result<LoanStatement, AppError> {
+    let header = sqlx::query_as::<_, StatementHeader>(
+        // Loans from a tape have no borrowers row
+        "SELECT COALESCE(b.f_name || ' ' || b.l_name, 'Borrower ' || l.borrower_id) AS borrower_name,
+            l.loan_status
+        FROM loans l LEFT JOIN borrowers b ON b.borrower_id = l.borrower_id WHERE l.loan_id = $1",
+    )
+    .bind(loan_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or(AppError::NotFound(format!("Loan {} not found", loan_id)))?;
+    let loan_status = LoanStatus::try_from(header.loan_status).map_err(|err| {
+        AppError::GenericError(format!("Loan {}: {} {}", loan_id, err, header.loan_status))
+    })?;
+    let account = load_loan_account(pool, loan_id).await?;
+    let ledger = payment_ledger(pool, loan_id).await?;
+    Ok(statement_from_ledger(
+        header.borrower_name,
+        loan_status,
+        &account,
+        &ledger,
+        period_start,
+        period_end,
+        generated_on,
+    ))
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct StoredStatement {
+    pub statement_id: i32,
+    pub loan_id: i32,
+    pub period_start: NaiveDate,
+    pub period_end: NaiveDate,
+    pub html_attachment_id: i32,
+    pub csv_attachment_id: i32,
+}
+
+/// Records a file written under a documents directory. Rewriting the same path keeps its row.
+pub(crate) async fn insert_attachment(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    path: &Path,
+    mime_type_id: i32,
+    user_id: i32,
+    channel: &str,
+    short_desc: &str,
+) -> Result<i32, AppError> {
+    sqlx::query_scalar(
+        "INSERT INTO attachments (path, mime_type_id, user_id, channel, short_desc)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (path) DO UPDATE SET updated_at = NOW(), user_id = EXCLUDED.user_id
+        RETURNING attachment_id",
+    )
+    .bind(path.to_string_lossy().to_string())
+    .bind(mime_type_id)
+    .bind(user_id)
+    .bind(channel)
+    .bind(short_desc)
+    .fetch_one(&mut **tx)
+    .await
+    .map_err(AppError::from)
+}
+
+/// Writes the HTML and CSV renderings under `dir` and records them as attachments owned by
+/// `user_id`. Regenerating a period overwrites its files and keeps the same rows.
+pub async fn store_statement(
+    pool: &PgPool,
+    statement: &LoanStatement,
+    user_id: i32,
+    dir: &str,
+) -> Result<StoredStatement, AppError> {
+    let io_err = |err: std::io::Error| AppError::GenericError(err.to_string());
+    fs::create_dir_all(dir).map_err(io_err)?;
+    let stem = statement.file_stem();
+    let html_path = Path::new(dir).join(format!("{}.html", stem));
+    let csv_path = Path::new(dir).join(format!("{}.csv", stem));
+    fs::write(&html_path, statement.to_html()?).map_err(io_err)?;
+    fs::write(&csv_path, statement.to_csv()?).map_err(io_err)?;
+
+    let short_desc = format!(
+        "Loan {} statement {}",
+        statement.loan_id,
+        statement.period_start.format("%Y-%m")
+    );
+    let mut tx = pool.begin().await?;
+    let html_attachment_id = insert_attachment(
+        &mut tx,
+        &html_path,
+        HTML_MIME_TYPE_ID,
+        user_id,
+        STATEMENT_CHANNEL,
+        &short_desc,
+    )
+    .await?;
+    let csv_attachment_id = insert_attachment(
+        &mut tx,
+        &csv_path,
+        CSV_MIME_TYPE_ID,
+        user_id,
+        STATEMENT_CHANNEL,
+        &short_desc,
+    )
+    .await?;
+    let stored = sqlx::query_as::<_, StoredStatement>(
+        "INSERT INTO loan_statements (loan_id, period_start, period_end, html_attachment_id, csv_attachment_id)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (loan_id, period_start) DO UPDATE SET period_end = EXCLUDED.period_end,
+            html_attachment_id = EXCLUDED.html_attachment_id, csv_attachment_id = EXCLUDED.csv_attachment_id,
+            created_at = NOW()
+        RETURNING statement_id, loan_id, period_start, period_end, html_attachment_id, csv_attachment_id",
+    )
+    .bind(statement.loan_id)
+    .bind(statement.period_start)
+    .bind(statement.period_end)
+    .bind(html_attachment_id)
+    .bind(csv_attachment_id)
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(stored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::libs::servicing::{amortized_installment, AccrualMethod, Allocation};
+    use chrono::Utc;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn account() -> LoanAccount {
+        LoanAccount {
+            loan_id: 1,
+            principal: 1_200_000,
+            interest_due: 0,
+            fees_due: 0,
+            due_paid: 0,
+            installment: amortized_installment(1_200_000, 12.0, 12),
+            interest_rate: 12.0,
+            remaining_term: 12,
+            accrual_method: AccrualMethod::Monthly,
+            last_accrual_date: date(2024, 1, 1),
+            next_due_date: date(2024, 2, 1),
+        }
+    }
+
+    fn entry(
+        payment_id: i32,
+        loan: &LoanAccount,
+        kind: PaymentKind,
+        on: NaiveDate,
+        amount: i64,
+        alloc: &Allocation,
+    ) -> LedgerEntry {
+        LedgerEntry {
+            payment_id,
+            loan_id: loan.loan_id,
+            kind,
+            effective_date: on,
+            amount,
+            accrued: alloc.accrued,
+            fees: alloc.fees,
+            interest: alloc.interest,
+            principal: alloc.principal,
+            unapplied: alloc.unapplied,
+            principal_after: loan.principal,
+            installment_after: loan.installment,
+            reverses_payment_id: None,
+            created_at: Utc::now(),
+            remaining_term_before: None,
+            installment_before: None,
+            due_paid_before: None,
+        }
+    }
+
+    /// Pays January and February, takes a late fee in March and waives it.
+    fn ledger() -> (LoanAccount, Vec<LedgerEntry>) {
+        let mut loan = account();
+        let mut ledger = Vec::new();
+        for (id, on) in [(1, date(2024, 2, 1)), (2, date(2024, 3, 1))] {
+            let alloc = loan
+                .post_payment(106_619, on, PaymentKind::Regular)
+                .unwrap();
+            ledger.push(entry(id, &loan, PaymentKind::Regular, on, 106_619, &alloc));
+        }
+        loan.assess_late_fee(5_331);
+        let fee = Allocation {
+            fees: 5_331,
+            ..Default::default()
+        };
+        ledger.push(entry(
+            3,
+            &loan,
+            PaymentKind::LateFee,
+            date(2024, 4, 7),
+            5_331,
+            &fee,
+        ));
+        loan.reverse_payment(&ledger[2]).unwrap();
+        let waived = Allocation {
+            fees: -5_331,
+            ..Default::default()
+        };
+        let mut waiver = entry(
+            4,
+            &loan,
+            PaymentKind::Reversal,
+            date(2024, 4, 9),
+            -5_331,
+            &waived,
+        );
+        waiver.reverses_payment_id = Some(3);
+        ledger.push(waiver);
+        (loan, ledger)
+    }
+
+    #[test]
+    fn period_is_a_calendar_month() {
+        assert_eq!(
+            statement_period("2024-02").unwrap(),
+            (date(2024, 2, 1), date(2024, 2, 29))
+        );
+        assert!(statement_period("2024-13").is_err());
+        assert!(statement_period("Feb").is_err());
+    }
+
+    #[test]
+    fn statement_covers_only_its_period() {
+        let (loan, ledger) = ledger();
+        let statement = statement_from_ledger(
+            "Jen Smith".to_owned(),
+            LoanStatus::Current,
+            &loan,
+            &ledger,
+            date(2024, 3, 1),
+            date(2024, 3, 31),
+            date(2024, 3, 31),
+        );
+        assert_eq!(statement.lines.len(), 1);
+        assert_eq!(statement.opening_balance, ledger[0].principal_after);
+        assert_eq!(statement.closing_balance, ledger[1].principal_after);
+        assert_eq!(statement.payments, 106_619);
+        assert_eq!(
+            statement.principal_paid,
+            statement.opening_balance - statement.closing_balance
+        );
+        assert_eq!(statement.fees_charged, 0);
+        assert_eq!(statement.next_due_date, date(2024, 4, 1));
+    }
+
+    #[test]
+    fn waived_fees_net_out() {
+        let (loan, ledger) = ledger();
+        let statement = statement_from_ledger(
+            "Jen Smith".to_owned(),
+            LoanStatus::Late1to15,
+            &loan,
+            &ledger,
+            date(2024, 4, 1),
+            date(2024, 4, 30),
+            date(2024, 4, 10),
+        );
+        assert_eq!(statement.lines.len(), 2);
+        assert_eq!(statement.lines[1].description, "Late fee waived");
+        assert_eq!(statement.fees_charged, 0);
+        assert_eq!(statement.payments, 0);
+        assert_eq!(statement.days_past_due, 9);
+        assert_eq!(statement.amount_due, loan.installment);
+    }
+
+    #[test]
+    fn renders_text_csv_and_html() {
+        let (loan, ledger) = ledger();
+        let statement = statement_from_ledger(
+            "Jen Smith".to_owned(),
+            LoanStatus::Current,
+            &loan,
+            &ledger,
+            date(2024, 2, 1),
+            date(2024, 2, 29),
+            date(2024, 2, 29),
+        );
+        assert_eq!(statement.file_stem(), "loan_1_2024-02");
+        assert!(statement.to_text().contains("$1066.19"));
+
+        let csv = statement.to_csv().unwrap();
+        let mut rows = csv.lines();
+        assert!(rows.next().unwrap().starts_with("payment_id,date"));
+        assert_eq!(
+            rows.next().unwrap(),
+            "1,2024-02-01,Payment,1066.19,0.00,120.00,946.19,11053.81"
+        );
+
+        let html = statement.to_html().unwrap();
+        assert!(html.contains("Jen Smith"));
+        assert!(html.contains("$11053.81"));
+    }
+
+    #[test]
+    fn formats_dollars() {
+        assert_eq!(dollars(106_619), "$1066.19");
+        assert_eq!(dollars(-5), "-$0.05");
+    }
+}
@@ -0,0 +1,695 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+};
+
+use chrono::NaiveDate;
+use csv::{Reader, StringRecord, Writer};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool, Postgres, QueryBuilder, Transaction};
+
+use crate::{
+    error::AppError,
+    libs::{
+        audit_trail::begin_audited,
+        credit_file_import::{required_columns, ImportError, RowReader},
+        loan_enums::{
+            convert_application_type, convert_disbursement_method, convert_initial_listing_status,
+            convert_loan_purpose, convert_loan_status,
+        },
+    },
+    models::loan::{
+        ApplicationType, DisbursementMethod, InitialListingStatus, LoanPurpose, LoanStatus,
+    },
+};
+
+/// Column order of an exported tape. An import needs all of them, extra columns are ignored.
+pub const LOAN_TAPE_COLUMNS: [&str; 19] = [
+    "loan_id",
+    "borrower_id",
+    "loan_purpose",
+    "application_type",
+    "loan_amount",
+    "term",
+    "interest_rate",
+    "installment",
+    "grade",
+    "sub_grade",
+    "issue_month",
+    "loan_status",
+    "initial_listing_status",
+    "disbursement_method",
+    "balance",
+    "paid_total",
+    "paid_principal",
+    "paid_interest",
+    "paid_late_fees",
+];
+
+// Parsed through the loan_enums converters, unmapped values are tallied in the report
+const CONVERTED_COLUMNS: [&str; 5] = [
+    "loan_purpose",
+    "application_type",
+    "loan_status",
+    "initial_listing_status",
+    "disbursement_method",
+];
+
+// 21 binds per row
+const BATCH_SIZE: usize = 1000;
+
+/// One loan as it appears on a tape. `loan_id` is the tape's identifier, not ours.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LoanTapeRow {
+    pub loan_id: String,
+    pub borrower_id: i32,
+    pub loan_purpose: LoanPurpose,
+    pub application_type: ApplicationType,
+    pub loan_amount: i32,
+    pub term: i32,
+    pub interest_rate: f32,
+    pub installment: f32,
+    pub grade: String,
+    pub sub_grade: String,
+    /// e.g. "Mar-2018"
+    pub issue_month: String,
+    pub loan_status: LoanStatus,
+    pub initial_listing_status: InitialListingStatus,
+    pub disbursement_method: DisbursementMethod,
+    pub balance: f32,
+    pub paid_total: f32,
+    pub paid_principal: f32,
+    pub paid_interest: f32,
+    pub paid_late_fees: f32,
+}
+
+/// Control totals, compared between the file and what ended up in `loans`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct TapeTotals {
+    pub loans: i64,
+    pub loan_amount: f64,
+    pub balance: f64,
+    pub paid_total: f64,
+}
+
+impl TapeTotals {
+    fn add(&mut self, row: &LoanTapeRow) {
+        self.loans += 1;
+        self.loan_amount += row.loan_amount as f64;
+        self.balance += row.balance as f64;
+        self.paid_total += row.paid_total as f64;
+    }
+
+    /// Amounts are REAL in the table, so allow for float summation order.
+    pub fn matches(&self, other: &TapeTotals) -> bool {
+        let close = |a: f64, b: f64| (a - b).abs() < 0.01;
+        self.loans == other.loans
+            && close(self.loan_amount, other.loan_amount)
+            && close(self.balance, other.balance)
+            && close(self.paid_total, other.paid_total)
+    }
+}
+
+/// A tape read into memory. Only rows without a single bad cell make it into `rows`.
+#[derive(Debug, Default)]
+pub struct ParsedTape {
+    pub rows_read: i32,
+    pub rows: Vec<LoanTapeRow>,
+    /// The file row each of `rows` came from
+    pub row_numbers: Vec<i32>,
+    pub errors: Vec<ImportError>,
+    pub unmapped: BTreeMap<String, BTreeMap<String, usize>>,
+    pub totals: TapeTotals,
+}
+
+impl ParsedTape {
+    /// Moves rows whose borrower_id isn't in `borrower_ids` over to the errors, loans has a
+    /// foreign key on borrowers
+    pub fn reject_unknown_borrowers(&mut self, borrower_ids: &HashSet<i32>) {
+        let rows = std::mem::take(&mut self.rows);
+        let row_numbers = std::mem::take(&mut self.row_numbers);
+        self.totals = TapeTotals::default();
+        for (loan, row) in rows.into_iter().zip(row_numbers) {
+            if borrower_ids.contains(&loan.borrower_id) {
+                self.totals.add(&loan);
+                self.rows.push(loan);
+                self.row_numbers.push(row);
+            } else {
+                self.errors.push(ImportError {
+                    row,
+                    column: "borrower_id".to_owned(),
+                    reason: format!("No borrower with borrower_id {}", loan.borrower_id),
+                });
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct LoanTapeReport {
+    pub import_id: i32,
+    pub file_name: String,
+    pub rows_read: i32,
+    pub inserted: i32,
+    pub updated: i32,
+    pub failed: i32,
+    pub errors: Vec<ImportError>,
+    /// Values a converter rejected, by column, with how many rows had each
+    pub unmapped: BTreeMap<String, BTreeMap<String, usize>>,
+    /// Over the rows that parsed
+    pub file_totals: TapeTotals,
+    /// The same loans read back from `loans` after the import
+    pub loaded_totals: TapeTotals,
+    pub reconciled: bool,
+}
+
+fn valid_issue_month(issue_month: &str) -> bool {
+    NaiveDate::parse_from_str(&format!("01-{}", issue_month), "%d-%b-%Y").is_ok()
+}
+
+/// Validates one tape record. Every bad cell is reported, not just the first.
+pub fn parse_loan_tape_row(
+    row: i32,
+    columns: &HashMap<String, usize>,
+    record: &StringRecord,
+) -> Result<LoanTapeRow, Vec<ImportError>> {
+    let mut r = RowReader::new(row, columns, record);
+    let loan_id = r.opt_text("loan_id").unwrap_or_default();
+    if loan_id.is_empty() {
+        r.error("loan_id", "Required value is missing");
+    }
+    let grade = r.raw("grade").to_uppercase();
+    if !matches!(grade.as_str(), "A" | "B" | "C" | "D" | "E" | "F" | "G") {
+        r.error("grade", &format!("Grade must be A-G: '{}'", r.raw("grade")));
+    }
+    let sub_grade = r.raw("sub_grade").to_uppercase();
+    let sub_level = sub_grade
+        .get(1..)
+        .and_then(|level| level.parse::<u8>().ok());
+    if !sub_grade.starts_with(&grade) || !matches!(sub_level, Some(1..=5)) {
+        r.error(
+            "sub_grade",
+            &format!(
+                "Sub grade must be {}1-{}5: '{}'",
+                grade,
+                grade,
+                r.raw("sub_grade")
+            ),
+        );
+    }
+    let issue_month = r.raw("issue_month").to_owned();
+    if !valid_issue_month(&issue_month) {
+        r.error(
+            "issue_month",
+            &format!("Issue month must look like Mar-2018: '{}'", issue_month),
+        );
+    }
+
+    let loan_purpose = r.converted("loan_purpose", convert_loan_purpose);
+    let application_type = r.converted("application_type", convert_application_type);
+    let loan_status = r.converted("loan_status", convert_loan_status);
+    let initial_listing_status =
+        r.converted("initial_listing_status", convert_initial_listing_status);
+    let disbursement_method = r.converted("disbursement_method", convert_disbursement_method);
+
+    let borrower_id = r.int("borrower_id");
+    let loan_amount = r.int("loan_amount");
+    let term = r.int("term");
+    if term == 0 {
+        r.error("term", "Term must be at least one month");
+    }
+    let interest_rate = r.float("interest_rate");
+    if !(0.0..=100.0).contains(&interest_rate) {
+        r.error("interest_rate", "Interest rate must be between 0 and 100");
+    }
+    let mut amount = |column: &str| {
+        let val = r.float(column);
+        if val < 0.0 {
+            r.error(column, "Value cannot be negative");
+        }
+        val
+    };
+    let installment = amount("installment");
+    let balance = amount("balance");
+    let paid_total = amount("paid_total");
+    let paid_principal = amount("paid_principal");
+    let paid_interest = amount("paid_interest");
+    let paid_late_fees = amount("paid_late_fees");
+
+    match (
+        loan_purpose,
+        application_type,
+        loan_status,
+        initial_listing_status,
+        disbursement_method,
+    ) {
+        (
+            Some(loan_purpose),
+            Some(application_type),
+            Some(loan_status),
+            Some(initial_listing_status),
+            Some(disbursement_method),
+        ) if r.errors.is_empty() => Ok(LoanTapeRow {
+            loan_id,
+            borrower_id,
+            loan_purpose,
+            application_type,
+            loan_amount,
+            term,
+            interest_rate,
+            installment,
+            grade,
+            sub_grade,
+            issue_month,
+            loan_status,
+            initial_listing_status,
+            disbursement_method,
+            balance,
+            paid_total,
+            paid_principal,
+            paid_interest,
+            paid_late_fees,
+        }),
+        _ => Err(r.errors),
+    }
+}
+
+/// Reads a whole tape. Structural problems (unreadable file, missing columns) are an `Err`,
+/// bad rows are collected in the result.
+pub fn parse_loan_tape<R: io::Read>(mut rdr: Reader<R>) -> Result<ParsedTape, AppError> {
+    let headers = rdr
+        .headers()
+        .map_err(|err| AppError::GenericError(format!("Unable to read CSV headers: {}", err)))?
+        .clone();
+    let columns = required_columns(&headers, &LOAN_TAPE_COLUMNS, "Loan tape")?;
+    let mut tape = ParsedTape::default();
+    let mut seen = HashSet::new();
+
+    for (idx, result) in rdr.records().enumerate() {
+        let row = idx as i32 + 1;
+        tape.rows_read = row;
+        let record = match result {
+            Ok(record) => record,
+            Err(err) => {
+                tape.errors.push(ImportError {
+                    row,
+                    column: String::new(),
+                    reason: format!("Unreadable row: {}", err),
+                });
+                continue;
+            }
+        };
+        match parse_loan_tape_row(row, &columns, &record) {
+            Ok(loan) if !seen.insert(loan.loan_id.clone()) => tape.errors.push(ImportError {
+                row,
+                column: "loan_id".to_owned(),
+                reason: format!("Duplicate loan_id: '{}'", loan.loan_id),
+            }),
+            Ok(loan) => {
+                tape.totals.add(&loan);
+                tape.rows.push(loan);
+                tape.row_numbers.push(row);
+            }
+            Err(errors) => {
+                for err in &errors {
+                    if CONVERTED_COLUMNS.contains(&err.column.as_str()) {
+                        let raw = columns
+                            .get(&err.column)
+                            .and_then(|idx| record.get(*idx))
+                            .unwrap_or("")
+                            .trim();
+                        *tape
+                            .unmapped
+                            .entry(err.column.clone())
+                            .or_default()
+                            .entry(raw.to_owned())
+                            .or_default() += 1;
+                    }
+                }
+                tape.errors.extend(errors);
+            }
+        }
+    }
+    Ok(tape)
+}
+
+/// Upserts on `external_loan_id`. Returns how many rows were new.
+async fn upsert_batch(
+    tx: &mut Transaction<'_, Postgres>,
+    batch: &[LoanTapeRow],
+    servicer_id: i32,
+) -> Result<i32, AppError> {
+    let mut query_builder = QueryBuilder::new(
+        "INSERT INTO loans (external_loan_id, borrower_id, servicer_id, loan_purpose, application_type,
+            loan_amount, term, interest_rate, installment, grade, sub_grade, issue_month, loan_status,
+            initial_listing_status, disbursement_method, balance, paid_total, paid_principal,
+            paid_interest, paid_late_fees, source) ",
+    );
+    query_builder.push_values(batch, |mut b, loan| {
+        b.push_bind(&loan.loan_id)
+            .push_bind(loan.borrower_id)
+            .push_bind(servicer_id)
+            .push_bind(loan.loan_purpose.clone() as i32)
+            .push_bind(loan.application_type.clone() as i32)
+            .push_bind(loan.loan_amount)
+            .push_bind(loan.term)
+            .push_bind(loan.interest_rate)
+            .push_bind(loan.installment)
+            .push_bind(&loan.grade)
+            .push_bind(&loan.sub_grade)
+            .push_bind(&loan.issue_month)
+            .push_bind(loan.loan_status.clone() as i32)
+            .push_bind(loan.initial_listing_status.clone() as i32)
+            .push_bind(loan.disbursement_method.clone() as i32)
+            .push_bind(loan.balance)
+            .push_bind(loan.paid_total)
+            .push_bind(loan.paid_principal)
+            .push_bind(loan.paid_interest)
+            .push_bind(loan.paid_late_fees)
+            .push_bind("imported");
+    });
+    query_builder.push(
+        " ON CONFLICT (external_loan_id) DO UPDATE SET borrower_id = EXCLUDED.borrower_id,
+            servicer_id = EXCLUDED.servicer_id, loan_purpose = EXCLUDED.loan_purpose,
+            application_type = EXCLUDED.application_type, loan_amount = EXCLUDED.loan_amount,
+            term = EXCLUDED.term, interest_rate = EXCLUDED.interest_rate,
+            installment = EXCLUDED.installment, grade = EXCLUDED.grade, sub_grade = EXCLUDED.sub_grade,
+            issue_month = EXCLUDED.issue_month, loan_status = EXCLUDED.loan_status,
+            initial_listing_status = EXCLUDED.initial_listing_status,
+            disbursement_method = EXCLUDED.disbursement_method, balance = EXCLUDED.balance,
+            paid_total = EXCLUDED.paid_total, paid_principal = EXCLUDED.paid_principal,
+            paid_interest = EXCLUDED.paid_interest, paid_late_fees = EXCLUDED.paid_late_fees
+        RETURNING (xmax = 0) AS inserted",
+    );
+    let inserted: Vec<bool> = query_builder
+        .build_query_scalar()
+        .fetch_all(&mut **tx)
+        .await?;
+    Ok(inserted.into_iter().filter(|new| *new).count() as i32)
+}
+
+async fn loaded_totals(
+    tx: &mut Transaction<'_, Postgres>,
+    loan_ids: &[String],
+) -> Result<TapeTotals, AppError> {
+    let (loans, loan_amount, balance, paid_total): (i64, f64, f64, f64) = sqlx::query_as(
+        "SELECT COUNT(*), COALESCE(SUM(loan_amount::FLOAT8), 0), COALESCE(SUM(balance::FLOAT8), 0),
+            COALESCE(SUM(paid_total::FLOAT8), 0)
+        FROM loans WHERE external_loan_id = ANY($1)",
+    )
+    .bind(loan_ids)
+    .fetch_one(&mut **tx)
+    .await?;
+    Ok(TapeTotals {
+        loans,
+        loan_amount,
+        balance,
+        paid_total,
+    })
+}
+
+/// Loads every good row of `file_name` into `loans` under `servicer_id` in one transaction,
+/// then reads the loans back to reconcile against the file. Re-importing a tape updates
+/// loans in place by the tape's `loan_id`.
+pub async fn import_loan_tape(
+    pool: &PgPool,
+    file_name: &str,
+    servicer_id: i32,
+) -> Result<LoanTapeReport, AppError> {
+    let rdr = Reader::from_path(file_name)
+        .map_err(|err| AppError::GenericError(format!("Unable to open {}: {}", file_name, err)))?;
+    let mut tape = parse_loan_tape(rdr)?;
+    let borrower_ids = tape
+        .rows
+        .iter()
+        .map(|loan| loan.borrower_id)
+        .collect::<Vec<i32>>();
+    let known: Vec<i32> =
+        sqlx::query_scalar("SELECT borrower_id FROM borrowers WHERE borrower_id = ANY($1)")
+            .bind(&borrower_ids)
+            .fetch_all(pool)
+            .await?;
+    tape.reject_unknown_borrowers(&known.into_iter().collect());
+    let failed_rows = tape
+        .errors
+        .iter()
+        .map(|err| err.row)
+        .collect::<HashSet<i32>>()
+        .len() as i32;
+
+    let mut tx = begin_audited(pool).await?;
+    let mut inserted = 0;
+    for batch in tape.rows.chunks(BATCH_SIZE) {
+        inserted += upsert_batch(&mut tx, batch, servicer_id).await?;
+    }
+    let loan_ids = tape
+        .rows
+        .iter()
+        .map(|loan| loan.loan_id.clone())
+        .collect::<Vec<String>>();
+    let loaded_totals = loaded_totals(&mut tx, &loan_ids).await?;
+
+    let mut report = LoanTapeReport {
+        import_id: 0,
+        file_name: file_name.to_owned(),
+        rows_read: tape.rows_read,
+        inserted,
+        updated: tape.rows.len() as i32 - inserted,
+        failed: failed_rows,
+        errors: tape.errors,
+        unmapped: tape.unmapped,
+        reconciled: tape.totals.matches(&loaded_totals),
+        file_totals: tape.totals,
+        loaded_totals,
+    };
+    report.import_id = sqlx::query_scalar(
+        "INSERT INTO loan_tape_imports (file_name, rows_read, rows_inserted, rows_updated, rows_failed, report)
+        VALUES ($1, $2, $3, $4, $5, $6) RETURNING import_id",
+    )
+    .bind(&report.file_name)
+    .bind(report.rows_read)
+    .bind(report.inserted)
+    .bind(report.updated)
+    .bind(report.failed)
+    .bind(sqlx::types::Json(&report))
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(report)
+}
+
+#[derive(Debug, FromRow)]
+struct LoanTapeRecord {
+    loan_id: i32,
+    external_loan_id: String,
+    borrower_id: i32,
+    loan_purpose: i32,
+    application_type: i32,
+    loan_amount: i32,
+    term: i32,
+    interest_rate: f32,
+    installment: f32,
+    grade: String,
+    sub_grade: String,
+    issue_month: String,
+    loan_status: i32,
+    initial_listing_status: i32,
+    disbursement_method: i32,
+    balance: f32,
+    paid_total: f32,
+    paid_principal: f32,
+    paid_interest: f32,
+    paid_late_fees: f32,
+}
+
+impl TryFrom<LoanTapeRecord> for LoanTapeRow {
+    type Error = &'static str;
+    fn try_from(rec: LoanTapeRecord) -> Result<Self, Self::Error> {
+        Ok(LoanTapeRow {
+            loan_id: rec.external_loan_id,
+            borrower_id: rec.borrower_id,
+            loan_purpose: LoanPurpose::try_from(rec.loan_purpose)?,
+            application_type: ApplicationType::try_from(rec.application_type)?,
+            loan_amount: rec.loan_amount,
+            term: rec.term,
+            interest_rate: rec.interest_rate,
+            installment: rec.installment,
+            grade: rec.grade,
+            sub_grade: rec.sub_grade,
+            issue_month: rec.issue_month,
+            loan_status: LoanStatus::try_from(rec.loan_status)?,
+            initial_listing_status: InitialListingStatus::try_from(rec.initial_listing_status)?,
+            disbursement_method: DisbursementMethod::try_from(rec.disbursement_method)?,
+            balance: rec.balance,
+            paid_total: rec.paid_total,
+            paid_principal: rec.paid_principal,
+            paid_interest: rec.paid_interest,
+            paid_late_fees: rec.paid_late_fees,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Default)]
+pub struct LoanTapeExport {
+    pub rows: usize,
+    /// (loan_id, reason) for loans whose codes don't decode. These are left off the tape
+    pub skipped: Vec<(i32, String)>,
+}
+
+/// Every loan as tape rows. Loans without an `external_loan_id` use our `loan_id`.
+pub async fn load_loan_tape(
+    pool: &PgPool,
+) -> Result<(Vec<LoanTapeRow>, Vec<(i32, String)>), AppError> {
+    let records = sqlx::query_as::<_, LoanTapeRecord>(
+        "SELECT loan_id, COALESCE(external_loan_id, loan_id::TEXT) AS external_loan_id, borrower_id,
+            loan_purpose, application_type, loan_amount, term, interest_rate, installment, grade,
+            sub_grade, issue_month, loan_status, initial_listing_status, disbursement_method, balance,
+            paid_total, paid_principal, paid_interest, paid_late_fees
+        FROM loans ORDER BY loan_id",
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut rows = Vec::with_capacity(records.len());
+    let mut skipped = Vec::new();
+    for record in records {
+        let loan_id = record.loan_id;
+        match LoanTapeRow::try_from(record) {
+            Ok(row) => rows.push(row),
+            Err(reason) => skipped.push((loan_id, reason.to_owned())),
+        }
+    }
+    Ok((rows, skipped))
+}
+
+/// Writes `rows` in `LOAN_TAPE_COLUMNS` order with the loan_enums strings, so the output
+/// imports back unchanged.
+pub fn write_loan_tape<W: io::Write>(rows: &[LoanTapeRow], writer: W) -> Result<(), AppError> {
+    let csv_err = |err: csv::Error| AppError::GenericError(err.to_string());
+    let mut wtr = Writer::from_writer(writer);
+    wtr.write_record(LOAN_TAPE_COLUMNS).map_err(csv_err)?;
+    for loan in rows {
+        wtr.write_record([
+            loan.loan_id.clone(),
+            loan.borrower_id.to_string(),
+            loan.loan_purpose.to_string(),
+            loan.application_type.to_string(),
+            loan.loan_amount.to_string(),
+            loan.term.to_string(),
+            loan.interest_rate.to_string(),
+            loan.installment.to_string(),
+            loan.grade.clone(),
+            loan.sub_grade.clone(),
+            loan.issue_month.clone(),
+            loan.loan_status.to_string(),
+            loan.initial_listing_status.to_string(),
+            loan.disbursement_method.to_string(),
+            loan.balance.to_string(),
+            loan.paid_total.to_string(),
+            loan.paid_principal.to_string(),
+            loan.paid_interest.to_string(),
+            loan.paid_late_fees.to_string(),
+        ])
+        .map_err(csv_err)?;
+    }
+    wtr.flush()
+        .map_err(|err| AppError::GenericError(err.to_string()))
+}
+
+pub async fn export_loan_tape(pool: &PgPool, file_name: &str) -> Result<LoanTapeExport, AppError> {
+    let (rows, skipped) = load_loan_tape(pool).await?;
+    let file = std::fs::File::create(file_name).map_err(|err| {
+        AppError::GenericError(format!("Unable to create {}: {}", file_name, err))
+    })?;
+    write_loan_tape(&rows, file)?;
+    Ok(LoanTapeExport {
+        rows: rows.len(),
+        skipped,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "loan_id,borrower_id,loan_purpose,application_type,loan_amount,term,interest_rate,installment,grade,sub_grade,issue_month,loan_status,initial_listing_status,disbursement_method,balance,paid_total,paid_principal,paid_interest,paid_late_fees";
+
+    fn tape(rows: &[&str]) -> ParsedTape {
+        let csv = format!("{}\n{}\n", HEADER, rows.join("\n"));
+        parse_loan_tape(Reader::from_reader(csv.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn parses_tape_rows_through_the_converters() {
+        let parsed = tape(&[
+            "L1,1,moving,individual,28000,60,14.07,652.53,C,C3,Mar-2018,Current,whole,Cash,27015.86,1999.33,984.14,1015.19,0",
+            "L2,2,debt_consolidation,joint,5000,36,12.62,167.54,B,B2,Feb-2018,Late (16-30 days),fractional,DirectPay,4651.37,499.12,348.63,150.49,0",
+        ]);
+        assert!(parsed.errors.is_empty(), "{:?}", parsed.errors);
+        assert_eq!(parsed.rows.len(), 2);
+        assert_eq!(parsed.rows[1].loan_status, LoanStatus::Late16to30);
+        assert_eq!(parsed.rows[1].application_type, ApplicationType::Joint);
+        assert_eq!(parsed.totals.loans, 2);
+        assert_eq!(parsed.totals.loan_amount, 33000.0);
+    }
+
+    #[test]
+    fn rows_without_a_borrower_are_rejected() {
+        let mut parsed = tape(&[
+            "L1,1,moving,individual,28000,60,14.07,652.53,C,C3,Mar-2018,Current,whole,Cash,27015.86,1999.33,984.14,1015.19,0",
+            "L2,2,debt_consolidation,joint,5000,36,12.62,167.54,B,B2,Feb-2018,Current,fractional,DirectPay,4651.37,499.12,348.63,150.49,0",
+        ]);
+        parsed.reject_unknown_borrowers(&HashSet::from([2]));
+        assert_eq!(parsed.rows.len(), 1);
+        assert_eq!(parsed.rows[0].loan_id, "L2");
+        assert_eq!(parsed.row_numbers, [2]);
+        assert_eq!(parsed.errors.len(), 1);
+        assert_eq!(
+            (parsed.errors[0].row, parsed.errors[0].column.as_str()),
+            (1, "borrower_id")
+        );
+        assert_eq!(parsed.totals.loans, 1);
+        assert_eq!(parsed.totals.loan_amount, 5000.0);
+    }
+
+    #[test]
+    fn reports_unparseable_rows() {
+        let parsed = tape(&[
+            "L1,1,renewable_energy,individual,28000,60,14.07,652.53,C,C3,Mar-2018,Current,whole,Cash,27015.86,1999.33,984.14,1015.19,0",
+            "L2,2,moving,individual,5000,36,12.62,167.54,B,C2,2018-02,Default,whole,Cash,4651.37,499.12,348.63,150.49,0",
+            "L3,3,car,individual,5000,36,12.62,167.54,B,B2,Feb-2018,Current,whole,Cash,4651.37,499.12,348.63,150.49,0",
+            "L3,3,car,individual,5000,36,12.62,167.54,B,B2,Feb-2018,Current,whole,Cash,4651.37,499.12,348.63,150.49,0",
+        ]);
+        assert_eq!(parsed.rows_read, 4);
+        assert_eq!(parsed.rows.len(), 1);
+        let columns = parsed
+            .errors
+            .iter()
+            .map(|err| (err.row, err.column.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            columns,
+            vec![
+                (1, "loan_purpose"),
+                (2, "sub_grade"),
+                (2, "issue_month"),
+                (2, "loan_status"),
+                (4, "loan_id"),
+            ]
+        );
+        assert_eq!(parsed.unmapped["loan_purpose"]["renewable_energy"], 1);
+        assert_eq!(parsed.unmapped["loan_status"]["Default"], 1);
+    }
+
+    #[test]
+    fn written_tape_reads_back_the_same() {
+        let parsed = tape(&[
+            "L1,1,small_business,individual,28000,60,14.07,652.53,C,C3,Mar-2018,Charged Off,whole,Cash,27015.86,1999.33,984.14,1015.19,15",
+        ]);
+        let mut out = Vec::new();
+        write_loan_tape(&parsed.rows, &mut out).unwrap();
+        let reread = parse_loan_tape(Reader::from_reader(out.as_slice())).unwrap();
+        assert!(reread.errors.is_empty());
+        assert_eq!(reread.rows, parsed.rows);
+        assert!(reread.totals.matches(&parsed.totals));
+    }
+}
@@ -0,0 +1,289 @@
+use std::future::Future;
+
+use deadpool_redis::{redis::cmd, Connection, Pool as RedisPool};
+use redis::{from_redis_value, ErrorKind, FromRedisValue, RedisResult, Value};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+
+use crate::{
+    config::{
+        default_entry_type_option, default_state_option, fetch_entry_type_options,
+        fetch_state_options, SelectOption, SelectOptionsVec, StringSelectOption,
+        StringSelectOptionsVec,
+    },
+    error::AppError,
+    libs::{application_lifecycle::ApplicationStatus, pg_notify_handle::TableUpdate},
+};
+
+/// Every key below starts with this
+const CACHE_KEY_PREFIX: &str = "cache:";
+pub const STATE_OPTIONS_KEY: &str = "cache:state_options";
+pub const ENTRY_TYPE_OPTIONS_KEY: &str = "cache:entry_type_options";
+/// Entries are dropped as soon as their rows change, the TTLs only bound how long one can
+/// go stale when a notification is missed
+const LOOKUP_TTL_SECONDS: u64 = 24 * 60 * 60;
+const APPLICATION_TTL_SECONDS: u64 = 10 * 60;
+
+pub fn application_key(application_id: i32) -> String {
+    format!("cache:application:{}", application_id)
+}
+
+/// The keys a table_update notification makes stale
+pub fn stale_keys(update: &TableUpdate) -> Vec<String> {
+    match update.table.as_str() {
+        "applications" => update.id.map(application_key).into_iter().collect(),
+        "states" => vec![STATE_OPTIONS_KEY.to_owned()],
+        "entry_types" => vec![ENTRY_TYPE_OPTIONS_KEY.to_owned()],
+        _ => vec![],
+    }
+}
+
+/// What pages show about an application without loading the whole thing
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq)]
+pub struct ApplicationSummary {
+    pub application_id: i32,
+    pub application_slug: String,
+    pub first_name: String,
+    pub application_status: ApplicationStatus,
+}
+
+impl FromRedisValue for ApplicationSummary {
+    fn from_redis_value(v: &Value) -> RedisResult<Self> {
+        let v: String = from_redis_value(v)?;
+        let result: Self = match serde_json::from_str::<Self>(&v) {
+            Ok(v) => v,
+            Err(_err) => return Err((ErrorKind::TypeError, "Parse to JSON Failed").into()),
+        };
+        Ok(result)
+    }
+}
+
+fn redis_err(err: impl std::fmt::Display) -> AppError {
+    AppError::GenericError(format!("Redis error: {}", err))
+}
+
+async fn connection(r_pool: &RedisPool) -> Option<Connection> {
+    match r_pool.get().await {
+        Ok(con) => Some(con),
+        Err(err) => {
+            tracing::warn!(error = ?err, "Redis unavailable, reading from Postgres");
+            None
+        }
+    }
+}
+
+/// Loads `key` from Redis, or from Postgres with `load` and caches it for `ttl_seconds`. Redis
+/// being down, or an entry that no longer parses, only costs the cache.
+async fn cached<T, F, Fut>(
+    r_pool: &RedisPool,
+    key: &str,
+    ttl_seconds: u64,
+    load: F,
+) -> Result<T, AppError>
+where
+    T: FromRedisValue + Serialize,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T, AppError>>,
+{
+    let mut con = connection(r_pool).await;
+    if let Some(con) = con.as_mut() {
+        match cmd("GET").arg(key).query_async::<_, Option<T>>(con).await {
+            Ok(Some(value)) => return Ok(value),
+            Ok(None) => {}
+            Err(err) => tracing::warn!(key, error = ?err, "Unreadable cache entry"),
+        }
+    }
+
+    let value = load().await?;
+    if let (Some(con), Ok(json)) = (con.as_mut(), serde_json::to_string(&value)) {
+        let stored = cmd("SET")
+            .arg(key)
+            .arg(json)
+            .arg("EX")
+            .arg(ttl_seconds)
+            .query_async::<_, ()>(con)
+            .await;
+        if let Err(err) = stored {
+            tracing::warn!(key, error = ?err, "Unable to cache");
+        }
+    }
+    Ok(value)
+}
+
+/// The states select, "Select One" if they can't be loaded
+pub async fn cached_state_options(pool: &PgPool, r_pool: &RedisPool) -> Vec<StringSelectOption> {
+    let options = cached(r_pool, STATE_OPTIONS_KEY, LOOKUP_TTL_SECONDS, || async {
+        let vec = fetch_state_options(pool).await?;
+        Ok(StringSelectOptionsVec { vec })
+    })
+    .await;
+    match options {
+        Ok(options) => options.vec,
+        Err(_) => default_state_option(),
+    }
+}
+
+/// The writing sample entry type select, "Select One" if they can't be loaded
+pub async fn cached_entry_type_options(pool: &PgPool, r_pool: &RedisPool) -> Vec<SelectOption> {
+    let options = cached(
+        r_pool,
+        ENTRY_TYPE_OPTIONS_KEY,
+        LOOKUP_TTL_SECONDS,
+        || async {
+            let vec = fetch_entry_type_options(pool).await?;
+            Ok(SelectOptionsVec { vec })
+        },
+    )
+    .await;
+    match options {
+        Ok(options) => options.vec,
+        Err(_) => default_entry_type_option(),
+    }
+}
+
+pub async fn cached_application(
+    pool: &PgPool,
+    r_pool: &RedisPool,
+    application_id: i32,
+) -> Result<ApplicationSummary, AppError> {
+    cached(
+        r_pool,
+        &application_key(application_id),
+        APPLICATION_TTL_SECONDS,
+        || async {
+            sqlx::query_as::<_, ApplicationSummary>(
+                "SELECT application_id, application_slug, first_name, application_status
+                FROM applications WHERE application_id = $1",
+            )
+            .bind(application_id)
+            .fetch_optional(pool)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("Application {} not found", application_id)))
+        },
+    )
+    .await
+}
+
+pub async fn invalidate(r_pool: &RedisPool, keys: &[String]) -> Result<(), AppError> {
+    let mut con = r_pool.get().await.map_err(redis_err)?;
+    cmd("DEL")
+        .arg(keys)
+        .query_async::<_, ()>(&mut con)
+        .await
+        .map_err(redis_err)
+}
+
+/// Deletes every cached entry, returning how many there were. SCANs rather than KEYS so Redis
+/// isn't blocked while it walks the keyspace.
+pub async fn invalidate_all(r_pool: &RedisPool) -> Result<usize, AppError> {
+    let mut con = r_pool.get().await.map_err(redis_err)?;
+    let pattern = format!("{}*", CACHE_KEY_PREFIX);
+    let mut cursor: u64 = 0;
+    let mut deleted = 0;
+    loop {
+        let (next, keys): (u64, Vec<String>) = cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(&pattern)
+            .arg("COUNT")
+            .arg(500)
+            .query_async(&mut con)
+            .await
+            .map_err(redis_err)?;
+        if !keys.is_empty() {
+            deleted += keys.len();
+            cmd("DEL")
+                .arg(&keys)
+                .query_async::<_, ()>(&mut con)
+                .await
+                .map_err(redis_err)?;
+        }
+        if next == 0 {
+            return Ok(deleted);
+        }
+        cursor = next;
+    }
+}
+
+/// The dispatcher's reconnect hook. Any table_update sent while it was disconnected is gone, so
+/// nothing cached can be trusted.
+pub async fn invalidate_on_reconnect(r_pool: RedisPool) {
+    match invalidate_all(&r_pool).await {
+        Ok(deleted) => tracing::info!(deleted, "Cache cleared after reconnecting"),
+        Err(err) => tracing::warn!(error = ?err, "Cache not cleared after reconnecting"),
+    }
+}
+
+/// The table_update handler. Inserts drop keys too, a cached lookup list is missing the new row.
+pub async fn invalidate_on_table_update(r_pool: RedisPool, update: TableUpdate) {
+    let keys = stale_keys(&update);
+    if keys.is_empty() {
+        return;
+    }
+    if let Err(err) = invalidate(&r_pool, &keys).await {
+        tracing::warn!(?keys, error = ?err, "Cache not invalidated");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::libs::pg_notify_handle::ActionType;
+
+    fn update(table: &str, id: Option<i32>) -> TableUpdate {
+        TableUpdate {
+            table: table.to_owned(),
+            action_type: ActionType::UPDATE,
+            id,
+        }
+    }
+
+    #[test]
+    fn changes_drop_only_their_keys() {
+        assert_eq!(
+            stale_keys(&update("applications", Some(42))),
+            ["cache:application:42"]
+        );
+        assert_eq!(stale_keys(&update("states", Some(3))), [STATE_OPTIONS_KEY]);
+        assert_eq!(
+            stale_keys(&update("entry_types", None)),
+            [ENTRY_TYPE_OPTIONS_KEY]
+        );
+        assert!(stale_keys(&update("applications", None)).is_empty());
+        assert!(stale_keys(&update("offers", Some(1))).is_empty());
+    }
+
+    #[test]
+    fn every_key_is_cleared_on_reconnect() {
+        for key in [
+            STATE_OPTIONS_KEY,
+            ENTRY_TYPE_OPTIONS_KEY,
+            &application_key(7),
+        ] {
+            assert!(key.starts_with(CACHE_KEY_PREFIX), "{}", key);
+        }
+    }
+
+    #[test]
+    fn cached_values_read_back() {
+        let summary = ApplicationSummary {
+            application_id: 42,
+            application_slug: "9f2c".to_owned(),
+            first_name: "Ann".to_owned(),
+            application_status: ApplicationStatus::UnderReview,
+        };
+        let stored = Value::Data(serde_json::to_vec(&summary).unwrap());
+        assert_eq!(
+            ApplicationSummary::from_redis_value(&stored).unwrap(),
+            summary
+        );
+
+        let options = StringSelectOptionsVec {
+            vec: default_state_option(),
+        };
+        let stored = Value::Data(serde_json::to_vec(&options).unwrap());
+        let read = StringSelectOptionsVec::from_redis_value(&stored).unwrap();
+        assert_eq!(read.vec[0].value, "Select One");
+        assert!(ApplicationSummary::from_redis_value(&Value::Data(b"{}".to_vec())).is_err());
+    }
+}
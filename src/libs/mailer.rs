@@ -0,0 +1,229 @@
+use std::{env, fs, path::PathBuf, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sendgrid::v3::{Content, Email, Message, Personalization, Sender};
+use serde::Serialize;
+use sqlx::{FromRow, PgPool, Postgres, Transaction};
+
+use crate::error::AppError;
+
+pub const DEFAULT_MAIL_SINK_DIR: &str = "mail_sink";
+/// Emails that fail this many times stay in the queue for someone to look at
+const MAX_ATTEMPTS: i32 = 5;
+const RELAY_BATCH: i64 = 50;
+const RELAY_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct QueuedEmail {
+    pub email_id: i32,
+    pub to_address: String,
+    pub subject: String,
+    pub html_body: String,
+    pub text_body: String,
+    pub attempts: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+#[async_trait]
+pub trait MailTransport: Send + Sync {
+    async fn send(&self, email: &QueuedEmail) -> Result<(), AppError>;
+}
+
+pub struct SendgridTransport {
+    sender: Sender,
+    from: String,
+}
+
+impl SendgridTransport {
+    pub fn new(api_key: String, from: String) -> Self {
+        Self {
+            sender: Sender::new(api_key),
+            from,
+        }
+    }
+}
+
+#[async_trait]
+impl MailTransport for SendgridTransport {
+    async fn send(&self, email: &QueuedEmail) -> Result<(), AppError> {
+        let message = Message::new(Email::new(&self.from))
+            .set_subject(&email.subject)
+            .add_content(
+                Content::new()
+                    .set_content_type("text/plain")
+                    .set_value(&email.text_body),
+            )
+            .add_content(
+                Content::new()
+                    .set_content_type("text/html")
+                    .set_value(&email.html_body),
+            )
+            .add_personalization(Personalization::new(Email::new(&email.to_address)));
+        let resp = self
+            .sender
+            .send(&message)
+            .await
+            .map_err(|err| AppError::GenericError(format!("SendGrid error: {}", err)))?;
+        if !resp.status().is_success() {
+            return Err(AppError::GenericError(format!(
+                "SendGrid returned {}",
+                resp.status()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Writes each email to `dir` instead of sending it, for local runs and tests.
+pub struct FileSinkTransport {
+    pub dir: PathBuf,
+}
+
+impl FileSinkTransport {
+    pub fn path_for(&self, email: &QueuedEmail) -> PathBuf {
+        self.dir.join(format!("email_{}.txt", email.email_id))
+    }
+}
+
+#[async_trait]
+impl MailTransport for FileSinkTransport {
+    async fn send(&self, email: &QueuedEmail) -> Result<(), AppError> {
+        let io_err = |err: std::io::Error| AppError::GenericError(err.to_string());
+        fs::create_dir_all(&self.dir).map_err(io_err)?;
+        let contents = format!(
+            "To: {}\nSubject: {}\n\n{}\n\n--- html ---\n{}\n",
+            email.to_address, email.subject, email.text_body, email.html_body
+        );
+        fs::write(self.path_for(email), contents).map_err(io_err)
+    }
+}
+
+/// MAIL_TRANSPORT=sendgrid sends with SENDGRID_API_KEY from MAIL_FROM. Anything else writes to
+/// MAIL_SINK_DIR, so nothing goes out unless it's asked for.
+pub fn transport_from_env() -> Result<Arc<dyn MailTransport>, AppError> {
+    match env::var("MAIL_TRANSPORT").as_deref() {
+        Ok("sendgrid") => {
+            let api_key = env::var("SENDGRID_API_KEY")
+                .map_err(|_| AppError::ConfigMissingEnv("SENDGRID_API_KEY".to_owned()))?;
+            let from = env::var("MAIL_FROM")
+                .map_err(|_| AppError::ConfigMissingEnv("MAIL_FROM".to_owned()))?;
+            Ok(Arc::new(SendgridTransport::new(api_key, from)))
+        }
+        _ => Ok(Arc::new(FileSinkTransport {
+            dir: env::var("MAIL_SINK_DIR")
+                .unwrap_or_else(|_| DEFAULT_MAIL_SINK_DIR.to_owned())
+                .into(),
+        })),
+    }
+}
+
+/// Queues inside the caller's transaction, so the email only exists if what it's about does.
+pub async fn queue_email(
+    tx: &mut Transaction<'_, Postgres>,
+    to_address: &str,
+    subject: &str,
+    html_body: &str,
+    text_body: &str,
+    attachment_id: Option<i32>,
+) -> Result<i32, AppError> {
+    sqlx::query_scalar(
+        "INSERT INTO email_queue (to_address, subject, html_body, text_body, attachment_id)
+        VALUES ($1, $2, $3, $4, $5) RETURNING email_id",
+    )
+    .bind(to_address)
+    .bind(subject)
+    .bind(html_body)
+    .bind(text_body)
+    .bind(attachment_id)
+    .fetch_one(&mut **tx)
+    .await
+    .map_err(AppError::from)
+}
+
+/// Sends up to `limit` unsent emails, oldest first. Returns how many went out.
+/// Each email is claimed and marked in its own transaction, so one sent is recorded as sent
+/// even if a later one fails, and the lock is held over a single send.
+pub async fn deliver_queued_emails(
+    pool: &PgPool,
+    transport: &dyn MailTransport,
+    limit: i64,
+) -> Result<usize, AppError> {
+    let mut sent = 0;
+    let mut last_email_id = 0;
+    for _ in 0..limit {
+        let mut tx = pool.begin().await?;
+        // Past the last one tried, so a failure isn't retried within the same run
+        let email = sqlx::query_as::<_, QueuedEmail>(
+            "SELECT email_id, to_address, subject, html_body, text_body, attempts, created_at
+            FROM email_queue WHERE sent_at IS NULL AND attempts < $1 AND email_id > $2
+            ORDER BY email_id LIMIT 1 FOR UPDATE SKIP LOCKED",
+        )
+        .bind(MAX_ATTEMPTS)
+        .bind(last_email_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(email) = email else {
+            break;
+        };
+        last_email_id = email.email_id;
+
+        match transport.send(&email).await {
+            Ok(()) => {
+                sqlx::query("UPDATE email_queue SET sent_at = NOW(), attempts = attempts + 1, last_error = NULL WHERE email_id = $1")
+                    .bind(email.email_id)
+                    .execute(&mut *tx)
+                    .await?;
+                sent += 1;
+            }
+            Err(err) => {
+                tracing::warn!(email_id = email.email_id, error = ?err, "Email not sent");
+                sqlx::query("UPDATE email_queue SET attempts = attempts + 1, last_error = $2 WHERE email_id = $1")
+                    .bind(email.email_id)
+                    .bind(format!("{:?}", err))
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+        tx.commit().await?;
+    }
+    Ok(sent)
+}
+
+/// Drains the queue every RELAY_INTERVAL for as long as the server runs.
+pub async fn run_mail_relay(pool: PgPool, transport: Arc<dyn MailTransport>) {
+    loop {
+        match deliver_queued_emails(&pool, transport.as_ref(), RELAY_BATCH).await {
+            Ok(0) => {}
+            Ok(sent) => tracing::info!(sent, "Queued emails sent"),
+            Err(err) => tracing::error!(error = ?err, "Mail relay failed"),
+        }
+        tokio::time::sleep(RELAY_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn file_sink_writes_the_email() {
+        let transport = FileSinkTransport {
+            dir: env::temp_dir().join(format!("mail_sink_{}", std::process::id())),
+        };
+        let email = QueuedEmail {
+            email_id: 7,
+            to_address: "applicant@example.com".to_owned(),
+            subject: "Your application".to_owned(),
+            html_body: "<p>Hello</p>".to_owned(),
+            text_body: "Hello".to_owned(),
+            attempts: 0,
+            created_at: Utc::now(),
+        };
+        transport.send(&email).await.unwrap();
+        let written = fs::read_to_string(transport.path_for(&email)).unwrap();
+        assert!(written.starts_with("To: applicant@example.com\nSubject: Your application\n"));
+        assert!(written.contains("<p>Hello</p>"));
+        fs::remove_dir_all(&transport.dir).unwrap();
+    }
+}
@@ -1,6 +1,32 @@
+pub mod address;
+pub mod adverse_action;
+pub mod affordability;
+pub mod application_document;
+pub mod application_draft;
+pub mod application_lifecycle;
+pub mod audit_trail;
 pub mod credit_file_enums;
+pub mod credit_file_import;
+pub mod credit_file_profile;
+pub mod credit_file_vector;
+pub mod credit_scorer;
 pub mod date_convert;
+pub mod delinquency;
+pub mod document_storage;
+pub mod fraud_screening;
 pub mod hamming;
+pub mod joint_application;
 pub mod loan_enums;
+pub mod loan_statement;
+pub mod loan_tape;
+pub mod lookup_cache;
+pub mod mailer;
+pub mod outbox;
 pub mod parse_image_links;
 pub mod pg_notify_handle;
+pub mod pii;
+pub mod portfolio_analytics;
+pub mod record_diff;
+pub mod review_queue;
+pub mod servicing;
+pub mod underwriting;
@@ -0,0 +1,299 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use deadpool_redis::{redis::cmd, Pool as RedisPool};
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{FromRow, PgPool, Postgres, Transaction};
+use tokio::sync::{broadcast, Notify};
+
+use crate::error::AppError;
+
+/// Events that fail this many times stay in the outbox for someone to look at
+const MAX_ATTEMPTS: i32 = 10;
+const RELAY_BATCH: i64 = 50;
+/// How often the relay looks for retries and anything it wasn't woken for
+const RELAY_INTERVAL: Duration = Duration::from_secs(5);
+const RETRY_BASE: Duration = Duration::from_secs(5);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventType {
+    ApplicationSubmitted,
+    ApplicationStatusChanged,
+    OfferCreated,
+    LoanStatusChanged,
+}
+
+impl EventType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventType::ApplicationSubmitted => "application_submitted",
+            EventType::ApplicationStatusChanged => "application_status_changed",
+            EventType::OfferCreated => "offer_created",
+            EventType::LoanStatusChanged => "loan_status_changed",
+        }
+    }
+}
+
+impl std::fmt::Display for EventType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// One outbox row as it's published. Delivery is at least once, subscribers that can't
+/// handle a repeat should keep track of event_id.
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct OutboxEvent {
+    pub event_id: i64,
+    pub event_type: String,
+    /// The application or loan the event is about
+    pub aggregate_id: i32,
+    pub payload: Value,
+    pub created_at: DateTime<Utc>,
+}
+
+impl OutboxEvent {
+    pub fn is(&self, event_type: EventType) -> bool {
+        self.event_type == event_type.as_str()
+    }
+}
+
+#[async_trait]
+pub trait EventSink: Send + Sync {
+    /// Recorded in delivered_to, so it has to stay the same across restarts
+    fn name(&self) -> &'static str;
+    async fn publish(&self, event: &OutboxEvent) -> Result<(), AppError>;
+}
+
+/// In-process subscribers, including the SSE streams. Nobody listening counts as delivered.
+pub struct BroadcastSink {
+    pub events: broadcast::Sender<OutboxEvent>,
+}
+
+#[async_trait]
+impl EventSink for BroadcastSink {
+    fn name(&self) -> &'static str {
+        "broadcast"
+    }
+
+    async fn publish(&self, event: &OutboxEvent) -> Result<(), AppError> {
+        let _ = self.events.send(event.clone());
+        Ok(())
+    }
+}
+
+/// PUBLISHes the event as JSON on `events:<event_type>`
+pub struct RedisSink {
+    pub r_pool: RedisPool,
+}
+
+pub fn redis_channel(event: &OutboxEvent) -> String {
+    format!("events:{}", event.event_type)
+}
+
+#[async_trait]
+impl EventSink for RedisSink {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    async fn publish(&self, event: &OutboxEvent) -> Result<(), AppError> {
+        let mut con = self.r_pool.get().await.map_err(|err| {
+            AppError::GenericError(format!("Unable to get a Redis connection: {}", err))
+        })?;
+        let message = serde_json::to_string(event)
+            .map_err(|err| AppError::GenericError(format!("Unable to encode event: {}", err)))?;
+        cmd("PUBLISH")
+            .arg(redis_channel(event))
+            .arg(message)
+            .query_async::<_, ()>(&mut con)
+            .await
+            .map_err(|err| AppError::GenericError(format!("Unable to publish event: {}", err)))
+    }
+}
+
+/// Records an event inside the caller's transaction, so it's only published if the change
+/// it describes commits.
+pub async fn record_event<T: Serialize>(
+    tx: &mut Transaction<'_, Postgres>,
+    event_type: EventType,
+    aggregate_id: i32,
+    payload: &T,
+) -> Result<i64, AppError> {
+    let payload = serde_json::to_value(payload).map_err(|err| {
+        AppError::GenericError(format!("Unable to encode {} event: {}", event_type, err))
+    })?;
+    sqlx::query_scalar(
+        "INSERT INTO event_outbox (event_type, aggregate_id, payload) VALUES ($1, $2, $3) RETURNING event_id",
+    )
+    .bind(event_type.as_str())
+    .bind(aggregate_id)
+    .bind(payload)
+    .fetch_one(&mut **tx)
+    .await
+    .map_err(AppError::from)
+}
+
+/// Wait before the next try of an event that has failed `attempts` times, doubling each time
+fn retry_delay(attempts: i32) -> Duration {
+    RETRY_BASE
+        .saturating_mul(1 << attempts.clamp(0, 16))
+        .min(MAX_RETRY_DELAY)
+}
+
+#[derive(Debug, FromRow)]
+struct OutboxRow {
+    #[sqlx(flatten)]
+    event: OutboxEvent,
+    delivered_to: Vec<String>,
+    attempts: i32,
+}
+
+/// Publishes up to `limit` due events, oldest first, to each sink that doesn't have them yet.
+/// Returns how many are now fully delivered.
+pub async fn deliver_outbox_events(
+    pool: &PgPool,
+    sinks: &[Arc<dyn EventSink>],
+    limit: i64,
+) -> Result<usize, AppError> {
+    let mut tx = pool.begin().await?;
+    let rows = sqlx::query_as::<_, OutboxRow>(
+        "SELECT event_id, event_type, aggregate_id, payload, created_at, delivered_to, attempts
+        FROM event_outbox WHERE delivered_at IS NULL AND attempts < $1 AND next_attempt_at <= NOW()
+        ORDER BY event_id LIMIT $2 FOR UPDATE SKIP LOCKED",
+    )
+    .bind(MAX_ATTEMPTS)
+    .bind(limit)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut delivered = 0;
+    for row in rows {
+        let OutboxRow {
+            event,
+            mut delivered_to,
+            attempts,
+        } = row;
+        let mut errors = vec![];
+        for sink in sinks {
+            if delivered_to.iter().any(|name| name == sink.name()) {
+                continue;
+            }
+            match sink.publish(&event).await {
+                Ok(()) => delivered_to.push(sink.name().to_owned()),
+                Err(err) => errors.push(format!("{}: {:?}", sink.name(), err)),
+            }
+        }
+        if errors.is_empty() {
+            sqlx::query("UPDATE event_outbox SET delivered_to = $2, delivered_at = NOW(), attempts = attempts + 1, last_error = NULL WHERE event_id = $1")
+                .bind(event.event_id)
+                .bind(&delivered_to)
+                .execute(&mut *tx)
+                .await?;
+            delivered += 1;
+        } else {
+            let last_error = errors.join("; ");
+            tracing::warn!(event_id = event.event_id, error = %last_error, "Event not delivered");
+            sqlx::query("UPDATE event_outbox SET delivered_to = $2, attempts = attempts + 1, last_error = $3, next_attempt_at = NOW() + make_interval(secs => $4) WHERE event_id = $1")
+                .bind(event.event_id)
+                .bind(&delivered_to)
+                .bind(last_error)
+                .bind(retry_delay(attempts).as_secs_f64())
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
+    tx.commit().await?;
+    Ok(delivered)
+}
+
+/// Publishes outbox rows for as long as the server runs. It wakes when the outbox_event
+/// notification comes in and every RELAY_INTERVAL regardless, for retries.
+pub struct OutboxRelay {
+    pool: PgPool,
+    sinks: Vec<Arc<dyn EventSink>>,
+    wake: Arc<Notify>,
+}
+
+impl OutboxRelay {
+    pub fn new(pool: PgPool, sinks: Vec<Arc<dyn EventSink>>) -> Self {
+        Self {
+            pool,
+            sinks,
+            wake: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Notify this when new events have been committed
+    pub fn waker(&self) -> Arc<Notify> {
+        self.wake.clone()
+    }
+
+    pub async fn run(self) {
+        loop {
+            match deliver_outbox_events(&self.pool, &self.sinks, RELAY_BATCH).await {
+                Ok(0) => {}
+                // There may be more waiting
+                Ok(delivered) if delivered as i64 == RELAY_BATCH => continue,
+                Ok(delivered) => tracing::debug!(delivered, "Outbox events delivered"),
+                Err(err) => tracing::error!(error = ?err, "Outbox relay failed"),
+            }
+            tokio::select! {
+                _ = self.wake.notified() => {}
+                _ = tokio::time::sleep(RELAY_INTERVAL) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn event() -> OutboxEvent {
+        OutboxEvent {
+            event_id: 12,
+            event_type: EventType::OfferCreated.to_string(),
+            aggregate_id: 4,
+            payload: json!({"application_id": 4}),
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn retries_back_off_to_an_hour() {
+        let delays: Vec<u64> = [0, 1, 2, 9, 10, 40]
+            .map(|attempts| retry_delay(attempts).as_secs())
+            .to_vec();
+        assert_eq!(delays, [5, 10, 20, 2560, 3600, 3600]);
+    }
+
+    #[tokio::test]
+    async fn broadcast_reaches_subscribers_and_doesnt_need_any() {
+        let (events, _) = broadcast::channel(4);
+        let sink = BroadcastSink { events };
+        sink.publish(&event()).await.unwrap();
+
+        let mut subscriber = sink.events.subscribe();
+        sink.publish(&event()).await.unwrap();
+        let received = subscriber.recv().await.unwrap();
+        assert_eq!(received.event_id, 12);
+        assert!(received.is(EventType::OfferCreated));
+        assert!(!received.is(EventType::LoanStatusChanged));
+    }
+
+    #[test]
+    fn events_go_out_on_their_type_channel() {
+        assert_eq!(redis_channel(&event()), "events:offer_created");
+        assert_eq!(
+            serde_json::to_value(EventType::ApplicationSubmitted).unwrap(),
+            json!(EventType::ApplicationSubmitted.as_str())
+        );
+    }
+}
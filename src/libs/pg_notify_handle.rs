@@ -1,21 +1,18 @@
-use std::fmt::Debug;
+use std::{collections::HashMap, fmt::Debug, future::Future, time::Duration};
 
-use serde::Deserialize;
+use futures_util::future::BoxFuture;
+use serde::{de::DeserializeOwned, Deserialize};
+use sqlx::{error::Error, postgres::PgListener, PgPool};
+use tracing::{debug, info, warn};
 
-use serde::de::DeserializeOwned;
-use sqlx::error::Error;
-use sqlx::postgres::PgListener;
-use sqlx::Pool;
-use sqlx::Postgres;
-use tracing::info;
-
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum ActionType {
     INSERT,
     UPDATE,
     DELETE,
 }
 
+/// Sent on `new_app_notification` by the applications insert trigger
 #[derive(Deserialize, Debug)]
 pub struct Payload {
     pub table: String,
@@ -24,27 +21,233 @@ pub struct Payload {
     pub first_name: String,
 }
 
-pub async fn start_listening<T: DeserializeOwned + Sized + Debug>(
-    // pool: &Pool<Postgres>,
-    mut lst: PgListener,
-    channels: Vec<&str>,
-    call_back: impl Fn(T),
-) -> Result<(), Error> {
-    // let mut listener = PgListener::connect_with(pool).await.unwrap();
-    // listener.listen_all(channels).await?;
-    loop {
-        while let Some(notification) = lst.try_recv().await? {
-            info!(
-                "Getting notification with payload: {:?} from channel {:?}",
-                notification.payload(),
-                notification.channel()
-            );
-
-            let strr = notification.payload().to_owned();
-            let payload: T = serde_json::from_str::<T>(&strr).unwrap();
-            info!("des payload is {:?}", payload);
-
-            call_back(payload);
+/// Sent on `table_update` by the cache invalidation triggers
+#[derive(Deserialize, Debug)]
+pub struct TableUpdate {
+    pub table: String,
+    pub action_type: ActionType,
+    /// The changed row's primary key
+    pub id: Option<i32>,
+}
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Doubling delay between reconnect attempts, back to the minimum once one succeeds
+#[derive(Debug)]
+struct Backoff {
+    next: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff { next: MIN_BACKOFF }
+    }
+}
+
+impl Backoff {
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(MAX_BACKOFF);
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.next = MIN_BACKOFF;
+    }
+}
+
+type Handler = Box<dyn Fn(&str) -> Result<BoxFuture<'static, ()>, serde_json::Error> + Send + Sync>;
+type Hook = Box<dyn Fn() -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// Routes pg_notify messages by channel to a handler that takes that channel's payload type
+#[derive(Default)]
+pub struct NotifyDispatcher {
+    handlers: HashMap<String, Handler>,
+    reconnect_hook: Option<Hook>,
+}
+
+impl NotifyDispatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decodes `channel`'s payloads as `T` and hands them to `handler`, replacing any handler
+    /// already registered for it
+    pub fn on<T, F, Fut>(mut self, channel: &str, handler: F) -> Self
+    where
+        T: DeserializeOwned + Debug + Send + 'static,
+        F: Fn(T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.handlers.insert(
+            channel.to_owned(),
+            Box::new(move |payload| {
+                let payload = serde_json::from_str::<T>(payload)?;
+                debug!(?payload, "Decoded notification");
+                Ok(Box::pin(handler(payload)))
+            }),
+        );
+        self
+    }
+
+    /// Runs `hook` every time the channels are subscribed, the first time included. Whatever was
+    /// sent while nothing listened is lost, so the hook should drop anything it might have changed.
+    pub fn on_reconnect<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.reconnect_hook = Some(Box::new(move || Box::pin(hook())));
+        self
+    }
+
+    pub fn channels(&self) -> Vec<&str> {
+        let mut channels: Vec<&str> = self.handlers.keys().map(String::as_str).collect();
+        channels.sort_unstable();
+        channels
+    }
+
+    /// Runs `channel`'s handler on `payload`. A payload that doesn't decode, or comes in on a
+    /// channel nothing handles, is logged and skipped; returns whether a handler ran.
+    pub async fn dispatch(&self, channel: &str, payload: &str) -> bool {
+        let Some(handler) = self.handlers.get(channel) else {
+            warn!(channel, "No handler for notification");
+            return false;
+        };
+        match handler(payload) {
+            Ok(handled) => {
+                handled.await;
+                true
+            }
+            Err(err) => {
+                warn!(channel, payload, error = %err, "Skipping malformed notification");
+                false
+            }
+        }
+    }
+
+    async fn subscribe(&self, pool: &PgPool) -> Result<PgListener, Error> {
+        let mut listener = PgListener::connect_with(pool).await?;
+        listener.listen_all(self.channels()).await?;
+        info!(channels = ?self.channels(), "Listening for notifications");
+        Ok(listener)
+    }
+
+    /// Listens on every registered channel for as long as the app runs. A dropped connection is
+    /// replaced, with backoff while the database is unreachable, and the channels subscribed again.
+    /// Anything sent while disconnected is lost, see `on_reconnect`.
+    pub async fn run(self, pool: PgPool) {
+        let mut backoff = Backoff::default();
+        loop {
+            let mut listener = match self.subscribe(&pool).await {
+                Ok(listener) => {
+                    backoff.reset();
+                    if let Some(hook) = &self.reconnect_hook {
+                        hook().await;
+                    }
+                    listener
+                }
+                Err(err) => {
+                    let delay = backoff.next_delay();
+                    warn!(error = ?err, ?delay, "Unable to listen for notifications, retrying");
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+            };
+            loop {
+                match listener.try_recv().await {
+                    Ok(Some(notification)) => {
+                        self.dispatch(notification.channel(), notification.payload())
+                            .await;
+                    }
+                    Ok(None) => {
+                        warn!("Notification connection lost, reconnecting");
+                        break;
+                    }
+                    Err(err) => {
+                        warn!(error = ?err, "Notification listener failed, reconnecting");
+                        break;
+                    }
+                }
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    fn recording_dispatcher(seen: Arc<Mutex<Vec<String>>>) -> NotifyDispatcher {
+        let app_seen = seen.clone();
+        NotifyDispatcher::new()
+            .on("new_app_notification", move |payload: Payload| {
+                let seen = app_seen.clone();
+                async move { seen.lock().unwrap().push(payload.application_slug) }
+            })
+            .on("table_update", move |update: TableUpdate| {
+                let seen = seen.clone();
+                async move { seen.lock().unwrap().push(update.table) }
+            })
+    }
+
+    #[tokio::test]
+    async fn notifications_go_to_their_channels_handler() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let dispatcher = recording_dispatcher(seen.clone());
+        assert_eq!(
+            dispatcher.channels(),
+            ["new_app_notification", "table_update"]
+        );
+        assert!(
+            dispatcher
+                .dispatch(
+                    "new_app_notification",
+                    r#"{"table":"applications","id":4,"application_slug":"abc","first_name":"Ann","action_type":"INSERT"}"#,
+                )
+                .await
+        );
+        assert!(
+            dispatcher
+                .dispatch(
+                    "table_update",
+                    r#"{"table":"offers","action_type":"DELETE"}"#
+                )
+                .await
+        );
+        assert_eq!(*seen.lock().unwrap(), ["abc", "offers"]);
+    }
+
+    #[tokio::test]
+    async fn bad_payloads_are_skipped() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let dispatcher = recording_dispatcher(seen.clone());
+        assert!(
+            !dispatcher
+                .dispatch("new_app_notification", "not json")
+                .await
+        );
+        assert!(
+            !dispatcher
+                .dispatch(
+                    "new_app_notification",
+                    r#"{"table":"offers","action_type":"INSERT"}"#
+                )
+                .await
+        );
+        assert!(!dispatcher.dispatch("loans_changed", "{}").await);
+        assert!(seen.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let mut backoff = Backoff::default();
+        let delays: Vec<u64> = (0..8).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), MIN_BACKOFF);
+    }
+}
@@ -0,0 +1,608 @@
+use std::{collections::BTreeMap, env, fs, io::Write, path::Path, sync::OnceLock};
+
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono::NaiveDate;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::{FromRow, PgPool};
+
+use crate::{error::AppError, libs::fraud_screening::SSN_LOOKBACK_DAYS};
+
+pub const DEFAULT_KEY_FILE: &str = "keys/pii_keys.json";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+/// Associated data for wrapped data keys. Kept apart from the field contexts so a key can be
+/// rewrapped without knowing which column it came from.
+const DEK_CONTEXT: &[u8] = b"pii-data-key";
+
+/// Stands in for a PII field in `Debug` output
+pub struct Redacted;
+
+impl std::fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[redacted]")
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VersionedSecret {
+    version: u32,
+    /// Base64
+    secret: String,
+}
+
+/// The key file on disk. Keys are only ever added, the old versions are needed to open what was
+/// written under them. There is one pepper for good, see the pii_protection migration.
+#[derive(Debug, Serialize, Deserialize)]
+struct KeyFile {
+    current_key: u32,
+    keys: Vec<VersionedSecret>,
+    current_pepper: u32,
+    peppers: Vec<VersionedSecret>,
+}
+
+/// Key encryption keys and lookup peppers by version
+pub struct Keyring {
+    current_key: u32,
+    keys: BTreeMap<u32, Vec<u8>>,
+    current_pepper: u32,
+    peppers: BTreeMap<u32, Vec<u8>>,
+}
+
+impl std::fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keyring")
+            .field("current_key", &self.current_key)
+            .field("key_versions", &self.keys.keys().collect::<Vec<_>>())
+            .field("current_pepper", &self.current_pepper)
+            .field("pepper_versions", &self.peppers.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+fn pii_err(msg: impl Into<String>) -> AppError {
+    AppError::GenericError(msg.into())
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+fn cipher(key: &[u8]) -> Result<Aes256Gcm, AppError> {
+    Aes256Gcm::new_from_slice(key).map_err(|_| pii_err("PII key is not 32 bytes"))
+}
+
+/// nonce || ciphertext
+fn encrypt(key: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, AppError> {
+    let nonce = random_bytes(NONCE_LEN);
+    let mut sealed = nonce.clone();
+    sealed.extend(
+        cipher(key)?
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| pii_err("PII encryption failed"))?,
+    );
+    Ok(sealed)
+}
+
+fn decrypt(key: &[u8], sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, AppError> {
+    if sealed.len() < NONCE_LEN {
+        return Err(pii_err("Sealed PII is truncated"));
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    cipher(key)?
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| pii_err("Sealed PII failed to decrypt"))
+}
+
+fn decode(part: &str) -> Result<Vec<u8>, AppError> {
+    STANDARD
+        .decode(part)
+        .map_err(|_| pii_err("Sealed PII is not base64"))
+}
+
+/// `<key version>$<wrapped data key>$<ciphertext>`
+struct Envelope {
+    key_version: u32,
+    wrapped_key: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+impl Envelope {
+    fn parse(sealed: &str) -> Result<Self, AppError> {
+        let mut parts = sealed.split('$');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(version), Some(wrapped_key), Some(ciphertext), None) => Ok(Envelope {
+                key_version: version
+                    .parse()
+                    .map_err(|_| pii_err("Sealed PII has no key version"))?,
+                wrapped_key: decode(wrapped_key)?,
+                ciphertext: decode(ciphertext)?,
+            }),
+            _ => Err(pii_err("Sealed PII is malformed")),
+        }
+    }
+
+    fn to_sealed(&self) -> String {
+        format!(
+            "{}${}${}",
+            self.key_version,
+            STANDARD.encode(&self.wrapped_key),
+            STANDARD.encode(&self.ciphertext)
+        )
+    }
+}
+
+impl Keyring {
+    /// A keyring with one fresh key and pepper, for a new install
+    pub fn generate() -> Self {
+        Keyring {
+            current_key: 1,
+            keys: BTreeMap::from([(1, random_bytes(KEY_LEN))]),
+            current_pepper: 1,
+            peppers: BTreeMap::from([(1, random_bytes(KEY_LEN))]),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, AppError> {
+        let s = fs::read_to_string(path).map_err(|err| {
+            pii_err(format!(
+                "Unable to read PII key file {}: {}. Create one with `cargo run -- pii_new_key`",
+                path.display(),
+                err
+            ))
+        })?;
+        let file: KeyFile = serde_json::from_str(&s)
+            .map_err(|err| pii_err(format!("Invalid PII key file: {}", err)))?;
+        let secrets = |secrets: Vec<VersionedSecret>| {
+            secrets
+                .into_iter()
+                .map(|secret| Ok((secret.version, decode(&secret.secret)?)))
+                .collect::<Result<BTreeMap<u32, Vec<u8>>, AppError>>()
+        };
+        let keyring = Keyring {
+            current_key: file.current_key,
+            keys: secrets(file.keys)?,
+            current_pepper: file.current_pepper,
+            peppers: secrets(file.peppers)?,
+        };
+        if keyring.keys.values().any(|key| key.len() != KEY_LEN) {
+            return Err(pii_err("PII keys must be 32 bytes"));
+        }
+        if !keyring.keys.contains_key(&keyring.current_key)
+            || !keyring.peppers.contains_key(&keyring.current_pepper)
+        {
+            return Err(pii_err(
+                "PII key file names a current version it doesn't have",
+            ));
+        }
+        Ok(keyring)
+    }
+
+    /// Written readable by the owner only
+    pub fn save(&self, path: &Path) -> Result<(), AppError> {
+        let secrets = |secrets: &BTreeMap<u32, Vec<u8>>| {
+            secrets
+                .iter()
+                .map(|(version, secret)| VersionedSecret {
+                    version: *version,
+                    secret: STANDARD.encode(secret),
+                })
+                .collect()
+        };
+        let file = KeyFile {
+            current_key: self.current_key,
+            keys: secrets(&self.keys),
+            current_pepper: self.current_pepper,
+            peppers: secrets(&self.peppers),
+        };
+        let io_err = |err: std::io::Error| pii_err(err.to_string());
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(io_err)?;
+        }
+        let json = serde_json::to_string_pretty(&file).map_err(|err| pii_err(err.to_string()))?;
+        // Written beside the old file and renamed over it, so a crash leaves one or the other
+        // and the keys are never readable by anyone else, not even for a moment
+        let tmp = path.with_extension("json.tmp");
+        // A leftover from a failed save may have other permissions, mode only applies on create
+        let _ = fs::remove_file(&tmp);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut out = options.open(&tmp).map_err(io_err)?;
+        out.write_all(json.as_bytes()).map_err(io_err)?;
+        out.sync_all().map_err(io_err)?;
+        fs::rename(&tmp, path).map_err(io_err)?;
+        Ok(())
+    }
+
+    /// The keyring in PII_KEY_FILE, or DEFAULT_KEY_FILE
+    pub fn load_active() -> Result<Self, AppError> {
+        Self::load(Path::new(&key_file_path()))
+    }
+
+    pub fn current_key(&self) -> u32 {
+        self.current_key
+    }
+
+    /// Adds a key and makes it current. New values are sealed with it, existing ones open with
+    /// their old key until `rewrap`ped.
+    pub fn rotate_key(&mut self) -> u32 {
+        let version = self.keys.keys().max().copied().unwrap_or(0) + 1;
+        self.keys.insert(version, random_bytes(KEY_LEN));
+        self.current_key = version;
+        version
+    }
+
+    fn key(&self, version: u32) -> Result<&[u8], AppError> {
+        self.keys
+            .get(&version)
+            .map(Vec::as_slice)
+            .ok_or_else(|| pii_err(format!("No PII key version {}", version)))
+    }
+
+    fn hmac(&self, pepper_version: u32, pepper: &[u8], value: &str) -> String {
+        let mut mac =
+            <Hmac<Sha256> as Mac>::new_from_slice(pepper).expect("HMAC takes a key of any length");
+        mac.update(value.as_bytes());
+        let digest: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        format!("{}${}", pepper_version, digest)
+    }
+
+    /// Peppered HMAC-SHA256 of `value`, for equality lookups. The pepper is never rotated, stored
+    /// keys are only ever compared with each other.
+    pub fn lookup_key(&self, value: &str) -> String {
+        self.hmac(
+            self.current_pepper,
+            &self.peppers[&self.current_pepper],
+            value,
+        )
+    }
+
+    /// Encrypts `plaintext` under a fresh data key, which is itself encrypted under the current
+    /// key. `context` (e.g. "applications.dob") is bound in, so a value can't be moved to
+    /// another column and still open.
+    pub fn seal(&self, context: &str, plaintext: &str) -> Result<String, AppError> {
+        let data_key = random_bytes(KEY_LEN);
+        Ok(Envelope {
+            key_version: self.current_key,
+            wrapped_key: encrypt(self.key(self.current_key)?, &data_key, DEK_CONTEXT)?,
+            ciphertext: encrypt(&data_key, plaintext.as_bytes(), context.as_bytes())?,
+        }
+        .to_sealed())
+    }
+
+    pub fn open(&self, context: &str, sealed: &str) -> Result<String, AppError> {
+        let envelope = Envelope::parse(sealed)?;
+        let data_key = decrypt(
+            self.key(envelope.key_version)?,
+            &envelope.wrapped_key,
+            DEK_CONTEXT,
+        )?;
+        let plaintext = decrypt(&data_key, &envelope.ciphertext, context.as_bytes())?;
+        String::from_utf8(plaintext).map_err(|_| pii_err("Sealed PII is not UTF-8"))
+    }
+
+    /// The sealed value or, for rows from before encryption, the plaintext column
+    pub fn open_or(
+        &self,
+        context: &str,
+        sealed: Option<&str>,
+        plaintext: Option<String>,
+    ) -> Result<Option<String>, AppError> {
+        match sealed {
+            Some(sealed) => self.open(context, sealed).map(Some),
+            None => Ok(plaintext),
+        }
+    }
+
+    /// As `open_or`, for dates sealed as YYYY-MM-DD
+    pub fn open_date(
+        &self,
+        context: &str,
+        sealed: Option<&str>,
+        plaintext: Option<NaiveDate>,
+    ) -> Result<Option<NaiveDate>, AppError> {
+        match sealed {
+            Some(sealed) => NaiveDate::parse_from_str(&self.open(context, sealed)?, "%Y-%m-%d")
+                .map(Some)
+                .map_err(|_| pii_err("Sealed date is not YYYY-MM-DD")),
+            None => Ok(plaintext),
+        }
+    }
+
+    /// None for a blank value, there's nothing to protect
+    pub fn seal_opt(&self, context: &str, plaintext: &str) -> Result<Option<String>, AppError> {
+        match plaintext.trim() {
+            "" => Ok(None),
+            plaintext => self.seal(context, plaintext).map(Some),
+        }
+    }
+
+    /// Re-encrypts the data key under the current key, leaving the ciphertext alone. None if
+    /// it's already current.
+    pub fn rewrap(&self, sealed: &str) -> Result<Option<String>, AppError> {
+        let mut envelope = Envelope::parse(sealed)?;
+        if envelope.key_version == self.current_key {
+            return Ok(None);
+        }
+        let data_key = decrypt(
+            self.key(envelope.key_version)?,
+            &envelope.wrapped_key,
+            DEK_CONTEXT,
+        )?;
+        envelope.wrapped_key = encrypt(self.key(self.current_key)?, &data_key, DEK_CONTEXT)?;
+        envelope.key_version = self.current_key;
+        Ok(Some(envelope.to_sealed()))
+    }
+}
+
+pub fn key_file_path() -> String {
+    env::var("PII_KEY_FILE").unwrap_or_else(|_| DEFAULT_KEY_FILE.to_owned())
+}
+
+static KEYRING: OnceLock<Result<Keyring, String>> = OnceLock::new();
+
+/// The active keyring, loaded on first use. A missing or bad key file fails every call rather
+/// than letting PII be written in the clear.
+pub fn keyring() -> Result<&'static Keyring, AppError> {
+    KEYRING
+        .get_or_init(|| Keyring::load_active().map_err(|err| err.to_string()))
+        .as_ref()
+        .map_err(|err| pii_err(err.clone()))
+}
+
+/// SSN with the dashes taken out
+pub fn ssn_digits(ssn: &str) -> String {
+    ssn.chars().filter(char::is_ascii_digit).collect()
+}
+
+pub fn ssn_last4(ssn: &str) -> String {
+    let digits = ssn_digits(ssn);
+    digits[digits.len().saturating_sub(4)..].to_owned()
+}
+
+/// Sealed columns by table, with the key column used to update them
+const SEALED_COLUMNS: [(&str, &str, &[&str]); 3] = [
+    (
+        "applications",
+        "application_id",
+        &[
+            "ssn_last4_enc",
+            "dob_enc",
+            "phone_enc",
+            "address_one_enc",
+            "address_two_enc",
+        ],
+    ),
+    (
+        "co_borrowers",
+        "co_borrower_id",
+        &["ssn_last4_enc", "dob_enc"],
+    ),
+    ("application_drafts", "draft_id", &["data_enc"]),
+];
+
+#[derive(Debug, FromRow)]
+struct SealedValue {
+    id: i32,
+    sealed: String,
+}
+
+/// Moves every sealed column onto the current key. Returns how many values were rewrapped.
+pub async fn rewrap_all(pool: &PgPool, keyring: &Keyring) -> Result<usize, AppError> {
+    let current = format!("{}$%", keyring.current_key);
+    let mut rewrapped = 0;
+    for (table, id_column, columns) in SEALED_COLUMNS {
+        for column in columns {
+            let rows = sqlx::query_as::<_, SealedValue>(&format!(
+                "SELECT {id_column} AS id, {column} AS sealed FROM {table}
+                WHERE {column} IS NOT NULL AND {column} NOT LIKE $1"
+            ))
+            .bind(&current)
+            .fetch_all(pool)
+            .await?;
+            for row in rows {
+                if let Some(sealed) = keyring.rewrap(&row.sealed)? {
+                    sqlx::query(&format!(
+                        "UPDATE {table} SET {column} = $1 WHERE {id_column} = $2"
+                    ))
+                    .bind(sealed)
+                    .bind(row.id)
+                    .execute(pool)
+                    .await?;
+                    rewrapped += 1;
+                }
+            }
+        }
+    }
+    Ok(rewrapped)
+}
+
+#[derive(Debug, FromRow)]
+struct PlaintextApplication {
+    application_id: i32,
+    dob: Option<NaiveDate>,
+    phone: Option<String>,
+    address_one: Option<String>,
+    address_two: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct BackfillReport {
+    pub applications: usize,
+    pub co_borrowers: usize,
+    /// Legacy SSN digests cleared because they're past the fraud screening lookback
+    pub ssn_digests_cleared: u64,
+}
+
+/// Seals the plaintext PII of rows from before encryption and clears the plaintext.
+pub async fn backfill(pool: &PgPool, keyring: &Keyring) -> Result<BackfillReport, AppError> {
+    let mut report = BackfillReport::default();
+    let rows = sqlx::query_as::<_, PlaintextApplication>(
+        "SELECT application_id, dob, phone, address_one, address_two FROM applications
+        WHERE dob IS NOT NULL OR phone IS NOT NULL OR address_one IS NOT NULL OR address_two IS NOT NULL",
+    )
+    .fetch_all(pool)
+    .await?;
+    for row in rows {
+        let seal = |context: &str, value: Option<String>| match value {
+            Some(value) => keyring.seal_opt(context, &value),
+            None => Ok(None),
+        };
+        sqlx::query(
+            "UPDATE applications SET
+                dob_enc = COALESCE($2, dob_enc), phone_enc = COALESCE($3, phone_enc),
+                address_one_enc = COALESCE($4, address_one_enc), address_two_enc = COALESCE($5, address_two_enc),
+                dob = NULL, phone = NULL, address_one = NULL, address_two = NULL
+            WHERE application_id = $1",
+        )
+        .bind(row.application_id)
+        .bind(seal("applications.dob", row.dob.map(|dob| dob.to_string()))?)
+        .bind(seal("applications.phone", row.phone)?)
+        .bind(seal("applications.address_one", row.address_one)?)
+        .bind(seal("applications.address_two", row.address_two)?)
+        .execute(pool)
+        .await?;
+        report.applications += 1;
+    }
+
+    let co_borrowers = sqlx::query_as::<_, (i32, NaiveDate)>(
+        "SELECT co_borrower_id, dob FROM co_borrowers WHERE dob IS NOT NULL",
+    )
+    .fetch_all(pool)
+    .await?;
+    for (co_borrower_id, dob) in co_borrowers {
+        sqlx::query("UPDATE co_borrowers SET dob_enc = $2, dob = NULL WHERE co_borrower_id = $1")
+            .bind(co_borrower_id)
+            .bind(keyring.seal("co_borrowers.dob", &dob.to_string())?)
+            .execute(pool)
+            .await?;
+        report.co_borrowers += 1;
+    }
+
+    for table in ["applications", "co_borrowers"] {
+        report.ssn_digests_cleared += sqlx::query(&format!(
+            "UPDATE {table} SET ssn_nacl = NULL
+            WHERE ssn_nacl IS NOT NULL AND created_at < NOW() - make_interval(days => $1)"
+        ))
+        .bind(SSN_LOOKBACK_DAYS)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed_values_open_only_in_their_context() {
+        let keyring = Keyring::generate();
+        let sealed = keyring.seal("applications.dob", "1987-01-01").unwrap();
+        assert!(sealed.starts_with("1$"));
+        assert!(!sealed.contains("1987"));
+        assert_eq!(
+            keyring.open("applications.dob", &sealed).unwrap(),
+            "1987-01-01"
+        );
+        assert!(keyring.open("applications.phone", &sealed).is_err());
+        // Fresh data key and nonce every time
+        assert_ne!(
+            keyring.seal("applications.dob", "1987-01-01").unwrap(),
+            sealed
+        );
+        assert!(Keyring::generate()
+            .open("applications.dob", &sealed)
+            .is_err());
+    }
+
+    #[test]
+    fn rotation_rewraps_without_losing_old_values() {
+        let mut keyring = Keyring::generate();
+        let old = keyring.seal("applications.phone", "555-555-5555").unwrap();
+        assert_eq!(keyring.rotate_key(), 2);
+        assert_eq!(
+            keyring.open("applications.phone", &old).unwrap(),
+            "555-555-5555"
+        );
+        let rewrapped = keyring.rewrap(&old).unwrap().unwrap();
+        assert!(rewrapped.starts_with("2$"));
+        assert_eq!(rewrapped.rsplit('$').next(), old.rsplit('$').next());
+        assert_eq!(
+            keyring.open("applications.phone", &rewrapped).unwrap(),
+            "555-555-5555"
+        );
+        assert_eq!(keyring.rewrap(&rewrapped).unwrap(), None);
+    }
+
+    #[test]
+    fn lookup_keys_are_peppered_and_versioned() {
+        let keyring = Keyring::generate();
+        let key = keyring.lookup_key("123456789");
+        assert!(key.starts_with("1$"));
+        assert_eq!(key, keyring.lookup_key("123456789"));
+        assert_ne!(key, Keyring::generate().lookup_key("123456789"));
+    }
+
+    #[test]
+    fn key_file_round_trips() {
+        let path = env::temp_dir().join(format!("pii_keys_{}.json", std::process::id()));
+        let mut keyring = Keyring::generate();
+        keyring.rotate_key();
+        keyring.save(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let loaded = Keyring::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.current_key(), 2);
+        let sealed = keyring.seal("co_borrowers.dob", "1988-04-12").unwrap();
+        assert_eq!(
+            loaded.open("co_borrowers.dob", &sealed).unwrap(),
+            "1988-04-12"
+        );
+        assert_eq!(loaded.lookup_key("x"), keyring.lookup_key("x"));
+        assert!(!format!("{:?}", loaded).contains(&STANDARD.encode(&loaded.keys[&1])));
+    }
+
+    #[test]
+    fn ssn_helpers() {
+        assert_eq!(ssn_digits("123-45-6789"), "123456789");
+        assert_eq!(ssn_last4("123-45-6789"), "6789");
+        assert_eq!(format!("{:?}", Redacted), "[redacted]");
+    }
+}
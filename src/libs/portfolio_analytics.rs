@@ -0,0 +1,399 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use deadpool_redis::{redis::cmd, Pool as RedisPool};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+
+use crate::{
+    error::AppError,
+    libs::pg_notify_handle::TableUpdate,
+    models::loan::{LoanPurpose, LoanStatus},
+};
+
+pub const PORTFOLIO_CACHE_KEY: &str = "portfolio:analytics";
+/// Backstop in case a `loans_changed` notification is missed
+const PORTFOLIO_CACHE_TTL_SECONDS: u64 = 15 * 60;
+/// Months of roll rates shown, ending with the current month
+const ROLL_RATE_MONTHS: i32 = 12;
+
+// Tapes and seeds use "Mar-2018". Anything else is left out of the month based queries
+// rather than failing to_date
+const ISSUE_MONTH_PATTERN: &str = "'^[A-Z][a-z]{2}-[0-9]{4}$'";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, FromRow)]
+pub struct PortfolioSummary {
+    pub loans: i64,
+    /// Not FullyPaid or ChargedOff
+    pub active_loans: i64,
+    pub outstanding_principal: f64,
+    /// Share of all loans, by count
+    pub charge_off_rate: f64,
+    /// Share of all originated principal
+    pub charge_off_amount_rate: f64,
+    /// Interest rate weighted by outstanding principal over active loans
+    pub weighted_average_apr: f64,
+}
+
+/// Outstanding principal on active loans for one grade, purpose or servicer.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, FromRow)]
+pub struct PrincipalBreakdown {
+    pub label: String,
+    pub loans: i64,
+    pub outstanding: f64,
+    pub share: f64,
+    pub weighted_average_apr: f64,
+}
+
+/// Of the loans in `from` at the end of the previous month, how many were in `to` at the end of `month`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RollRate {
+    pub month: NaiveDate,
+    pub from: String,
+    pub to: String,
+    pub loans: i64,
+    pub rate: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, FromRow)]
+pub struct Vintage {
+    pub issue_month: String,
+    pub loans: i64,
+    pub originated: f64,
+    pub outstanding: f64,
+    pub charge_off_rate: f64,
+    /// Currently in grace or late
+    pub delinquent_rate: f64,
+}
+
+/// Cumulative charged off principal as a share of the vintage's originated principal,
+/// by months on book at charge off. Only charge offs with a status history date count.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, FromRow)]
+pub struct VintagePoint {
+    pub issue_month: String,
+    pub months_on_book: i32,
+    pub cumulative_charge_off_rate: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PortfolioAnalytics {
+    pub generated_at: DateTime<Utc>,
+    pub summary: PortfolioSummary,
+    pub by_grade: Vec<PrincipalBreakdown>,
+    pub by_purpose: Vec<PrincipalBreakdown>,
+    pub by_servicer: Vec<PrincipalBreakdown>,
+    pub roll_rates: Vec<RollRate>,
+    pub vintages: Vec<Vintage>,
+    pub vintage_curves: Vec<VintagePoint>,
+}
+
+pub fn status_label(code: i32) -> String {
+    LoanStatus::try_from(code)
+        .map(|status| status.to_string())
+        .unwrap_or_else(|_| format!("Unknown ({})", code))
+}
+
+pub fn purpose_label(code: i32) -> String {
+    LoanPurpose::try_from(code)
+        .map(|purpose| purpose.to_string())
+        .unwrap_or_else(|_| format!("Unknown ({})", code))
+}
+
+/// `$1` FullyPaid, `$2` ChargedOff
+const ACTIVE: &str = "loan_status NOT IN ($1, $2)";
+
+async fn summary(pool: &PgPool) -> Result<PortfolioSummary, AppError> {
+    sqlx::query_as::<_, PortfolioSummary>(&format!(
+        "SELECT COUNT(*) AS loans,
+            COUNT(*) FILTER (WHERE {active}) AS active_loans,
+            COALESCE(SUM(balance::FLOAT8) FILTER (WHERE {active}), 0) AS outstanding_principal,
+            COALESCE(COUNT(*) FILTER (WHERE loan_status = $2)::FLOAT8 / NULLIF(COUNT(*), 0), 0) AS charge_off_rate,
+            COALESCE(SUM(loan_amount::FLOAT8) FILTER (WHERE loan_status = $2)
+                / NULLIF(SUM(loan_amount::FLOAT8), 0), 0) AS charge_off_amount_rate,
+            COALESCE(SUM(interest_rate::FLOAT8 * balance::FLOAT8) FILTER (WHERE {active})
+                / NULLIF(SUM(balance::FLOAT8) FILTER (WHERE {active}), 0), 0) AS weighted_average_apr
+        FROM loans",
+        active = ACTIVE
+    ))
+    .bind(LoanStatus::FullyPaid as i32)
+    .bind(LoanStatus::ChargedOff as i32)
+    .fetch_one(pool)
+    .await
+    .map_err(AppError::from)
+}
+
+/// `label_sql` is the grouping expression over `loans l`, and `servicers s` when joined.
+async fn breakdown(
+    pool: &PgPool,
+    label_sql: &str,
+    join: &str,
+) -> Result<Vec<PrincipalBreakdown>, AppError> {
+    sqlx::query_as::<_, PrincipalBreakdown>(&format!(
+        "SELECT {label}::TEXT AS label, COUNT(*) AS loans,
+            COALESCE(SUM(l.balance::FLOAT8), 0) AS outstanding,
+            COALESCE(SUM(l.balance::FLOAT8) / NULLIF(SUM(SUM(l.balance::FLOAT8)) OVER (), 0), 0) AS share,
+            COALESCE(SUM(l.interest_rate::FLOAT8 * l.balance::FLOAT8) / NULLIF(SUM(l.balance::FLOAT8), 0), 0)
+                AS weighted_average_apr
+        FROM loans l {join}
+        WHERE l.{active}
+        GROUP BY {label}
+        ORDER BY outstanding DESC",
+        label = label_sql,
+        join = join,
+        active = ACTIVE
+    ))
+    .bind(LoanStatus::FullyPaid as i32)
+    .bind(LoanStatus::ChargedOff as i32)
+    .fetch_all(pool)
+    .await
+    .map_err(AppError::from)
+}
+
+/// Status at each month end comes from loan_status_history: the last change on or before it,
+/// else the `from_status` of the first change after it, else the loan's current status.
+async fn roll_rates(pool: &PgPool, as_of: NaiveDate) -> Result<Vec<RollRate>, AppError> {
+    #[derive(FromRow)]
+    struct Row {
+        month: NaiveDate,
+        from_status: i32,
+        to_status: i32,
+        loans: i64,
+        rate: f64,
+    }
+    let rows = sqlx::query_as::<_, Row>(&format!(
+        "WITH months AS (
+            SELECT month_start::DATE AS month_start,
+                (month_start + INTERVAL '1 month' - INTERVAL '1 day')::DATE AS month_end
+            FROM generate_series(
+                date_trunc('month', $1::DATE) - make_interval(months => $2),
+                date_trunc('month', $1::DATE),
+                INTERVAL '1 month'
+            ) AS month_start
+        ),
+        status_at AS (
+            SELECT m.month_start, l.loan_id, COALESCE(
+                (SELECT h.to_status FROM loan_status_history h
+                    WHERE h.loan_id = l.loan_id AND h.as_of <= m.month_end
+                    ORDER BY h.as_of DESC, h.history_id DESC LIMIT 1),
+                (SELECT h.from_status FROM loan_status_history h
+                    WHERE h.loan_id = l.loan_id AND h.as_of > m.month_end
+                    ORDER BY h.as_of, h.history_id LIMIT 1),
+                l.loan_status
+            ) AS status
+            FROM months m CROSS JOIN loans l
+            WHERE l.issue_month ~ {pattern} AND to_date(l.issue_month, 'Mon-YYYY') <= m.month_end
+        )
+        SELECT cur.month_start AS month, prev.status AS from_status, cur.status AS to_status,
+            COUNT(*) AS loans,
+            COUNT(*)::FLOAT8 / SUM(COUNT(*)) OVER (PARTITION BY cur.month_start, prev.status) AS rate
+        FROM status_at cur
+        JOIN status_at prev
+            ON prev.loan_id = cur.loan_id AND prev.month_start = (cur.month_start - INTERVAL '1 month')::DATE
+        GROUP BY cur.month_start, prev.status, cur.status
+        ORDER BY cur.month_start, prev.status, cur.status",
+        pattern = ISSUE_MONTH_PATTERN
+    ))
+    .bind(as_of)
+    .bind(ROLL_RATE_MONTHS)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| RollRate {
+            month: row.month,
+            from: status_label(row.from_status),
+            to: status_label(row.to_status),
+            loans: row.loans,
+            rate: row.rate,
+        })
+        .collect())
+}
+
+async fn vintages(pool: &PgPool) -> Result<Vec<Vintage>, AppError> {
+    sqlx::query_as::<_, Vintage>(&format!(
+        "SELECT issue_month, COUNT(*) AS loans,
+            COALESCE(SUM(loan_amount::FLOAT8), 0) AS originated,
+            COALESCE(SUM(balance::FLOAT8) FILTER (WHERE {active}), 0) AS outstanding,
+            COUNT(*) FILTER (WHERE loan_status = $2)::FLOAT8 / COUNT(*) AS charge_off_rate,
+            COUNT(*) FILTER (WHERE loan_status IN ($3, $4, $5, $6))::FLOAT8 / COUNT(*) AS delinquent_rate
+        FROM loans
+        WHERE issue_month ~ {pattern}
+        GROUP BY issue_month
+        ORDER BY to_date(issue_month, 'Mon-YYYY')",
+        active = ACTIVE,
+        pattern = ISSUE_MONTH_PATTERN
+    ))
+    .bind(LoanStatus::FullyPaid as i32)
+    .bind(LoanStatus::ChargedOff as i32)
+    .bind(LoanStatus::InGracePeriod as i32)
+    .bind(LoanStatus::Late1to15 as i32)
+    .bind(LoanStatus::Late16to30 as i32)
+    .bind(LoanStatus::Late31to120 as i32)
+    .fetch_all(pool)
+    .await
+    .map_err(AppError::from)
+}
+
+async fn vintage_curves(pool: &PgPool) -> Result<Vec<VintagePoint>, AppError> {
+    sqlx::query_as::<_, VintagePoint>(&format!(
+        "WITH vintages AS (
+            SELECT loan_id, issue_month, to_date(issue_month, 'Mon-YYYY') AS issued, loan_amount
+            FROM loans WHERE issue_month ~ {pattern}
+        ),
+        originated AS (
+            SELECT issue_month, SUM(loan_amount::FLOAT8) AS originated FROM vintages GROUP BY issue_month
+        ),
+        charge_offs AS (
+            SELECT v.issue_month, v.issued, v.loan_amount,
+                (EXTRACT(YEAR FROM age(MIN(h.as_of), v.issued)) * 12
+                    + EXTRACT(MONTH FROM age(MIN(h.as_of), v.issued)))::INT AS months_on_book
+            FROM vintages v
+            JOIN loan_status_history h ON h.loan_id = v.loan_id AND h.to_status = $1
+            GROUP BY v.loan_id, v.issue_month, v.issued, v.loan_amount
+        )
+        SELECT c.issue_month, c.months_on_book,
+            SUM(SUM(c.loan_amount::FLOAT8)) OVER (PARTITION BY c.issue_month ORDER BY c.months_on_book)
+                / MAX(o.originated) AS cumulative_charge_off_rate
+        FROM charge_offs c JOIN originated o ON o.issue_month = c.issue_month
+        GROUP BY c.issue_month, c.issued, c.months_on_book
+        ORDER BY c.issued, c.months_on_book",
+        pattern = ISSUE_MONTH_PATTERN
+    ))
+    .bind(LoanStatus::ChargedOff as i32)
+    .fetch_all(pool)
+    .await
+    .map_err(AppError::from)
+}
+
+pub async fn portfolio_analytics(
+    pool: &PgPool,
+    as_of: NaiveDate,
+) -> Result<PortfolioAnalytics, AppError> {
+    let by_purpose = breakdown(pool, "l.loan_purpose", "")
+        .await?
+        .into_iter()
+        .map(|mut row| {
+            row.label = purpose_label(row.label.parse().unwrap_or_default());
+            row
+        })
+        .collect();
+    Ok(PortfolioAnalytics {
+        generated_at: Utc::now(),
+        summary: summary(pool).await?,
+        by_grade: breakdown(pool, "l.grade", "").await?,
+        by_purpose,
+        by_servicer: breakdown(
+            pool,
+            "COALESCE(s.servicer_name, 'Servicer ' || l.servicer_id)",
+            "LEFT JOIN servicers s ON s.servicer_id = l.servicer_id",
+        )
+        .await?,
+        roll_rates: roll_rates(pool, as_of).await?,
+        vintages: vintages(pool).await?,
+        vintage_curves: vintage_curves(pool).await?,
+    })
+}
+
+/// Serves from Redis when it can. Redis being down only costs the cache, the numbers still
+/// come back from Postgres.
+pub async fn cached_portfolio_analytics(
+    pool: &PgPool,
+    r_pool: &RedisPool,
+) -> Result<PortfolioAnalytics, AppError> {
+    let mut con = match r_pool.get().await {
+        Ok(con) => Some(con),
+        Err(err) => {
+            tracing::warn!(error = ?err, "Redis unavailable, computing portfolio analytics");
+            None
+        }
+    };
+    if let Some(con) = con.as_mut() {
+        let cached: Option<String> = cmd("GET")
+            .arg(PORTFOLIO_CACHE_KEY)
+            .query_async(con)
+            .await
+            .unwrap_or(None);
+        if let Some(analytics) = cached.and_then(|json| serde_json::from_str(&json).ok()) {
+            return Ok(analytics);
+        }
+    }
+
+    let analytics = portfolio_analytics(pool, Utc::now().date_naive()).await?;
+    if let (Some(con), Ok(json)) = (con.as_mut(), serde_json::to_string(&analytics)) {
+        let stored = cmd("SET")
+            .arg(PORTFOLIO_CACHE_KEY)
+            .arg(json)
+            .arg("EX")
+            .arg(PORTFOLIO_CACHE_TTL_SECONDS)
+            .query_async::<_, ()>(con)
+            .await;
+        if let Err(err) = stored {
+            tracing::warn!(error = ?err, "Unable to cache portfolio analytics");
+        }
+    }
+    Ok(analytics)
+}
+
+pub async fn invalidate_portfolio_cache(r_pool: &RedisPool) -> Result<(), AppError> {
+    let mut con = r_pool.get().await.map_err(|err| {
+        AppError::GenericError(format!("Unable to get a Redis connection: {}", err))
+    })?;
+    cmd("DEL")
+        .arg(PORTFOLIO_CACHE_KEY)
+        .query_async::<_, ()>(&mut con)
+        .await
+        .map_err(|err| AppError::GenericError(format!("Unable to invalidate cache: {}", err)))
+}
+
+/// The loans_changed handler, drops the cached analytics on any change to the loans table.
+pub async fn invalidate_on_loan_changes(r_pool: RedisPool, update: TableUpdate) {
+    if let Err(err) = invalidate_portfolio_cache(&r_pool).await {
+        tracing::warn!(?update, error = ?err, "Portfolio cache not invalidated");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_use_tape_strings() {
+        assert_eq!(status_label(5), "Late (16-30 days)");
+        assert_eq!(status_label(0), "Unknown (0)");
+        assert_eq!(purpose_label(3), "debt_consolidation");
+    }
+
+    #[test]
+    fn analytics_round_trip_through_the_cache_format() {
+        let analytics = PortfolioAnalytics {
+            generated_at: Utc::now(),
+            summary: PortfolioSummary {
+                loans: 2,
+                active_loans: 1,
+                outstanding_principal: 27015.86,
+                charge_off_rate: 0.5,
+                charge_off_amount_rate: 0.39,
+                weighted_average_apr: 14.07,
+            },
+            by_grade: vec![PrincipalBreakdown {
+                label: "C".to_owned(),
+                loans: 1,
+                outstanding: 27015.86,
+                share: 1.0,
+                weighted_average_apr: 14.07,
+            }],
+            by_purpose: vec![],
+            by_servicer: vec![],
+            roll_rates: vec![RollRate {
+                month: NaiveDate::from_ymd_opt(2024, 2, 1).unwrap(),
+                from: status_label(1),
+                to: status_label(3),
+                loans: 1,
+                rate: 1.0,
+            }],
+            vintages: vec![],
+            vintage_curves: vec![],
+        };
+        let json = serde_json::to_string(&analytics).unwrap();
+        let cached: PortfolioAnalytics = serde_json::from_str(&json).unwrap();
+        assert_eq!(cached, analytics);
+    }
+}
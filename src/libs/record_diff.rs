@@ -0,0 +1,224 @@
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+use serde_json::Value;
+use struct_iterable::Iterable;
+
+use crate::models::credit_file::{HomeOwnership, IncomeVerification};
+
+/// One field that differs between two records.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct FieldDiff {
+    pub field: &'static str,
+    pub old: Value,
+    pub new: Value,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct RecordDiff {
+    /// Fields that were compared, i.e. excluding ignored and unsupported fields
+    pub compared: usize,
+    pub changes: Vec<FieldDiff>,
+}
+
+impl RecordDiff {
+    /// Number of compared fields that differ.
+    pub fn distance(&self) -> usize {
+        self.changes.len()
+    }
+
+    /// Number of compared fields that agree.
+    pub fn matching(&self) -> usize {
+        self.compared - self.changes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn changed(&self, field: &str) -> bool {
+        self.changes.iter().any(|change| change.field == field)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DiffOptions {
+    ignored: HashSet<&'static str>,
+    tolerances: HashMap<&'static str, f64>,
+    default_tolerance: f64,
+}
+
+impl DiffOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn ignore(mut self, field: &'static str) -> Self {
+        self.ignored.insert(field);
+        self
+    }
+
+    /// Numeric values of `field` within `tolerance` of each other count as equal.
+    pub fn tolerance(mut self, field: &'static str, tolerance: f64) -> Self {
+        self.tolerances.insert(field, tolerance);
+        self
+    }
+
+    /// Tolerance for numeric fields without their own.
+    pub fn default_tolerance(mut self, tolerance: f64) -> Self {
+        self.default_tolerance = tolerance;
+        self
+    }
+
+    fn equal(&self, field: &str, old: &Value, new: &Value) -> bool {
+        match (old.as_f64(), new.as_f64()) {
+            (Some(old), Some(new)) => {
+                let tolerance = self.tolerances.get(field).copied().unwrap_or(self.default_tolerance);
+                (old - new).abs() <= tolerance
+            }
+            _ => old == new,
+        }
+    }
+}
+
+macro_rules! downcast_to_json {
+    ($value:expr, $($ty:ty),*) => {
+        $(
+            if let Some(v) = $value.downcast_ref::<$ty>() {
+                return serde_json::to_value(v).ok();
+            }
+            if let Some(v) = $value.downcast_ref::<Option<$ty>>() {
+                return serde_json::to_value(v).ok();
+            }
+        )*
+    };
+}
+
+/// JSON value of an Iterable field, None when the type isn't one we know how to compare.
+pub fn field_value(value: &dyn Any) -> Option<Value> {
+    downcast_to_json!(
+        value,
+        i32,
+        i64,
+        u32,
+        usize,
+        f32,
+        f64,
+        bool,
+        String,
+        &'static str,
+        HomeOwnership,
+        IncomeVerification
+    );
+    None
+}
+
+/// Compares two field lists by name. A field only one side has always counts as a change.
+pub fn diff_fields(
+    old: Vec<(&'static str, &dyn Any)>,
+    new: Vec<(&'static str, &dyn Any)>,
+    opts: &DiffOptions,
+) -> RecordDiff {
+    let new_values: HashMap<&'static str, Option<Value>> = new
+        .into_iter()
+        .map(|(field, value)| (field, field_value(value)))
+        .collect();
+    let mut seen = HashSet::new();
+    let mut diff = RecordDiff {
+        compared: 0,
+        changes: Vec::new(),
+    };
+
+    for (field, value) in old {
+        seen.insert(field);
+        if opts.ignored.contains(field) {
+            continue;
+        }
+        let old_value = field_value(value);
+        let new_value = new_values.get(field).cloned();
+        match (old_value, new_value) {
+            (Some(old), Some(Some(new))) => {
+                diff.compared += 1;
+                if !opts.equal(field, &old, &new) {
+                    diff.changes.push(FieldDiff { field, old, new });
+                }
+            }
+            // Missing on the new side
+            (Some(old), None) => {
+                diff.compared += 1;
+                diff.changes.push(FieldDiff { field, old, new: Value::Null });
+            }
+            // Unsupported type on either side
+            _ => continue,
+        }
+    }
+
+    for (field, value) in new_values {
+        if seen.contains(field) || opts.ignored.contains(field) {
+            continue;
+        }
+        if let Some(new) = value {
+            diff.compared += 1;
+            diff.changes.push(FieldDiff { field, old: Value::Null, new });
+        }
+    }
+    diff
+}
+
+pub fn diff_records<T: Iterable>(old: &T, new: &T, opts: &DiffOptions) -> RecordDiff {
+    diff_fields(old.iter().collect(), new.iter().collect(), opts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::libs::hamming::Record;
+    use serde_json::json;
+
+    fn record(name: &str, count: i32) -> Record {
+        Record {
+            name: name.to_owned(),
+            count,
+            quote: "What".to_owned(),
+        }
+    }
+
+    #[test]
+    fn equal_records_have_no_changes() {
+        let diff = diff_records(&record("Jim", 3), &record("Jim", 3), &DiffOptions::new());
+        assert!(diff.is_empty());
+        assert_eq!(diff.compared, 3);
+        assert_eq!(diff.matching(), 3);
+    }
+
+    #[test]
+    fn changed_fields_carry_old_and_new() {
+        let diff = diff_records(&record("Jim", 3), &record("Steve", 3), &DiffOptions::new());
+        assert_eq!(diff.distance(), 1);
+        assert_eq!(
+            diff.changes[0],
+            FieldDiff {
+                field: "name",
+                old: json!("Jim"),
+                new: json!("Steve"),
+            }
+        );
+    }
+
+    #[test]
+    fn tolerance_and_ignored_fields() {
+        let old = record("Jim", 3);
+        let new = record("Steve", 5);
+
+        let opts = DiffOptions::new().ignore("name").tolerance("count", 2.0);
+        let diff = diff_records(&old, &new, &opts);
+        assert!(diff.is_empty());
+        assert_eq!(diff.compared, 2);
+
+        let opts = DiffOptions::new().default_tolerance(1.0);
+        let diff = diff_records(&old, &new, &opts);
+        assert!(diff.changed("count"));
+        assert!(diff.changed("name"));
+    }
+}
@@ -0,0 +1,632 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{FromRow, PgPool, Postgres, Transaction};
+use validator::Validate;
+
+use crate::{
+    config::FilterOptions,
+    error::AppError,
+    libs::{
+        affordability::{comp_offer, policy_max_dti, AffordabilityProfile},
+        application_lifecycle::{
+            application_owner, transition_application_in, ApplicationStatus, StatusChange,
+        },
+        audit_trail::begin_audited,
+        credit_scorer::latest_credit_score,
+        outbox::{record_event, EventType},
+        underwriting::{application_underwriting_input, Decision, RuleSet},
+    },
+    models::offer::Offer,
+};
+
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+/// A claim nobody has acted on for this long can be taken over by another reviewer
+const CLAIM_TIMEOUT_HOURS: i32 = 8;
+const MAX_NOTE_LEN: usize = 2000;
+/// Territory whose consultants take applications from territories that have none
+const NATIONAL_TERRITORY_ID: i32 = 1;
+
+/// Sort keys the queue takes, and the column behind each. Anything else is refused rather than
+/// put into the query.
+const SORT_COLUMNS: [(&str, &str); 7] = [
+    ("created_at", "a.created_at"),
+    ("application_id", "a.application_id"),
+    ("last_name", "a.last_name"),
+    ("state", "a.state"),
+    ("desired_loan_amount", "a.desired_loan_amount"),
+    ("annual_income", "a.annual_income"),
+    ("fraud_flags", "fraud_flags"),
+];
+
+/// `FilterOptions` checked and turned into what the queue query binds. Oldest first by default,
+/// they've waited longest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueQuery {
+    /// ILIKE pattern over name, city and ZIP
+    pub search: Option<String>,
+    pub sort_key: &'static str,
+    pub descending: bool,
+    pub year: Option<i32>,
+    pub month: Option<i32>,
+    pub page: usize,
+    pub limit: usize,
+}
+
+fn like_pattern(search: &str) -> String {
+    let escaped = search
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+impl QueueQuery {
+    pub fn from_filter(opts: &FilterOptions) -> Result<Self, AppError> {
+        opts.validate()
+            .map_err(|err| AppError::InvalidRequest(err.to_string()))?;
+        let key = opts.key.as_deref().unwrap_or("created_at");
+        let sort_key = SORT_COLUMNS
+            .iter()
+            .find(|(name, _)| *name == key)
+            .map(|(name, _)| *name)
+            .ok_or_else(|| AppError::InvalidRequest(format!("Cannot sort the queue by {}", key)))?;
+        let descending = match opts.dir.as_deref() {
+            None | Some("asc") => false,
+            Some("desc") => true,
+            Some(dir) => {
+                return Err(AppError::InvalidRequest(format!(
+                    "Sort direction must be asc or desc, not {}",
+                    dir
+                )))
+            }
+        };
+        if opts.month.is_some_and(|month| !(1..=12).contains(&month)) {
+            return Err(AppError::InvalidRequest("Month must be 1 to 12".to_owned()));
+        }
+        Ok(QueueQuery {
+            search: opts
+                .search
+                .as_deref()
+                .map(str::trim)
+                .filter(|search| !search.is_empty())
+                .map(like_pattern),
+            sort_key,
+            descending,
+            year: opts.year.map(|year| year as i32),
+            month: opts.month.map(|month| month as i32),
+            page: opts.page.unwrap_or(1).max(1),
+            limit: opts
+                .limit
+                .unwrap_or(DEFAULT_PAGE_SIZE)
+                .clamp(1, MAX_PAGE_SIZE),
+        })
+    }
+
+    fn order_by(&self) -> String {
+        let column = SORT_COLUMNS
+            .iter()
+            .find(|(name, _)| *name == self.sort_key)
+            .map(|(_, column)| *column)
+            .unwrap_or("a.created_at");
+        let dir = if self.descending { "DESC" } else { "ASC" };
+        // application_id keeps pages stable when the sort column ties
+        format!("{} {} NULLS LAST, a.application_id {}", column, dir, dir)
+    }
+
+    fn offset(&self) -> usize {
+        (self.page - 1) * self.limit
+    }
+}
+
+/// An application waiting on a reviewer
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct QueueEntry {
+    pub application_id: i32,
+    pub first_name: String,
+    pub last_name: String,
+    pub city: String,
+    pub state: Option<String>,
+    pub desired_loan_amount: i32,
+    pub annual_income: i32,
+    pub application_type: i32,
+    pub created_at: Option<DateTime<Utc>>,
+    pub consultant_id: Option<i32>,
+    pub consultant: Option<String>,
+    pub claimed_by: Option<i32>,
+    pub claimed_by_username: Option<String>,
+    pub claimed_at: Option<DateTime<Utc>>,
+    pub fraud_flags: i64,
+    /// The latest underwriting decision, None if the rules couldn't be run
+    pub decision: Option<Decision>,
+}
+
+#[derive(Debug, FromRow)]
+struct QueueRow {
+    #[sqlx(flatten)]
+    entry: QueueEntry,
+    total: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReviewQueue {
+    pub entries: Vec<QueueEntry>,
+    pub total: i64,
+    pub page: usize,
+    pub limit: usize,
+}
+
+impl ReviewQueue {
+    pub fn pages(&self) -> usize {
+        (self.total.max(0) as usize).div_ceil(self.limit).max(1)
+    }
+}
+
+/// One row of review_notes
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct ReviewNote {
+    pub note_id: i32,
+    pub application_id: i32,
+    pub user_id: i32,
+    pub note: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct Claim {
+    pub application_id: i32,
+    pub claimed_by: i32,
+    pub claimed_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReviewAction {
+    Approve,
+    Decline,
+}
+
+impl ReviewAction {
+    pub fn status(&self) -> ApplicationStatus {
+        match self {
+            ReviewAction::Approve => ApplicationStatus::OffersPresented,
+            ReviewAction::Decline => ApplicationStatus::Declined,
+        }
+    }
+}
+
+/// An active consultant who could take a new application
+#[derive(Debug, Clone, FromRow)]
+pub struct Candidate {
+    pub consultant_id: i32,
+    pub territory_id: i32,
+    /// Assigned applications not yet past review
+    pub open_applications: i64,
+}
+
+/// The least loaded consultant in `territory_id`, or in the national territory if it has none.
+/// Ties go to the lowest consultant_id.
+pub fn pick_consultant(territory_id: i32, candidates: &[Candidate]) -> Option<i32> {
+    [territory_id, NATIONAL_TERRITORY_ID]
+        .into_iter()
+        .find_map(|territory_id| {
+            candidates
+                .iter()
+                .filter(|candidate| candidate.territory_id == territory_id)
+                .min_by_key(|candidate| (candidate.open_applications, candidate.consultant_id))
+        })
+        .map(|candidate| candidate.consultant_id)
+}
+
+pub async fn review_queue(pool: &PgPool, query: &QueueQuery) -> Result<ReviewQueue, AppError> {
+    let rows = sqlx::query_as::<_, QueueRow>(&format!(
+        "SELECT a.application_id, a.first_name, a.last_name, a.city, a.state, a.desired_loan_amount,
+            a.annual_income, a.application_type, a.created_at, a.consultant_id, cu.username AS consultant,
+            a.claimed_by, u.username AS claimed_by_username, a.claimed_at,
+            (SELECT COUNT(*) FROM fraud_flags f WHERE f.application_id = a.application_id) AS fraud_flags,
+            (SELECT d.decision FROM underwriting_decisions d WHERE d.application_id = a.application_id
+                ORDER BY d.decision_id DESC LIMIT 1) AS decision,
+            COUNT(*) OVER () AS total
+        FROM applications a
+        LEFT JOIN consultants c ON c.consultant_id = a.consultant_id
+        LEFT JOIN users cu ON cu.user_id = c.user_id
+        LEFT JOIN users u ON u.user_id = a.claimed_by
+        WHERE a.application_status = $1
+            AND ($2::TEXT IS NULL OR a.first_name ILIKE $2 OR a.last_name ILIKE $2 OR a.city ILIKE $2 OR a.zip ILIKE $2)
+            AND ($3::INT IS NULL OR EXTRACT(YEAR FROM a.created_at) = $3)
+            AND ($4::INT IS NULL OR EXTRACT(MONTH FROM a.created_at) = $4)
+        ORDER BY {}
+        LIMIT $5 OFFSET $6",
+        query.order_by()
+    ))
+    .bind(ApplicationStatus::UnderReview)
+    .bind(&query.search)
+    .bind(query.year)
+    .bind(query.month)
+    .bind(query.limit as i64)
+    .bind(query.offset() as i64)
+    .fetch_all(pool)
+    .await?;
+    Ok(ReviewQueue {
+        // COUNT(*) OVER () comes back on every row, a page past the end has none to carry it
+        total: rows.first().map_or(0, |row| row.total),
+        entries: rows.into_iter().map(|row| row.entry).collect(),
+        page: query.page,
+        limit: query.limit,
+    })
+}
+
+/// Assigns a new application by the territory of the location it came in through. None when
+/// there's no active consultant to give it to, it still shows in the queue.
+pub async fn assign_consultant(
+    pool: &PgPool,
+    application_id: i32,
+) -> Result<Option<i32>, AppError> {
+    let mut tx = begin_audited(pool).await?;
+    let consultant_id = assign_consultant_in(&mut tx, application_id).await?;
+    tx.commit().await?;
+    Ok(consultant_id)
+}
+
+/// `assign_consultant` inside the caller's transaction, for a new application in the one that
+/// inserts it.
+pub async fn assign_consultant_in(
+    tx: &mut Transaction<'_, Postgres>,
+    application_id: i32,
+) -> Result<Option<i32>, AppError> {
+    let territory_id = sqlx::query_scalar::<_, i32>(
+        "SELECT l.territory_id FROM applications a
+        JOIN locations l ON l.location_id = a.location_id
+        WHERE a.application_id = $1",
+    )
+    .bind(application_id)
+    .fetch_optional(&mut **tx)
+    .await?
+    .unwrap_or(NATIONAL_TERRITORY_ID);
+    let candidates = sqlx::query_as::<_, Candidate>(
+        "SELECT c.consultant_id, c.territory_id, COUNT(a.application_id) AS open_applications
+        FROM consultants c
+        LEFT JOIN applications a ON a.consultant_id = c.consultant_id AND a.application_status IN ($1, $2)
+        WHERE c.active AND c.territory_id IN ($3, $4)
+        GROUP BY c.consultant_id, c.territory_id",
+    )
+    .bind(ApplicationStatus::Submitted)
+    .bind(ApplicationStatus::UnderReview)
+    .bind(territory_id)
+    .bind(NATIONAL_TERRITORY_ID)
+    .fetch_all(&mut **tx)
+    .await?;
+    let consultant_id = pick_consultant(territory_id, &candidates);
+    if let Some(consultant_id) = consultant_id {
+        sqlx::query("UPDATE applications SET consultant_id = $2 WHERE application_id = $1")
+            .bind(application_id)
+            .bind(consultant_id)
+            .execute(&mut **tx)
+            .await?;
+        tracing::info!(application_id, consultant_id, "Application assigned");
+    }
+    Ok(consultant_id)
+}
+
+#[derive(Debug, FromRow)]
+struct ClaimState {
+    application_status: ApplicationStatus,
+    claimed_by: Option<i32>,
+}
+
+async fn claim_state(pool: &PgPool, application_id: i32) -> Result<ClaimState, AppError> {
+    sqlx::query_as::<_, ClaimState>(
+        "SELECT application_status, claimed_by FROM applications WHERE application_id = $1",
+    )
+    .bind(application_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Application {} not found", application_id)))
+}
+
+pub(crate) async fn is_active_consultant(pool: &PgPool, user_id: i32) -> Result<bool, AppError> {
+    sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS (SELECT 1 FROM consultants WHERE user_id = $1 AND active)",
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await
+    .map_err(AppError::from)
+}
+
+/// Reviewer only actions check this first
+pub(crate) async fn ensure_active_consultant(pool: &PgPool, user_id: i32) -> Result<(), AppError> {
+    if !is_active_consultant(pool, user_id).await? {
+        return Err(AppError::InvalidRequest(format!(
+            "User {} is not an active consultant",
+            user_id
+        )));
+    }
+    Ok(())
+}
+
+/// An application is only shown to the applicant who submitted it and to consultants. Anyone
+/// else gets the same NotFound as for an application that doesn't exist.
+pub(crate) async fn ensure_application_access(
+    pool: &PgPool,
+    application_id: i32,
+    user_id: i32,
+) -> Result<(), AppError> {
+    if is_active_consultant(pool, user_id).await?
+        || application_owner(pool, application_id).await? == Some(user_id)
+    {
+        return Ok(());
+    }
+    Err(AppError::NotFound(format!(
+        "Application {} not found",
+        application_id
+    )))
+}
+
+/// Claims the application for `user_id`, who has to be an active consultant. Claiming one you
+/// already hold renews it, someone else's can only be taken once it's gone stale.
+pub async fn claim_application(
+    pool: &PgPool,
+    application_id: i32,
+    user_id: i32,
+) -> Result<Claim, AppError> {
+    ensure_active_consultant(pool, user_id).await?;
+    let mut tx = begin_audited(pool).await?;
+    let claim = sqlx::query_as::<_, Claim>(
+        "UPDATE applications SET claimed_by = $2, claimed_at = NOW()
+        WHERE application_id = $1 AND application_status = $3
+            AND (claimed_by IS NULL OR claimed_by = $2 OR claimed_at < NOW() - make_interval(hours => $4))
+        RETURNING application_id, claimed_by, claimed_at",
+    )
+    .bind(application_id)
+    .bind(user_id)
+    .bind(ApplicationStatus::UnderReview)
+    .bind(CLAIM_TIMEOUT_HOURS)
+    .fetch_optional(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    if let Some(claim) = claim {
+        tracing::info!(application_id, user_id, "Application claimed");
+        return Ok(claim);
+    }
+    let state = claim_state(pool, application_id).await?;
+    Err(AppError::InvalidRequest(
+        match (state.application_status, state.claimed_by) {
+            (ApplicationStatus::UnderReview, Some(other)) => format!(
+                "Application {} is claimed by user {}",
+                application_id, other
+            ),
+            (status, _) => format!(
+                "Application {} is {}, not awaiting review",
+                application_id, status
+            ),
+        },
+    ))
+}
+
+/// Only the user holding the claim can release it
+pub async fn release_application(
+    pool: &PgPool,
+    application_id: i32,
+    user_id: i32,
+) -> Result<(), AppError> {
+    let mut tx = begin_audited(pool).await?;
+    let released = sqlx::query(
+        "UPDATE applications SET claimed_by = NULL, claimed_at = NULL
+        WHERE application_id = $1 AND claimed_by = $2",
+    )
+    .bind(application_id)
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    tx.commit().await?;
+    if released == 0 {
+        claim_state(pool, application_id).await?;
+        return Err(AppError::InvalidRequest(format!(
+            "Application {} is not claimed by user {}",
+            application_id, user_id
+        )));
+    }
+    tracing::info!(application_id, user_id, "Application released");
+    Ok(())
+}
+
+pub async fn add_review_note(
+    pool: &PgPool,
+    application_id: i32,
+    user_id: i32,
+    note: &str,
+) -> Result<ReviewNote, AppError> {
+    let note = note.trim();
+    if note.is_empty() || note.chars().count() > MAX_NOTE_LEN {
+        return Err(AppError::InvalidRequest(format!(
+            "A note must be 1 to {} characters",
+            MAX_NOTE_LEN
+        )));
+    }
+    ensure_active_consultant(pool, user_id).await?;
+    claim_state(pool, application_id).await?;
+    sqlx::query_as::<_, ReviewNote>(
+        "INSERT INTO review_notes (application_id, user_id, note) VALUES ($1, $2, $3)
+        RETURNING note_id, application_id, user_id, note, created_at",
+    )
+    .bind(application_id)
+    .bind(user_id)
+    .bind(note)
+    .fetch_one(pool)
+    .await
+    .map_err(AppError::from)
+}
+
+pub async fn review_notes(pool: &PgPool, application_id: i32) -> Result<Vec<ReviewNote>, AppError> {
+    sqlx::query_as::<_, ReviewNote>(
+        "SELECT note_id, application_id, user_id, note, created_at FROM review_notes
+        WHERE application_id = $1 ORDER BY note_id",
+    )
+    .bind(application_id)
+    .fetch_all(pool)
+    .await
+    .map_err(AppError::from)
+}
+
+/// The comp offer an approval presents, priced off the latest credit score and cut to what the
+/// applicant can afford. Same as the one an automatic approval gets.
+async fn approval_offer(pool: &PgPool, application_id: i32) -> Result<Offer, AppError> {
+    let score = latest_credit_score(pool, application_id).await?;
+    let input = application_underwriting_input(pool, application_id).await?;
+    let profile =
+        AffordabilityProfile::new(&input, policy_max_dti(RuleSet::load_active().ok().as_ref()));
+    comp_offer(score.as_ref())
+        .and_then(|offer| profile.affordable_offer(&offer))
+        .ok_or_else(|| {
+            AppError::InvalidRequest(format!(
+                "No offer fits application {}, decline it instead",
+                application_id
+            ))
+        })
+}
+
+/// Approves or declines an application the user has claimed, and clears the claim. The row
+/// stays locked from the claim check to the commit, so a claim can't lapse and be taken over
+/// part way through. A decline needs a reason, as any decline does. An approval creates the
+/// comp offer, published through the outbox once the move to OffersPresented commits.
+pub async fn review_application(
+    pool: &PgPool,
+    application_id: i32,
+    user_id: i32,
+    action: ReviewAction,
+    reason: Option<&str>,
+) -> Result<StatusChange, AppError> {
+    let offer = match action {
+        ReviewAction::Approve => Some(approval_offer(pool, application_id).await?),
+        ReviewAction::Decline => None,
+    };
+    let mut tx = begin_audited(pool).await?;
+    let claimed_by = sqlx::query_scalar::<_, Option<i32>>(
+        "SELECT claimed_by FROM applications WHERE application_id = $1 FOR UPDATE",
+    )
+    .bind(application_id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Application {} not found", application_id)))?;
+    if claimed_by != Some(user_id) {
+        return Err(AppError::InvalidRequest(format!(
+            "Claim application {} before reviewing it",
+            application_id
+        )));
+    }
+    // The page sends the reason box along either way
+    let reason = reason.map(str::trim).filter(|reason| !reason.is_empty());
+    let change = transition_application_in(
+        &mut tx,
+        application_id,
+        action.status(),
+        Some(user_id),
+        reason,
+    )
+    .await?;
+    if let Some(offer) = &offer {
+        let created = json!({
+            "application_id": application_id,
+            "user_id": application_owner(pool, application_id).await?,
+            "offer": offer,
+        });
+        record_event(&mut tx, EventType::OfferCreated, application_id, &created).await?;
+    }
+    sqlx::query(
+        "UPDATE applications SET claimed_by = NULL, claimed_at = NULL WHERE application_id = $1",
+    )
+    .bind(application_id)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(change)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(consultant_id: i32, territory_id: i32, open_applications: i64) -> Candidate {
+        Candidate {
+            consultant_id,
+            territory_id,
+            open_applications,
+        }
+    }
+
+    #[test]
+    fn assigns_least_loaded_in_territory_then_national() {
+        let candidates = [
+            candidate(1, 1, 0),
+            candidate(2, 5, 4),
+            candidate(3, 5, 2),
+            candidate(4, 5, 2),
+        ];
+        assert_eq!(pick_consultant(5, &candidates), Some(3));
+        // Nobody in the west, the national consultant takes it however busy the midwest is
+        assert_eq!(pick_consultant(4, &candidates), Some(1));
+        assert_eq!(pick_consultant(4, &candidates[1..]), None);
+    }
+
+    #[test]
+    fn queue_query_defaults_and_limits() {
+        let query = QueueQuery::from_filter(&FilterOptions::default()).unwrap();
+        assert_eq!(query.sort_key, "created_at");
+        assert!(!query.descending);
+        assert_eq!((query.page, query.limit, query.offset()), (1, 20, 0));
+
+        let query = QueueQuery::from_filter(&FilterOptions {
+            page: Some(3),
+            limit: Some(500),
+            search: Some(" 50%_off ".to_owned()),
+            key: Some("fraud_flags".to_owned()),
+            dir: Some("desc".to_owned()),
+            year: Some(2024),
+            month: Some(3),
+        })
+        .unwrap();
+        assert_eq!(query.limit, MAX_PAGE_SIZE);
+        assert_eq!(query.offset(), 200);
+        assert_eq!(query.search.as_deref(), Some("%50\\%\\_off%"));
+        assert_eq!(
+            query.order_by(),
+            "fraud_flags DESC NULLS LAST, a.application_id DESC"
+        );
+        assert_eq!((query.year, query.month), (Some(2024), Some(3)));
+    }
+
+    #[test]
+    fn queue_query_refuses_unknown_sorts() {
+        let filter = |key: &str, dir: &str| FilterOptions {
+            key: Some(key.to_owned()),
+            dir: Some(dir.to_owned()),
+            ..FilterOptions::default()
+        };
+        assert!(QueueQuery::from_filter(&filter("ssn_hmac", "asc")).is_err());
+        assert!(
+            QueueQuery::from_filter(&filter("created_at; DROP TABLE applications", "asc")).is_err()
+        );
+        assert!(QueueQuery::from_filter(&filter("state", "sideways")).is_err());
+        assert!(QueueQuery::from_filter(&FilterOptions {
+            month: Some(13),
+            ..FilterOptions::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn pages_round_up() {
+        let queue = |total| ReviewQueue {
+            entries: Vec::new(),
+            total,
+            page: 1,
+            limit: 20,
+        };
+        assert_eq!(queue(0).pages(), 1);
+        assert_eq!(queue(20).pages(), 1);
+        assert_eq!(queue(21).pages(), 2);
+    }
+}
@@ -0,0 +1,789 @@
+use chrono::{DateTime, Months, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgExecutor, PgPool, Postgres, Transaction};
+
+use crate::{
+    error::AppError,
+    libs::{audit_trail::begin_audited, review_queue::is_active_consultant},
+    models::loan::LoanStatus,
+};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[repr(i32)]
+pub enum AccrualMethod {
+    /// actual/365 on the outstanding principal
+    Daily = 1,
+    /// 1/12 of the annual rate for each whole month since the last accrual
+    Monthly = 2,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[repr(i32)]
+pub enum PaymentKind {
+    /// Counts toward the installment schedule
+    Regular = 1,
+    /// Principal curtailment. Doesn't advance the due date, re-amortizes the installment
+    Prepayment = 2,
+    Reversal = 3,
+    /// Not a payment, a fee charged by the delinquency job. Reversing it waives the fee
+    LateFee = 4,
+}
+
+/// A loan's servicing state with every amount in cents.
+#[derive(Debug, Clone, PartialEq, Serialize, FromRow)]
+pub struct LoanAccount {
+    pub loan_id: i32,
+    pub principal: i64,
+    pub interest_due: i64,
+    pub fees_due: i64,
+    pub due_paid: i64,
+    pub installment: i64,
+    /// Annual rate in percent, as stored on loans
+    pub interest_rate: f32,
+    pub remaining_term: i32,
+    pub accrual_method: AccrualMethod,
+    pub last_accrual_date: NaiveDate,
+    pub next_due_date: NaiveDate,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Allocation {
+    /// Interest accrued up to the effective date before allocating
+    pub accrued: i64,
+    pub fees: i64,
+    pub interest: i64,
+    pub principal: i64,
+    /// Overpayment beyond the payoff amount, to be refunded
+    pub unapplied: i64,
+    pub installments_paid: i32,
+    pub reamortized: bool,
+}
+
+/// What it takes to close the loan on `as_of`, in cents.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PayoffQuote {
+    pub loan_id: i32,
+    pub as_of: NaiveDate,
+    pub principal: i64,
+    /// Interest due after accruing to `as_of`
+    pub interest: i64,
+    pub fees: i64,
+    pub total: i64,
+    /// Interest a day on the current principal, for quoting a later date by hand
+    pub per_diem: i64,
+}
+
+impl Allocation {
+    pub fn applied(&self) -> i64 {
+        self.fees + self.interest + self.principal
+    }
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct LedgerEntry {
+    pub payment_id: i32,
+    pub loan_id: i32,
+    pub kind: PaymentKind,
+    pub effective_date: NaiveDate,
+    pub amount: i64,
+    pub accrued: i64,
+    pub fees: i64,
+    pub interest: i64,
+    pub principal: i64,
+    pub unapplied: i64,
+    pub principal_after: i64,
+    pub installment_after: i64,
+    pub reverses_payment_id: Option<i32>,
+    pub created_at: DateTime<Utc>,
+    /// The schedule the posting found, None on postings made before it was recorded
+    pub remaining_term_before: Option<i32>,
+    pub installment_before: Option<i64>,
+    pub due_paid_before: Option<i64>,
+}
+
+pub fn to_cents(dollars: f64) -> i64 {
+    (dollars * 100.0).round() as i64
+}
+
+/// Level payment that retires `principal` over `term` months.
+pub fn amortized_installment(principal: i64, annual_rate: f64, term: i32) -> i64 {
+    if term <= 0 {
+        return principal;
+    }
+    let monthly = annual_rate / 100.0 / 12.0;
+    if monthly == 0.0 {
+        return (principal as f64 / term as f64).ceil() as i64;
+    }
+    let factor = (1.0 + monthly).powi(term);
+    (principal as f64 * monthly * factor / (factor - 1.0)).round() as i64
+}
+
+fn add_month(date: NaiveDate) -> NaiveDate {
+    date.checked_add_months(Months::new(1)).unwrap_or(date)
+}
+
+fn sub_month(date: NaiveDate) -> NaiveDate {
+    date.checked_sub_months(Months::new(1)).unwrap_or(date)
+}
+
+impl LoanAccount {
+    pub fn payoff_amount(&self) -> i64 {
+        self.principal + self.interest_due + self.fees_due
+    }
+
+    pub fn paid_off(&self) -> bool {
+        self.payoff_amount() == 0
+    }
+
+    /// Accrues a copy of the account to `as_of` the same way posting a payment that day
+    /// would, so paying `total` on `as_of` pays the loan off exactly.
+    pub fn payoff_quote(&self, as_of: NaiveDate) -> PayoffQuote {
+        let mut quoted = self.clone();
+        quoted.accrue(as_of);
+        PayoffQuote {
+            loan_id: self.loan_id,
+            as_of,
+            principal: quoted.principal,
+            interest: quoted.interest_due,
+            fees: quoted.fees_due,
+            total: quoted.payoff_amount(),
+            per_diem: (self.principal as f64 * self.interest_rate as f64 / 100.0 / 365.0).round()
+                as i64,
+        }
+    }
+
+    /// Accrues interest up to `to` and returns the amount added. Backdated calls accrue nothing.
+    pub fn accrue(&mut self, to: NaiveDate) -> i64 {
+        let rate = self.interest_rate as f64 / 100.0;
+        let accrued = match self.accrual_method {
+            AccrualMethod::Daily => {
+                let days = (to - self.last_accrual_date).num_days();
+                if days <= 0 {
+                    return 0;
+                }
+                self.last_accrual_date = to;
+                self.principal as f64 * rate / 365.0 * days as f64
+            }
+            AccrualMethod::Monthly => {
+                let mut months = 0;
+                while add_month(self.last_accrual_date) <= to {
+                    self.last_accrual_date = add_month(self.last_accrual_date);
+                    months += 1;
+                }
+                self.principal as f64 * rate / 12.0 * months as f64
+            }
+        };
+        let accrued = accrued.round() as i64;
+        self.interest_due += accrued;
+        accrued
+    }
+
+    fn reamortize(&mut self) {
+        self.installment = amortized_installment(
+            self.principal,
+            self.interest_rate as f64,
+            self.remaining_term,
+        );
+    }
+
+    /// Accrues to `date`, then allocates `amount` to fees, interest and principal in that order.
+    pub fn post_payment(
+        &mut self,
+        amount: i64,
+        date: NaiveDate,
+        kind: PaymentKind,
+    ) -> Result<Allocation, AppError> {
+        if amount <= 0 {
+            return Err(AppError::InvalidRequest(
+                "Payment amount must be positive".to_owned(),
+            ));
+        }
+        if kind == PaymentKind::Reversal {
+            return Err(AppError::InvalidRequest(
+                "Reversals are posted with reverse_payment".to_owned(),
+            ));
+        }
+        if kind == PaymentKind::LateFee {
+            return Err(AppError::InvalidRequest(
+                "Late fees are assessed with assess_late_fee".to_owned(),
+            ));
+        }
+        if self.paid_off() {
+            return Err(AppError::InvalidRequest(
+                "Loan is already paid off".to_owned(),
+            ));
+        }
+
+        let mut alloc = Allocation {
+            accrued: self.accrue(date),
+            ..Default::default()
+        };
+        let mut left = amount;
+        alloc.fees = left.min(self.fees_due);
+        left -= alloc.fees;
+        alloc.interest = left.min(self.interest_due);
+        left -= alloc.interest;
+        alloc.principal = left.min(self.principal);
+        left -= alloc.principal;
+        alloc.unapplied = left;
+
+        self.fees_due -= alloc.fees;
+        self.interest_due -= alloc.interest;
+        self.principal -= alloc.principal;
+
+        if self.principal == 0 {
+            self.remaining_term = 0;
+            self.installment = 0;
+            self.due_paid = 0;
+            return Ok(alloc);
+        }
+
+        match kind {
+            PaymentKind::Regular => {
+                self.due_paid += alloc.applied();
+                while self.installment > 0
+                    && self.remaining_term > 0
+                    && self.due_paid >= self.installment
+                {
+                    self.due_paid -= self.installment;
+                    self.next_due_date = add_month(self.next_due_date);
+                    self.remaining_term -= 1;
+                    alloc.installments_paid += 1;
+                }
+            }
+            PaymentKind::Prepayment => {
+                if alloc.principal > 0 {
+                    self.reamortize();
+                    alloc.reamortized = true;
+                }
+            }
+            PaymentKind::Reversal | PaymentKind::LateFee => unreachable!(),
+        }
+        Ok(alloc)
+    }
+
+    pub fn assess_late_fee(&mut self, fee: i64) {
+        self.fees_due += fee;
+    }
+
+    /// Puts back what `entry` allocated and winds the due schedule back. Interest accrued
+    /// since the payment on the lower principal is not recalculated.
+    pub fn reverse_payment(&mut self, entry: &LedgerEntry) -> Result<(), AppError> {
+        if entry.kind == PaymentKind::Reversal {
+            return Err(AppError::InvalidRequest(
+                "A reversal can't be reversed".to_owned(),
+            ));
+        }
+        if entry.kind == PaymentKind::LateFee {
+            // Whatever of the fee was already paid stays paid
+            self.fees_due = (self.fees_due - entry.fees).max(0);
+            return Ok(());
+        }
+        let was_paid_off = self.principal == 0;
+        if was_paid_off && entry.principal > 0 {
+            // Payoff zeroed the schedule, nothing posts after it, so put back the one it found
+            let (Some(remaining_term), Some(installment), Some(due_paid)) = (
+                entry.remaining_term_before,
+                entry.installment_before,
+                entry.due_paid_before,
+            ) else {
+                return Err(AppError::InvalidRequest(format!(
+                    "Payment {} predates recorded schedules, reverse it by hand",
+                    entry.payment_id
+                )));
+            };
+            self.fees_due += entry.fees;
+            self.interest_due += entry.interest;
+            self.principal += entry.principal;
+            self.remaining_term = remaining_term;
+            self.installment = installment;
+            self.due_paid = due_paid;
+            return Ok(());
+        }
+        self.fees_due += entry.fees;
+        self.interest_due += entry.interest;
+        self.principal += entry.principal;
+
+        match entry.kind {
+            PaymentKind::Regular => {
+                self.due_paid -= entry.fees + entry.interest + entry.principal;
+                while self.due_paid < 0 && self.installment > 0 {
+                    self.due_paid += self.installment;
+                    self.next_due_date = sub_month(self.next_due_date);
+                    self.remaining_term += 1;
+                }
+                self.due_paid = self.due_paid.max(0);
+            }
+            PaymentKind::Prepayment => self.reamortize(),
+            PaymentKind::Reversal | PaymentKind::LateFee => unreachable!(),
+        }
+        Ok(())
+    }
+}
+
+const SELECT_LOAN_ACCOUNT: &str = "SELECT loan_id,
+        ROUND(balance * 100)::BIGINT AS principal,
+        ROUND(interest_due * 100)::BIGINT AS interest_due,
+        ROUND(fees_due * 100)::BIGINT AS fees_due,
+        ROUND(due_paid * 100)::BIGINT AS due_paid,
+        ROUND(installment * 100)::BIGINT AS installment,
+        interest_rate,
+        COALESCE(remaining_term, term) AS remaining_term,
+        accrual_method,
+        COALESCE(last_accrual_date, CURRENT_DATE) AS last_accrual_date,
+        COALESCE(next_due_date, (date_trunc('month', CURRENT_DATE) + INTERVAL '1 month')::DATE) AS next_due_date
+    FROM loans WHERE loan_id = $1";
+
+pub async fn load_loan_account(pool: &PgPool, loan_id: i32) -> Result<LoanAccount, AppError> {
+    sqlx::query_as::<_, LoanAccount>(SELECT_LOAN_ACCOUNT)
+        .bind(loan_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or(AppError::NotFound(format!("Loan {} not found", loan_id)))
+}
+
+/// The user signed up with the borrower's email, None if they haven't signed up
+pub async fn loan_borrower_user(
+    executor: impl PgExecutor<'_>,
+    loan_id: i32,
+) -> Result<Option<i32>, AppError> {
+    sqlx::query_scalar::<_, i32>(
+        "SELECT u.user_id FROM loans l
+        JOIN borrowers b ON b.borrower_id = l.borrower_id
+        JOIN users u ON u.email = b.email
+        WHERE l.loan_id = $1",
+    )
+    .bind(loan_id)
+    .fetch_optional(executor)
+    .await
+    .map_err(AppError::from)
+}
+
+/// A loan is only shown to its borrower and to consultants. Anyone else gets the same NotFound
+/// as for a loan that doesn't exist.
+pub(crate) async fn ensure_loan_access(
+    pool: &PgPool,
+    loan_id: i32,
+    user_id: i32,
+) -> Result<(), AppError> {
+    if is_active_consultant(pool, user_id).await?
+        || loan_borrower_user(pool, loan_id).await? == Some(user_id)
+    {
+        return Ok(());
+    }
+    Err(AppError::NotFound(format!("Loan {} not found", loan_id)))
+}
+
+pub(crate) async fn lock_loan_account(
+    tx: &mut Transaction<'_, Postgres>,
+    loan_id: i32,
+) -> Result<LoanAccount, AppError> {
+    sqlx::query_as::<_, LoanAccount>(&format!("{} FOR UPDATE", SELECT_LOAN_ACCOUNT))
+        .bind(loan_id)
+        .fetch_optional(&mut **tx)
+        .await?
+        .ok_or(AppError::NotFound(format!("Loan {} not found", loan_id)))
+}
+
+/// Writes the account back to loans. `paid` is what to add to the paid_* running totals,
+/// negative for a reversal.
+pub async fn save_loan_account(
+    tx: &mut Transaction<'_, Postgres>,
+    account: &LoanAccount,
+    paid: &Allocation,
+) -> Result<(), AppError> {
+    let status = if account.paid_off() {
+        Some(LoanStatus::FullyPaid as i32)
+    } else {
+        None
+    };
+    sqlx::query(
+        "UPDATE loans SET balance = $2 / 100.0, interest_due = $3 / 100.0, fees_due = $4 / 100.0,
+            due_paid = $5 / 100.0, installment = $6 / 100.0, remaining_term = $7,
+            last_accrual_date = $8, next_due_date = $9,
+            paid_total = paid_total + $10 / 100.0, paid_principal = paid_principal + $11 / 100.0,
+            paid_interest = paid_interest + $12 / 100.0, paid_late_fees = paid_late_fees + $13 / 100.0,
+            loan_status = COALESCE($14, CASE WHEN loan_status = $15 THEN $16 ELSE loan_status END)
+        WHERE loan_id = $1",
+    )
+    .bind(account.loan_id)
+    .bind(account.principal as f64)
+    .bind(account.interest_due as f64)
+    .bind(account.fees_due as f64)
+    .bind(account.due_paid as f64)
+    .bind(account.installment as f64)
+    .bind(account.remaining_term)
+    .bind(account.last_accrual_date)
+    .bind(account.next_due_date)
+    .bind(paid.applied() as f64)
+    .bind(paid.principal as f64)
+    .bind(paid.interest as f64)
+    .bind(paid.fees as f64)
+    .bind(status)
+    // A reversed payoff puts the loan back to Current
+    .bind(LoanStatus::FullyPaid as i32)
+    .bind(LoanStatus::Current as i32)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+/// Records a posting that took the account from `before` to `account`
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn insert_ledger_entry(
+    tx: &mut Transaction<'_, Postgres>,
+    before: &LoanAccount,
+    account: &LoanAccount,
+    kind: PaymentKind,
+    effective_date: NaiveDate,
+    amount: i64,
+    alloc: &Allocation,
+    reverses_payment_id: Option<i32>,
+) -> Result<LedgerEntry, AppError> {
+    sqlx::query_as::<_, LedgerEntry>(
+        "INSERT INTO loan_payments (loan_id, kind, effective_date, amount, accrued, fees, interest, principal,
+            unapplied, principal_after, installment_after, reverses_payment_id, remaining_term_before,
+            installment_before, due_paid_before)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15) RETURNING *",
+    )
+    .bind(account.loan_id)
+    .bind(kind)
+    .bind(effective_date)
+    .bind(amount)
+    .bind(alloc.accrued)
+    .bind(alloc.fees)
+    .bind(alloc.interest)
+    .bind(alloc.principal)
+    .bind(alloc.unapplied)
+    .bind(account.principal)
+    .bind(account.installment)
+    .bind(reverses_payment_id)
+    .bind(before.remaining_term)
+    .bind(before.installment)
+    .bind(before.due_paid)
+    .fetch_one(&mut **tx)
+    .await
+    .map_err(AppError::from)
+}
+
+pub async fn post_payment(
+    pool: &PgPool,
+    loan_id: i32,
+    amount: i64,
+    effective_date: NaiveDate,
+    kind: PaymentKind,
+) -> Result<(LedgerEntry, Allocation), AppError> {
+    let mut tx = begin_audited(pool).await?;
+    let mut account = lock_loan_account(&mut tx, loan_id).await?;
+    let before = account.clone();
+    let alloc = account.post_payment(amount, effective_date, kind)?;
+    save_loan_account(&mut tx, &account, &alloc).await?;
+    let entry = insert_ledger_entry(
+        &mut tx,
+        &before,
+        &account,
+        kind,
+        effective_date,
+        amount,
+        &alloc,
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok((entry, alloc))
+}
+
+pub async fn reverse_payment(
+    pool: &PgPool,
+    loan_id: i32,
+    payment_id: i32,
+) -> Result<LedgerEntry, AppError> {
+    let mut tx = begin_audited(pool).await?;
+    let mut account = lock_loan_account(&mut tx, loan_id).await?;
+    let original = sqlx::query_as::<_, LedgerEntry>(
+        "SELECT * FROM loan_payments WHERE payment_id = $1 AND loan_id = $2",
+    )
+    .bind(payment_id)
+    .bind(loan_id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(AppError::NotFound(format!(
+        "Payment {} not found",
+        payment_id
+    )))?;
+
+    let already_reversed: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM loan_payments WHERE reverses_payment_id = $1)",
+    )
+    .bind(payment_id)
+    .fetch_one(&mut *tx)
+    .await?;
+    if already_reversed {
+        return Err(AppError::InvalidRequest(format!(
+            "Payment {} was already reversed",
+            payment_id
+        )));
+    }
+
+    let before = account.clone();
+    account.reverse_payment(&original)?;
+    let reversed = Allocation {
+        fees: -original.fees,
+        interest: -original.interest,
+        principal: -original.principal,
+        unapplied: -original.unapplied,
+        ..Default::default()
+    };
+    // A waived fee was never paid, so the paid_* totals don't move
+    let paid = if original.kind == PaymentKind::LateFee {
+        Allocation::default()
+    } else {
+        reversed.clone()
+    };
+    save_loan_account(&mut tx, &account, &paid).await?;
+    let entry = insert_ledger_entry(
+        &mut tx,
+        &before,
+        &account,
+        PaymentKind::Reversal,
+        Utc::now().date_naive(),
+        -original.amount,
+        &reversed,
+        Some(payment_id),
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(entry)
+}
+
+pub async fn payment_ledger(pool: &PgPool, loan_id: i32) -> Result<Vec<LedgerEntry>, AppError> {
+    sqlx::query_as::<_, LedgerEntry>(
+        "SELECT * FROM loan_payments WHERE loan_id = $1 ORDER BY payment_id",
+    )
+    .bind(loan_id)
+    .fetch_all(pool)
+    .await
+    .map_err(AppError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn account(method: AccrualMethod) -> LoanAccount {
+        LoanAccount {
+            loan_id: 1,
+            principal: 1_200_000,
+            interest_due: 0,
+            fees_due: 0,
+            due_paid: 0,
+            installment: amortized_installment(1_200_000, 12.0, 12),
+            interest_rate: 12.0,
+            remaining_term: 12,
+            accrual_method: method,
+            last_accrual_date: date(2024, 1, 1),
+            next_due_date: date(2024, 2, 1),
+        }
+    }
+
+    #[test]
+    fn test_amortized_installment() {
+        // $12,000 at 12% over a year
+        assert_eq!(amortized_installment(1_200_000, 12.0, 12), 106_619);
+        assert_eq!(amortized_installment(1_200_000, 0.0, 12), 100_000);
+        assert_eq!(amortized_installment(5_000, 12.0, 0), 5_000);
+    }
+
+    #[test]
+    fn test_accrual_methods() {
+        let mut daily = account(AccrualMethod::Daily);
+        assert_eq!(daily.accrue(date(2024, 1, 31)), 11_836);
+        assert_eq!(daily.accrue(date(2024, 1, 15)), 0);
+
+        let mut monthly = account(AccrualMethod::Monthly);
+        assert_eq!(monthly.accrue(date(2024, 1, 31)), 0);
+        assert_eq!(monthly.accrue(date(2024, 2, 1)), 12_000);
+        assert_eq!(monthly.last_accrual_date, date(2024, 2, 1));
+    }
+
+    #[test]
+    fn allocates_fees_then_interest_then_principal() {
+        let mut loan = account(AccrualMethod::Monthly);
+        loan.fees_due = 2_500;
+        let alloc = loan
+            .post_payment(106_619, date(2024, 2, 1), PaymentKind::Regular)
+            .unwrap();
+        assert_eq!(alloc.fees, 2_500);
+        assert_eq!(alloc.interest, 12_000);
+        assert_eq!(alloc.principal, 106_619 - 2_500 - 12_000);
+        assert_eq!(alloc.installments_paid, 1);
+        assert_eq!(loan.next_due_date, date(2024, 3, 1));
+        assert_eq!(loan.remaining_term, 11);
+    }
+
+    #[test]
+    fn partial_payments_accumulate_toward_the_installment() {
+        let mut loan = account(AccrualMethod::Monthly);
+        let alloc = loan
+            .post_payment(50_000, date(2024, 2, 1), PaymentKind::Regular)
+            .unwrap();
+        assert_eq!(alloc.installments_paid, 0);
+        assert_eq!(loan.next_due_date, date(2024, 2, 1));
+        let alloc = loan
+            .post_payment(56_619, date(2024, 2, 1), PaymentKind::Regular)
+            .unwrap();
+        assert_eq!(alloc.installments_paid, 1);
+        assert_eq!(loan.due_paid, 0);
+    }
+
+    #[test]
+    fn overpayment_beyond_payoff_is_unapplied() {
+        let mut loan = account(AccrualMethod::Monthly);
+        let alloc = loan
+            .post_payment(1_300_000, date(2024, 2, 1), PaymentKind::Regular)
+            .unwrap();
+        assert_eq!(alloc.principal, 1_200_000);
+        assert_eq!(alloc.unapplied, 1_300_000 - 1_200_000 - 12_000);
+        assert!(loan.paid_off());
+        assert!(loan
+            .post_payment(100, date(2024, 2, 2), PaymentKind::Regular)
+            .is_err());
+    }
+
+    #[test]
+    fn prepayment_reamortizes() {
+        let mut loan = account(AccrualMethod::Monthly);
+        let before = loan.installment;
+        let alloc = loan
+            .post_payment(612_000, date(2024, 2, 1), PaymentKind::Prepayment)
+            .unwrap();
+        assert!(alloc.reamortized);
+        assert_eq!(loan.principal, 600_000);
+        assert_eq!(loan.next_due_date, date(2024, 2, 1));
+        assert_eq!(loan.installment, amortized_installment(600_000, 12.0, 12));
+        assert!(loan.installment < before);
+    }
+
+    /// The ledger entry for a regular payment that took `before` to `after`
+    fn posted(
+        before: &LoanAccount,
+        after: &LoanAccount,
+        on: NaiveDate,
+        amount: i64,
+        alloc: &Allocation,
+    ) -> LedgerEntry {
+        LedgerEntry {
+            payment_id: 1,
+            loan_id: after.loan_id,
+            kind: PaymentKind::Regular,
+            effective_date: on,
+            amount,
+            accrued: alloc.accrued,
+            fees: alloc.fees,
+            interest: alloc.interest,
+            principal: alloc.principal,
+            unapplied: alloc.unapplied,
+            principal_after: after.principal,
+            installment_after: after.installment,
+            reverses_payment_id: None,
+            created_at: Utc::now(),
+            remaining_term_before: Some(before.remaining_term),
+            installment_before: Some(before.installment),
+            due_paid_before: Some(before.due_paid),
+        }
+    }
+
+    #[test]
+    fn reversal_restores_balances_and_schedule() {
+        let mut loan = account(AccrualMethod::Monthly);
+        loan.accrue(date(2024, 2, 1));
+        let before = loan.clone();
+        let alloc = loan
+            .post_payment(106_619, date(2024, 2, 1), PaymentKind::Regular)
+            .unwrap();
+        let entry = posted(&before, &loan, date(2024, 2, 1), 106_619, &alloc);
+        loan.reverse_payment(&entry).unwrap();
+        assert_eq!(loan, before);
+    }
+
+    #[test]
+    fn reversing_a_payoff_restores_the_schedule() {
+        let mut loan = account(AccrualMethod::Monthly);
+        loan.post_payment(106_619, date(2024, 2, 1), PaymentKind::Regular)
+            .unwrap();
+        // Part of March's installment is already in
+        loan.post_payment(50_000, date(2024, 2, 15), PaymentKind::Regular)
+            .unwrap();
+        let before = loan.clone();
+        let total = loan.payoff_quote(date(2024, 2, 20)).total;
+        let alloc = loan
+            .post_payment(total, date(2024, 2, 20), PaymentKind::Regular)
+            .unwrap();
+        assert!(loan.paid_off());
+
+        let mut payoff = posted(&before, &loan, date(2024, 2, 20), total, &alloc);
+        let mut reversed = loan.clone();
+        reversed.reverse_payment(&payoff).unwrap();
+        // Still 11 installments from March, not the whole balance due at once
+        assert_eq!(reversed, before);
+        assert_eq!(reversed.remaining_term, 11);
+
+        payoff.remaining_term_before = None;
+        assert!(loan.clone().reverse_payment(&payoff).is_err());
+    }
+
+    #[test]
+    fn waived_late_fee_keeps_what_was_paid() {
+        let mut loan = account(AccrualMethod::Monthly);
+        loan.assess_late_fee(1_500);
+        let fee = LedgerEntry {
+            payment_id: 1,
+            loan_id: 1,
+            kind: PaymentKind::LateFee,
+            effective_date: date(2024, 2, 20),
+            amount: 1_500,
+            accrued: 0,
+            fees: 1_500,
+            interest: 0,
+            principal: 0,
+            unapplied: 0,
+            principal_after: loan.principal,
+            installment_after: loan.installment,
+            reverses_payment_id: None,
+            created_at: Utc::now(),
+            remaining_term_before: None,
+            installment_before: None,
+            due_paid_before: None,
+        };
+        assert!(loan
+            .post_payment(1_500, date(2024, 2, 20), PaymentKind::LateFee)
+            .is_err());
+        let alloc = loan
+            .post_payment(1_000, date(2024, 2, 20), PaymentKind::Regular)
+            .unwrap();
+        assert_eq!(alloc.fees, 1_000);
+        loan.reverse_payment(&fee).unwrap();
+        assert_eq!(loan.fees_due, 0);
+    }
+
+    #[test]
+    fn paying_the_quote_pays_off() {
+        let mut loan = account(AccrualMethod::Daily);
+        loan.assess_late_fee(1_500);
+        let quote = loan.payoff_quote(date(2024, 1, 31));
+        assert_eq!(quote.interest, 11_836);
+        assert_eq!(quote.total, 1_200_000 + 11_836 + 1_500);
+        // Quoting doesn't touch the account
+        assert_eq!(loan.interest_due, 0);
+
+        let alloc = loan
+            .post_payment(quote.total, date(2024, 1, 31), PaymentKind::Regular)
+            .unwrap();
+        assert!(loan.paid_off());
+        assert_eq!(alloc.unapplied, 0);
+    }
+}
@@ -0,0 +1,678 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    env, fs,
+    path::Path,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+
+use crate::{
+    error::AppError,
+    libs::{
+        credit_file_profile::numeric_cells,
+        credit_scorer::CreditScore,
+        joint_application::{co_borrower, CoBorrowerIncome, Household},
+    },
+    models::{
+        credit_file::{mock_credit_file, CreditFile, HomeOwnership},
+        loan::LoanPurpose,
+    },
+};
+
+pub const DEFAULT_RULES_PATH: &str = "underwriting/rules.toml";
+
+/// What the rule checks, phrased as the condition to pass.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Condition {
+    Min { value: f64 },
+    Max { value: f64 },
+    In { values: Vec<String> },
+    NotIn { values: Vec<String> },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+    Decline,
+    Refer,
+}
+
+/// What a rule does when its field has no value, e.g. DTI on an application with no bureau pull.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingAction {
+    Pass,
+    #[default]
+    Refer,
+    Decline,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Rule {
+    /// Reason code stored with the decision, e.g. INCOME_BELOW_MINIMUM
+    pub code: String,
+    pub description: String,
+    pub field: String,
+    #[serde(flatten)]
+    pub condition: Condition,
+    pub action: RuleAction,
+    #[serde(default)]
+    pub on_missing: MissingAction,
+}
+
+/// Rules are evaluated in order and every one runs, so a decline lists all of its reasons.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RuleSet {
+    /// Bump whenever the rules change. Decisions record the version they were made under.
+    pub version: i32,
+    pub name: String,
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[repr(i32)]
+pub enum Decision {
+    Approve = 1,
+    Refer = 2,
+    Decline = 3,
+}
+
+/// A value the rules can look at, by field name.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum Fact {
+    Number(f64),
+    Text(String),
+}
+
+/// The parts of a submitted application the rules see. The credit file and score are optional,
+/// an application from the web form has neither a bureau pull nor, until a model is trained, a score.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct UnderwritingInput {
+    pub state: String,
+    pub annual_income: i32,
+    pub desired_loan_amount: i32,
+    pub emp_length: i32,
+    pub loan_purpose: i32,
+    pub homeownership: i32,
+    #[serde(default)]
+    pub credit_file: Option<CreditFile>,
+    /// Probability of default from the credit scorer
+    #[serde(default)]
+    pub credit_score_pd: Option<f64>,
+    /// What the applicant says they pay on their debts each month
+    #[serde(default)]
+    pub monthly_debt: Option<i32>,
+    /// Set on joint applications
+    #[serde(default)]
+    pub co_borrower: Option<CoBorrowerIncome>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RuleResult {
+    pub code: String,
+    pub field: String,
+    pub value: Option<Fact>,
+    pub passed: bool,
+    /// Set when the rule failed
+    pub action: Option<RuleAction>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ReasonCode {
+    pub code: String,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct UnderwritingDecision {
+    pub rule_set_version: i32,
+    pub decision: Decision,
+    /// The failed rules behind the decision: the declines when declined, the referrals when referred
+    pub reasons: Vec<ReasonCode>,
+    pub results: Vec<RuleResult>,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct StoredDecision {
+    pub decision_id: i32,
+    pub application_id: i32,
+    pub rule_set_version: i32,
+    pub decision: Decision,
+    pub reason_codes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl UnderwritingInput {
+    pub fn with_score(mut self, score: Option<&CreditScore>) -> Self {
+        self.credit_score_pd = score.map(|score| score.pd);
+        self
+    }
+
+    pub fn household(&self) -> Household {
+        Household {
+            annual_income: self.annual_income,
+            monthly_debt: self.monthly_debt,
+            co_borrower: self.co_borrower,
+        }
+    }
+
+    /// Every field a rule can name. Credit file fields are absent without a credit file, and the
+    /// application's own income, state, employment length and stated debts win over the file's.
+    /// The qualifying_ fields count both parties on a joint application.
+    pub fn facts(&self) -> BTreeMap<&'static str, Option<Fact>> {
+        let mut facts: BTreeMap<&'static str, Option<Fact>> =
+            credit_file_facts(self.credit_file.as_ref());
+        let household = self.household();
+        let joint = self.co_borrower.is_some();
+        if let Some(dti) = household.debt_to_income() {
+            facts.insert("debt_to_income", Some(Fact::Number(dti)));
+        }
+        if joint {
+            facts.insert(
+                "annual_income_joint",
+                Some(Fact::Number(household.qualifying_income() as f64)),
+            );
+            if let Some(dti) = household.qualifying_debt_to_income() {
+                facts.insert("debt_to_income_joint", Some(Fact::Number(dti)));
+            }
+        }
+        let file_dti = if joint {
+            "debt_to_income_joint"
+        } else {
+            "debt_to_income"
+        };
+        let qualifying_dti = facts.get(file_dti).cloned().flatten();
+        let qualifying_income = household.qualifying_income();
+        let homeownership = HomeOwnership::try_from(self.homeownership)
+            .ok()
+            .map(|homeownership| Fact::Text(format!("{:?}", homeownership)));
+        let loan_purpose = LoanPurpose::try_from(self.loan_purpose)
+            .ok()
+            .map(|purpose| Fact::Text(purpose.to_string()));
+        let loan_to_income = (qualifying_income > 0)
+            .then(|| Fact::Number(self.desired_loan_amount as f64 / qualifying_income as f64));
+        facts.extend([
+            ("state", Some(Fact::Text(self.state.trim().to_uppercase()))),
+            (
+                "annual_income",
+                Some(Fact::Number(self.annual_income as f64)),
+            ),
+            (
+                "desired_loan_amount",
+                Some(Fact::Number(self.desired_loan_amount as f64)),
+            ),
+            ("emp_length", Some(Fact::Number(self.emp_length as f64))),
+            ("loan_purpose", loan_purpose),
+            ("homeownership", homeownership),
+            ("loan_to_income", loan_to_income),
+            ("credit_score_pd", self.credit_score_pd.map(Fact::Number)),
+            (
+                "application_type",
+                Some(Fact::Text(household.application_type().to_string())),
+            ),
+            (
+                "qualifying_income",
+                Some(Fact::Number(qualifying_income as f64)),
+            ),
+            ("qualifying_dti", qualifying_dti),
+            (
+                "co_borrower_income",
+                self.co_borrower
+                    .map(|co_borrower| Fact::Number(co_borrower.annual_income as f64)),
+            ),
+            (
+                "co_borrower_emp_length",
+                self.co_borrower
+                    .map(|co_borrower| Fact::Number(co_borrower.emp_length as f64)),
+            ),
+        ]);
+        facts
+    }
+}
+
+fn credit_file_facts(file: Option<&CreditFile>) -> BTreeMap<&'static str, Option<Fact>> {
+    let sample = mock_credit_file();
+    numeric_cells(file.unwrap_or(&sample))
+        .into_iter()
+        .map(|(column, value)| (column, value.filter(|_| file.is_some()).map(Fact::Number)))
+        .collect()
+}
+
+/// Fields taken from the application rather than the credit file, and whether they're numeric
+const APPLICATION_FIELDS: [(&str, bool); 13] = [
+    ("state", false),
+    ("annual_income", true),
+    ("desired_loan_amount", true),
+    ("emp_length", true),
+    ("loan_purpose", false),
+    ("homeownership", false),
+    ("loan_to_income", true),
+    ("credit_score_pd", true),
+    ("application_type", false),
+    ("qualifying_income", true),
+    ("qualifying_dti", true),
+    ("co_borrower_income", true),
+    ("co_borrower_emp_length", true),
+];
+
+/// Every field name a rule can use, with whether it's numeric.
+fn field_kinds() -> BTreeMap<&'static str, bool> {
+    let mut kinds: BTreeMap<&'static str, bool> = numeric_cells(&mock_credit_file())
+        .into_iter()
+        .map(|(column, _)| (column, true))
+        .collect();
+    kinds.extend(APPLICATION_FIELDS);
+    kinds
+}
+
+impl Condition {
+    fn passes(&self, fact: &Fact) -> bool {
+        match (self, fact) {
+            (Condition::Min { value }, Fact::Number(n)) => n >= value,
+            (Condition::Max { value }, Fact::Number(n)) => n <= value,
+            (Condition::In { values }, Fact::Text(s)) => values.iter().any(|v| v == s),
+            (Condition::NotIn { values }, Fact::Text(s)) => !values.iter().any(|v| v == s),
+            // Ruled out by validate
+            _ => false,
+        }
+    }
+
+    fn is_numeric(&self) -> bool {
+        matches!(self, Condition::Min { .. } | Condition::Max { .. })
+    }
+}
+
+impl RuleSet {
+    pub fn from_toml(s: &str) -> Result<Self, AppError> {
+        let rule_set: RuleSet = toml::from_str(s)
+            .map_err(|err| AppError::InvalidRequest(format!("Invalid rule set: {}", err)))?;
+        rule_set.validate()?;
+        Ok(rule_set)
+    }
+
+    pub fn from_json(s: &str) -> Result<Self, AppError> {
+        let rule_set: RuleSet = serde_json::from_str(s)
+            .map_err(|err| AppError::InvalidRequest(format!("Invalid rule set: {}", err)))?;
+        rule_set.validate()?;
+        Ok(rule_set)
+    }
+
+    /// TOML or JSON by extension
+    pub fn load(path: &Path) -> Result<Self, AppError> {
+        let s = fs::read_to_string(path).map_err(|err| {
+            AppError::GenericError(format!("Unable to read {}: {}", path.display(), err))
+        })?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Self::from_json(&s),
+            _ => Self::from_toml(&s),
+        }
+    }
+
+    /// The rule set in UNDERWRITING_RULES, or the one checked in at DEFAULT_RULES_PATH.
+    pub fn load_active() -> Result<Self, AppError> {
+        let path = env::var("UNDERWRITING_RULES").unwrap_or_else(|_| DEFAULT_RULES_PATH.to_owned());
+        Self::load(Path::new(&path))
+    }
+
+    pub fn validate(&self) -> Result<(), AppError> {
+        let invalid = |msg: String| Err(AppError::InvalidRequest(msg));
+        if self.version < 1 {
+            return invalid(format!(
+                "Rule set {} needs a version of 1 or more",
+                self.name
+            ));
+        }
+        let kinds = field_kinds();
+        let mut codes = HashSet::new();
+        for rule in &self.rules {
+            if rule.code.trim().is_empty() {
+                return invalid(format!("A rule on {} has no code", rule.field));
+            }
+            if !codes.insert(rule.code.as_str()) {
+                return invalid(format!("Rule code {} is used twice", rule.code));
+            }
+            let Some(&numeric) = kinds.get(rule.field.as_str()) else {
+                return invalid(format!(
+                    "Rule {} names unknown field {}",
+                    rule.code, rule.field
+                ));
+            };
+            if numeric != rule.condition.is_numeric() {
+                return invalid(format!(
+                    "Rule {} compares {} field {} with {:?}",
+                    rule.code,
+                    if numeric { "numeric" } else { "text" },
+                    rule.field,
+                    rule.condition
+                ));
+            }
+            if let Condition::In { values } | Condition::NotIn { values } = &rule.condition {
+                if values.is_empty() {
+                    return invalid(format!("Rule {} has no values", rule.code));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn evaluate(&self, input: &UnderwritingInput) -> UnderwritingDecision {
+        let facts = input.facts();
+        let results: Vec<RuleResult> = self
+            .rules
+            .iter()
+            .map(|rule| {
+                let value = facts.get(rule.field.as_str()).cloned().flatten();
+                let failed_as = match &value {
+                    Some(fact) if rule.condition.passes(fact) => None,
+                    Some(_) => Some(rule.action),
+                    None => match rule.on_missing {
+                        MissingAction::Pass => None,
+                        MissingAction::Refer => Some(RuleAction::Refer),
+                        MissingAction::Decline => Some(RuleAction::Decline),
+                    },
+                };
+                RuleResult {
+                    code: rule.code.clone(),
+                    field: rule.field.clone(),
+                    value,
+                    passed: failed_as.is_none(),
+                    action: failed_as,
+                }
+            })
+            .collect();
+
+        let failed = |action: RuleAction| -> Vec<ReasonCode> {
+            self.rules
+                .iter()
+                .zip(&results)
+                .filter(|(_, result)| result.action == Some(action))
+                .map(|(rule, _)| ReasonCode {
+                    code: rule.code.clone(),
+                    description: rule.description.clone(),
+                })
+                .collect()
+        };
+        let (decision, reasons) = match (failed(RuleAction::Decline), failed(RuleAction::Refer)) {
+            (declines, _) if !declines.is_empty() => (Decision::Decline, declines),
+            (_, referrals) if !referrals.is_empty() => (Decision::Refer, referrals),
+            _ => (Decision::Approve, vec![]),
+        };
+        UnderwritingDecision {
+            rule_set_version: self.version,
+            decision,
+            reasons,
+            results,
+        }
+    }
+}
+
+/// Records the rule set under its version. A version can't be reused for different rules,
+/// old decisions have to stay explainable.
+pub async fn register_rule_set(pool: &PgPool, rule_set: &RuleSet) -> Result<(), AppError> {
+    let rules = serde_json::to_value(rule_set)
+        .map_err(|err| AppError::GenericError(format!("Unable to serialize rule set: {}", err)))?;
+    let stored: serde_json::Value = sqlx::query_scalar(
+        "INSERT INTO underwriting_rule_sets (version, name, rules) VALUES ($1, $2, $3)
+        ON CONFLICT (version) DO UPDATE SET version = EXCLUDED.version
+        RETURNING rules",
+    )
+    .bind(rule_set.version)
+    .bind(&rule_set.name)
+    .bind(&rules)
+    .fetch_one(pool)
+    .await?;
+    if stored != rules {
+        return Err(AppError::GenericError(format!(
+            "Rule set version {} is already recorded with different rules, bump the version",
+            rule_set.version
+        )));
+    }
+    Ok(())
+}
+
+/// Evaluates an application against the active rules and stores the decision with its reason codes.
+pub async fn underwrite_application(
+    pool: &PgPool,
+    application_id: i32,
+    input: &UnderwritingInput,
+) -> Result<UnderwritingDecision, AppError> {
+    let rule_set = RuleSet::load_active()?;
+    register_rule_set(pool, &rule_set).await?;
+    let decision = rule_set.evaluate(input);
+    let reason_codes: Vec<&str> = decision.reasons.iter().map(|r| r.code.as_str()).collect();
+    let results = serde_json::to_value(&decision.results)
+        .map_err(|err| AppError::GenericError(format!("Unable to serialize results: {}", err)))?;
+    sqlx::query(
+        "INSERT INTO underwriting_decisions (application_id, rule_set_version, decision, reason_codes, results)
+        VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(application_id)
+    .bind(decision.rule_set_version)
+    .bind(decision.decision)
+    .bind(&reason_codes)
+    .bind(&results)
+    .execute(pool)
+    .await?;
+    tracing::info!(application_id, decision = ?decision.decision, ?reason_codes, "Application underwritten");
+    Ok(decision)
+}
+
+#[derive(Debug, FromRow)]
+struct ApplicationInputRow {
+    state: Option<String>,
+    annual_income: i32,
+    desired_loan_amount: i32,
+    emp_length: i32,
+    loan_purpose: i32,
+    homeownership: i32,
+    monthly_debt: Option<i32>,
+}
+
+/// A stored application as the rules saw it when it was submitted, less the credit score
+pub async fn application_underwriting_input(
+    pool: &PgPool,
+    application_id: i32,
+) -> Result<UnderwritingInput, AppError> {
+    let row = sqlx::query_as::<_, ApplicationInputRow>(
+        "SELECT state, annual_income, desired_loan_amount, emp_length, loan_purpose, homeownership, monthly_debt
+        FROM applications WHERE application_id = $1",
+    )
+    .bind(application_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Application {} not found", application_id)))?;
+    let co_borrower = co_borrower(pool, application_id).await?;
+    Ok(UnderwritingInput {
+        state: row.state.unwrap_or_default(),
+        annual_income: row.annual_income,
+        desired_loan_amount: row.desired_loan_amount,
+        emp_length: row.emp_length,
+        loan_purpose: row.loan_purpose,
+        homeownership: row.homeownership,
+        credit_file: None,
+        credit_score_pd: None,
+        monthly_debt: row.monthly_debt,
+        co_borrower: co_borrower.as_ref().map(CoBorrowerIncome::from),
+    })
+}
+
+pub async fn application_decisions(
+    pool: &PgPool,
+    application_id: i32,
+) -> Result<Vec<StoredDecision>, AppError> {
+    sqlx::query_as::<_, StoredDecision>(
+        "SELECT decision_id, application_id, rule_set_version, decision, reason_codes, created_at
+        FROM underwriting_decisions WHERE application_id = $1 ORDER BY decision_id",
+    )
+    .bind(application_id)
+    .fetch_all(pool)
+    .await
+    .map_err(AppError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = r#"
+        version = 2
+        name = "test"
+
+        [[rules]]
+        code = "INCOME_BELOW_MINIMUM"
+        description = "Income insufficient for amount of credit requested"
+        field = "annual_income"
+        op = "min"
+        value = 25000
+        action = "decline"
+
+        [[rules]]
+        code = "STATE_NOT_SERVED"
+        description = "We do not lend in the applicant's state"
+        field = "state"
+        op = "not_in"
+        values = ["IA", "WV"]
+        action = "decline"
+
+        [[rules]]
+        code = "BANKRUPTCY"
+        description = "Bankruptcy on the credit file"
+        field = "public_record_bankrupt"
+        op = "max"
+        value = 0
+        action = "decline"
+        on_missing = "pass"
+
+        [[rules]]
+        code = "DTI_UNVERIFIED"
+        description = "Debt to income needs a bureau pull"
+        field = "debt_to_income"
+        op = "max"
+        value = 40
+        action = "decline"
+    "#;
+
+    fn input() -> UnderwritingInput {
+        UnderwritingInput {
+            state: "ne".to_owned(),
+            annual_income: 60000,
+            desired_loan_amount: 15000,
+            emp_length: 4,
+            loan_purpose: LoanPurpose::DebtConsolidation as i32,
+            homeownership: HomeOwnership::Rent as i32,
+            credit_file: None,
+            credit_score_pd: None,
+            monthly_debt: None,
+            co_borrower: None,
+        }
+    }
+
+    fn codes(decision: &UnderwritingDecision) -> Vec<&str> {
+        decision.reasons.iter().map(|r| r.code.as_str()).collect()
+    }
+
+    #[test]
+    fn missing_bureau_data_refers() {
+        let rules = RuleSet::from_toml(RULES).unwrap();
+        let decision = rules.evaluate(&input());
+        assert_eq!(decision.rule_set_version, 2);
+        assert_eq!(decision.decision, Decision::Refer);
+        assert_eq!(codes(&decision), ["DTI_UNVERIFIED"]);
+    }
+
+    #[test]
+    fn credit_file_approves_or_declines_with_every_reason() {
+        let rules = RuleSet::from_toml(RULES).unwrap();
+        let mut file = mock_credit_file();
+        file.debt_to_income = Some(18.5);
+        file.public_record_bankrupt = 0;
+        let approved = rules.evaluate(&UnderwritingInput {
+            credit_file: Some(file.clone()),
+            ..input()
+        });
+        assert_eq!(approved.decision, Decision::Approve);
+        assert!(approved.reasons.is_empty());
+
+        file.public_record_bankrupt = 1;
+        let declined = rules.evaluate(&UnderwritingInput {
+            state: "WV".to_owned(),
+            annual_income: 20000,
+            credit_file: Some(file),
+            ..input()
+        });
+        assert_eq!(declined.decision, Decision::Decline);
+        assert_eq!(
+            codes(&declined),
+            ["INCOME_BELOW_MINIMUM", "STATE_NOT_SERVED", "BANKRUPTCY"]
+        );
+        assert_eq!(declined.results[0].value, Some(Fact::Number(20000.0)));
+    }
+
+    #[test]
+    fn joint_applications_qualify_on_both_parties() {
+        let rules = RuleSet::from_toml(
+            &RULES
+                .replace(
+                    r#"field = "annual_income""#,
+                    r#"field = "qualifying_income""#,
+                )
+                .replace(r#"field = "debt_to_income""#, r#"field = "qualifying_dti""#),
+        )
+        .unwrap();
+        let individual = UnderwritingInput {
+            annual_income: 20000,
+            monthly_debt: Some(800),
+            ..input()
+        };
+        let declined = rules.evaluate(&individual);
+        assert_eq!(codes(&declined), ["INCOME_BELOW_MINIMUM", "DTI_UNVERIFIED"]);
+
+        let joint = UnderwritingInput {
+            co_borrower: Some(CoBorrowerIncome {
+                annual_income: 40000,
+                emp_length: 2,
+                monthly_debt: Some(400),
+            }),
+            ..individual
+        };
+        let facts = joint.facts();
+        assert_eq!(
+            facts["application_type"],
+            Some(Fact::Text("joint".to_owned()))
+        );
+        assert_eq!(facts["debt_to_income"], Some(Fact::Number(48.0)));
+        assert_eq!(facts["qualifying_dti"], Some(Fact::Number(24.0)));
+        assert_eq!(facts["loan_to_income"], Some(Fact::Number(0.25)));
+        assert_eq!(rules.evaluate(&joint).decision, Decision::Approve);
+    }
+
+    #[test]
+    fn json_rule_sets_load_too() {
+        let toml_rules = RuleSet::from_toml(RULES).unwrap();
+        let json = serde_json::to_string(&toml_rules).unwrap();
+        assert_eq!(RuleSet::from_json(&json).unwrap(), toml_rules);
+    }
+
+    #[test]
+    fn rejects_bad_rule_sets() {
+        let unknown_field = RULES.replace("public_record_bankrupt", "bankruptcies");
+        assert!(RuleSet::from_toml(&unknown_field).is_err());
+        let text_compared_numerically =
+            RULES.replace(r#"field = "annual_income""#, r#"field = "state""#);
+        assert!(RuleSet::from_toml(&text_compared_numerically).is_err());
+        let duplicate_code = RULES.replace("DTI_UNVERIFIED", "BANKRUPTCY");
+        assert!(RuleSet::from_toml(&duplicate_code).is_err());
+        assert!(RuleSet::from_toml(&RULES.replace("version = 2", "version = 0")).is_err());
+    }
+
+    #[test]
+    fn checked_in_rules_are_valid() {
+        let rules = RuleSet::load(Path::new(DEFAULT_RULES_PATH)).unwrap();
+        assert_eq!(rules.evaluate(&input()).decision, Decision::Approve);
+    }
+}
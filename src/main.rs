@@ -11,6 +11,7 @@ mod users;
 mod web;
 // Import modules
 mod actors;
+mod cli;
 mod config;
 mod controllers;
 mod error;
@@ -50,5 +51,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     //     .json()
     //     .init();
 
+    // One-off jobs, e.g. ```cargo run -- import_credit_file assets/data/____credit_file.csv```
+    if let Some(result) = cli::run_command(run_migration, args.get(2..).unwrap_or(&[])).await {
+        return result;
+    }
+
     App::new(run_migration).await?.serve().await
 }
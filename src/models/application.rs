@@ -1,11 +1,16 @@
 use askama::Template;
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
+use struct_iterable::Iterable;
 use validator::Validate;
 
-use crate::config::{
-    default_state_opts, employment_options, get_state_options, homeownership_options,
-    marital_status_options, purpose_options, FormErrorResponse, SelectOption, StringSelectOption,
+use crate::{
+    config::{
+        default_state_opts, employment_options, get_state_options, homeownership_options,
+        marital_status_options, purpose_options, FormErrorResponse, SelectOption,
+        StringSelectOption,
+    },
+    libs::record_diff::{diff_records, DiffOptions, RecordDiff},
 };
 
 use super::auth::CurrentUser;
@@ -31,6 +36,7 @@ pub struct Application<'a> {
     pub ssn: &'a str,
     pub contact_id: i32,
     pub dob: &'a str,
+    pub monthly_debt: &'a str,
 }
 
 impl Default for Application<'_> {
@@ -55,6 +61,7 @@ impl Default for Application<'_> {
             zip: "68124",
             phone: "402-392-0126",
             contact_id: 1,
+            monthly_debt: "",
         }
     }
 }
@@ -131,3 +138,106 @@ impl Default for ApplicationTemplate<'_> {
         }
     }
 }
+
+/// The comparable columns of an `applications` row, for telling what changed between two reads.
+/// Legacy plaintext PII and the timestamps are left out.
+#[derive(Debug, Clone, FromRow, Iterable)]
+pub struct StoredApplication {
+    pub application_id: i32,
+    pub location_id: i32,
+    pub first_name: String,
+    pub last_name: String,
+    pub city: String,
+    pub state: Option<String>,
+    pub zip: Option<String>,
+    pub annual_income: i32,
+    pub marital_status: i32,
+    pub desired_loan_amount: i32,
+    pub loan_purpose: i32,
+    pub homeownership: i32,
+    pub employment_status: i32,
+    pub emp_length: i32,
+    pub monthly_debt: Option<i32>,
+    pub application_status: i32,
+    pub application_type: i32,
+    pub consultant_id: Option<i32>,
+    pub ssn_hmac: Option<String>,
+    pub ssn_last4_enc: Option<String>,
+    pub dob_enc: Option<String>,
+    pub phone_enc: Option<String>,
+    pub address_one_enc: Option<String>,
+    pub address_two_enc: Option<String>,
+}
+
+/// Sealing uses a fresh nonce, so the same value sealed twice never compares equal
+pub const SEALED_APPLICATION_COLUMNS: [&str; 5] = [
+    "ssn_last4_enc",
+    "dob_enc",
+    "phone_enc",
+    "address_one_enc",
+    "address_two_enc",
+];
+
+impl StoredApplication {
+    pub fn diff_options() -> DiffOptions {
+        SEALED_APPLICATION_COLUMNS
+            .into_iter()
+            .fold(DiffOptions::new(), DiffOptions::ignore)
+    }
+
+    pub fn diff(&self, other: &Self) -> RecordDiff {
+        diff_records(self, other, &Self::diff_options())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored() -> StoredApplication {
+        StoredApplication {
+            application_id: 7,
+            location_id: 1,
+            first_name: "Jimbo".to_owned(),
+            last_name: "Smith".to_owned(),
+            city: "Omaha".to_owned(),
+            state: Some("NE".to_owned()),
+            zip: Some("68124".to_owned()),
+            annual_income: 75000,
+            marital_status: 1,
+            desired_loan_amount: 56000,
+            loan_purpose: 1,
+            homeownership: 1,
+            employment_status: 1,
+            emp_length: 3,
+            monthly_debt: None,
+            application_status: 1,
+            application_type: 1,
+            consultant_id: None,
+            ssn_hmac: Some("1$ab12".to_owned()),
+            ssn_last4_enc: Some("1$key$one".to_owned()),
+            dob_enc: Some("1$key$one".to_owned()),
+            phone_enc: Some("1$key$one".to_owned()),
+            address_one_enc: Some("1$key$one".to_owned()),
+            address_two_enc: None,
+        }
+    }
+
+    #[test]
+    fn resealing_is_not_a_change() {
+        let old = stored();
+        let mut new = stored();
+        new.address_one_enc = Some("1$key$two".to_owned());
+        new.annual_income = 80000;
+        new.monthly_debt = Some(1200);
+        let diff = old.diff(&new);
+        assert_eq!(diff.distance(), 2);
+        assert!(diff.changed("annual_income"));
+        assert!(diff.changed("monthly_debt"));
+        assert!(!diff.changed("address_one_enc"));
+        assert_eq!(
+            diff.compared,
+            old.iter().count() - SEALED_APPLICATION_COLUMNS.len()
+        );
+    }
+}
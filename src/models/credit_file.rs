@@ -4,8 +4,11 @@ use sqlx::FromRow;
 use struct_iterable::Iterable;
 use validator::Validate;
 
-use crate::libs::credit_file_enums::{
-    deserialize_homeownership, deserialize_income_verification, deserialize_na_col,
+use crate::libs::{
+    credit_file_enums::{
+        deserialize_homeownership, deserialize_income_verification, deserialize_na_col,
+    },
+    record_diff::{diff_records, DiffOptions, RecordDiff},
 };
 
 #[derive(Debug, Validate, Serialize, Clone, FromRow, Deserialize, PartialEq, Iterable)]
@@ -59,82 +62,94 @@ pub struct CreditFile {
     pub public_record_bankrupt: i32,
 }
 
-impl CreditFile {
-    fn hamming_distance(&self, other: &Self) -> usize {
-        if other == self {
-            // They all match
-            other.iter().count()
-        } else {
-            let mut count = 0;
-
-            // if self.borrower_id == other.borrower_id {count += 1} else {};
-            if self.emp_title == other.emp_title {
-                count += 1
-            } else {
-            };
-            if self.emp_length == other.emp_length {
-                count += 1
-            } else {
-            };
-            if self.state == other.state {
-                count += 1
-            } else {
-            };
-            if self.homeownership == other.homeownership {
-                count += 1
-            } else {
-            };
-            if self.annual_income == other.borrower_id {
-                count += 1
-            } else {
-            };
+// verification_income_joint is NULL unless the file is joint, which maps to IncomeVerification::Empty
+pub const SELECT_CREDIT_FILES: &str = "SELECT borrower_id, emp_title, emp_length, state, homeownership, annual_income, \
+    verified_income, debt_to_income, annual_income_joint, COALESCE(verification_income_joint, 0) AS verification_income_joint, \
+    debt_to_income_joint, delinq_2y, months_since_last_delinq, earliest_credit_line, inquiries_last_12m, total_credit_lines, \
+    open_credit_lines, total_credit_limit, total_credit_utilized, num_collections_last_12m, num_historical_failed_to_pay, \
+    months_since_90d_late, current_accounts_delinq, total_collection_amount_ever, current_installment_accounts, \
+    accounts_opened_24m, months_since_last_credit_inquiry, num_satisfactory_accounts, num_accounts_120d_past_due, \
+    num_accounts_30d_past_due, num_active_debit_accounts, total_debit_limit, num_total_cc_accounts, num_open_cc_accounts, \
+    num_cc_carrying_balance, num_mort_accounts, account_never_delinq_percent, tax_liens, public_record_bankrupt \
+    FROM credit_file";
 
-            // if self.verification_income_joint == other.verification_income_joint {count += 1} else {};
-            // if self.debt_to_income == other.debt_to_income {count += 1} else {};
-            // if self.delinq_2y == other.delinq_2y {count += 1} else {};
-            // if self.months_since_last_delinq == other.months_since_last_delinq {count += 1} else {};
-            // if self.earliest_credit_line == other.earliest_credit_line {count += 1} else {};
-            // if self.inquiries_last_12m == other.inquiries_last_12m {count += 1} else {};
-            // if self.total_credit_lines == other.total_credit_lines {count += 1} else {};
-            // if self.open_credit_lines == other.open_credit_lines {count += 1} else {};
-            // if self.total_credit_limit == other.total_credit_limit {count += 1} else {};
-            // if self.total_credit_utilized == other.total_credit_utilized {count += 1} else {};
-            // if self.num_collections_last_12m == other.num_collections_last_12m {count += 1} else {};
-            // if self.num_historical_failed_to_pay == other.num_historical_failed_to_pay {count += 1} else {};
-            // if self.months_since_90d_late == other.months_since_90d_late {count += 1} else {};
-            // if self.current_accounts_delinq == other.current_accounts_delinq {count += 1} else {};
-            // if self.total_collection_amount_ever == other.total_collection_amount_ever {count += 1} else {};
-            // if self.current_installment_accounts == other.current_installment_accounts {count += 1} else {};
-            // if self.accounts_opened_24m == other.accounts_opened_24m {count += 1} else {};
-            // if self.months_since_last_credit_inquiry == other.months_since_last_credit_inquiry {count += 1} else {};
-            // if self.num_satisfactory_accounts == other.num_satisfactory_accounts {count += 1} else {};
-            // if self.num_accounts_120d_past_due == other.num_accounts_120d_past_due {count += 1} else {};
-            // if self.num_accounts_30d_past_due == other.num_accounts_30d_past_due {count += 1} else {};
-            // if self.num_active_debit_accounts == other.num_active_debit_accounts {count += 1} else {};
-            // if self.total_debit_limit == other.total_debit_limit {count += 1} else {};
-            // if self.num_total_cc_accounts == other.num_total_cc_accounts {count += 1} else {};
-            // if self.num_open_cc_accounts == other.num_open_cc_accounts {count += 1} else {};
-            // if self.num_cc_carrying_balance == other.num_cc_carrying_balance {count += 1} else {};
-            // if self.num_mort_accounts == other.num_mort_accounts {count += 1} else {};
-            // if self.account_never_delinq_percent == other.account_never_delinq_percent {count += 1} else {};
-            // if self.tax_liens == other.tax_liens {count += 1} else {};
-            // if self.public_record_bankrupt == other.public_record_bankrupt {count += 1} else {};
+// Rows whose enum codes map to a HomeOwnership / IncomeVerification variant
+pub const DECODABLE_CREDIT_FILES: &str = "homeownership BETWEEN 1 AND 3 AND verified_income BETWEEN 0 AND 3 \
+    AND COALESCE(verification_income_joint, 0) BETWEEN 0 AND 3";
 
-            count
+impl CreditFile {
+    /// What an application tells us before there is a bureau pull. Everything else is
+    /// zero or NA, so score these with only the application columns marked as known.
+    pub fn applicant(
+        state: &str,
+        annual_income: i32,
+        homeownership: HomeOwnership,
+        emp_length: i32,
+    ) -> Self {
+        CreditFile {
+            borrower_id: 0,
+            emp_title: None,
+            emp_length: Some(emp_length),
+            state: state.to_owned(),
+            homeownership,
+            annual_income,
+            verified_income: IncomeVerification::NotVerified,
+            debt_to_income: None,
+            annual_income_joint: None,
+            verification_income_joint: IncomeVerification::Empty,
+            debt_to_income_joint: None,
+            delinq_2y: 0,
+            months_since_last_delinq: None,
+            earliest_credit_line: 0,
+            inquiries_last_12m: 0,
+            total_credit_lines: None,
+            open_credit_lines: 0,
+            total_credit_limit: 0,
+            total_credit_utilized: 0,
+            num_collections_last_12m: 0,
+            num_historical_failed_to_pay: 0,
+            months_since_90d_late: None,
+            current_accounts_delinq: 0,
+            total_collection_amount_ever: 0,
+            current_installment_accounts: 0,
+            accounts_opened_24m: 0,
+            months_since_last_credit_inquiry: None,
+            num_satisfactory_accounts: 0,
+            num_accounts_120d_past_due: None,
+            num_accounts_30d_past_due: 0,
+            num_active_debit_accounts: 0,
+            total_debit_limit: 0,
+            num_total_cc_accounts: 0,
+            num_open_cc_accounts: 0,
+            num_cc_carrying_balance: 0,
+            num_mort_accounts: 0,
+            account_never_delinq_percent: 100.0,
+            tax_liens: 0,
+            public_record_bankrupt: 0,
         }
     }
+
+    pub fn diff(&self, other: &Self, opts: &DiffOptions) -> RecordDiff {
+        diff_records(self, other, opts)
+    }
+
+    /// Number of fields the two files agree on, so equal files score the full field count.
+    /// The Hamming distance is `diff(..).distance()`.
+    fn matching_fields(&self, other: &Self) -> usize {
+        self.diff(other, &DiffOptions::new()).matching()
+    }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
-#[repr(u32)]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, sqlx::Type)]
+#[repr(i32)]
 pub enum HomeOwnership {
     Own = 1,
     Mortgage = 2,
     Rent = 3,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
-#[repr(u32)]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, sqlx::Type)]
+#[repr(i32)]
 pub enum IncomeVerification {
     Verified = 1,
     SourceVerified = 2,
@@ -220,7 +235,7 @@ mod tests {
         let cf = mock_credit_file();
         let cf2 = cf.clone();
         let len = cf.iter().count();
-        let dist = cf.hamming_distance(&cf2);
+        let dist = cf.matching_fields(&cf2);
         assert_eq!(dist, len);
     }
     // This should almost always pass FIXME
@@ -229,9 +244,22 @@ mod tests {
         let cf = mock_credit_file();
         let cf2 = mock_credit_file();
         let len = cf.iter().count();
-        let dist = cf.hamming_distance(&cf2);
+        let dist = cf.matching_fields(&cf2);
         assert!(dist < len);
     }
+    #[test]
+    fn diff_reports_changed_fields() {
+        let cf = mock_credit_file();
+        let mut cf2 = cf.clone();
+        cf2.annual_income = cf.annual_income + 100;
+        cf2.tax_liens = 1;
+        let diff = cf.diff(&cf2, &DiffOptions::new());
+        assert_eq!(diff.distance(), 2);
+        assert!(diff.changed("annual_income") && diff.changed("tax_liens"));
+        let opts = DiffOptions::new().tolerance("annual_income", 500.0).ignore("tax_liens");
+        assert!(cf.diff(&cf2, &opts).is_empty());
+        assert_eq!(cf.matching_fields(&cf2), cf.iter().count() - 2);
+    }
     // #[test]
     // fn test_date_convert() {
     //     let converted_date = convert_date(DATE_STR).unwrap();
@@ -2,6 +2,7 @@ use std::{collections::HashSet, hash::RandomState, sync::{Arc, MutexGuard}};
 
 use crate::{
     actors::actor::mock_offer,
+    libs::credit_scorer::CreditScore,
     models::{auth::CurrentUser, offer::Offer},
     users::AuthSession,
 };
@@ -27,6 +28,8 @@ use super::AppState;
 use super::SharedState;
 use lrtc::{CompressionAlgorithm, classify};
 
+pub(crate) use post::{create_application, ApplicationInput, FormValidationTemplate};
+
 pub fn router() -> Router<Arc<Mutex<SharedState>>> {
     Router::new()
         .route("/application", get(self::get::get_application))
@@ -41,13 +44,6 @@ pub fn router() -> Router<Arc<Mutex<SharedState>>> {
         .route("/websocket", get(websocket_handler))
 }
 
-fn get_comp_offer(app: ApplicationPostResponse) -> Offer {
-    // let offers = aggregate_offers(1);
-    let lc_offer = mock_offer(1);
-    // let lc_offers = vec![&lc_offer];
-    lc_offer
-}
-
 #[derive(Debug, Deserialize, FromRow)]
 pub struct ApplicationPostResponse {
     pub application_id: i32,
@@ -143,9 +139,9 @@ fn check_username(user_set: &Mutex<HashSet<String, RandomState>>, string: &mut S
 
 
 mod post {
-    use std::{collections::HashMap, convert::Infallible, time::Duration};
+    use std::{collections::HashMap, convert::Infallible, net::SocketAddr, time::Duration};
 
-    use axum::{extract::State, http::HeaderMap, response::Redirect, Extension, Form};
+    use axum::{extract::{ConnectInfo, State}, http::HeaderMap, response::Redirect, Extension, Form};
     use chrono::NaiveDate;
     use fastembed::TextEmbedding;
     use futures_util::{stream, Stream, StreamExt};
@@ -164,11 +160,29 @@ mod post {
         actors::actor::{aggregate_offers, mock_offer, ActorHandle, ActorMessage, EmbeddingSimilarsResponse},
         config::{get_validation_response, FormErrorResponse, UserAlert},
         controllers::offer_controller::OffersTemplate,
+        error::AppError,
+        libs::{
+            address::{merge_errors, validate_address, ValidatedAddress},
+            application_draft::claim_draft,
+            adverse_action::{issue_adverse_action_notice, CREDIT_SCORE_DECLINE_CODE, DEFAULT_NOTICE_DIR, DTI_DECLINE_CODE},
+            affordability::{comp_offer, policy_max_dti, AffordabilityProfile},
+            audit_trail::{begin_audited, current_audit_context, in_audit_context},
+            application_lifecycle::{record_created_in, transition_application, transition_application_in, ApplicationStatus},
+            credit_scorer::{save_credit_score, CreditScorer},
+            fraud_screening::screen_application,
+            joint_application::{insert_co_borrower, Household, HouseholdForm, HouseholdInput},
+            outbox::{record_event, EventType},
+            pii::{keyring, ssn_digits, ssn_last4, Redacted},
+            review_queue::assign_consultant_in,
+            underwriting::{underwrite_application, Decision, RuleSet, UnderwritingInput},
+        },
+        models::credit_file::HomeOwnership,
+        users::User,
     };
 
     use super::*;
 
-    #[derive(Debug, Deserialize, Validate)]
+    #[derive(Deserialize, Validate)]
     pub struct ApplicationInput {
         pub location_id: i32,
         pub first_name: String,
@@ -188,6 +202,45 @@ mod post {
         pub homeownership: i32,
         pub employment_status: i32,
         pub emp_length: i32,
+        #[serde(flatten)]
+        pub household: HouseholdForm,
+    }
+
+    impl std::fmt::Debug for ApplicationInput {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("ApplicationInput")
+                .field("location_id", &self.location_id)
+                .field("first_name", &Redacted)
+                .field("last_name", &Redacted)
+                .field("address_one", &Redacted)
+                .field("address_two", &Redacted)
+                .field("city", &self.city)
+                .field("state", &self.state)
+                .field("zip", &self.zip)
+                .field("phone", &Redacted)
+                .field("ssn", &Redacted)
+                .field("dob", &Redacted)
+                .field("annual_income", &self.annual_income)
+                .field("marital_status", &self.marital_status)
+                .field("desired_loan_amount", &self.desired_loan_amount)
+                .field("loan_purpose", &self.loan_purpose)
+                .field("homeownership", &self.homeownership)
+                .field("employment_status", &self.employment_status)
+                .field("emp_length", &self.emp_length)
+                .field("household", &self.household)
+                .finish()
+        }
+    }
+
+    impl ApplicationInput {
+        /// Stores the address the way USPS writes it
+        pub fn standardize_address(&mut self, address: ValidatedAddress) {
+            self.address_one = address.address_one();
+            self.address_two = address.address_two();
+            self.city = address.city;
+            self.state = address.state;
+            self.zip = address.zip;
+        }
     }
 
     #[derive(Debug, Deserialize, Validate)]
@@ -227,8 +280,8 @@ mod post {
 
     #[derive(Debug, Template)]
     #[template(path = "form/form-validation.html")]
-    struct FormValidationTemplate {
-        form_response: FormErrorResponse,
+    pub(crate) struct FormValidationTemplate {
+        pub form_response: FormErrorResponse,
     }
 
     #[derive(Debug, Template)]
@@ -237,12 +290,224 @@ mod post {
         pub message: &'a str,
     }
 
+    /// Scores with the model loaded at startup and stores the score against the application.
+    /// None if no model has been trained or the form's homeownership code is unknown.
+    /// A joint application is scored on the joint income and DTI as well.
+    async fn score_application(
+        pool: &PgPool,
+        scorer: Option<&CreditScorer>,
+        application_id: i32,
+        application: &ApplicationInput,
+        household: &Household,
+    ) -> Option<CreditScore> {
+        let scorer = scorer?;
+        let homeownership = HomeOwnership::try_from(application.homeownership).ok()?;
+        let (file, known) = household.credit_file(&application.state, homeownership, application.emp_length);
+        let score = scorer.score_partial(&file, &known);
+        if let Err(err) = save_credit_score(pool, application_id, &score).await {
+            tracing::error!(application_id, error = ?err, "Credit score not saved");
+        }
+        Some(score)
+    }
+
+    /// Stores a validated application, then screens, underwrites and prices it in the background.
+    /// Shared by the one page form and the draft wizard's submit, which passes the draft to claim
+    /// in the same transaction. Also returns the new application's id, None if it wasn't stored.
+    pub(crate) async fn create_application(
+        state: Arc<Mutex<SharedState>>,
+        pool: PgPool,
+        addr: SocketAddr,
+        user: User,
+        application: ApplicationInput,
+        household_input: HouseholdInput,
+        draft_id: Option<i32>,
+    ) -> (Option<i32>, Response) {
+        let household = household_input.household(application.annual_income);
+        let ssn_str = ssn_digits(&application.ssn);
+        let dob = NaiveDate::parse_from_str(&application.dob, "%Y-%m-%d").unwrap();
+        // let ssn = ssn_str.parse::<i32>().unwrap();
+        // let ssn_to_nacl = SsnToNacl { ssn: ssn };
+        // let mut hasher = DefaultHasher::new();
+        // ssn_to_nacl.hash(&mut hasher);
+        // let ssn_nacl = hasher.finish();
+        // println!("{:?}", &ssn_nacl);
+        let app_slug = Uuid::new_v4().simple().to_string();
+        let inserted = async {
+            // Only the lookup key and sealed copies are stored, see libs::pii
+            let keyring = keyring()?;
+            let dob = Some(dob).filter(|dob| dob.to_string() != "1900-01-01");
+            let mut tx = begin_audited(&pool).await.map_err(|err| AppError::GenericError(err.to_string()))?;
+            let app = sqlx::query_as::<_, ApplicationPostResponse>(
+                "INSERT INTO applications (application_slug, location_id, first_name, last_name, address_one_enc, address_two_enc, city, state, zip, phone_enc, ssn_hmac, ssn_last4_enc, dob_enc, marital_status, desired_loan_amount, loan_purpose, annual_income, homeownership, employment_status, emp_length, application_status, application_type, monthly_debt, ip_address) 
+                        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22, $23, $24) RETURNING application_id",
+            )
+            .bind(app_slug)
+            .bind(&application.location_id)
+            .bind(&application.first_name)
+            .bind(&application.last_name)
+            .bind(keyring.seal("applications.address_one", &application.address_one)?)
+            .bind(keyring.seal_opt("applications.address_two", &application.address_two)?)
+            .bind(&application.city)
+            .bind(&application.state)
+            .bind(&application.zip)
+            .bind(keyring.seal_opt("applications.phone", &application.phone)?)
+            .bind(keyring.lookup_key(&ssn_str))
+            .bind(keyring.seal("applications.ssn_last4", &ssn_last4(&ssn_str))?)
+            .bind(dob.map(|dob| keyring.seal("applications.dob", &dob.to_string())).transpose()?)
+            .bind(&application.marital_status)
+            .bind(&application.desired_loan_amount)
+            .bind(&application.loan_purpose)
+            .bind(&application.annual_income)
+            .bind(&application.homeownership)
+            .bind(&application.employment_status)
+            .bind(&application.emp_length)
+            .bind(ApplicationStatus::Submitted)
+            .bind(household.application_type() as i32)
+            .bind(household_input.monthly_debt)
+            .bind(addr.ip().to_string())
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|err| AppError::GenericError(err.to_string()))?;
+            if let Some(co_borrower) = &household_input.co_borrower {
+                insert_co_borrower(&mut tx, keyring, app.application_id, co_borrower).await?;
+            }
+            if let Some(draft_id) = draft_id {
+                claim_draft(&mut tx, draft_id, user.user_id, app.application_id).await?;
+            }
+            // The first history row is what makes the applicant its owner
+            record_created_in(&mut tx, app.application_id, ApplicationStatus::Submitted, Some(user.user_id)).await?;
+            assign_consultant_in(&mut tx, app.application_id).await?;
+            let submitted = json!({
+                "application_id": app.application_id,
+                "user_id": user.user_id,
+                "application_type": household.application_type() as i32,
+                "desired_loan_amount": application.desired_loan_amount,
+            });
+            record_event(&mut tx, EventType::ApplicationSubmitted, app.application_id, &submitted).await?;
+            tx.commit().await.map_err(|err| AppError::GenericError(err.to_string()))?;
+            Ok::<_, AppError>(app)
+        }
+        .await;
+        match inserted
+        {
+            Ok(app) => {
+                tracing::info!(application_id = app.application_id, "Application submitted");
+                // Del / Invalidate Redis Key to force a DB fetch
+                // let mut con = r_state.r_pool.get().await.unwrap();
+                // let key = format!("{}:{}", "query", "location_options");
+                // let deleted: RedisResult<bool> = con.del(&key).await;
+                // match deleted {
+                //     Ok(bool) => {
+                //         println!("Key:{} -> {}", &key, {if bool {"Found & Deleted"} else {"Not Found"}});
+                //     },
+                //     Err(err) => println!("Error: {}", err)
+                // }
+                let user_alert = UserAlert::from((format!("Location added successfully: ID #{:?}", app.application_id).as_str(), "alert_success"));
+                let template_data = json!({
+                    "user_alert": user_alert,
+                    "user": user,
+                });
+
+                // return OffersTemplate {offers: &offers, lc_offers: Some(lc_offers), message: None}.into_response()
+                let application_id = app.application_id;
+                let user_id = user.user_id;
+                let scorer = state.lock().unwrap().scorer.clone();
+                let score = score_application(&pool, scorer.as_deref(), application_id, &application, &household).await;
+                let underwriting_input = UnderwritingInput {
+                    state: application.state.clone(),
+                    annual_income: application.annual_income,
+                    desired_loan_amount: application.desired_loan_amount,
+                    emp_length: application.emp_length,
+                    loan_purpose: application.loan_purpose,
+                    homeownership: application.homeownership,
+                    credit_file: None,
+                    credit_score_pd: None,
+                    monthly_debt: household.monthly_debt,
+                    co_borrower: household.co_borrower,
+                }
+                .with_score(score.as_ref());
+                // Changes made in the background are still put down to this request
+                let audit_context = current_audit_context();
+                let _ = tokio::task::Builder::new().name("comp_offer_task").spawn(in_audit_context(audit_context, async move {
+                    if let Err(err) = transition_application(&pool, application_id, ApplicationStatus::UnderReview, None, None).await {
+                        tracing::error!(application_id, error = ?err, "Application not moved to review");
+                    }
+                    let flagged = match screen_application(&pool, application_id).await {
+                        Ok(flags) => !flags.is_empty(),
+                        // Unscreened applications wait for a reviewer too
+                        Err(err) => {
+                            tracing::error!(application_id, error = ?err, "Application not screened");
+                            true
+                        }
+                    };
+                    let decision = underwrite_application(&pool, application_id, &underwriting_input).await;
+                    sleep(Duration::from_millis(5000)).await;
+                    // Fraud flags always go to a reviewer, whatever the rules decided
+                    if flagged {
+                        return;
+                    }
+                    let (status, reason, comp_offer) = match decision {
+                        Ok(decision) if decision.decision == Decision::Decline => {
+                            let codes: Vec<&str> = decision.reasons.iter().map(|r| r.code.as_str()).collect();
+                            (ApplicationStatus::Declined, codes.join(", "), None)
+                        }
+                        Ok(decision) if decision.decision == Decision::Approve => {
+                            let profile = AffordabilityProfile::new(&underwriting_input, policy_max_dti(RuleSet::load_active().ok().as_ref()));
+                            // Declined applications get no comp offer, and it's cut to what the applicant can afford
+                            match comp_offer(score.as_ref()).map(|comp_offer| profile.affordable_offer(&comp_offer)) {
+                                Some(Some(comp_offer)) => (ApplicationStatus::OffersPresented, String::new(), Some(comp_offer)),
+                                Some(None) => (ApplicationStatus::Declined, DTI_DECLINE_CODE.to_owned(), None),
+                                None => (ApplicationStatus::Declined, CREDIT_SCORE_DECLINE_CODE.to_owned(), None),
+                            }
+                        }
+                        // Referred, or the rules couldn't be run. Either way it waits in UnderReview for a reviewer
+                        Ok(_) => return,
+                        Err(err) => {
+                            tracing::error!(application_id, error = ?err, "Application not underwritten");
+                            return;
+                        }
+                    };
+                    // The offer goes out through the outbox, only once the move to OffersPresented commits
+                    let transitioned = async {
+                        let mut tx = begin_audited(&pool).await.map_err(|err| AppError::GenericError(err.to_string()))?;
+                        transition_application_in(&mut tx, application_id, status, None, Some(&reason)).await?;
+                        if let Some(comp_offer) = &comp_offer {
+                            let created = json!({ "application_id": application_id, "user_id": user_id, "offer": comp_offer });
+                            record_event(&mut tx, EventType::OfferCreated, application_id, &created).await?;
+                        }
+                        tx.commit().await.map_err(|err| AppError::GenericError(err.to_string()))?;
+                        Ok::<_, AppError>(())
+                    }
+                    .await;
+                    if let Err(err) = transitioned {
+                        tracing::error!(application_id, error = ?err, "Underwriting decision not applied");
+                        return;
+                    }
+                    if status == ApplicationStatus::Declined {
+                        if let Err(err) = issue_adverse_action_notice(&pool, application_id, user_id, DEFAULT_NOTICE_DIR).await {
+                            tracing::error!(application_id, error = ?err, "Adverse action notice not issued");
+                        }
+                    }
+                }));
+                return (Some(application_id), (StatusCode::CREATED, ApplyOffersTemplate { message: "Hey" }).into_response())
+            }
+            // A draft submitted twice
+            Err(err @ AppError::InvalidRequest(_)) => (None, err.into_response()),
+            Err(err) => {
+                tracing::error!(error = ?err, "Application not stored");
+                let user_alert = UserAlert::from((format!("Error adding location: {:?}", err).as_str(), "alert_error"));
+                return (None, StatusCode::INTERNAL_SERVER_ERROR.into_response())
+            }
+        }
+    }
+
     #[debug_handler]
     pub async fn apply(
         mut auth_session: AuthSession,
         State(state): State<Arc<Mutex<SharedState>>>,
+        ConnectInfo(addr): ConnectInfo<SocketAddr>,
         Extension(pool): Extension<PgPool>,
-        Form(application): Form<ApplicationInput>,
+        Form(mut application): Form<ApplicationInput>,
     ) -> impl IntoResponse {
         match auth_session.user {
             Some(user) => {
@@ -251,7 +516,16 @@ mod post {
                 // let lc_offer = mock_offer(1);
                 // let lc_offers = vec![&lc_offer];
                 dbg!(&application);
-                let is_valid = application.validate();
+                let household_input = application.validate().and_then(|_| application.household.parse(&application.ssn));
+                let household_input = match validate_address(&pool, &application.address_one, &application.address_two, &application.city, &application.state, &application.zip).await {
+                    Ok(Ok(address)) => {
+                        application.standardize_address(address);
+                        household_input
+                    }
+                    Ok(Err(errors)) => merge_errors(household_input, errors),
+                    Err(err) => return err.into_response(),
+                };
+                let is_valid = household_input.clone().map(|_| ());
                 if is_valid.is_err() {
                     let validation_response = get_validation_response(is_valid);
                     let mut headers = HeaderMap::new();
@@ -271,77 +545,7 @@ mod post {
                     )
                         .into_response();
                 } else {
-                    let ssn_str = application.ssn.replace("-", "");
-                    let dob = NaiveDate::parse_from_str(&application.dob, "%Y-%m-%d").unwrap();
-                    dbg!(&dob);
-                    // let ssn = ssn_str.parse::<i32>().unwrap();
-                    // let ssn_to_nacl = SsnToNacl { ssn: ssn };
-                    // let mut hasher = DefaultHasher::new();
-                    // ssn_to_nacl.hash(&mut hasher);
-                    // let ssn_nacl = hasher.finish();
-                    // println!("{:?}", &ssn_nacl);
-                    let app_slug = Uuid::new_v4().simple().to_string();
-                    match sqlx::query_as::<_, ApplicationPostResponse>(
-                        "INSERT INTO applications (application_slug, location_id, first_name, last_name, address_one, address_two, city, state, zip, phone, ssn_nacl, dob, marital_status, desired_loan_amount, loan_purpose, annual_income, homeownership, employment_status, emp_length) 
-                                VALUES ($1, $2, $3, $4, $5, NULLIF($6, ''), $7, $8, $9, NULLIF($10, ''), DIGEST($11, 'sha256'), NULLIF($12, '1900-01-01'), $13, $14, $15, $16, $17, $18, $19) RETURNING application_id",
-                    )
-                    .bind(app_slug)
-                    .bind(&application.location_id)
-                    .bind(&application.first_name)
-                    .bind(&application.last_name)
-                    .bind(&application.address_one)
-                    .bind(&application.address_two)
-                    .bind(&application.city)
-                    .bind(&application.state)
-                    .bind(&application.zip)
-                    .bind(&application.phone)
-                    .bind(ssn_str)
-                    .bind(dob)
-                    .bind(&application.marital_status)
-                    .bind(&application.desired_loan_amount)
-                    .bind(&application.loan_purpose)
-                    .bind(&application.annual_income)
-                    .bind(&application.homeownership)
-                    .bind(&application.employment_status)
-                    .bind(&application.emp_length)
-                    .fetch_one(&pool)
-                    .await
-                    {
-                        Ok(app) => {
-                            dbg!(app.application_id);
-                            // Del / Invalidate Redis Key to force a DB fetch
-                            // let mut con = r_state.r_pool.get().await.unwrap();
-                            // let key = format!("{}:{}", "query", "location_options");
-                            // let deleted: RedisResult<bool> = con.del(&key).await;
-                            // match deleted {
-                            //     Ok(bool) => {
-                            //         println!("Key:{} -> {}", &key, {if bool {"Found & Deleted"} else {"Not Found"}});
-                            //     },
-                            //     Err(err) => println!("Error: {}", err)
-                            // }
-                            let user_alert = UserAlert::from((format!("Location added successfully: ID #{:?}", app.application_id).as_str(), "alert_success"));
-                            let template_data = json!({
-                                "user_alert": user_alert,
-                                "user": user,
-                            });
-
-                            // return OffersTemplate {offers: &offers, lc_offers: Some(lc_offers), message: None}.into_response()
-                            let _ = tokio::task::Builder::new().name("comp_offer_task").spawn(async move {
-                                sleep(Duration::from_millis(5000)).await;
-                                let comp_offer = get_comp_offer(app);
-                                // Find comp record in credit file CSV and use that to decision on
-                                // Get Id form that, then look at load CSV with that ID and see if in good shape.
-                                // If so, give offer. If not, decline.
-                                state.lock().unwrap().offer_tx.clone().unwrap().send(comp_offer);
-                            });
-                            return (StatusCode::CREATED, ApplyOffersTemplate { message: "Hey" }).into_response()
-                        }
-                        Err(err) => {
-                            dbg!(&err);
-                            let user_alert = UserAlert::from((format!("Error adding location: {:?}", err).as_str(), "alert_error"));
-                            return StatusCode::INTERNAL_SERVER_ERROR.into_response()
-                        }
-                    }
+                    create_application(state, pool, addr, user, application, household_input.unwrap(), None).await.1
                 }
                 // OffersTemplate {offers: &offers, lc_offers: Some(lc_offers), message: None}.into_response()
             }
@@ -495,7 +699,8 @@ mod get {
     };
 
     use crate::{
-        config::{get_entry_type_options, get_state_options, FormErrorResponse, SelectOption},
+        config::{FormErrorResponse, SelectOption},
+        libs::lookup_cache::{cached_entry_type_options, cached_state_options},
         error::AppError,
         models::{
             self,
@@ -512,6 +717,7 @@ mod get {
         Query(params): Query<HashMap<String, String>>,
         auth_session: AuthSession,
         Extension(pool): Extension<PgPool>,
+        Extension(r_pool): Extension<RedisPool>,
     ) -> Response {
         // let msg = ActorMessage::RegularMessage { text: "Hey from get_users()".to_owned() };
         // let _ = state.lock().unwrap().actor_handle.sender.send(msg).await;
@@ -526,7 +732,7 @@ mod get {
             AppError::InternalServerError
         });
 
-        let state_options = get_state_options(&pool).await;
+        let state_options = cached_state_options(&pool, &r_pool).await;
 
         let current_user = match auth_session.user {
             Some(user) => Some(CurrentUser {
@@ -568,6 +774,7 @@ mod get {
         Query(params): Query<HashMap<String, String>>,
         auth_session: AuthSession,
         Extension(pool): Extension<PgPool>,
+        Extension(r_pool): Extension<RedisPool>,
     ) -> Response {
         // let msg = ActorMessage::RegularMessage { text: "Hey from get_users()".to_owned() };
         // let _ = state.lock().unwrap().actor_handle.sender.send(msg).await;
@@ -582,7 +789,7 @@ mod get {
             AppError::InternalServerError
         });
 
-        let entry_type_options = get_entry_type_options(&pool).await;
+        let entry_type_options = cached_entry_type_options(&pool, &r_pool).await;
 
         dbg!(&entry_type_options);
 
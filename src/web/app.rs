@@ -6,9 +6,57 @@ use crate::{
         employment_options, get_state_options, marital_status_options, purpose_options,
         FormErrorResponse, SelectOption,
     },
-    controllers::{offer_controller::get_offers, ticker_controller::get_ticker},
+    controllers::{
+        application_controller::{
+            create_adverse_action_notice, create_fraud_screening, create_transition,
+            get_adverse_action_notice, get_application_status, get_co_borrower, get_fraud_flags,
+        },
+        application_draft_controller::{
+            create_application_draft, delete_application_draft, get_application_draft,
+            get_application_drafts, get_draft_step, save_draft_step,
+        },
+        audit_controller::{
+            get_application_history, get_application_history_json, get_loan_history,
+            get_loan_history_json, get_offer_history, get_offer_history_json, get_user_history,
+            get_user_history_json,
+        },
+        document_controller::{
+            create_document, create_verification, get_document, get_documents, get_documents_json,
+        },
+        credit_file_controller::{
+            get_credit_file_profile, get_credit_file_profile_json, get_similar_borrowers,
+            post_similar_borrowers,
+        },
+        loan_controller::{
+            create_payment, create_reversal, create_statement, get_loan_tape, get_payments,
+            get_payoff_quote, get_statement,
+        },
+        offer_controller::{get_affordability, get_application_offers, get_offers},
+        portfolio_controller::{get_portfolio, get_portfolio_json},
+        review_controller::{
+            create_claim, create_review, create_review_note, delete_claim, get_review_notes,
+            get_review_queue, get_review_queue_json,
+        },
+        ticker_controller::get_ticker,
+        underwriting_controller::{
+            get_application_decisions, get_underwriting_rules, post_underwriting_dry_run,
+        },
+    },
     error::AppError,
-    libs::pg_notify_handle::{start_listening, ActionType, Payload},
+    libs::{
+        application_document::MAX_DOCUMENT_BYTES,
+        audit_trail::audit_context,
+        credit_file_import::ImportProgress,
+        credit_file_profile::invalidate_on_credit_file_changes,
+        credit_scorer::{CreditScorer, DEFAULT_MODEL_DIR},
+        delinquency::{run_nightly, DelinquencyConfig},
+        document_storage::storage_from_env,
+        lookup_cache::{invalidate_on_reconnect, invalidate_on_table_update},
+        mailer::{run_mail_relay, transport_from_env},
+        outbox::{BroadcastSink, OutboxEvent, OutboxRelay, RedisSink},
+        pg_notify_handle::{NotifyDispatcher, TableUpdate},
+        portfolio_analytics::invalidate_on_loan_changes,
+    },
     models::{
         self,
         application::ApplicationTemplate,
@@ -33,8 +81,9 @@ use axum::http::{
 use axum::{
     body::Body,
     debug_handler,
-    extract::{ConnectInfo, Query, State},
+    extract::{ConnectInfo, DefaultBodyLimit, Query, State},
     http::{Request, StatusCode, Uri},
+    middleware,
     response::{IntoResponse, Response},
     routing::{get, post},
     Extension, Json, Router,
@@ -53,7 +102,7 @@ use futures_util::{
 use models::auth::User;
 use sendgrid::error::SendgridError;
 use sendgrid::v3::*;
-use serde::{Deserialize, Serialize};
+use serde::{de::IgnoredAny, Deserialize, Serialize};
 use serde_json::json;
 use sqlx::{types::time::Date, Pool, Postgres, QueryBuilder};
 use sqlx::FromRow;
@@ -80,7 +129,7 @@ use tower_http::{
     cors::{Any, CorsLayer},
     services::ServeDir,
 };
-use tracing::{info, Level};
+use tracing::{debug, error, info, Level};
 use casbin::prelude::*;
 
 // mod errors;
@@ -97,13 +146,17 @@ pub struct AppState {
 #[derive()]
 pub struct SharedState {
     pub enforcer: Enforcer,
-    pub offer_tx: Option<broadcast::Sender<Offer>>,
-    pub offer_rx: Option<broadcast::Receiver<Offer>>,
     pub name: Option<String>,
     pub actor_handle: ActorHandle,
     pub user_set: Mutex<HashSet<String>>,
     // Channel used to send messages to all connected clients.
     pub tx: broadcast::Sender<String>,
+    // Credit file import progress, streamed over SSE
+    pub import_tx: broadcast::Sender<ImportProgress>,
+    // Outbox events as the relay publishes them, loan status changes included
+    pub event_tx: broadcast::Sender<OutboxEvent>,
+    // Latest trained credit scorer, read once at startup. Restart to pick up a new model
+    pub scorer: Option<Arc<CreditScorer>>,
 }
 
 pub struct App {
@@ -467,32 +520,48 @@ impl App {
 
         let user_set = Mutex::new(HashSet::new());
         let (tx, _rx) = broadcast::channel(100);
+        let (import_tx, _import_rx) = broadcast::channel(100);
+        let (event_tx, _event_rx) = broadcast::channel(1000);
+
+        let outbox_relay = OutboxRelay::new(
+            self.pool.clone(),
+            vec![
+                Arc::new(BroadcastSink { events: event_tx.clone() }),
+                Arc::new(RedisSink { r_pool: self.r_pool.clone() }),
+            ],
+        );
+        let outbox_waker = outbox_relay.waker();
 
-        let channels = vec!["table_update", "new_app_notification"];
-        let hm: HashMap<String, String> = HashMap::new();
-        let constants = Arc::new(RwLock::new(hm));
-
-        let call_back = move |payload: Payload| {
-            match payload.action_type {
-                ActionType::INSERT => {
-                    let mut constants = constants.write().unwrap();
-                    constants.insert(payload.application_slug, payload.first_name);
-                }
-                ActionType::UPDATE => {
-                    let mut constants = constants.write().unwrap();
-                    constants.insert(payload.application_slug, payload.first_name);
-                }
-                ActionType::DELETE => {
-                    let mut constants = constants.write().unwrap();
-                    constants.remove(&payload.application_slug);
-                }
-            };
-            println!("constants: {:?}", constants);
-            println!(" ");
-        };
-        let mut listener = PgListener::connect_with(&self.pool).await.unwrap();
-        listener.listen_all(channels.clone()).await?;
-        tokio::task::Builder::new().name("pg_notify_task").spawn({ start_listening(listener, channels, call_back) });
+        let dispatcher = NotifyDispatcher::new()
+            .on("table_update", {
+                let r_pool = self.r_pool.clone();
+                move |update: TableUpdate| invalidate_on_table_update(r_pool.clone(), update)
+            })
+            .on("loans_changed", {
+                let r_pool = self.r_pool.clone();
+                move |update: TableUpdate| invalidate_on_loan_changes(r_pool.clone(), update)
+            })
+            .on("credit_file_changed", {
+                let r_pool = self.r_pool.clone();
+                move |update: TableUpdate| invalidate_on_credit_file_changes(r_pool.clone(), update)
+            })
+            .on("outbox_event", move |_: IgnoredAny| {
+                outbox_waker.notify_one();
+                async {}
+            })
+            .on_reconnect({
+                let r_pool = self.r_pool.clone();
+                move || invalidate_on_reconnect(r_pool.clone())
+            });
+        tokio::task::Builder::new().name("pg_notify_task").spawn(dispatcher.run(self.pool.clone()))?;
+        tokio::task::Builder::new()
+            .name("delinquency_task")
+            .spawn(run_nightly(self.pool.clone(), DelinquencyConfig::from_env()?))?;
+        tokio::task::Builder::new().name("outbox_task").spawn(outbox_relay.run())?;
+        tokio::task::Builder::new()
+            .name("mail_task")
+            .spawn(run_mail_relay(self.pool.clone(), transport_from_env()?))?;
+        let document_storage = storage_from_env()?;
 
         // println!("Connecting to - {}", kraken);
         // let (ws_stream, _) = connect_async(kraken).await.expect("Failed to connect");
@@ -524,14 +593,23 @@ impl App {
 
         // let state = AppState { name: None, actor_handle: actor_handle.clone() };
 
+        let scorer = match CreditScorer::load_latest(DEFAULT_MODEL_DIR) {
+            Ok(scorer) => scorer.map(Arc::new),
+            Err(err) => {
+                error!(error = ?err, "Could not load the credit scorer, applications go unscored");
+                None
+            }
+        };
+
         let state = Arc::new(Mutex::new(SharedState {
             enforcer: e,
             name: None,
             actor_handle: actor_handle.clone(),
-            offer_tx: None,
-            offer_rx: None,
             tx: tx,
             user_set: user_set,
+            import_tx,
+            event_tx,
+            scorer,
         }));
 
         let offer_handle = ActorHandle::new();
@@ -611,10 +689,88 @@ impl App {
             .route("/users", get(get_users))
             .route("/offers", get(get_offers))
             .route("/ticker", get(get_ticker))
+            .route("/credit-file/profile", get(get_credit_file_profile))
+            .route("/credit-file/profile.json", get(get_credit_file_profile_json))
+            .route("/credit-file/similar", post(post_similar_borrowers))
+            .route("/credit-file/:borrower_id/similar", get(get_similar_borrowers))
+            .route("/loans/tape.csv", get(get_loan_tape))
+            .route("/loans/:loan_id/payments", get(get_payments).post(create_payment))
+            .route(
+                "/loans/:loan_id/payments/:payment_id/reverse",
+                post(create_reversal),
+            )
+            .route("/loans/:loan_id/payoff", get(get_payoff_quote))
+            .route(
+                "/loans/:loan_id/statements/:period",
+                get(get_statement).post(create_statement),
+            )
+            .route("/applications/:application_id/status", get(get_application_status))
+            .route(
+                "/applications/:application_id/transitions",
+                post(create_transition),
+            )
+            .route("/applications/:application_id/decisions", get(get_application_decisions))
+            .route("/applications/:application_id/co-borrower", get(get_co_borrower))
+            .route(
+                "/applications/:application_id/fraud-flags",
+                get(get_fraud_flags).post(create_fraud_screening),
+            )
+            .route(
+                "/applications/:application_id/adverse-action",
+                get(get_adverse_action_notice).post(create_adverse_action_notice),
+            )
+            .route("/underwriting/rules", get(get_underwriting_rules))
+            .route("/underwriting/dry-run", post(post_underwriting_dry_run))
+            .route("/portfolio", get(get_portfolio))
+            .route("/portfolio.json", get(get_portfolio_json))
+            .route("/review-queue", get(get_review_queue))
+            .route("/review-queue.json", get(get_review_queue_json))
+            .route("/applications/:application_id/claim", post(create_claim).delete(delete_claim))
+            .route(
+                "/applications/:application_id/notes",
+                get(get_review_notes).post(create_review_note),
+            )
+            .route("/applications/:application_id/review", post(create_review))
+            .route(
+                "/apply/drafts",
+                get(get_application_drafts).post(create_application_draft),
+            )
+            .route(
+                "/apply/drafts/:draft_id",
+                get(get_application_draft).delete(delete_application_draft),
+            )
+            .route("/apply/drafts/:draft_id/:step", get(get_draft_step).post(save_draft_step))
+            .route(
+                "/applications/:application_id/documents",
+                get(get_documents)
+                    .post(create_document)
+                    .layer(DefaultBodyLimit::max(MAX_DOCUMENT_BYTES + 64 * 1024)),
+            )
+            .route("/applications/:application_id/documents.json", get(get_documents_json))
+            .route(
+                "/applications/:application_id/documents/:document_id",
+                get(get_document),
+            )
+            .route(
+                "/applications/:application_id/documents/:document_id/verification",
+                post(create_verification),
+            )
+            .route("/applications/:application_id/offers", get(get_application_offers))
+            .route("/applications/:application_id/affordability", get(get_affordability))
+            .route("/applications/:application_id/history", get(get_application_history))
+            .route("/applications/:application_id/history.json", get(get_application_history_json))
+            .route("/offers/:offer_id/history", get(get_offer_history))
+            .route("/offers/:offer_id/history.json", get(get_offer_history_json))
+            .route("/loans/:loan_id/history", get(get_loan_history))
+            .route("/loans/:loan_id/history.json", get(get_loan_history_json))
+            .route("/users/:user_id/history", get(get_user_history))
+            .route("/users/:user_id/history.json", get(get_user_history_json))
             .route_layer(login_required!(Backend, login_url = "/login"))
             // `POST /users` goes to `create_user`
             .route("/users", post(create_user))
             .merge(auth::router())
+            // Inside the auth layer, it needs the signed in user
+            .layer(middleware::from_fn(audit_context))
             .layer(auth_layer);
         // build our application with a route
         let app = Router::new()
@@ -627,6 +783,8 @@ impl App {
             })
             .layer(cors)
             .layer(Extension(self.pool))
+            .layer(Extension(document_storage))
+            .layer(Extension(self.r_pool))
             .layer(
                 TraceLayer::new_for_http()
                     .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
@@ -5,7 +5,13 @@ use crate::{models::auth::CurrentUser, users::AuthSession};
 use askama::Template;
 use async_stream::try_stream;
 use axum::response::sse::{Event, Sse};
-use axum::{debug_handler, http::StatusCode, response::IntoResponse, routing::get, Router};
+use axum::{
+    debug_handler,
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+    Router,
+};
 use axum_extra::{headers, TypedHeader};
 use serde::Deserialize;
 use sqlx::FromRow;
@@ -39,14 +45,59 @@ pub fn router() -> Router<Arc<Mutex<SharedState>>> {
     Router::new()
         .route("/", get(self::get::protected))
         .route("/sse", get(self::get::event_stream))
-        .route("/trigger", get(self::get::trigger_call))
+        .route("/trigger", post(self::post::trigger_call))
+        .route("/import/progress", get(self::get::import_progress))
+        .route("/loans/events", get(self::get::loan_events))
+        .route("/events", get(self::get::outbox_events))
         .route("/metrics", get(self::get::metrics))
 }
 
+mod post {
+    use axum::{extract::State, Extension};
+    use sqlx::PgPool;
+
+    use crate::{
+        actors::actor::{ActorHandle, ActorMessage},
+        error::AppError,
+        libs::{
+            credit_file_import::configured_credit_file_csv, review_queue::ensure_active_consultant,
+        },
+    };
+
+    use super::*;
+
+    /// Imports the configured credit file. Consultants only.
+    pub async fn trigger_call(
+        auth_session: AuthSession,
+        State(state): State<Arc<Mutex<SharedState>>>,
+        Extension(pool): Extension<PgPool>,
+    ) -> Result<StatusCode, AppError> {
+        let user = auth_session.user.ok_or(AppError::UserDoesNotExist)?;
+        ensure_active_consultant(&pool, user.user_id).await?;
+        let offer_handle = ActorHandle::new();
+        let progress_tx = state.lock().unwrap().import_tx.clone();
+
+        // Progress is streamed on /import/progress, so don't wait on the response here
+        let offer_msg = ActorMessage::PopulateDB {
+            file_name: configured_credit_file_csv(),
+            respond_to: None,
+            pool: Some(pool),
+            progress_tx: Some(progress_tx),
+            report: None,
+        };
+
+        let _ = offer_handle.sender.send(offer_msg).await;
+        Ok(StatusCode::ACCEPTED)
+    }
+}
+
 mod get {
     use std::{collections::HashMap, convert::Infallible, time::Duration};
 
-    use axum::{extract::State, Extension};
+    use axum::{
+        extract::{Query, State},
+        Extension,
+    };
     use chrono::NaiveDate;
     use futures_util::{stream, Stream, StreamExt};
     use rand::{distributions::Alphanumeric, Rng};
@@ -59,7 +110,7 @@ mod get {
     };
 
     use crate::{
-        actors::actor::{get_mock_offers, mock_offer, ActorHandle, ActorMessage, LoopInstructions}, controllers::metrics_controller::task_dump, models::{credit_file::mock_credit_file, loan::mock_loan, offer::Offer}
+        actors::actor::{get_mock_offers, mock_offer, ActorHandle, ActorMessage, LoopInstructions}, controllers::metrics_controller::task_dump, libs::outbox::EventType, models::{credit_file::mock_credit_file, loan::mock_loan, offer::Offer}
     };
 
     use super::*;
@@ -98,19 +149,77 @@ mod get {
         }
     }
 
-    pub async fn trigger_call(State(state): State<Arc<Mutex<SharedState>>>, Extension(pool): Extension<PgPool>) -> () {
-        let offer_handle = ActorHandle::new();
-        let (send, recv) = oneshot::channel();
+    pub async fn import_progress(
+        State(state): State<Arc<Mutex<SharedState>>>,
+    ) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+        let mut progress_rx = state.lock().unwrap().import_tx.subscribe();
 
-        let offer_msg = ActorMessage::PopulateDB {
-            respond_to: Some(send),
-            text: "".to_owned(),
-        };
-        // With default InitOptions
+        Sse::new(async_stream::stream! {
+            loop {
+                match progress_rx.recv().await {
+                    Ok(progress) => {
+                        let event = Event::default()
+                            .event("import_progress")
+                            .data(serde_json::to_string(&progress).unwrap_or_default());
+                        yield Ok(event);
+                        if progress.done {
+                            break;
+                        }
+                    }
+                    // Slow client, skip to the latest progress
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        })
+        .keep_alive(axum::response::sse::KeepAlive::default())
+    }
 
-        let _ = offer_handle.sender.send(offer_msg).await;
-        // let _ = offer_handle.sender.send(offer_loop_msg).await;
-        // state.lock().unwrap().offer_tx.send(rand::thread_rng().sample_iter(&Alphanumeric).take(5).map(char::from).collect::<String>());
+    pub async fn loan_events(
+        State(state): State<Arc<Mutex<SharedState>>>,
+    ) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+        let mut event_rx = state.lock().unwrap().event_tx.subscribe();
+
+        Sse::new(async_stream::stream! {
+            loop {
+                match event_rx.recv().await {
+                    Ok(outbox_event) if outbox_event.is(EventType::LoanStatusChanged) => {
+                        let event = Event::default()
+                            .event("loan_status")
+                            .data(outbox_event.payload.to_string());
+                        yield Ok(event);
+                    }
+                    Ok(_) => continue,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        })
+        .keep_alive(axum::response::sse::KeepAlive::default())
+    }
+
+    /// Every event the outbox relay publishes, named by type with the event_id as the SSE id
+    pub async fn outbox_events(
+        State(state): State<Arc<Mutex<SharedState>>>,
+    ) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+        let mut event_rx = state.lock().unwrap().event_tx.subscribe();
+
+        Sse::new(async_stream::stream! {
+            loop {
+                match event_rx.recv().await {
+                    Ok(outbox_event) => {
+                        let event = Event::default()
+                            .event(&outbox_event.event_type)
+                            .id(outbox_event.event_id.to_string())
+                            .data(serde_json::to_string(&outbox_event).unwrap_or_default());
+                        yield Ok(event);
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        })
+        .keep_alive(axum::response::sse::KeepAlive::default())
     }
 
     #[debug_handler]
@@ -152,50 +261,54 @@ mod get {
         )
     }
 
+    /// Comp offers for the signed in applicant's applications, as they come off the outbox
     pub async fn event_stream(
+        auth_session: AuthSession,
         State(state): State<Arc<Mutex<SharedState>>>,
     ) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-        // let offer_handle = ActorHandle::new();
-        // let (send, mut recv) = mpsc::channel(8);
-
-        let (offer_tx, mut offer_rx) = broadcast::channel(5000);
-        // let offer_msg = ActorMessage::GetOffersBroadcast {respond_to: Some(&tx), offers: None };
-        // let _ = offer_handle.sender.send(offer_msg).await;
-        // let date = NaiveDate::from_ymd_opt(2025, 6, 3).unwrap();
-        // let default_offer = Offer { servicer_id: 1, max_amount: 12, min_amount: 2, terms: 22, percent_fee: 1.2, apr: 2.2, expires: date };
-        // let default_offers = vec![default_offer];
-        let mut lock = state.lock().unwrap();
-        lock.offer_tx = Some(offer_tx.clone());
-
-        // FIXME Comment this out to get crazy amount of tracing errors 
-        let offers = get_mock_offers(3);
-        let offer_tx_clone = offer_tx.clone();
-
-        let _ = tokio::task::Builder::new().name("offer_task").spawn(async move {
-            for offer in offers {
-                sleep(Duration::from_millis(5000)).await;
-                let _ = offer_tx.send(offer);
-            }
-        });
-
-        // let what = rx.recv().await;
-
-        // dbg!(&what);
-
-        let events = ["new_offer", "new_event"];
-
-        Sse::new(try_stream! {
-            match offer_rx.recv().await {
-                Ok(i) => {
-                    let event = Event::default()
-                        .event(events[rand::thread_rng().gen_range(0..events.len())])
-                        .data(i.terms.to_string());
-
-                    yield event;
-                },
+        let user_id = auth_session.user.map(|user| user.user_id);
+        let mut event_rx = state.lock().unwrap().event_tx.subscribe();
 
-                Err(e) => {
-                    tracing::error!(error = ?e, "Failed to get");
+        Sse::new(async_stream::stream! {
+            loop {
+                match event_rx.recv().await {
+                    Ok(outbox_event) if outbox_event.is(EventType::OfferCreated) => {
+                        let payload = &outbox_event.payload;
+                        if user_id.is_none() || payload["user_id"].as_i64() != user_id.map(i64::from) {
+                            continue;
+                        }
+                        let Ok(offer) = serde_json::from_value::<Offer>(payload["offer"].clone()) else {
+                            continue;
+                        };
+                        let event = Event::default()
+                            .event("new_offer")
+                            .id(outbox_event.event_id.to_string())
+                            .data(format!(
+                                "Up to ${} over {} months at {:.2}% APR",
+                                offer.max_amount, offer.terms, offer.apr
+                            ));
+                        yield Ok(event);
+                    }
+                    // The in-app notice of a delinquency status change, to the borrower only
+                    Ok(outbox_event) if outbox_event.is(EventType::LoanStatusChanged) => {
+                        let payload = &outbox_event.payload;
+                        if user_id.is_none() || payload["borrower_user_id"].as_i64() != user_id.map(i64::from) {
+                            continue;
+                        }
+                        let event = Event::default()
+                            .event("loan_status")
+                            .id(outbox_event.event_id.to_string())
+                            .data(format!(
+                                "Loan {} is now {}, {} days past due",
+                                payload["loan_id"],
+                                payload["to"].as_str().unwrap_or_default(),
+                                payload["days_past_due"]
+                            ));
+                        yield Ok(event);
+                    }
+                    Ok(_) => continue,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
                 }
             }
         })